@@ -0,0 +1,38 @@
+
+extern crate mo_gc;
+
+use mo_gc::bench::{compare, compare_single_threaded, BenchResult, Lifetime, Workload};
+
+
+fn main() {
+    let workloads = [("short lived", Lifetime::Uniform(100)),
+                     ("immediate", Lifetime::Immediate),
+                     ("long lived", Lifetime::Fixed(100000))];
+
+    for &(name, lifetime) in workloads.iter() {
+        let mut workload = Workload::new();
+        workload.lifetime = lifetime;
+        workload.threads = 2;
+
+        println!("{}: {:?}", name, workload);
+        print_results(&compare(&workload));
+    }
+
+    // the per-object cost of the single threaded mode, on one app thread
+    let workload = Workload::new();
+    println!("single app thread: {:?}", workload);
+    print_results(&compare_single_threaded(&workload));
+}
+
+
+fn print_results(results: &[(&'static str, BenchResult)]) {
+    for &(config, ref result) in results.iter() {
+        println!("    {:>10}: {:>12.0} objects/s, peak heap {:>8}, peak buffers {:>4}, \
+                  duty {:.2}",
+                 config,
+                 result.throughput(),
+                 result.peak_heap,
+                 result.peak_journal_buffers,
+                 result.duty_cycle);
+    }
+}
@@ -4,10 +4,11 @@ use stopwatch::Stopwatch;
 
 extern crate mo_gc;
 
+use std::env;
 use std::thread;
 use std::time::Duration;
 
-use mo_gc::{GcThread, GcRoot, Trace, StatsLogger};
+use mo_gc::{DefaultLogger, GcConfig, GcThread, GcRoot, ParHeap, Trace, StatsLogger};
 
 
 const THING_SIZE: usize = 8;
@@ -48,8 +49,14 @@ fn app() {
 }
 
 
+/// Pass `--single-threaded` to compare against the GC's single app thread mode
 fn main() {
-    let gc = GcThread::spawn_gc();
+    let mut config = GcConfig::new();
+    config.single_threaded = env::args().any(|arg| arg == "--single-threaded");
+
+    let gc = GcThread::spawn_gc_with_config(config,
+                                            ParHeap::new(config.shard_count()),
+                                            DefaultLogger::new());
 
     let app_handle = gc.spawn(|| app());
 
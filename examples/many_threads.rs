@@ -0,0 +1,85 @@
+extern crate stopwatch;
+use stopwatch::Stopwatch;
+
+extern crate mo_gc;
+
+use mo_gc::{Gc, GcRoot, GcThread, StatsLogger, Trace, TraceStack};
+
+
+const THREAD_COUNT: usize = 48;
+const LIST_COUNT: usize = 200;
+const LIST_LENGTH: usize = 1000;
+
+
+struct Link {
+    index: usize,
+    next: Gc<Link>,
+}
+
+
+unsafe impl Trace for Link {
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, heap: &mut TraceStack) {
+        self.next.trace(heap);
+    }
+}
+
+
+// Build LIST_COUNT linked lists, holding each one until the next is built and checking that it
+// is still whole before letting it go
+fn app() {
+    let mut previous: Option<GcRoot<Link>> = None;
+
+    for _ in 0..LIST_COUNT {
+        let mut head = GcRoot::new(Link {
+            index: 0,
+            next: Gc::null(),
+        });
+
+        for index in 1..LIST_LENGTH {
+            head = GcRoot::new(Link {
+                index: index,
+                next: head.as_gc(),
+            });
+        }
+
+        if let Some(list) = previous.take() {
+            let mut link = list.as_gc();
+            for index in (0..LIST_LENGTH).rev() {
+                assert!(link.index == index);
+                link = link.next;
+            }
+        }
+
+        previous = Some(head);
+    }
+}
+
+
+fn main() {
+    let gc = GcThread::spawn_gc();
+
+    let sw = Stopwatch::start_new();
+
+    let apps: Vec<_> = (0..THREAD_COUNT).map(|_| gc.spawn(|| app())).collect();
+    for app in apps {
+        app.join().expect("app failed");
+    }
+
+    let elapsed_ms = sw.elapsed_ms();
+    let count = (THREAD_COUNT * LIST_COUNT * LIST_LENGTH) as i64;
+    println!("{} app threads allocated {} objects in {}ms, {} objects per second",
+             THREAD_COUNT,
+             count,
+             elapsed_ms,
+             (count * 1000) / elapsed_ms);
+
+    let logger = gc.join().expect("gc failed");
+    logger.dump_to_stdout();
+
+    assert!(logger.journals_disconnected() == THREAD_COUNT);
+    assert!(logger.total_dropped() == THREAD_COUNT * LIST_COUNT * LIST_LENGTH);
+}
@@ -4,11 +4,12 @@ use stopwatch::Stopwatch;
 
 extern crate mo_gc;
 
-use mo_gc::{GcThread, GcRoot, Trace, StatsLogger};
+use mo_gc::{AppThread, GcThread, GcRoot, Trace, StatsLogger};
 
 
 const THING_SIZE: usize = 8;
 const THING_COUNT: i64 = 2500000;
+const BATCH_SIZE: usize = 32;
 
 
 struct Thing {
@@ -26,24 +27,34 @@ impl Thing {
 unsafe impl Trace for Thing {}
 
 
-fn app() {
+// Allocate and unroot THING_COUNT objects, holding back journal entries in batches of
+// `batch` or writing each one straight away if zero
+fn app(batch: usize) {
+    AppThread::batch_entries(batch);
+
     let sw = Stopwatch::start_new();
 
     for _ in 0..THING_COUNT {
         let _new = GcRoot::new(Thing::new());
     }
 
-    let per_second = (THING_COUNT * 1000) / sw.elapsed_ms();
-    println!("app allocated {} objects at {} objects per second", THING_COUNT, per_second);
-    println!("app finished in {}ms", sw.elapsed_ms());
+    let elapsed_ms = sw.elapsed_ms();
+    let per_second = (THING_COUNT * 1000) / elapsed_ms;
+    let ns_per_op = (elapsed_ms * 1000000) / (THING_COUNT * 2);
+    println!("app (batch {}) allocated {} objects at {} objects per second",
+             batch,
+             THING_COUNT,
+             per_second);
+    println!("app (batch {}) spent {}ns per journal entry", batch, ns_per_op);
+    println!("app (batch {}) finished in {}ms", batch, elapsed_ms);
 }
 
 
 fn main() {
     let gc = GcThread::spawn_gc();
 
-    let app_handle1 = gc.spawn(|| app());
-    let app_handle2 = gc.spawn(|| app());
+    let app_handle1 = gc.spawn(|| app(0));
+    let app_handle2 = gc.spawn(|| app(BATCH_SIZE));
 
     let logger = gc.join().expect("gc failed");
     logger.dump_to_stdout();
@@ -0,0 +1,70 @@
+//! Pinning threads to CPUs, for keeping the GC thread and its thread pool away from the cores app
+//! threads run on.
+//!
+//! CPUs are given as a bit mask, bit n for CPU n, so only the first 64 CPUs can be named. Threads
+//! inherit the affinity of the thread that spawns them, which is how the GC thread pool comes to
+//! be pinned along with the GC thread.
+//!
+//! Only Linux is supported. Elsewhere pinning does nothing and the affinity cannot be queried.
+
+
+/// Pin the calling thread to the CPUs in `mask`. Returns false if the platform does not support
+/// pinning or the mask names no CPU the thread is allowed to run on.
+pub fn set_current_thread(mask: u64) -> bool {
+    sys::set_current_thread(mask)
+}
+
+
+/// Return the CPUs the calling thread may run on, or `None` if the platform does not support
+/// querying it.
+pub fn current_thread() -> Option<u64> {
+    sys::current_thread()
+}
+
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::mem::size_of;
+
+    // the size of glibc's cpu_set_t, in words
+    const CPU_SET_WORDS: usize = 16;
+
+    extern "C" {
+        fn sched_setaffinity(pid: i32, cpusetsize: usize, mask: *const u64) -> i32;
+        fn sched_getaffinity(pid: i32, cpusetsize: usize, mask: *mut u64) -> i32;
+    }
+
+    pub fn set_current_thread(mask: u64) -> bool {
+        let mut set = [0u64; CPU_SET_WORDS];
+        set[0] = mask;
+
+        // pid 0 is the calling thread
+        unsafe { sched_setaffinity(0, size_of::<[u64; CPU_SET_WORDS]>(), set.as_ptr()) == 0 }
+    }
+
+    pub fn current_thread() -> Option<u64> {
+        let mut set = [0u64; CPU_SET_WORDS];
+
+        let result = unsafe {
+            sched_getaffinity(0, size_of::<[u64; CPU_SET_WORDS]>(), set.as_mut_ptr())
+        };
+
+        if result == 0 {
+            Some(set[0])
+        } else {
+            None
+        }
+    }
+}
+
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    pub fn set_current_thread(_mask: u64) -> bool {
+        false
+    }
+
+    pub fn current_thread() -> Option<u64> {
+        None
+    }
+}
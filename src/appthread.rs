@@ -1,18 +1,32 @@
 //! Types for the mutator to use to build data structures
 
 
-use std::cell::Cell;
-use std::mem::transmute;
+use std::cell::{Cell, RefCell};
+use std::cmp::{max, min};
+use std::intrinsics::abort;
+use std::mem::{align_of, forget, replace, size_of, transmute};
 use std::ops::{Deref, DerefMut};
-use std::ptr::{null, null_mut};
+use std::ptr::{self, copy_nonoverlapping, drop_in_place, null, null_mut};
 use std::raw::TraitObject;
+use std::slice;
+use std::sync::{Arc, mpsc};
 use std::sync::atomic::{AtomicPtr, Ordering};
 use std::thread;
+use std::time::Duration;
 
-use constants::{INC_BIT, JOURNAL_BUFFER_SIZE, NEW_BIT, TRAVERSE_BIT};
-use gcthread::{JournalSender, EntrySender};
-use heap::{Object, TraceStack};
+extern crate alloc;
+use self::alloc::heap::{allocate, deallocate};
+
+use cardtable::CardTable;
+use constants::{BATCH_BIT, EPHEMERAL_KIND, FLAGS_MASK, INC, INC_BIT, JOURNAL_BUFFER_SIZE,
+                KIND_BIT, MAX_ENTRY_BATCH, MAX_KINDS, NEW_BIT, PTR_ALIGN, PTR_MASK, TAG_BITS,
+                TAG_MASK, TENURE_BIT, TRAVERSE_BIT, UNTRACK_BIT};
+use gcthread::{EntrySender, GcCommand, JournalSender};
+use heap::{Object, RootEntry, TraceOps, TraceStack};
 use journal;
+#[cfg(feature = "checked")]
+use liveness;
+use statistics::{Accounting, CollectionProgress, CollectionReport};
 use trace::Trace;
 
 
@@ -21,17 +35,140 @@ thread_local!(
     static GC_JOURNAL: Cell<*const EntrySender> = Cell::new(null())
 );
 
+/// Each thread counts new allocations in the GC's shared Accounting
+thread_local!(
+    static GC_ACCOUNTING: Cell<*const Accounting> = Cell::new(null())
+);
+
+/// Each thread marks cards dirty in the GC's shared CardTable
+thread_local!(
+    static GC_CARDS: Cell<*const CardTable> = Cell::new(null())
+);
+
+/// Each thread can send requests to the GC
+thread_local!(
+    static GC_CONTROL: Cell<*const mpsc::Sender<GcCommand>> = Cell::new(null())
+);
+
+/// Whether this thread belongs to a GC, see `enter_gc_thread()`
+thread_local!(
+    static GC_THREAD: Cell<bool> = Cell::new(false)
+);
+
+/// Whether this thread holds back the journal entry of each object allocated by `Gc::new()`, see
+/// `AppThread::coalesce_new()`
+thread_local!(
+    static GC_COALESCE_NEW: Cell<bool> = Cell::new(false)
+);
+
+/// The held back journal entry of the object most recently allocated by `Gc::new()`, if any
+thread_local!(
+    static GC_PENDING_NEW: Cell<Option<Object>> = Cell::new(None)
+);
+
+/// Journal entries held back to be written together, see `AppThread::batch_entries()`
+thread_local!(
+    static GC_BATCH: RefCell<EntryBatch> = RefCell::new(EntryBatch {
+        entries: [Object { ptr: 0, vtable: 0 }; MAX_ENTRY_BATCH + 1],
+        len: 0,
+        size: 0,
+    })
+);
+
+
+/// The journal entries an app thread has held back to write in one go. Kept inline, without drop
+/// glue, so that the thread-local is still usable by roots dropped in thread-local destructors.
+struct EntryBatch {
+    /// one more than the largest batch, for a held back new object entry added with the last
+    entries: [Object; MAX_ENTRY_BATCH + 1],
+    len: usize,
+    /// the number of entries to hold back before writing them, zero if batching is disabled
+    size: usize,
+}
+
+
+impl EntryBatch {
+    fn push(&mut self, entry: Object) {
+        self.entries[self.len] = entry;
+        self.len += 1;
+    }
+}
+
+
+/// Sends an app thread's held back journal entries, then clears its thread-local pointers to its
+/// journal, accounting, card table and control channel when dropped. Declared after the journal
+/// in the app thread so that it is dropped first, whether the thread's function returns or
+/// panics, so that no held back entry is lost and nothing dropped or allocated later in the
+/// thread's teardown reaches freed memory.
+struct AppThreadGuard;
+
+
+impl Drop for AppThreadGuard {
+    fn drop(&mut self) {
+        flush_pending();
+
+        GC_CONTROL.with(|c| c.set(null()));
+        GC_CARDS.with(|c| c.set(null()));
+        GC_ACCOUNTING.with(|a| a.set(null()));
+        GC_JOURNAL.with(|j| j.set(null()));
+    }
+}
+
 
 /// GcBox struct and traits: a boxed object that is GC managed
+#[repr(C)]
 pub struct GcBox<T: Trace> {
     value: T,
 }
 
 
+/// A GC managed slice: the length followed inline by the elements, all in a single allocation.
+/// Created by `Gc::new_slice()` and dereferences to `[T]`.
+#[repr(C)]
+pub struct GcSlice<T: Trace> {
+    len: usize,
+    elements: [T; 0],
+}
+
+
 /// Root smart pointer, sends reference count changes to the journal.
 ///
 /// Whenever a reference to an object on the heap must be retained on the stack, this type must be
 /// used. It's use will ensure that the object will be seen as a root.
+///
+/// `GcRoot` is for the stack and for data that is not itself GC managed. Inside a GC managed
+/// object use `Gc`, or `GcAtomic` for a pointer that changes while the GC may be tracing: a
+/// `GcRoot` there keeps its object rooted for as long as the containing object lives, so a cycle
+/// through it is never collected, and each one journals its own increment. To prevent this
+/// mistake `GcRoot` does not implement `Trace`, so it cannot be allocated on the GC heap or held
+/// in a `GcVec`, and `no_trace!` rejects types that contain one:
+///
+/// ```compile_fail
+/// use mo_gc::{Gc, GcRoot};
+///
+/// fn nest(root: GcRoot<usize>) -> Gc<GcRoot<usize>> {
+///     Gc::new(root)
+/// }
+///
+/// fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate mo_gc;
+///
+/// use mo_gc::GcRoot;
+///
+/// struct Registry {
+///     entries: Vec<GcRoot<usize>>,
+/// }
+///
+/// no_trace!(Registry);
+///
+/// fn main() {}
+/// ```
+///
+/// A type that implements `Trace` by hand must not contain a `GcRoot` either.
 pub struct GcRoot<T: Trace> {
     ptr: *mut GcBox<T>,
 }
@@ -52,6 +189,26 @@ pub struct Gc<T: Trace> {
 }
 
 
+/// A GC managed allocation whose value has not been written yet, created by `Gc::new_uninit()`.
+/// The GC is not told about the object until it is initialized, so a collection in the meantime
+/// neither traces nor drops it. Dropping a `GcUninit` frees the allocation without dropping a
+/// value.
+pub struct GcUninit<T: Trace> {
+    ptr: *mut GcBox<T>,
+}
+
+
+/// Builds a structure of objects, such as a cycle, that the GC only learns about once it is
+/// complete. Objects are allocated with `alloc()` and can be freely linked together through the
+/// returned `Gc` handles. Nothing is written to the journal until `publish()`, so the GC never
+/// traces a partly built structure.
+///
+/// Objects that are never published are dropped along with the builder.
+pub struct GcBuilder<T: Trace> {
+    objects: Vec<*mut GcBox<T>>,
+}
+
+
 /// Atomic pointer type that points at a traceable object. This type is `Sync` and can be used to
 /// build concurrent data structures.
 ///
@@ -64,6 +221,60 @@ pub struct GcAtomic<T: Trace> {
 }
 
 
+/// A `Gc` that is `Send` and `Sync`, for keeping pointers in shared places such as a registry
+/// behind a lock. Every access is an atomic load or store of the underlying `GcAtomic`, so unlike
+/// `GcAtomic` it can be cloned, and two `SyncGc`s are equal if they point at the same object.
+///
+/// As with `Gc`, a `SyncGc` does not root the object. Kept inside a GC managed object it is
+/// traced; kept anywhere else, the object must be kept live by a root for as long as the `SyncGc`
+/// is used, which `root()` provides to any app thread that loads it.
+pub struct SyncGc<T: Trace> {
+    ptr: GcAtomic<T>,
+}
+
+
+/// The objects that were rooted when `GcThread::snapshot_roots()` was called. The snapshot holds
+/// a reference to each object, so they all stay live while it exists whatever happens to the roots
+/// they were captured from. A new context, such as a forked interpreter, can take roots of its own
+/// from the snapshot with `root()` to start out with the same live objects.
+///
+/// The references are released by a command sent to the GC thread when the snapshot is dropped,
+/// so it can be dropped on any thread.
+pub struct RootSnapshot {
+    entries: Vec<RootEntry>,
+    control: mpsc::Sender<GcCommand>,
+}
+
+
+/// Roots a batch of objects for the duration of a lexical scope, as an alternative to holding a
+/// `GcRoot` for each. Each object is rooted as it is added with `root()` and all of them are
+/// unrooted together when the scope is dropped: a decrement entry per object, written to the
+/// journal in one go with a single journal access rather than one each, and never held back by
+/// `AppThread::batch_entries()`. See also the `root_scope!` macro.
+///
+/// Must be created and dropped on an app thread.
+pub struct RootScope {
+    objects: Vec<Object>,
+}
+
+
+/// Root each of the given `Gc` pointers until the end of the enclosing scope, returning the
+/// `RootScope` that holds them. Bind the result to a name, not `_`, or the objects are unrooted
+/// straight away:
+///
+/// ```ignore
+/// let _scope = root_scope!(first, second);
+/// ```
+#[macro_export]
+macro_rules! root_scope {
+    ($($gc:expr),*) => {{
+        let mut scope = $crate::RootScope::new();
+        $(scope.root($gc);)*
+        scope
+    }};
+}
+
+
 /// An Application Thread, manages a thread-local reference to a tx channel
 ///
 /// TODO: a version of `spawn()` is required that can be called from an existing mutator thread.
@@ -71,8 +282,14 @@ pub struct AppThread;
 
 
 impl AppThread {
-    /// As thread::spawn but takes a journal Sender to initialize the thread_local instance with.
-    pub fn spawn_from_gc<F, T>(tx: JournalSender, f: F) -> thread::JoinHandle<T>
+    /// As thread::spawn but takes a journal Sender, the GC's allocation Accounting, CardTable and
+    /// control channel to initialize the thread_local instances with.
+    pub fn spawn_from_gc<F, T>(tx: JournalSender,
+                               accounting: Arc<Accounting>,
+                               cards: Arc<CardTable>,
+                               control: mpsc::Sender<GcCommand>,
+                               f: F)
+                               -> thread::JoinHandle<T>
         where F: FnOnce() -> T,
               F: Send + 'static,
               T: Send + 'static
@@ -86,9 +303,139 @@ impl AppThread {
                 j.set(&jtx);
             });
 
+            GC_ACCOUNTING.with(|a| {
+                a.set(&*accounting);
+            });
+
+            GC_CARDS.with(|c| {
+                c.set(&*cards);
+            });
+
+            GC_CONTROL.with(|c| {
+                c.set(&control);
+            });
+
+            // the journal and the rest are dropped when this closure returns or unwinds, before
+            // thread-local destructors run, so the guard must be dropped before them
+            let _guard = AppThreadGuard;
+
             f()
         })
     }
+
+    /// Ask the GC to read this thread's journal and collect, returning once it has done so. Any
+    /// objects this thread has already unrooted and which are unreachable have been dropped by
+    /// the time this returns. The calling thread blocks meanwhile, yielding its CPU to the GC.
+    ///
+    /// Must be called from an app thread. Panics if the GC thread has exited.
+    pub fn collect_now() {
+        let (tx, rx) = mpsc::channel();
+        flush_pending();
+
+        GC_CONTROL.with(|c| {
+            let control = c.get();
+            assert!(!control.is_null(), "collect_now() called from outside an app thread");
+
+            unsafe { &*control }.send(GcCommand::Collect(tx)).expect("GC thread has exited!");
+        });
+
+        rx.recv().expect("GC thread has exited!");
+    }
+
+    /// As `collect_now()` but aim to spend about `budget` collecting before returning, for callers
+    /// with a time budget such as a frame. Returns `Partial` if the collection is not done yet, in
+    /// which case the next call carries on with it. The budget is best-effort: a call can overrun
+    /// it by a whole collection step, see `YoungHeap::collect_in_steps()`.
+    ///
+    /// Must be called from an app thread. Panics if the GC thread has exited.
+    pub fn collect_in_steps(budget: Duration) -> CollectionProgress {
+        let (tx, rx) = mpsc::channel();
+        flush_pending();
+
+        GC_CONTROL.with(|c| {
+            let control = c.get();
+            assert!(!control.is_null(), "collect_in_steps() called from outside an app thread");
+
+            unsafe { &*control }
+                .send(GcCommand::CollectInSteps(budget, tx))
+                .expect("GC thread has exited!");
+        });
+
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// As `GcThread::minor_now_blocking()`: ask the GC to read the journals and run a single young
+    /// generation collection, returning its report once it is done.
+    ///
+    /// Must be called from an app thread. Panics if the GC thread has exited.
+    pub fn minor_now() -> CollectionReport {
+        let (tx, rx) = mpsc::channel();
+        flush_pending();
+
+        GC_CONTROL.with(|c| {
+            let control = c.get();
+            assert!(!control.is_null(), "minor_now() called from outside an app thread");
+
+            unsafe { &*control }.send(GcCommand::MinorOnly(tx)).expect("GC thread has exited!");
+        });
+
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// Ask the GC to read this thread's journal to empty on its next cycle, instead of a limited
+    /// number of entries per cycle as it shares its time between journals. The journal still
+    /// takes its turn with the others, and if `GcConfig::journal_drain_time` runs out first the
+    /// drain carries on in the following cycle. Call after a burst of allocation and before
+    /// blocking so that the burst is collected promptly. Does not block or wake the GC.
+    ///
+    /// Does nothing if called from outside an app thread.
+    pub fn request_drain() {
+        with_journal(|j| j.request_drain());
+    }
+
+    /// Enable or disable coalescing of new object entries on this thread. It is disabled by
+    /// default.
+    ///
+    /// `Gc::new()` followed by `Gc::root()` writes a new-object entry and then a separate
+    /// increment to the journal, where `GcRoot::new()` writes a single entry for both. While
+    /// coalescing is enabled the entry for the object most recently allocated with `Gc::new()` is
+    /// held back until the thread next writes to the journal, marks a card or asks for a
+    /// collection, so that a `root()` of that object straight away is folded into it, halving the
+    /// journal traffic of allocate-then-root code that cannot use `GcRoot::new()`.
+    ///
+    /// The GC does not know about a held back object, so neither collects it nor traces it. Store
+    /// a new object only through a `Gc` or `GcRoot`, whose write barrier sends the held back
+    /// entry first, never through a raw pointer. After storing one through interior mutability,
+    /// such as a `Cell` reached through `Deref`, call `Gc::write_barrier()` on the object holding
+    /// it, which sends the held back entry too.
+    pub fn coalesce_new(enabled: bool) {
+        if !enabled {
+            flush_pending();
+        }
+
+        GC_COALESCE_NEW.with(|c| c.set(enabled));
+    }
+
+    /// Hold back up to `size` journal entries on this thread and write them to the journal
+    /// together, or write each entry as it is made if `size` is zero, the default. `size` is
+    /// capped at `MAX_ENTRY_BATCH`.
+    ///
+    /// Writing an entry to the journal looks up the thread's journal and updates the journal
+    /// buffer each time. In a tight loop of allocation and rooting, batching entries amortizes
+    /// that over the batch. The batch is written out when it is full and at the same points as a
+    /// held back new object, see `coalesce_new()`: when the thread marks a card, asks for a
+    /// collection or exits. Allocating with `Gc::new()` while coalescing is enabled also writes
+    /// it out.
+    ///
+    /// Only decrements and new object entries are held back. An increment is written straight
+    /// away, after the entries held back before it: the reference it counts may have been taken
+    /// over from another thread, whose decrement of its own reference must not be read first.
+    pub fn batch_entries(size: usize) {
+        // a smaller batch may already be over the new size
+        flush_pending();
+
+        GC_BATCH.with(|b| b.borrow_mut().size = min(size, MAX_ENTRY_BATCH));
+    }
 }
 
 // Reference count functions. Only new-objects need to specify the traverse bit.
@@ -100,261 +447,2080 @@ fn as_traitobject<T: Trace>(object: &T) -> TraitObject {
 }
 
 
-/// Write a reference count increment to the journal for a newly allocated object
+/// Call `f` with this thread's journal. Does nothing if there is none, which is the case once the
+/// app thread's function has returned: a root dropped by a thread-local destructor then leaks its
+/// object, and an object allocated by one is never collected, rather than the journal being
+/// written after it has been freed.
+///
+/// Held back entries, the batch and then the new object entry, are sent before anything `f`
+/// writes, keeping the journal in the order it was written in.
 #[inline]
-fn write<T: Trace>(object: &T, is_new: bool, flags: usize) {
+fn with_journal<F: FnOnce(&EntrySender)>(f: F) {
     GC_JOURNAL.with(|j| {
-        let tx = unsafe { &*j.get() };
+        let tx = j.get();
+
+        // without a journal there is nowhere to send held back entries
+        GC_BATCH.with(|b| {
+            let mut batch = b.borrow_mut();
+            if !tx.is_null() {
+                for entry in batch.entries[..batch.len].iter() {
+                    unsafe { &*tx }.send(*entry);
+                }
+            }
+            batch.len = 0;
+        });
 
-        let tobj = as_traitobject(object);
+        if !tx.is_null() {
+            let tx = unsafe { &*tx };
 
-        // set the refcount-increment bit
-        let ptr = (tobj.data as usize) | flags;
+            if let Some(entry) = GC_PENDING_NEW.with(|p| p.get()) {
+                GC_PENDING_NEW.with(|p| p.set(None));
+                tx.send(entry);
+            }
 
-        // set the traversible bit
-        let mut vtable = tobj.vtable as usize;
-        if is_new && object.traversible() {
-            vtable |= TRAVERSE_BIT;
+            f(tx);
         }
-
-        tx.send(Object {
-            ptr: ptr,
-            vtable: vtable,
-        });
     });
 }
 
-// GcBox implementation
 
-impl<T: Trace> GcBox<T> {
-    fn new(value: T) -> GcBox<T> {
-        GcBox {
-            value: value,
-        }
-    }
+/// Send the held back entries, if any, to the journal.
+#[inline]
+fn flush_pending() {
+    with_journal(|_| {});
 }
 
 
-unsafe impl<T: Trace> Trace for GcBox<T> {
-    #[inline]
-    fn traversible(&self) -> bool {
-        self.value.traversible()
-    }
+/// Write an entry to this thread's journal, or add it to the batch if batching is enabled,
+/// writing out the batch once it is full. Increments of existing objects are never held back.
+#[inline]
+fn send_entry(entry: Object) {
+    let batched = GC_BATCH.with(|b| {
+        let mut batch = b.borrow_mut();
+        if batch.size == 0 || entry.ptr & FLAGS_MASK == INC {
+            return None;
+        }
 
-    #[inline]
-    unsafe fn trace(&self, heap: &mut TraceStack) {
-        self.value.trace(heap);
+        // a held back new object entry was written before this one
+        if let Some(pending) = GC_PENDING_NEW.with(|p| p.get()) {
+            GC_PENDING_NEW.with(|p| p.set(None));
+            batch.push(pending);
+        }
+
+        batch.push(entry);
+        Some(batch.len >= batch.size)
+    });
+
+    match batched {
+        None => with_journal(|tx| tx.send(entry)),
+        Some(true) => flush_pending(),
+        Some(false) => {}
     }
 }
 
-// GcRoot implementation
-
-impl<T: Trace> GcRoot<T> {
-    /// Put a new object on the heap and hand ownership to the GC, writing a reference count
-    /// increment to the journal.
-    pub fn new(value: T) -> GcRoot<T> {
-        let boxed = Box::new(GcBox::new(value));
-        write(&*boxed, true, NEW_BIT | INC_BIT);
 
-        GcRoot {
-            ptr: Box::into_raw(boxed)
+/// As `with_journal()` for this thread's allocation accounting.
+#[inline]
+fn with_accounting<F: FnOnce(&Accounting)>(f: F) {
+    GC_ACCOUNTING.with(|a| {
+        let accounting = a.get();
+        if !accounting.is_null() {
+            f(unsafe { &*accounting });
         }
-    }
+    });
+}
 
-    fn from_raw(ptr: *mut GcBox<T>) -> GcRoot<T> {
-        let root = GcRoot { ptr: ptr };
-        write(&*root, false, INC_BIT);
-        root
-    }
 
-    fn ptr(&self) -> *mut GcBox<T> {
-        self.ptr
-    }
+/// Send `command` to the GC of this thread. Returns false if this is not an app thread.
+pub fn send_command(command: GcCommand) -> bool {
+    GC_CONTROL.with(|c| {
+        let control = c.get();
+        if control.is_null() {
+            return false;
+        }
 
-    fn value(&self) -> &T {
-        unsafe { &(*self.ptr).value }
-    }
+        unsafe { &*control }.send(command).expect("GC thread has exited!");
+        true
+    })
+}
 
-    fn value_mut(&mut self) -> &mut T {
-        unsafe { &mut (*self.ptr).value }
-    }
+
+/// Mark the calling thread as belonging to a GC: the GC thread, its thread pool workers and the
+/// threads it spawns. GC managed objects cannot be allocated on such a thread, see
+/// `Trace::trace()`.
+pub fn enter_gc_thread() {
+    GC_THREAD.with(|g| g.set(true));
 }
 
 
-impl<T: Trace> Drop for GcRoot<T> {
-    fn drop(&mut self) {
-        write(&**self, false, 0);
+/// Panic if the calling thread belongs to a GC. A GC thread has no journal, so an object
+/// allocated on it would never be known to the GC: it would be neither traced nor dropped, nor
+/// would anything it points at be kept alive through it.
+#[inline]
+fn forbid_gc_thread() {
+    if GC_THREAD.with(|g| g.get()) {
+        panic!("GC managed objects cannot be allocated on a GC thread, such as in Trace::trace()");
     }
 }
 
 
-impl<T: Trace> Deref for GcRoot<T> {
-    type Target = T;
-
-    fn deref(&self) -> &T {
-        self.value()
-    }
+/// As `with_journal()` for this thread's card table.
+#[inline]
+fn with_cards<F: FnOnce(&CardTable)>(f: F) {
+    GC_CARDS.with(|c| {
+        let cards = c.get();
+        if !cards.is_null() {
+            f(unsafe { &*cards });
+        }
+    });
 }
 
 
-impl<T: Trace> DerefMut for GcRoot<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        self.value_mut()
+/// Write a reference count increment to the journal for a newly allocated object
+#[inline]
+fn write<T: Trace>(object: &T, is_new: bool, flags: usize) {
+    if is_new {
+        forbid_gc_thread();
+        with_accounting(|a| a.take_token());
+        track_new(object);
     }
-}
 
+    send_entry(make_entry(object, is_new, flags));
 
-impl<T: Trace> Clone for GcRoot<T> {
-    fn clone(&self) -> Self {
-        GcRoot::from_raw(self.ptr())
+    if is_new {
+        with_accounting(|a| a.add_allocated());
     }
 }
 
-// Gc implementation
 
-impl<T: Trace> Gc<T> {
-    /// Creates a new null pointer.
-    pub fn null() -> Gc<T> {
-        Gc {
-            ptr: null_mut(),
-        }
+/// As `write()` for a new object, but if coalescing is enabled on this thread the entry is held
+/// back in case the object is rooted straight away, see `AppThread::coalesce_new()`.
+#[inline]
+fn write_new<T: Trace>(object: &T) {
+    if !GC_COALESCE_NEW.with(|c| c.get()) {
+        return write(object, true, NEW_BIT);
     }
 
-    /// Move a value to the heap and create a pointer to it.
-    pub fn new(value: T) -> Gc<T> {
-        let boxed = Box::new(GcBox::new(value));
-        write(&*boxed, true, NEW_BIT);
+    forbid_gc_thread();
+    with_accounting(|a| a.take_token());
+    track_new(object);
 
-        Gc {
-            ptr: Box::into_raw(boxed)
-        }
-    }
+    // sends any entry held back before this one
+    with_journal(|_| {
+        let entry = make_entry(object, true, NEW_BIT);
+        GC_PENDING_NEW.with(|p| p.set(Some(entry)));
+    });
 
-    /// Return the raw pointer value, or None if it is a null pointer.
-    pub fn as_raw(&self) -> Option<*mut GcBox<T>> {
-        if self.ptr.is_null() {
-            None
-        } else {
-            Some(self.ptr)
-        }
-    }
+    with_accounting(|a| a.add_allocated());
+}
 
-    /// Pointer equality comparison.
-    pub fn is(&self, other: Gc<T>) -> bool {
-        self.ptr == other.ptr
-    }
 
-    fn from_raw(ptr: *mut GcBox<T>) -> Gc<T> {
-        Gc {
-            ptr: ptr,
-        }
-    }
+/// Record a new object as live for `Gc::try_deref()`, before the GC can read it and drop it
+#[cfg(feature = "checked")]
+#[inline]
+fn track_new<T: Trace>(object: &T) {
+    liveness::insert(object as *const T as usize);
+}
 
-    fn ptr(&self) -> *mut GcBox<T> {
-        self.ptr
-    }
 
-    fn value(&self) -> &T {
-        unsafe { &(*self.ptr).value }
-    }
+#[cfg(not(feature = "checked"))]
+#[inline]
+fn track_new<T: Trace>(_object: &T) {}
 
-    fn value_mut(&mut self) -> &mut T {
-        unsafe { &mut (*self.ptr).value }
-    }
-}
 
+/// Encode a journal entry for an object
+#[inline]
+fn make_entry<T: Trace>(object: &T, is_new: bool, flags: usize) -> Object {
+    let tobj = as_traitobject(object);
 
-impl<T: Trace> Deref for Gc<T> {
-    type Target = T;
+    // set the refcount-increment bit
+    let ptr = (tobj.data as usize) | flags;
 
-    fn deref(&self) -> &T {
-        self.value()
+    // set the traversible bit
+    let mut vtable = tobj.vtable as usize;
+    if is_new && object.traversible() {
+        vtable |= TRAVERSE_BIT;
+    }
+
+    Object {
+        ptr: ptr,
+        vtable: vtable,
     }
 }
 
 
-impl<T: Trace> DerefMut for Gc<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        self.value_mut()
-    }
+/// Write a newly allocated object to the journal along with the kind of mature heap it is to be
+/// promoted into.
+#[inline]
+fn write_kind<T: Trace>(object: &T, flags: usize, kind: usize) {
+    assert!(kind < MAX_KINDS, "mature heap kind out of range");
+    write_kind_operand(object, flags, kind);
 }
 
 
-impl<T: Trace> Clone for Gc<T> {
-    fn clone(&self) -> Self {
-        Gc {
-            ptr: self.ptr,
+/// Write a newly allocated object to the journal as a new object entry with the kind bit set,
+/// followed by an entry holding the kind operand: the kind of mature heap, with
+/// `EPHEMERAL_KIND` set if the object is never to be promoted.
+#[inline]
+fn write_kind_operand<T: Trace>(object: &T, flags: usize, kind: usize) {
+    forbid_gc_thread();
+    with_accounting(|a| a.take_token());
+    track_new(object);
+
+    let tobj = as_traitobject(object);
+
+    let mut vtable = (tobj.vtable as usize) | KIND_BIT;
+    if object.traversible() {
+        vtable |= TRAVERSE_BIT;
+    }
+
+    // the GC reads the operand straight after the entry, so neither is held back
+    with_journal(|tx| {
+        tx.send(Object {
+            ptr: (tobj.data as usize) | flags,
+            vtable: vtable,
+        });
+
+        tx.send(Object {
+            ptr: kind,
+            vtable: 0,
+        });
+    });
+
+    with_accounting(|a| a.add_allocated());
+}
+
+
+/// Write a single reference count increment of `count` to the journal. This is encoded as an
+/// increment entry with the batch bit set, followed by an entry holding the count.
+#[inline]
+fn write_batch<T: Trace>(object: &T, count: usize) {
+    let tobj = as_traitobject(object);
+
+    with_journal(|tx| {
+        tx.send(Object {
+            ptr: (tobj.data as usize) | INC_BIT,
+            vtable: (tobj.vtable as usize) | BATCH_BIT,
+        });
+
+        tx.send(Object {
+            ptr: count,
+            vtable: 0,
+        });
+    });
+}
+
+
+/// Write a reference count increment to the journal for an object known only by its address and
+/// vtable.
+#[inline]
+fn write_inc_raw(ptr: usize, vtable: usize) {
+    send_entry(Object {
+        ptr: ptr | INC_BIT,
+        vtable: vtable,
+    });
+}
+
+
+/// Write a reference count decrement to the journal for an object known only by its address and
+/// vtable.
+#[inline]
+fn write_dec_raw(ptr: usize, vtable: usize) {
+    send_entry(Object {
+        ptr: ptr,
+        vtable: vtable,
+    });
+}
+
+
+/// Write a request to move an object straight to the mature heap to the journal. This is encoded
+/// as a decrement entry with the tenure bit set, and does not change the reference count.
+#[inline]
+fn write_tenure<T: Trace>(object: &T) {
+    let tobj = as_traitobject(object);
+
+    send_entry(Object {
+        ptr: tobj.data as usize,
+        vtable: (tobj.vtable as usize) | TENURE_BIT,
+    });
+}
+
+
+/// Write a request to stop tracking an object to the journal. This is encoded as a decrement
+/// entry with the untrack bit set, and does not change the reference count.
+#[inline]
+fn write_untrack<T: Trace>(object: &T) {
+    let tobj = as_traitobject(object);
+
+    send_entry(Object {
+        ptr: tobj.data as usize,
+        vtable: (tobj.vtable as usize) | UNTRACK_BIT,
+    });
+}
+
+
+/// Write barrier: mark the card containing `addr` as dirty so that the young generation mark
+/// phase will trace the object it belongs to.
+#[inline]
+pub fn write_barrier(addr: usize) {
+    flush_pending();
+    with_cards(|c| c.barrier(addr));
+}
+
+// GcBox implementation
+
+impl<T: Trace> GcBox<T> {
+    fn new(value: T) -> GcBox<T> {
+        GcBox {
+            value: value,
         }
     }
 }
 
 
-impl<T: Trace> Copy for Gc<T> {}
+unsafe impl<T: Trace> Trace for GcBox<T> {
+    #[inline]
+    fn traversible(&self) -> bool {
+        self.value.traversible()
+    }
 
-// GcAtomic implementation
+    #[inline]
+    unsafe fn trace(&self, heap: &mut TraceStack) {
+        self.value.trace(heap);
+    }
 
-impl<T: Trace> GcAtomic<T> {
-    /// Instantiate a new null pointer
-    pub fn null() -> GcAtomic<T> {
-        GcAtomic {
-            ptr: AtomicPtr::new(null_mut())
+    // the value is the only field of the repr(C) box and so shares its address: the value's own
+    // gc_drop() frees the whole allocation, which for a GcSlice is not a plain Box
+    unsafe fn gc_drop(&mut self) {
+        self.value.gc_drop();
+    }
+}
+
+// GcSlice implementation
+
+impl<T: Trace> GcSlice<T> {
+    /// Size and alignment of a slice of `len` elements: the elements start immediately after
+    /// the header as the zero length array field is aligned for `T`.
+    fn layout(len: usize) -> (usize, usize) {
+        (size_of::<GcSlice<T>>() + len * size_of::<T>(), align_of::<GcSlice<T>>())
+    }
+
+    /// Move the elements out of `vec` into a single new allocation.
+    fn from_vec(mut vec: Vec<T>) -> *mut GcBox<GcSlice<T>> {
+        let len = vec.len();
+        let (size, align) = GcSlice::<T>::layout(len);
+
+        unsafe {
+            let ptr = allocate(size, align) as *mut GcBox<GcSlice<T>>;
+            if ptr.is_null() {
+                abort();
+            }
+
+            (*ptr).value.len = len;
+            copy_nonoverlapping(vec.as_ptr(), (*ptr).value.elements.as_mut_ptr(), len);
+
+            // the elements have been moved, only the vec's buffer is left to free
+            vec.set_len(0);
+
+            ptr
         }
     }
+}
+
+
+unsafe impl<T: Trace> Trace for GcSlice<T> {
+    fn traversible(&self) -> bool {
+        self.iter().any(|element| element.traversible())
+    }
 
-    /// Instantiate a new pointer, moving `value` to the heap. Writes to the journal.
-    pub fn new(value: T) -> GcAtomic<T> {
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        for element in self.iter() {
+            element.trace(stack);
+        }
+    }
+
+    unsafe fn gc_drop(&mut self) {
+        let (size, align) = GcSlice::<T>::layout(self.len);
+
+        for element in self.iter_mut() {
+            drop_in_place(element);
+        }
+
+        deallocate(self as *mut GcSlice<T> as *mut u8, size, align);
+    }
+}
+
+
+impl<T: Trace> Deref for GcSlice<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.elements.as_ptr(), self.len) }
+    }
+}
+
+
+impl<T: Trace> DerefMut for GcSlice<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.elements.as_mut_ptr(), self.len) }
+    }
+}
+
+// GcRoot implementation
+
+impl<T: Trace> GcRoot<T> {
+    /// Put a new object on the heap and hand ownership to the GC, writing a reference count
+    /// increment to the journal.
+    pub fn new(value: T) -> GcRoot<T> {
         let boxed = Box::new(GcBox::new(value));
-        write(&*boxed, true, NEW_BIT);
+        write(&*boxed, true, NEW_BIT | INC_BIT);
 
-        GcAtomic {
-            ptr: AtomicPtr::new(Box::into_raw(boxed)),
+        GcRoot {
+            ptr: Box::into_raw(boxed)
         }
     }
 
-    /// Root the pointer by loading it into a `GcRoot<T>`
+    /// As `new()` but the object is promoted into the mature heap of the given `kind`. Kinds
+    /// beyond the number of mature heaps the GC was created with are promoted into the first.
     ///
-    /// Panics if `order` is `Release` or `AcqRel`.
-    pub fn load_into_root(&self, order: Ordering) -> GcRoot<T> {
-        let root = GcRoot {
-            ptr: self.ptr.load(order),
-        };
+    /// Panics if `kind` is not less than `MAX_KINDS`.
+    pub fn new_in(value: T, kind: usize) -> GcRoot<T> {
+        if kind == 0 {
+            return GcRoot::new(value);
+        }
 
-        write(&*root, false, INC_BIT);
-        root
+        let boxed = Box::new(GcBox::new(value));
+        write_kind(&*boxed, NEW_BIT | INC_BIT, kind);
+
+        GcRoot {
+            ptr: Box::into_raw(boxed)
+        }
     }
 
-    /// Copy the pointer into a new `Gc` instance.
+    /// As `new()` but returns `count` roots to the new object. Only a fixed number of journal
+    /// entries are written rather than one per root.
     ///
-    /// Panics if `order` is `Release` or `AcqRel`.
-    pub fn load_into_gc(&self, order: Ordering) -> Gc<T> {
-        Gc::from_raw(self.ptr.load(order))
+    /// Panics if `count` is zero.
+    pub fn new_with_count(value: T, count: usize) -> Vec<GcRoot<T>> {
+        assert!(count > 0, "GcRoot::new_with_count() requires a count of at least one");
+
+        let first = GcRoot::new(value);
+        let mut roots = Gc::from_raw(first.ptr()).root_n(count - 1);
+        roots.push(first);
+        roots
     }
 
-    /// Fetch the current raw pointer value
-    ///
-    /// Panics if `order` is `Release` or `AcqRel`.
-    pub fn load_raw(&self, order: Ordering) -> *mut GcBox<T> {
-        self.ptr.load(order)
+    /// Consume the root without unrooting the object, returning a reference to it that is valid
+    /// for the rest of the program. Like `Box::leak()`, this leaks by design: no decrement is
+    /// written to the journal, so the object's reference count never returns to zero and the
+    /// object, along with everything reachable from it, is never dropped.
+    pub fn leak(self) -> &'static T
+        where T: 'static
+    {
+        let value = unsafe { &(*self.ptr).value };
+        forget(self);
+        value
     }
 
-    /// Replace the current pointer value with the pointer from the given `GcRoot`.
-    ///
-    /// Panics if `order` is `Acquire` or `AcqRel`.
-    pub fn store_from_root(&self, root: GcRoot<T>, order: Ordering) {
-        self.ptr.store(root.ptr(), order);
+    /// Copy the pointer into a new `Gc`. The `Gc` does not root the object, so it is only valid
+    /// while this root or another path from a root keeps the object live.
+    pub fn as_gc(&self) -> Gc<T> {
+        Gc::from_raw(self.ptr)
     }
 
-    /// Replace the current pointer value with the pointer from the given `Gc`.
+    /// Apply `f` to the object as a single update that the GC never observes half done, see
+    /// `Gc::update()`.
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        self.as_gc().update(f)
+    }
+
+    /// Give up this root now rather than at the end of its scope. The decrement is written to
+    /// the journal straight away, so if nothing else keeps the object live it is dropped by the
+    /// next collection that reads it. The same as `drop(root)`, but says so.
     ///
-    /// Panics of `order` is `Acquire` or `AcqRel`.
-    pub fn store_from_gc(&self, gc: Gc<T>, order: Ordering) {
-        self.ptr.store(gc.ptr(), order);
+    /// Any `Gc` copied from this root is only valid while something else keeps the object live.
+    pub fn release(self) {
+        drop(self);
     }
 
-    /// Replace the current pointer value with the given raw pointer
+    fn from_raw(ptr: *mut GcBox<T>) -> GcRoot<T> {
+        let root = GcRoot { ptr: ptr };
+        write(&*root, false, INC_BIT);
+        root
+    }
+
+    fn ptr(&self) -> *mut GcBox<T> {
+        self.ptr
+    }
+
+    fn value(&self) -> &T {
+        unsafe { &(*self.ptr).value }
+    }
+
+    fn value_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.ptr).value }
+    }
+}
+
+
+impl<T: Trace> Drop for GcRoot<T> {
+    fn drop(&mut self) {
+        write(&**self, false, 0);
+    }
+}
+
+
+impl<T: Trace> Deref for GcRoot<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value()
+    }
+}
+
+
+impl<T: Trace> DerefMut for GcRoot<T> {
+    /// Applies the write barrier before returning the reference, so before the caller's store.
+    /// The card stays dirty through the collection after the one that next traces the object,
+    /// so writes through the reference must be done by then. See `Gc::write_barrier()` for
+    /// references held longer.
+    fn deref_mut(&mut self) -> &mut T {
+        write_barrier(self.ptr as usize);
+        self.value_mut()
+    }
+}
+
+
+impl<T: Trace> Clone for GcRoot<T> {
+    fn clone(&self) -> Self {
+        GcRoot::from_raw(self.ptr())
+    }
+}
+
+// Gc implementation
+
+impl<T: Trace> Gc<T> {
+    /// Creates a new null pointer.
+    pub fn null() -> Gc<T> {
+        Gc {
+            ptr: null_mut(),
+        }
+    }
+
+    /// Move a value to the heap and create a pointer to it.
+    pub fn new(value: T) -> Gc<T> {
+        let boxed = Box::new(GcBox::new(value));
+        write_new(&*boxed);
+
+        Gc {
+            ptr: Box::into_raw(boxed)
+        }
+    }
+
+    /// Allocate space for a value to be written later with `GcUninit::write()`, or through
+    /// `GcUninit::as_mut_ptr()` followed by `GcUninit::assume_init()`.
+    pub fn new_uninit() -> GcUninit<T> {
+        let size = size_of::<GcBox<T>>();
+        let align = align_of::<GcBox<T>>();
+
+        // a zero sized value takes no memory, only a pointer aligned for both the value and the
+        // flag bits the journal packs into object addresses
+        if size == 0 {
+            return GcUninit { ptr: max(align, PTR_ALIGN) as *mut GcBox<T> };
+        }
+
+        unsafe {
+            let ptr = allocate(size, align) as *mut GcBox<T>;
+            if ptr.is_null() {
+                abort();
+            }
+
+            GcUninit { ptr: ptr }
+        }
+    }
+
+    /// As `new()` but the object is promoted into the mature heap of the given `kind`. Kinds
+    /// beyond the number of mature heaps the GC was created with are promoted into the first.
     ///
-    /// Panics if `order` is `Acquire` or `AcqRel`.
-    pub fn store_raw(&self, ptr: *mut GcBox<T>, order: Ordering) {
-        self.ptr.store(ptr, order);
+    /// Panics if `kind` is not less than `MAX_KINDS`.
+    pub fn new_in(value: T, kind: usize) -> Gc<T> {
+        if kind == 0 {
+            return Gc::new(value);
+        }
+
+        let boxed = Box::new(GcBox::new(value));
+        write_kind(&*boxed, NEW_BIT, kind);
+
+        Gc {
+            ptr: Box::into_raw(boxed)
+        }
+    }
+
+    /// As `new()` but the object is never promoted into the mature heap, however many
+    /// collections it survives. Use this for objects known to be short-lived, such as iterators
+    /// and temporaries, that would otherwise be promoted by a major collection that happens to
+    /// run while they are live. The object is dropped by the first minor collection after it
+    /// becomes unreachable.
+    pub fn new_ephemeral(value: T) -> Gc<T> {
+        let boxed = Box::new(GcBox::new(value));
+        write_kind_operand(&*boxed, NEW_BIT, EPHEMERAL_KIND);
+
+        Gc {
+            ptr: Box::into_raw(boxed)
+        }
+    }
+
+    /// Return a root to the object. If the object is the last one this thread allocated with
+    /// `new()` and coalescing is enabled, its new-object entry is written as a new and rooted
+    /// entry rather than followed by a separate increment, see `AppThread::coalesce_new()`.
+    pub fn root(&self) -> GcRoot<T> {
+        let ptr = self.ptr() as usize;
+
+        let fused = GC_PENDING_NEW.with(|p| {
+            match p.get() {
+                Some(entry) if entry.ptr & PTR_MASK == ptr => {
+                    p.set(Some(Object {
+                        ptr: entry.ptr | INC_BIT,
+                        vtable: entry.vtable,
+                    }));
+                    true
+                }
+                _ => false,
+            }
+        });
+
+        if fused {
+            flush_pending();
+            GcRoot { ptr: self.ptr() }
+        } else {
+            GcRoot::from_raw(self.ptr())
+        }
+    }
+
+    /// Return `count` roots to the object, writing a single batched reference count increment to
+    /// the journal rather than one per root.
+    pub fn root_n(&self, count: usize) -> Vec<GcRoot<T>> {
+        if count > 0 {
+            write_batch(self.value(), count);
+        }
+
+        (0..count).map(|_| GcRoot { ptr: self.ptr() }).collect()
+    }
+
+    /// Ask the GC to move the object straight to the mature heap on its next cycle rather than
+    /// waiting for it to age through the young generation. Use this for objects that are known to
+    /// be long-lived. Does nothing for a null pointer.
+    pub fn tenure(&self) {
+        if !self.ptr().is_null() {
+            write_tenure(&**self);
+        }
+    }
+
+    /// Mark the object's card dirty, as `DerefMut` does, so that the next young generation
+    /// collection traces it. Call this after storing a pointer into the object through interior
+    /// mutability, such as a `Cell` reached through `Deref`, which the GC cannot otherwise see:
+    /// a mature object whose card is clean is not traced, and a new object stored only in it
+    /// would be dropped.
+    ///
+    /// `DerefMut` marks the card before the caller writes, and the mark cleans a card before
+    /// tracing its object, so a card is kept dirty for one collection more than it needs to be:
+    /// a store through a mutable reference is seen as long as it lands before the end of the
+    /// collection after the one that first traces the object. Call this too after writes
+    /// through a mutable reference that is held for longer than that. Does nothing for a null
+    /// pointer.
+    pub fn write_barrier(&self) {
+        if !self.ptr().is_null() {
+            write_barrier(self.ptr() as usize);
+        }
+    }
+
+    /// Tell the GC to stop managing the object, without dropping or freeing it, and return the
+    /// pointer to it, for handing ownership to code outside the GC such as a foreign function.
+    /// The object was allocated as a `Box<GcBox<T>>` and must be freed as one, for example with
+    /// `Box::from_raw()`. If the new owner never frees it, it leaks. Returns a null pointer for a
+    /// null `Gc`.
+    ///
+    /// Once the GC has read this from the journal the object is no longer traced, so the caller
+    /// must make sure that no GC managed object still points at it and that no `GcRoot` to it is
+    /// dropped afterwards, as the GC would find the decrement for an object it does not know.
+    ///
+    /// Nor are the GC managed objects it points at traced through it any more: a child reachable
+    /// only through the untracked object is dropped by the next collection that finds it
+    /// unreachable, leaving the untracked object with a dangling pointer. Root any such child,
+    /// for example by holding a `GcRoot` to it alongside the untracked object, for as long as the
+    /// untracked object uses it.
+    pub fn untrack(self) -> *mut GcBox<T> {
+        if !self.ptr().is_null() {
+            write_untrack(&**self);
+        }
+
+        self.ptr()
+    }
+
+    /// Apply `f` to the object as a single update that the GC never observes half done.
+    ///
+    /// `DerefMut` marks the object's card before the mutation, so a trace that runs while the
+    /// mutation is under way may see a torn intermediate state, for example a child that is
+    /// being moved from one field to another present in neither. If that child is reachable
+    /// only through this object it can be dropped while still referenced. `update()` closes that
+    /// window:
+    ///
+    /// * the object and every child it points to when `update()` is called are rooted until `f`
+    ///   returns, so no trace can drop them whatever it sees of the intermediate state
+    /// * the object's card is marked dirty again after `f` returns, so the next collection
+    ///   traces the final state before those roots are released, as decrements are only applied
+    ///   after the collection that reads them
+    ///
+    /// A pointer `f` stores that was not already a child must be kept live by the caller until
+    /// `update()` returns, as for any write through a `Gc`. The guarantee is about reachability
+    /// only: the GC thread still reads each pointer field without synchronization, so a field
+    /// that must be read atomically should be a `GcAtomic`.
+    ///
+    /// This costs a trace of the object and two journal entries per child, so prefer `DerefMut`
+    /// for mutations that only ever add pointers. Does nothing for a null pointer.
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        if self.ptr().is_null() {
+            return;
+        }
+
+        let pin = GcRoot::from_raw(self.ptr());
+
+        let mut children = TraceStack::new();
+        if self.value().traversible() {
+            unsafe { self.value().trace(&mut children) };
+        }
+
+        let mut pinned = Vec::with_capacity(children.len());
+        while let Some(child) = children.pop() {
+            write_inc_raw(child.ptr, child.vtable);
+            pinned.push(child);
+        }
+
+        write_barrier(self.ptr() as usize);
+        f(unsafe { &mut (*self.ptr()).value });
+        write_barrier(self.ptr() as usize);
+
+        for child in pinned {
+            write_dec_raw(child.ptr, child.vtable);
+        }
+
+        drop(pin);
+    }
+
+    /// Return the raw pointer value, or None if it is a null pointer.
+    pub fn as_raw(&self) -> Option<*mut GcBox<T>> {
+        if self.ptr().is_null() {
+            None
+        } else {
+            Some(self.ptr())
+        }
+    }
+
+    /// Return a copy of the pointer carrying `tag`, such as a type tag for an interpreter value,
+    /// in the low bits of the address that are always zero for objects aligned to `PTR_ALIGN`
+    /// bytes. There is room for `TAG_BITS` bits: 3 on 64 bit targets and 2 on 32 bit targets.
+    /// Objects are allocated at the alignment of their type, so only types aligned to at least
+    /// `PTR_ALIGN` can be tagged: wrap a less aligned value in a struct with a `usize` field.
+    ///
+    /// The tag is masked off wherever the pointer is dereferenced, traced, rooted, journaled or
+    /// compared, so it never reaches the GC. Only `Gc` carries it: the `GcRoot` returned by
+    /// `root()` and a `GcAtomic` the pointer is stored in hold the untagged pointer. A null
+    /// pointer can carry a tag too.
+    ///
+    /// Panics if `tag` does not fit in `TAG_BITS` bits, or if `T` is aligned to less than
+    /// `PTR_ALIGN` bytes.
+    pub fn with_tag(&self, tag: u8) -> Gc<T> {
+        assert!(tag as usize & !TAG_MASK == 0, "Gc tag {} does not fit in {} bits", tag, TAG_BITS);
+        assert!(align_of::<GcBox<T>>() >= PTR_ALIGN,
+                "Gc tag needs {} byte alignment, the object has {}",
+                PTR_ALIGN,
+                align_of::<GcBox<T>>());
+
+        Gc::from_raw((self.ptr() as usize | tag as usize) as *mut GcBox<T>)
+    }
+
+    /// Return the tag given by `with_tag()`, or zero if there is none.
+    pub fn tag(&self) -> u8 {
+        (self.ptr as usize & TAG_MASK) as u8
+    }
+
+    /// Pointer equality comparison, ignoring tags.
+    pub fn is(&self, other: Gc<T>) -> bool {
+        self.ptr() == other.ptr()
+    }
+
+    /// Return the object, or `None` if this is a null pointer or the GC has already dropped the
+    /// object, where dereferencing would be Undefined Behavior. Only built with the `checked`
+    /// feature, under which every allocation and every drop updates a shared table of live
+    /// objects, so it is for debugging.
+    ///
+    /// An object that is unreachable but not yet dropped is still returned, and may be dropped
+    /// while the reference is held. Once the memory of a dropped object is reused by a new
+    /// allocation its address is live again, so a stale pointer is only caught until then.
+    #[cfg(feature = "checked")]
+    pub fn try_deref(&self) -> Option<&T> {
+        if !self.ptr().is_null() && liveness::contains(self.ptr() as usize) {
+            Some(self.value())
+        } else {
+            None
+        }
+    }
+
+    /// Value equality comparison of the pointed-at objects. Unlike `==`, which compares pointer
+    /// identity, this is true for two distinct objects that hold equal values.
+    pub fn value_eq(&self, other: &Gc<T>) -> bool
+        where T: PartialEq
+    {
+        self.value() == other.value()
+    }
+
+    fn from_raw(ptr: *mut GcBox<T>) -> Gc<T> {
+        Gc {
+            ptr: ptr,
+        }
+    }
+
+    // The pointer with any tag masked off
+    fn ptr(&self) -> *mut GcBox<T> {
+        (self.ptr as usize & !TAG_MASK) as *mut GcBox<T>
+    }
+
+    fn value(&self) -> &T {
+        unsafe { &(*self.ptr()).value }
+    }
+
+    fn value_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.ptr()).value }
+    }
+}
+
+
+impl<T: Trace> Gc<GcSlice<T>> {
+    /// Move the elements of `elements` into a single new GC managed object that holds the length
+    /// inline. Each element is traced and dropped along with the slice.
+    pub fn new_slice(elements: Vec<T>) -> Gc<GcSlice<T>> {
+        let ptr = GcSlice::from_vec(elements);
+
+        // journaled through the GcBox vtable as every other object is, GcBox's gc_drop() passing
+        // the drop on to GcSlice's
+        write(unsafe { &*ptr }, true, NEW_BIT);
+
+        Gc::from_raw(ptr)
+    }
+}
+
+
+unsafe impl<T: Trace> Trace for Gc<T> {
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        if let Some(ptr) = self.as_raw() {
+            stack.push_to_trace(&*ptr);
+        }
+    }
+}
+
+
+impl<T: Trace> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value()
+    }
+}
+
+
+impl<T: Trace> DerefMut for Gc<T> {
+    /// Applies the write barrier before returning the reference, as for `GcRoot`.
+    fn deref_mut(&mut self) -> &mut T {
+        write_barrier(self.ptr() as usize);
+        self.value_mut()
+    }
+}
+
+
+impl<T: Trace> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        Gc {
+            ptr: self.ptr,
+        }
+    }
+}
+
+
+impl<T: Trace> Copy for Gc<T> {}
+
+
+/// Pointer identity: two `Gc`s are equal if they point at the same object, whatever their tags.
+impl<T: Trace> PartialEq for Gc<T> {
+    fn eq(&self, other: &Gc<T>) -> bool {
+        self.ptr() == other.ptr()
+    }
+}
+
+
+impl<T: Trace> Eq for Gc<T> {}
+
+// GcUninit implementation
+
+impl<T: Trace> GcUninit<T> {
+    /// Return a pointer to the object for linking it into other objects before it is initialized,
+    /// for example to build a cycle. It must not be dereferenced until the object is initialized.
+    /// The GC skips the object when tracing until then, so a pointer stored in an object that
+    /// may already be in the mature heap must be stored again after initializing for the write
+    /// barrier to record it.
+    pub fn as_gc(&self) -> Gc<T> {
+        Gc::from_raw(self.ptr)
+    }
+
+    /// Return a pointer to the uninitialized value.
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        unsafe { &mut (*self.ptr).value }
+    }
+
+    /// Initialize the object with `value` and hand it to the GC.
+    pub fn write(mut self, value: T) -> Gc<T> {
+        unsafe {
+            ptr::write(self.as_mut_ptr(), value);
+            self.assume_init()
+        }
+    }
+
+    /// Hand the object to the GC once its value has been written through `as_mut_ptr()`.
+    ///
+    /// Unsafe because the value must have been fully initialized.
+    pub unsafe fn assume_init(self) -> Gc<T> {
+        let ptr = self.ptr;
+        forget(self);
+
+        write(&*ptr, true, NEW_BIT);
+        Gc::from_raw(ptr)
+    }
+}
+
+
+impl<T: Trace> Drop for GcUninit<T> {
+    fn drop(&mut self) {
+        let size = size_of::<GcBox<T>>();
+
+        // nothing was allocated for a zero sized value
+        if size != 0 {
+            unsafe { deallocate(self.ptr as *mut u8, size, align_of::<GcBox<T>>()) };
+        }
+    }
+}
+
+// GcBuilder implementation
+
+impl<T: Trace> GcBuilder<T> {
+    /// Create an empty builder.
+    pub fn new() -> GcBuilder<T> {
+        GcBuilder {
+            objects: Vec::new(),
+        }
+    }
+
+    /// Move a value to the heap without telling the GC about it yet, returning a pointer to it
+    /// for use while building.
+    pub fn alloc(&mut self, value: T) -> Gc<T> {
+        let ptr = Box::into_raw(Box::new(GcBox::new(value)));
+        self.objects.push(ptr);
+
+        Gc::from_raw(ptr)
+    }
+
+    /// Hand every object allocated by this builder to the GC, rooting `root`. The root is written
+    /// to the journal first so that the other objects are reachable from it as soon as the GC
+    /// reads them. Objects that are not reachable from `root` are dropped by the next collection.
+    ///
+    /// Panics if `root` was not allocated by this builder.
+    pub fn publish(mut self, root: Gc<T>) -> GcRoot<T> {
+        let objects = replace(&mut self.objects, Vec::new());
+
+        assert!(objects.iter().any(|&ptr| ptr == root.ptr()),
+                "GcBuilder::publish() root was not allocated by this builder");
+
+        write(unsafe { &*root.ptr() }, true, NEW_BIT | INC_BIT);
+
+        for &ptr in objects.iter().filter(|&&ptr| ptr != root.ptr()) {
+            write(unsafe { &*ptr }, true, NEW_BIT);
+        }
+
+        GcRoot { ptr: root.ptr() }
+    }
+}
+
+
+impl<T: Trace> Drop for GcBuilder<T> {
+    fn drop(&mut self) {
+        for &ptr in self.objects.iter() {
+            unsafe { drop(Box::from_raw(ptr)) };
+        }
+    }
+}
+
+// GcAtomic implementation
+
+impl<T: Trace> GcAtomic<T> {
+    /// Instantiate a new null pointer
+    pub fn null() -> GcAtomic<T> {
+        GcAtomic {
+            ptr: AtomicPtr::new(null_mut())
+        }
+    }
+
+    /// Instantiate a new pointer, moving `value` to the heap. Writes to the journal.
+    pub fn new(value: T) -> GcAtomic<T> {
+        let boxed = Box::new(GcBox::new(value));
+        write(&*boxed, true, NEW_BIT);
+
+        GcAtomic {
+            ptr: AtomicPtr::new(Box::into_raw(boxed)),
+        }
+    }
+
+    /// Move the pointer from `gc` into a new atomic slot. Nothing is written to the journal and
+    /// the reference count is unchanged: like `Gc`, a `GcAtomic` does not root the object, so the
+    /// object lives only as long as it is reachable from a root, here through whatever object
+    /// the new `GcAtomic` is stored in.
+    pub fn from_gc(gc: Gc<T>) -> GcAtomic<T> {
+        GcAtomic {
+            ptr: AtomicPtr::new(gc.ptr()),
+        }
+    }
+
+    /// Consume the atomic slot, returning its pointer as a `Gc`. Nothing is written to the
+    /// journal and the reference count is unchanged, so the returned `Gc` must be stored in a
+    /// reachable object or rooted before the object that held the slot becomes unreachable.
+    pub fn into_gc(self) -> Gc<T> {
+        Gc::from_raw(self.ptr.into_inner())
+    }
+
+    /// Root the pointer by loading it into a `GcRoot<T>`
+    ///
+    /// Panics if `order` is `Release` or `AcqRel`.
+    pub fn load_into_root(&self, order: Ordering) -> GcRoot<T> {
+        let root = GcRoot {
+            ptr: self.ptr.load(order),
+        };
+
+        write(&*root, false, INC_BIT);
+        root
+    }
+
+    /// Copy the pointer into a new `Gc` instance.
+    ///
+    /// Panics if `order` is `Release` or `AcqRel`.
+    pub fn load_into_gc(&self, order: Ordering) -> Gc<T> {
+        Gc::from_raw(self.ptr.load(order))
+    }
+
+    /// Fetch the current raw pointer value
+    ///
+    /// Panics if `order` is `Release` or `AcqRel`.
+    pub fn load_raw(&self, order: Ordering) -> *mut GcBox<T> {
+        self.ptr.load(order)
+    }
+
+    /// Replace the current pointer value with the pointer from the given `GcRoot`.
+    ///
+    /// Panics if `order` is `Acquire` or `AcqRel`.
+    pub fn store_from_root(&self, root: GcRoot<T>, order: Ordering) {
+        write_barrier(self as *const _ as usize);
+        self.ptr.store(root.ptr(), order);
+    }
+
+    /// Replace the current pointer value with the pointer from the given `Gc`.
+    ///
+    /// Panics of `order` is `Acquire` or `AcqRel`.
+    pub fn store_from_gc(&self, gc: Gc<T>, order: Ordering) {
+        write_barrier(self as *const _ as usize);
+        self.ptr.store(gc.ptr(), order);
+    }
+
+    /// Replace the current pointer value with the given raw pointer
+    ///
+    /// Panics if `order` is `Acquire` or `AcqRel`.
+    pub fn store_raw(&self, ptr: *mut GcBox<T>, order: Ordering) {
+        write_barrier(self as *const _ as usize);
+        self.ptr.store(ptr, order);
+    }
+
+    /// Replace the current pointer value with the pointer from the given `Gc`, returning the
+    /// previous pointer value.
+    pub fn swap_gc(&self, gc: Gc<T>, order: Ordering) -> Gc<T> {
+        write_barrier(self as *const _ as usize);
+        Gc::from_raw(self.ptr.swap(gc.ptr(), order))
+    }
+
+    /// Replace the current pointer value with the pointer from `new`, returning the previous
+    /// pointer value rooted. An increment for the returned root is written to the journal
+    /// straight after the exchange, and `new` is dropped, writing its decrement, only once its
+    /// object is reachable through the slot.
+    ///
+    /// The previous object cannot be dropped before its increment is counted outside a mark
+    /// phase: the journal is read before every mark, increments are applied as they are read and
+    /// decrements only from the deferred buffer after a sweep, and this thread's later decrement
+    /// for the returned root follows the increment in the journal. During a mark the exchange
+    /// shares the race described in the project TODO, as `load_into_root()` does.
+    pub fn swap_root(&self, new: GcRoot<T>, order: Ordering) -> GcRoot<T> {
+        write_barrier(self as *const _ as usize);
+
+        let old = GcRoot {
+            ptr: self.ptr.swap(new.ptr(), order),
+        };
+
+        if !old.ptr().is_null() {
+            write(&*old, false, INC_BIT);
+        }
+
+        drop(new);
+        old
+    }
+
+    /// Root the pointer by loading it into a `GcRoot<T>` using `Acquire` ordering.
+    pub fn load_root(&self) -> GcRoot<T> {
+        self.load_into_root(Ordering::Acquire)
+    }
+
+    /// Replace the current pointer value with the pointer from the given `Gc` using `Release`
+    /// ordering.
+    pub fn store(&self, gc: Gc<T>) {
+        self.store_from_gc(gc, Ordering::Release);
+    }
+
+    /// Replace the current pointer value with the pointer from the given `Gc` using `AcqRel`
+    /// ordering, returning the previous pointer value.
+    pub fn swap(&self, gc: Gc<T>) -> Gc<T> {
+        self.swap_gc(gc, Ordering::AcqRel)
+    }
+}
+
+
+unsafe impl<T: Trace> Trace for GcAtomic<T> {
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        let ptr = self.ptr.load(Ordering::Acquire);
+        if !ptr.is_null() {
+            stack.push_to_trace(&*ptr);
+        }
+    }
+}
+
+// SyncGc implementation
+
+impl<T: Trace> SyncGc<T> {
+    /// Creates a new null pointer.
+    pub fn null() -> SyncGc<T> {
+        SyncGc { ptr: GcAtomic::null() }
+    }
+
+    /// Move a value to the heap and create a pointer to it. Writes to the journal.
+    pub fn new(value: T) -> SyncGc<T> {
+        SyncGc { ptr: GcAtomic::new(value) }
+    }
+
+    /// Create a pointer to the same object as `gc`.
+    pub fn from_gc(gc: Gc<T>) -> SyncGc<T> {
+        SyncGc { ptr: GcAtomic::from_gc(gc) }
+    }
+
+    /// Load the pointer into a `Gc` for use on the calling thread.
+    pub fn load(&self) -> Gc<T> {
+        self.ptr.load_into_gc(Ordering::Acquire)
+    }
+
+    /// Load the pointer into a root. Must be called from an app thread for the root to have
+    /// effect.
+    pub fn root(&self) -> GcRoot<T> {
+        self.ptr.load_root()
+    }
+
+    /// Point at the same object as `gc`.
+    pub fn store(&self, gc: Gc<T>) {
+        self.ptr.store(gc);
+    }
+
+    /// Return true if this is a null pointer.
+    pub fn is_null(&self) -> bool {
+        self.ptr.load_raw(Ordering::Acquire).is_null()
+    }
+
+    /// Pointer equality comparison.
+    pub fn is(&self, other: Gc<T>) -> bool {
+        self.load().is(other)
+    }
+}
+
+
+unsafe impl<T: Trace> Trace for SyncGc<T> {
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        self.ptr.trace(stack);
+    }
+}
+
+
+impl<T: Trace> Clone for SyncGc<T> {
+    fn clone(&self) -> Self {
+        SyncGc::from_gc(self.load())
+    }
+}
+
+
+impl<T: Trace> PartialEq for SyncGc<T> {
+    fn eq(&self, other: &SyncGc<T>) -> bool {
+        self.is(other.load())
+    }
+}
+
+
+impl<T: Trace> Eq for SyncGc<T> {}
+
+// RootScope implementation
+
+impl RootScope {
+    /// Create a scope that roots nothing yet.
+    pub fn new() -> RootScope {
+        RootScope { objects: Vec::new() }
+    }
+
+    /// Root the object until the scope is dropped, returning the pointer for convenience. Does
+    /// nothing for a null pointer.
+    pub fn root<T: Trace>(&mut self, gc: Gc<T>) -> Gc<T> {
+        if !gc.ptr().is_null() {
+            write(gc.value(), false, INC_BIT);
+            self.objects.push(Object::from(as_traitobject(gc.value())));
+        }
+        gc
+    }
+
+    /// Return the number of objects rooted by the scope.
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+}
+
+
+impl Drop for RootScope {
+    fn drop(&mut self) {
+        let objects = &self.objects;
+
+        with_journal(|tx| {
+            for object in objects.iter() {
+                tx.send(*object);
+            }
+        });
+    }
+}
+
+// RootSnapshot implementation
+
+impl RootSnapshot {
+    /// Wrap entries captured by `YoungHeap::snapshot_roots()`. The GC must already hold a
+    /// reference to each object on behalf of the snapshot, as that method takes. The references
+    /// are released through `control`, the GC thread's control channel, when it is dropped.
+    pub unsafe fn from_entries(entries: Vec<RootEntry>,
+                               control: mpsc::Sender<GcCommand>)
+                               -> RootSnapshot {
+        RootSnapshot {
+            entries: entries,
+            control: control,
+        }
+    }
+
+    /// Return the captured objects in no particular order.
+    pub fn entries(&self) -> &[RootEntry] {
+        &self.entries
+    }
+
+    /// Return true if `object` was rooted when the snapshot was taken.
+    pub fn contains<T: Trace>(&self, object: *const GcBox<T>) -> bool {
+        self.entries.iter().any(|entry| entry.ptr == object as usize)
+    }
+
+    /// Root the object of `entry` in the calling app thread. The root remains valid after the
+    /// snapshot is dropped.
+    ///
+    /// Unsafe because the caller must know that the object is a `GcBox<T>`.
+    pub unsafe fn root<T: Trace>(&self, entry: &RootEntry) -> GcRoot<T> {
+        GcRoot::from_raw(entry.ptr as *mut GcBox<T>)
+    }
+}
+
+
+impl Drop for RootSnapshot {
+    fn drop(&mut self) {
+        let entries = replace(&mut self.entries, Vec::new());
+
+        // once the GC thread has exited there is nothing left to release
+        let _ = self.control.send(GcCommand::ReleaseSnapshot(entries));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::Cell;
+    use std::mem::align_of;
+    use std::ptr::null;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use scoped_pool::Pool;
+
+    use constants::{BATCH_BIT, DEC, INC, INC_BIT, JOURNAL_BUFFER_SIZE, NEW, NEW_BIT, NEW_INC,
+                    PTR_ALIGN, PTR_MASK, TAG_MASK, VTABLE_MASK};
+    use cardtable::CardTable;
+    use config::GcConfig;
+    use heap::Object;
+    use journal;
+    use parheap::ParHeap;
+    use statistics::{Accounting, DefaultLogger};
+    use heap::TraceStack;
+    use trace::Trace;
+    use youngheap::YoungHeap;
+
+    use super::{AppThread, Gc, GcAtomic, GcRoot, GC_ACCOUNTING, GC_CARDS, GC_JOURNAL};
+
+
+    /// Holds a pointer that is stored through `Deref`
+    struct Slot {
+        child: Cell<Gc<usize>>,
+    }
+
+
+    unsafe impl Trace for Slot {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            self.child.get().trace(stack);
+        }
+    }
+
+
+    /// Counts its own drops
+    struct Counted {
+        drops: Arc<AtomicUsize>,
+    }
+
+
+    unsafe impl Trace for Counted {}
+
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+
+    /// Holds another object through a pointer that may be tagged
+    struct Tagged {
+        child: Gc<Counted>,
+    }
+
+
+    unsafe impl Trace for Tagged {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            self.child.trace(stack);
+        }
+    }
+
+
+    /// Run `f` with a journal installed for the current thread, returning every entry written.
+    /// No GC thread reads the journal so nothing is collected while `f` runs.
+    fn journaled<F: FnOnce()>(f: F) -> Vec<Object> {
+        let (jtx, jrx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        let accounting = Accounting::new();
+        let cards = CardTable::new();
+        let mut entries = Vec::new();
+
+        GC_JOURNAL.with(|j| j.set(&jtx));
+        GC_ACCOUNTING.with(|a| a.set(&accounting));
+        GC_CARDS.with(|c| c.set(&cards));
+        f();
+        GC_CARDS.with(|c| c.set(null()));
+        GC_ACCOUNTING.with(|a| a.set(null()));
+        GC_JOURNAL.with(|j| j.set(null()));
+
+        while let Ok(entry) = jrx.try_recv() {
+            entries.push(entry);
+        }
+
+        entries
+    }
+
+    /// A single threaded young heap to feed journaled entries into
+    fn test_heap() -> YoungHeap<DefaultLogger, ParHeap> {
+        let mut config = GcConfig::new();
+        config.num_threads = 1;
+
+        YoungHeap::new(config,
+                       ParHeap::new(1),
+                       DefaultLogger::new(),
+                       Arc::new(Accounting::new()),
+                       Arc::new(CardTable::new()))
+    }
+
+    #[test]
+    fn test_gcatomic_default_orderings() {
+        journaled(|| {
+            let a = Gc::new(1usize);
+            let b = Gc::new(2usize);
+
+            let explicit = GcAtomic::null();
+            let simple = GcAtomic::null();
+
+            explicit.store_from_gc(a, Ordering::Release);
+            simple.store(a);
+            assert!(explicit.load_raw(Ordering::Acquire) == simple.load_raw(Ordering::Acquire));
+
+            assert_eq!(*explicit.load_into_root(Ordering::Acquire), 1);
+            assert_eq!(*simple.load_root(), 1);
+
+            assert!(explicit.swap_gc(b, Ordering::AcqRel).is(a));
+            assert!(simple.swap(b).is(a));
+            assert!(explicit.load_raw(Ordering::Acquire) == simple.load_raw(Ordering::Acquire));
+        });
+    }
+
+    #[test]
+    fn test_gc_value_eq() {
+        journaled(|| {
+            let a = Gc::new(String::from("mo"));
+            let b = Gc::new(String::from("mo"));
+            let c = Gc::new(String::from("gc"));
+
+            assert!(a.value_eq(&b));
+            assert!(a != b);
+            assert!(!a.is(b));
+
+            assert!(!a.value_eq(&c));
+
+            assert!(a == a);
+            assert!(a.is(a));
+            assert!(a.value_eq(&a));
+        });
+    }
+
+    #[test]
+    fn test_gc_slice() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let counted = || Counted { drops: drops.clone() };
+        let mut box_vtable = 0;
+
+        let entries = journaled(|| {
+            let targets: Vec<_> = (0..3).map(|_| Gc::new(counted())).collect();
+            let slice = Gc::new_slice(targets.clone());
+            box_vtable = super::as_traitobject(unsafe { &*slice.ptr() }).vtable as usize;
+
+            assert_eq!(slice.len(), 3);
+            for (element, target) in slice.iter().zip(targets.iter()) {
+                assert!(element.is(*target));
+            }
+
+            // an unreferenced slice of values
+            Gc::new_slice(vec![counted(), counted()]);
+        });
+
+        // three targets, the slice of pointers and the slice of values
+        assert_eq!(entries.len(), 5);
+        let slice = entries[3];
+        assert_eq!(slice.vtable & VTABLE_MASK, box_vtable);
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        // root the slice of pointers as if it had been allocated as a GcRoot
+        for (index, entry) in entries.iter().enumerate() {
+            let mut entry = *entry;
+            if index == 3 {
+                entry.ptr |= INC_BIT;
+            }
+            tx.send(entry);
+        }
+        heap.read_journals();
+
+        // the slice keeps each element's target alive, the slice of values drops each value
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        assert_eq!(heap.live_count(), 4);
+
+        tx.send(Object {
+            ptr: slice.ptr & PTR_MASK,
+            vtable: slice.vtable,
+        });
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 5);
+        assert_eq!(heap.live_count(), 0);
+    }
+
+    #[test]
+    fn test_batched_roots() {
+        const ROOTS: usize = 1000;
+
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let entries = journaled(|| {
+            let roots = GcRoot::new_with_count(Counted { drops: drops.clone() }, ROOTS);
+            assert_eq!(roots.len(), ROOTS);
+            assert!(roots.iter().all(|root| root.ptr() == roots[0].ptr()));
+        });
+
+        // one new-object entry and a batch of two, followed by a decrement per dropped root
+        assert_eq!(entries.len(), 3 + ROOTS);
+        assert_eq!(entries[0].ptr & !PTR_MASK, NEW_INC);
+        assert_eq!(entries[1].ptr & !PTR_MASK, INC);
+        assert!(entries[1].vtable & BATCH_BIT != 0);
+        assert_eq!(entries[2].ptr, ROOTS - 1);
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        // all but one of the decrements leave the object rooted
+        for entry in entries.iter().take(entries.len() - 1) {
+            tx.send(*entry);
+        }
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        tx.send(entries[entries.len() - 1]);
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_gcatomic_conversions() {
+        /// Holds its only reference to a `Counted` in an atomic slot
+        struct Holder {
+            slot: GcAtomic<Counted>,
+        }
+
+        unsafe impl Trace for Holder {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.slot.trace(stack);
+            }
+        }
+
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let entries = journaled(|| {
+            let target = Gc::new(Counted { drops: drops.clone() });
+
+            // neither conversion journals anything
+            let back = GcAtomic::from_gc(target).into_gc();
+            assert!(back.is(target));
+
+            let holder = GcRoot::new(Holder { slot: GcAtomic::from_gc(back) });
+            assert!(holder.slot.load_into_gc(Ordering::Acquire).is(target));
+        });
+
+        // the target, the holder and the holder's decrement
+        assert_eq!(entries.len(), 3);
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        // hold back the holder's decrement so the target is only reachable through the atomic
+        tx.send(entries[0]);
+        tx.send(entries[1]);
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        assert_eq!(heap.live_count(), 2);
+
+        tx.send(entries[2]);
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert_eq!(heap.live_count(), 0);
+    }
+
+    #[test]
+    fn test_boxed_trace_objects() {
+        /// Owns children of any traceable type
+        struct Holder {
+            children: Vec<Box<Trace>>,
+        }
+
+        unsafe impl Trace for Holder {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                for child in self.children.iter() {
+                    child.trace(stack);
+                }
+            }
+        }
+
+        /// Wraps a pointer in a second concrete type
+        struct Wrapper {
+            child: Gc<Counted>,
+        }
+
+        unsafe impl Trace for Wrapper {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.child.trace(stack);
+            }
+        }
+
+        let leaf: Box<Trace> = Box::new(1usize);
+        assert!(!leaf.traversible());
+
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let entries = journaled(|| {
+            let first = Gc::new(Counted { drops: drops.clone() });
+            let second = Gc::new(Counted { drops: drops.clone() });
+
+            let children: Vec<Box<Trace>> = vec![Box::new(first),
+                                                 Box::new(Wrapper { child: second })];
+            assert!(children.iter().all(|child| child.traversible()));
+
+            GcRoot::new(Holder { children: children });
+        });
+
+        // both children, the holder and the holder's decrement
+        assert_eq!(entries.len(), 4);
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        for entry in entries.iter().take(3) {
+            tx.send(*entry);
+        }
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        assert_eq!(heap.live_count(), 3);
+
+        tx.send(entries[3]);
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        assert_eq!(heap.live_count(), 0);
+    }
+
+    #[test]
+    fn test_coalesce_new_root() {
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let allocate_and_root = |coalesce| {
+            journaled(|| {
+                AppThread::coalesce_new(coalesce);
+
+                let root = Gc::new(Counted { drops: drops.clone() }).root();
+                drop(root);
+
+                AppThread::coalesce_new(false);
+            })
+        };
+
+        // the naive path writes the new object and the increment separately
+        let naive = allocate_and_root(false);
+        assert_eq!(naive.len(), 3);
+        assert_eq!(naive[0].ptr & !PTR_MASK, NEW_BIT);
+        assert_eq!(naive[1].ptr & !PTR_MASK, INC);
+
+        // the fused path writes a single new and rooted entry
+        let fused = allocate_and_root(true);
+        assert_eq!(fused.len(), 2);
+        assert_eq!(fused[0].ptr & !PTR_MASK, NEW_INC);
+        assert_eq!(fused[0].vtable, naive[0].vtable);
+
+        // both are collected the same way
+        for entries in vec![naive, fused] {
+            let mut pool = Pool::new(1);
+            let mut heap = test_heap();
+
+            let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+            heap.add_journal(rx);
+
+            let before = drops.load(Ordering::SeqCst);
+
+            // rooted until the decrement is read
+            tx.send(entries[0]);
+            if entries.len() == 3 {
+                tx.send(entries[1]);
+            }
+            heap.read_journals();
+            heap.minor_collection(&mut pool);
+            assert_eq!(drops.load(Ordering::SeqCst), before);
+
+            tx.send(entries[entries.len() - 1]);
+            heap.read_journals();
+            heap.minor_collection(&mut pool);
+            heap.minor_collection(&mut pool);
+            assert_eq!(drops.load(Ordering::SeqCst), before + 1);
+        }
+    }
+
+    #[cfg(feature = "checked")]
+    #[test]
+    fn test_try_deref_after_collection() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut garbage = Gc::null();
+        let mut kept = None;
+
+        let entries = journaled(|| {
+            garbage = Gc::new(Counted { drops: drops.clone() });
+            kept = Some(GcRoot::new(Counted { drops: drops.clone() }));
+        });
+        let kept = kept.unwrap();
+
+        assert!(garbage.try_deref().is_some());
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        for entry in entries.iter() {
+            tx.send(*entry);
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        // the unrooted object was dropped, the rooted one was not
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert!(garbage.try_deref().is_none());
+        assert!(kept.as_gc().try_deref().is_some());
+        assert!(Gc::<Counted>::null().try_deref().is_none());
+    }
+
+    #[test]
+    fn test_batched_entries_not_lost() {
+        const BATCH: usize = 8;
+        const COUNT: usize = 20;
+
+        let (jtx, jrx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        let mut roots = Vec::new();
+        let mut received = Vec::new();
+
+        GC_JOURNAL.with(|j| j.set(&jtx));
+        AppThread::batch_entries(BATCH);
+
+        for i in 0..COUNT {
+            roots.push(GcRoot::new(i));
+        }
+
+        // only full batches have been written
+        while let Ok(entry) = jrx.try_recv() {
+            received.push(entry);
+        }
+        assert_eq!(received.len(), COUNT / BATCH * BATCH);
+
+        // a safepoint writes out the rest
+        super::flush_pending();
+        while let Ok(entry) = jrx.try_recv() {
+            received.push(entry);
+        }
+
+        AppThread::batch_entries(0);
+        GC_JOURNAL.with(|j| j.set(null()));
+
+        // every entry arrived once, in the order written
+        assert_eq!(received.len(), COUNT);
+        for (entry, root) in received.iter().zip(roots.iter()) {
+            assert_eq!(entry.ptr & PTR_MASK, root.as_gc().as_raw().unwrap() as usize);
+            assert_eq!(entry.ptr & !PTR_MASK, NEW_INC);
+        }
+    }
+
+    #[test]
+    fn test_gc_tags() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut parent = None;
+
+        let entries = journaled(|| {
+            let child = Gc::new(Counted { drops: drops.clone() }).with_tag(3);
+            parent = Some(GcRoot::new(Tagged { child: child }));
+        });
+        let parent = parent.unwrap();
+        let child = parent.child;
+
+        // the tag is kept apart from the address
+        assert_eq!(child.tag(), 3);
+        assert_eq!(child.with_tag(1).tag(), 1);
+        assert_eq!(child.with_tag(0).tag(), 0);
+        assert_eq!(child.as_raw().unwrap() as usize & TAG_MASK, 0);
+        assert!(child.is(child.with_tag(0)));
+        assert!(child == child.with_tag(2));
+        assert_eq!(Gc::<Counted>::null().with_tag(2).as_raw(), None);
+
+        // nothing tagged reaches the journal
+        for entry in entries.iter() {
+            assert_eq!(entry.ptr & PTR_MASK & TAG_MASK, 0);
+        }
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        for entry in entries.iter() {
+            tx.send(*entry);
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        // the child is only reachable through the tagged pointer
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        assert_eq!(child.drops.load(Ordering::SeqCst), 0);
+
+        // once the parent is unrooted both are collected as usual
+        for entry in journaled(|| drop(parent)) {
+            tx.send(entry);
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Gc tag needs")]
+    fn test_gc_tag_underaligned() {
+        // a byte may be allocated at any address, leaving no bits free for a tag
+        Gc::<u8>::null().with_tag(1);
+    }
+
+    #[test]
+    fn test_gcatomic_swap_root() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut addrs = (0, 0);
+
+        let entries = journaled(|| {
+            let old = GcRoot::new(Counted { drops: drops.clone() });
+            let new = GcRoot::new(Counted { drops: drops.clone() });
+            addrs = (old.as_gc().as_raw().unwrap() as usize,
+                     new.as_gc().as_raw().unwrap() as usize);
+
+            let slot = GcAtomic::from_gc(old.as_gc());
+            drop(old);
+
+            let previous = slot.swap_root(new, Ordering::AcqRel);
+            assert_eq!(previous.as_gc().as_raw().unwrap() as usize, addrs.0);
+            assert_eq!(slot.load_raw(Ordering::Acquire) as usize, addrs.1);
+        });
+        let (old, new) = addrs;
+
+        // the previous object is incremented before the new root is decremented
+        let written: Vec<_> = entries.iter().map(|entry| entry.ptr).collect();
+        assert_eq!(written,
+                   vec![old | NEW_INC, new | NEW_INC, old, old | INC, new, old]);
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        // the returned root keeps the previous object alive
+        for entry in entries[..4].iter() {
+            tx.send(*entry);
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        for entry in entries[4..].iter() {
+            tx.send(*entry);
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_alloc_on_gc_thread() {
+        /// Allocates a child the first time it is traced
+        struct Lazy {
+            child: Cell<Gc<usize>>,
+        }
+
+        unsafe impl Trace for Lazy {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                if self.child.get().is_null() {
+                    self.child.set(Gc::new(1));
+                }
+                self.child.get().trace(stack);
+            }
+        }
+
+        // outside a GC the allocation goes unjournaled
+        let lazy = Lazy { child: Cell::new(Gc::null()) };
+        let mut stack = TraceStack::new();
+        unsafe { lazy.trace(&mut stack) };
+        assert_eq!(stack.len(), 1);
+        unsafe { drop(Box::from_raw(lazy.child.get().as_raw().unwrap())) };
+
+        let traced = thread::spawn(|| {
+            super::enter_gc_thread();
+
+            let lazy = Lazy { child: Cell::new(Gc::null()) };
+            let mut stack = TraceStack::new();
+            unsafe { lazy.trace(&mut stack) };
+        });
+
+        let error = traced.join().unwrap_err();
+        let message = error.downcast_ref::<&str>().unwrap();
+        assert!(message.starts_with("GC managed objects cannot be allocated on a GC thread"));
+    }
+
+    #[test]
+    fn test_batched_increments_not_held_back() {
+        let (jtx, jrx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+
+        GC_JOURNAL.with(|j| j.set(&jtx));
+        AppThread::batch_entries(8);
+
+        // the new object entry is held back until the increment of the clone is written
+        let root = GcRoot::new(1usize);
+        assert!(jrx.try_recv().is_err());
+
+        let clone = root.clone();
+        let new = jrx.try_recv().expect("new object entry held back");
+        let inc = jrx.try_recv().expect("increment held back");
+        assert_eq!(new.ptr & !PTR_MASK, NEW_INC);
+        assert_eq!(inc.ptr & !PTR_MASK, INC);
+
+        // the decrement is held back
+        drop(clone);
+        assert!(jrx.try_recv().is_err());
+
+        super::flush_pending();
+        assert_eq!(jrx.try_recv().expect("decrement lost").ptr & !PTR_MASK, DEC);
+
+        AppThread::batch_entries(0);
+        GC_JOURNAL.with(|j| j.set(null()));
+    }
+
+    #[test]
+    fn test_root_scope_decrements_together() {
+        let (jtx, jrx) = journal::make_journal(JOURNAL_BUFFER_SIZE);
+
+        GC_JOURNAL.with(|j| j.set(&jtx));
+
+        let roots: Vec<_> = (0..3).map(|i| GcRoot::new(i)).collect();
+        let scope = root_scope!(roots[0].as_gc(), roots[1].as_gc(), roots[2].as_gc());
+        while let Ok(_) = jrx.try_recv() {}
+
+        // with batching enabled the decrements are still written straight away, together
+        AppThread::batch_entries(8);
+        drop(scope);
+
+        for root in roots.iter() {
+            let entry = jrx.try_recv().expect("decrement held back");
+            assert_eq!(entry.ptr, root.ptr() as usize);
+        }
+        assert!(jrx.try_recv().is_err());
+
+        AppThread::batch_entries(0);
+        drop(roots);
+        GC_JOURNAL.with(|j| j.set(null()));
+    }
+
+    #[test]
+    fn test_explicit_write_barrier() {
+        let cards = CardTable::new();
+        GC_CARDS.with(|c| c.set(&cards));
+
+        // outside an app thread nothing is journaled
+        let holder = Gc::new(Slot { child: Cell::new(Gc::null()) });
+        let addr = holder.as_raw().unwrap() as usize;
+        assert!(!cards.is_dirty_range(addr, 1));
+
+        holder.child.set(Gc::new(1));
+        holder.write_barrier();
+        assert!(cards.is_dirty_range(addr, 1));
+
+        Gc::<usize>::null().write_barrier();
+        GC_CARDS.with(|c| c.set(null()));
+    }
+
+    #[test]
+    fn test_new_uninit_zero_sized() {
+        struct Unit;
+
+        unsafe impl Trace for Unit {}
+
+        // nothing is allocated, so dropping it frees nothing
+        let unused = Gc::<Unit>::new_uninit();
+        assert!(!unused.ptr.is_null());
+        assert_eq!(unused.ptr as usize % align_of::<GcBox<Unit>>(), 0);
+        assert_eq!(unused.ptr as usize % PTR_ALIGN, 0);
+        drop(unused);
+
+        let entries = journaled(|| {
+            let gc = Gc::<Unit>::new_uninit().write(Unit);
+            assert!(!gc.ptr().is_null());
+        });
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].ptr & !PTR_MASK, NEW);
     }
 }
@@ -0,0 +1,222 @@
+//! A GC that is driven by polling rather than by running its own thread, for use from an
+//! executor task.
+//!
+//! Each call to `poll_collect()` does a bounded amount of work: it reads a run of journal
+//! entries and does one minor collection. Collection work is still done in the thread pool.
+//!
+//! An app thread that calls `AppThread::collect_now()` blocks until the next poll.
+
+
+use std::sync::{Arc, mpsc};
+use std::thread;
+
+use scoped_pool::Pool;
+
+use appthread::AppThread;
+use cardtable::CardTable;
+use config::GcConfig;
+use constants::MAJOR_COLLECT_THRESHOLD;
+use gcthread::{GcCommand, JournalReceiver, JournalSender};
+use heap::CollectOps;
+use statistics::{Accounting, StatsLogger};
+use youngheap::YoungHeap;
+
+
+/// The result of `AsyncGc::poll_collect()`, to be mapped onto the executor's own poll type.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Poll<T> {
+    /// Work is complete
+    Ready(T),
+    /// More work remains, poll again
+    Pending,
+}
+
+
+/// A GC that does a step of collection each time it is polled instead of running in a dedicated
+/// thread.
+pub struct AsyncGc<S: StatsLogger, T: CollectOps + Send> {
+    /// This is cloned and given to app threads.
+    tx_chan: JournalSender,
+
+    /// New app thread journals arrive here.
+    rx_chan: JournalReceiver,
+
+    /// This is cloned and given to app threads for `AppThread::collect_now()`.
+    ctl_tx: mpsc::Sender<GcCommand>,
+
+    /// Requests from app threads.
+    ctl_rx: mpsc::Receiver<GcCommand>,
+
+    heap: YoungHeap<S, T>,
+
+    pool: Pool,
+
+    /// Allocation accounting shared with app threads.
+    accounting: Arc<Accounting>,
+
+    /// Write barrier card table shared with app threads.
+    cards: Arc<CardTable>,
+
+    /// Count of consecutive polls in which no journal entries were read
+    idle_cycles: usize,
+
+    /// Set once the first journal has connected
+    started: bool,
+
+    /// Set once the final collection has been done
+    finished: bool,
+}
+
+
+impl<S: StatsLogger, T: CollectOps + Send> AsyncGc<S, T> {
+    /// Create a GC that collects only when polled.
+    pub fn new(config: GcConfig, mature: T, logger: S) -> AsyncGc<S, T> {
+        let (tx, rx) = mpsc::channel();
+        let (ctl_tx, ctl_rx) = mpsc::channel();
+
+        let accounting = Arc::new(Accounting::new());
+        let cards = Arc::new(CardTable::new());
+
+        AsyncGc {
+            tx_chan: tx,
+            rx_chan: rx,
+            ctl_tx: ctl_tx,
+            ctl_rx: ctl_rx,
+            heap: YoungHeap::new(config, mature, logger, accounting.clone(), cards.clone()),
+            pool: Pool::new(config.shard_count()),
+            accounting: accounting,
+            cards: cards,
+            idle_cycles: 0,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Spawn an app thread that journals to this GC.
+    pub fn spawn<F, R>(&self, f: F) -> thread::JoinHandle<R>
+        where F: FnOnce() -> R,
+              F: Send + 'static,
+              R: Send + 'static
+    {
+        AppThread::spawn_from_gc(self.tx_chan.clone(),
+                                 self.accounting.clone(),
+                                 self.cards.clone(),
+                                 self.ctl_tx.clone(),
+                                 f)
+    }
+
+    /// Do a step of collection: read the journals and run a minor collection, and a major
+    /// collection if the young generation has grown large. Returns `Ready` once app threads have
+    /// been spawned and all of them have finished, after a final collection.
+    pub fn poll_collect(&mut self) -> Poll<()> {
+        if self.finished {
+            return Poll::Ready(());
+        }
+
+        // new appthreads connected
+        while let Ok(journal) = self.rx_chan.try_recv() {
+            if !self.started {
+                self.heap.logger().mark_start_time();
+                self.started = true;
+            }
+
+            self.heap.add_journal(journal);
+        }
+
+        if !self.started {
+            return Poll::Pending;
+        }
+
+        // app threads only send collect requests, which are served on the next poll
+        while let Ok(command) = self.ctl_rx.try_recv() {
+            if let GcCommand::Collect(reply) = command {
+                self.heap.collect_all(&mut self.pool);
+                let _ = reply.send(());
+            }
+        }
+
+        if self.heap.num_journals() == 0 {
+            // do a final collection where all roots should be unrooted
+            self.heap.minor_collection(&mut self.pool);
+            self.heap.major_collection(&mut self.pool);
+
+            self.heap.logger().mark_end_time();
+            self.finished = true;
+
+            return Poll::Ready(());
+        }
+
+        if self.heap.read_journals() == 0 {
+            self.idle_cycles += 1;
+        } else {
+            self.idle_cycles = 0;
+        }
+
+        // the first idle poll still has the last read decrements to act on
+        if self.idle_cycles <= 1 {
+            let young_count = self.heap.minor_collection(&mut self.pool).young_live;
+
+            if young_count >= MAJOR_COLLECT_THRESHOLD {
+                self.heap.major_collection(&mut self.pool);
+            }
+        }
+
+        Poll::Pending
+    }
+
+    /// Return the live object count, young and mature, as of the most recent collection.
+    pub fn live_count(&self) -> usize {
+        self.heap.live_count()
+    }
+
+    /// Return the allocation accounting for this GC.
+    pub fn accounting(&self) -> &Accounting {
+        &self.accounting
+    }
+
+    /// Consume the GC, returning the object that implements `StatsLogger`.
+    pub fn shutdown(self) -> S {
+        self.heap.shutdown()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use appthread::GcRoot;
+    use config::GcConfig;
+    use parheap::ParHeap;
+    use statistics::DefaultLogger;
+
+    use super::{AsyncGc, Poll};
+
+
+    #[test]
+    fn test_poll_to_completion() {
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+
+        let mut gc = AsyncGc::new(config, ParHeap::new(2), DefaultLogger::new());
+        assert_eq!(gc.poll_collect(), Poll::Pending);
+
+        let app = gc.spawn(|| {
+            let roots: Vec<_> = (0..1000usize).map(|i| GcRoot::new(i)).collect();
+            drop(roots);
+        });
+        app.join().unwrap();
+
+        let mut polls = 0;
+        while gc.poll_collect() == Poll::Pending {
+            polls += 1;
+            assert!(polls < 100, "GC did not complete");
+        }
+
+        assert_eq!(gc.poll_collect(), Poll::Ready(()));
+        assert_eq!(gc.live_count(), 0);
+        assert_eq!(gc.accounting().allocated(), gc.accounting().tracked());
+
+        let logger = gc.shutdown();
+        assert_eq!(logger.total_dropped(), 1000);
+    }
+}
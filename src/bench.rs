@@ -0,0 +1,379 @@
+//! A benchmark harness for comparing GC configurations on a parameterized workload.
+//!
+//! A `Workload` describes what each app thread allocates and for how long it keeps each object
+//! rooted. `run()` spawns a GC with the given configuration and mature heap, runs the workload on
+//! its app threads and returns a `BenchResult` with the allocation throughput, the peak heap
+//! size, the peak number of journal buffers used up in one read and the GC duty cycle.
+//!
+//! `compare()` runs a workload against the default `ParHeap` configuration and a young-only one,
+//! and `compare_single_threaded()` a single app thread against the default and single threaded
+//! configurations, see `examples/bench.rs`.
+
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use appthread::GcRoot;
+use config::GcConfig;
+use gcthread::GcThread;
+use heap::CollectOps;
+use parheap::ParHeap;
+use statistics::{CollectionReport, StatsLogger};
+use trace::Trace;
+
+
+/// How often the GC duty cycle is sampled while a workload runs
+const SAMPLE_INTERVAL_MS: u64 = 10;
+
+/// Number of young generation age buckets in the young-only configuration. Objects that survive
+/// this many minor collections are promoted anyway.
+pub const YOUNG_ONLY_AGES: usize = 1024;
+
+
+/// How long each object stays rooted, counted in allocations made by the same app thread after
+/// it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Lifetime {
+    /// unrooted straight away
+    Immediate,
+    /// rooted for exactly this many allocations
+    Fixed(usize),
+    /// rooted for a uniformly distributed number of allocations from zero up to this many
+    Uniform(usize),
+}
+
+
+/// What each app thread of a benchmark does.
+#[derive(Copy, Clone, Debug)]
+pub struct Workload {
+    /// bytes of payload owned by each object, in addition to the object itself
+    pub object_size: usize,
+    /// number of objects each app thread allocates
+    pub count: usize,
+    /// how long each object stays rooted
+    pub lifetime: Lifetime,
+    /// number of app threads
+    pub threads: usize,
+}
+
+
+/// The measurements of a benchmark run.
+#[derive(Copy, Clone, Debug)]
+pub struct BenchResult {
+    /// objects allocated across all app threads
+    pub allocated: usize,
+    /// time from spawning the app threads until the last one finished, not including the final
+    /// collection
+    pub elapsed: Duration,
+    /// the most objects live at the end of any collection
+    pub peak_heap: usize,
+    /// the most journal buffers used up in a single read of the journals
+    pub peak_journal_buffers: usize,
+    /// the mean GC duty cycle while the app threads ran, see `GcThread::duty_cycle()`
+    pub duty_cycle: f64,
+}
+
+
+/// A `StatsLogger` that only tracks the peaks a `BenchResult` reports.
+pub struct BenchLogger {
+    peak_heap: AtomicUsize,
+    peak_journal_buffers: AtomicUsize,
+}
+
+
+/// The object allocated by a workload
+struct Payload {
+    _bytes: Vec<u8>,
+}
+
+
+unsafe impl Trace for Payload {}
+
+
+/// A small xorshift generator, so that workloads are repeatable without a dependency
+struct XorShift(u64);
+
+
+impl Lifetime {
+    // The longest lifetime this can give
+    fn max(&self) -> usize {
+        match *self {
+            Lifetime::Immediate => 0,
+            Lifetime::Fixed(n) => n,
+            Lifetime::Uniform(n) => n,
+        }
+    }
+
+    // Pick a lifetime
+    fn sample(&self, rng: &mut XorShift) -> usize {
+        match *self {
+            Lifetime::Immediate => 0,
+            Lifetime::Fixed(n) => n,
+            Lifetime::Uniform(n) => (rng.next() % (n as u64 + 1)) as usize,
+        }
+    }
+}
+
+
+impl Workload {
+    /// A single app thread allocating a million short lived 64 byte objects.
+    pub fn new() -> Workload {
+        Workload {
+            object_size: 64,
+            count: 1000000,
+            lifetime: Lifetime::Uniform(100),
+            threads: 1,
+        }
+    }
+}
+
+
+impl BenchResult {
+    /// Objects allocated per second.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        if seconds > 0.0 {
+            self.allocated as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+
+impl BenchLogger {
+    pub fn new() -> BenchLogger {
+        BenchLogger {
+            peak_heap: AtomicUsize::new(0),
+            peak_journal_buffers: AtomicUsize::new(0),
+        }
+    }
+}
+
+
+impl StatsLogger for BenchLogger {
+    fn mark_start_time(&mut self) {}
+
+    fn mark_end_time(&mut self) {}
+
+    fn add_sleep(&self, _ms: usize) {}
+
+    fn add_dropped(&self, _count: usize) {}
+
+    fn current_heap_size(&self, size: usize) {
+        store_max(&self.peak_heap, size);
+    }
+
+    fn collection_report(&self, report: &CollectionReport) {
+        store_max(&self.peak_heap, report.young_live + report.mature_live);
+    }
+
+    fn add_journal_stats(&self, _items_read: usize, buffer_transitions: usize) {
+        store_max(&self.peak_journal_buffers, buffer_transitions);
+    }
+
+    fn dump_to_stdout(&self) {}
+}
+
+
+impl XorShift {
+    fn new(seed: usize) -> XorShift {
+        // the state must never be zero
+        XorShift((seed as u64).wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+
+/// Run `workload` on a GC with the given parameters and mature heap and measure it.
+///
+/// Panics if an app thread or the GC thread panics.
+pub fn run<T>(workload: &Workload, config: GcConfig, mature: T) -> BenchResult
+    where T: CollectOps + Send + 'static
+{
+    let gc = GcThread::spawn_gc_with_config(config, mature, BenchLogger::new());
+
+    let start = Instant::now();
+    let (done_tx, done_rx) = mpsc::channel();
+
+    let apps: Vec<_> = (0..workload.threads)
+        .map(|index| {
+            let workload = *workload;
+            let done = done_tx.clone();
+
+            gc.spawn(move || {
+                allocate(&workload, index);
+                done.send(()).expect("Benchmark runner has exited!");
+            })
+        })
+        .collect();
+
+    drop(done_tx);
+
+    // sample the duty cycle until every app thread has finished
+    let mut finished = 0;
+    let mut duty_total = 0.0;
+    let mut samples = 0;
+
+    while finished < workload.threads {
+        match done_rx.recv_timeout(Duration::from_millis(SAMPLE_INTERVAL_MS)) {
+            Ok(()) => finished += 1,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        duty_total += gc.duty_cycle();
+        samples += 1;
+    }
+
+    let elapsed = start.elapsed();
+
+    for app in apps {
+        app.join().expect("Benchmark app thread panicked!");
+    }
+
+    let logger = gc.join().expect("GC thread panicked!");
+
+    BenchResult {
+        allocated: workload.count * workload.threads,
+        elapsed: elapsed,
+        peak_heap: logger.peak_heap.load(Ordering::Acquire),
+        peak_journal_buffers: logger.peak_journal_buffers.load(Ordering::Acquire),
+        duty_cycle: if samples > 0 {
+            duty_total / samples as f64
+        } else {
+            0.0
+        },
+    }
+}
+
+
+/// The default configuration with a `ParHeap`, as `GcThread::spawn_gc()`.
+pub fn par_config() -> GcConfig {
+    GcConfig::new()
+}
+
+
+/// A configuration in which objects are collected in the young generation only, by ageing them
+/// through `YOUNG_ONLY_AGES` buckets before promotion.
+pub fn young_only_config() -> GcConfig {
+    let mut config = GcConfig::new();
+    config.nursery_ages = YOUNG_ONLY_AGES;
+    config
+}
+
+
+/// The default configuration in single threaded mode, see `GcConfig::single_threaded`.
+pub fn single_threaded_config() -> GcConfig {
+    let mut config = GcConfig::new();
+    config.single_threaded = true;
+    config
+}
+
+
+/// Run `workload` against the default `ParHeap` configuration and the young-only configuration,
+/// returning the name and result of each.
+pub fn compare(workload: &Workload) -> Vec<(&'static str, BenchResult)> {
+    let par = par_config();
+    let young = young_only_config();
+
+    vec![("par", run(workload, par, ParHeap::new(par.num_threads))),
+         ("young-only", run(workload, young, ParHeap::new(young.num_threads)))]
+}
+
+
+/// Run `workload` on a single app thread, whatever its `threads`, against the default
+/// `ParHeap` configuration and the single threaded configuration, returning the name and result
+/// of each.
+pub fn compare_single_threaded(workload: &Workload) -> Vec<(&'static str, BenchResult)> {
+    let mut workload = *workload;
+    workload.threads = 1;
+
+    let par = par_config();
+    let single = single_threaded_config();
+
+    vec![("par", run(&workload, par, ParHeap::new(par.shard_count()))),
+         ("single", run(&workload, single, ParHeap::new(single.shard_count())))]
+}
+
+
+/// Allocate the workload of one app thread
+fn allocate(workload: &Workload, index: usize) {
+    let mut rng = XorShift::new(index + 1);
+
+    // roots held for n more allocations are kept in the bucket n ahead of the current one
+    let span = workload.lifetime.max() + 1;
+    let mut buckets: Vec<Vec<GcRoot<Payload>>> = (0..span).map(|_| Vec::new()).collect();
+
+    for i in 0..workload.count {
+        buckets[i % span].clear();
+
+        let root = GcRoot::new(Payload { _bytes: vec![0; workload.object_size] });
+
+        let lifetime = workload.lifetime.sample(&mut rng);
+        if lifetime > 0 {
+            buckets[(i + lifetime) % span].push(root);
+        }
+    }
+}
+
+
+/// Raise `atomic` to `value` if it is lower
+fn store_max(atomic: &AtomicUsize, value: usize) {
+    let mut current = atomic.load(Ordering::Relaxed);
+
+    while value > current {
+        match atomic.compare_exchange(current, value, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(previous) => current = previous,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::{compare, compare_single_threaded, Lifetime, Workload};
+
+
+    #[test]
+    fn test_compare() {
+        let workload = Workload {
+            object_size: 16,
+            count: 10000,
+            lifetime: Lifetime::Fixed(100),
+            threads: 2,
+        };
+
+        for &(name, ref result) in compare(&workload).iter() {
+            assert!(result.allocated == 20000, "{}", name);
+            assert!(result.peak_heap <= result.allocated, "{}", name);
+            assert!(result.duty_cycle >= 0.0 && result.duty_cycle <= 1.0, "{}", name);
+            assert!(result.throughput() > 0.0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_compare_single_threaded() {
+        let workload = Workload {
+            object_size: 16,
+            count: 10000,
+            lifetime: Lifetime::Uniform(100),
+            threads: 2,
+        };
+
+        for &(name, ref result) in compare_single_threaded(&workload).iter() {
+            assert!(result.allocated == 10000, "{}", name);
+            assert!(result.peak_heap <= result.allocated, "{}", name);
+            assert!(result.throughput() > 0.0, "{}", name);
+        }
+    }
+}
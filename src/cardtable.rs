@@ -0,0 +1,168 @@
+//! A card table for the young generation write barrier
+//!
+//! The address space is divided into cards of `1 << CARD_SHIFT` bytes. App threads mark the
+//! cards of an object dirty whenever they may write a pointer into it, and the young generation
+//! mark phase only traces old objects that have a dirty card.
+//!
+//! The table is of a fixed size and card numbers are wrapped into it, so unrelated cards can
+//! alias. That only ever causes extra tracing, never missed tracing.
+//!
+//! Note that the write barrier is applied when a mutable reference is taken, before the pointer
+//! is actually written, and the mark cleans a card before it traces the object. A store that
+//! lands after that trace would not be seen until the card is next dirtied, so a cleaned card
+//! stays dirty for one more mark: every store made before the end of the next collection is
+//! traced. A mutable reference held across more than one collection needs an explicit
+//! `Gc::write_barrier()` after its writes.
+//!
+//! With the `barrier_stats` feature the table also counts write barriers and the cards they
+//! found clean, for measuring the cost of the barrier. The counters are shared by every app
+//! thread, so counting slows the barrier down.
+
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use constants::{CARD_SHIFT, CARD_TABLE_SIZE};
+
+
+/// A fixed size table of dirty flags, one per card.
+pub struct CardTable {
+    cards: Vec<AtomicBool>,
+    // cards cleaned by the last mark, still reported dirty until the mark after
+    cleaned: Vec<AtomicBool>,
+    // write barriers applied since the counts were last taken
+    barriers: AtomicUsize,
+    // clean cards dirtied by those barriers
+    dirtied: AtomicUsize,
+}
+
+
+impl CardTable {
+    /// Create a new card table with all cards clean.
+    pub fn new() -> CardTable {
+        let mut cards = Vec::with_capacity(CARD_TABLE_SIZE);
+        let mut cleaned = Vec::with_capacity(CARD_TABLE_SIZE);
+        for _ in 0..CARD_TABLE_SIZE {
+            cards.push(AtomicBool::new(false));
+            cleaned.push(AtomicBool::new(false));
+        }
+
+        CardTable {
+            cards: cards,
+            cleaned: cleaned,
+            barriers: AtomicUsize::new(0),
+            dirtied: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn card(&self, card: usize) -> &AtomicBool {
+        &self.cards[card & (CARD_TABLE_SIZE - 1)]
+    }
+
+    #[inline]
+    fn cleaned(&self, card: usize) -> &AtomicBool {
+        &self.cleaned[card & (CARD_TABLE_SIZE - 1)]
+    }
+
+    /// Mark the card containing the given address as dirty.
+    #[inline]
+    pub fn dirty(&self, addr: usize) {
+        self.card(addr >> CARD_SHIFT).store(true, Ordering::Relaxed);
+    }
+
+    /// The app thread side of the write barrier: mark the card containing the given address as
+    /// dirty, counting the barrier with the `barrier_stats` feature.
+    #[inline]
+    pub fn barrier(&self, addr: usize) {
+        self.count_barrier(addr);
+        self.dirty(addr);
+    }
+
+    /// Return the number of write barriers applied and of clean cards they dirtied since the
+    /// last call, resetting both to zero. Always zero without the `barrier_stats` feature.
+    pub fn take_barrier_counts(&self) -> (usize, usize) {
+        (self.barriers.swap(0, Ordering::Relaxed), self.dirtied.swap(0, Ordering::Relaxed))
+    }
+
+    #[cfg(feature = "barrier_stats")]
+    #[inline]
+    fn count_barrier(&self, addr: usize) {
+        self.barriers.fetch_add(1, Ordering::Relaxed);
+
+        if !self.card(addr >> CARD_SHIFT).load(Ordering::Relaxed) {
+            self.dirtied.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(not(feature = "barrier_stats"))]
+    #[inline]
+    fn count_barrier(&self, _addr: usize) {}
+
+    /// Mark every card spanned by the given object as dirty.
+    pub fn dirty_range(&self, addr: usize, size: usize) {
+        for card in card_range(addr, size) {
+            self.card(card).store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Clean every card spanned by the given object, before the mark traces it. A card that was
+    /// dirty is still reported dirty until the next call, so that a store made after the write
+    /// barrier and after this trace is traced by the next mark.
+    pub fn clean_range(&self, addr: usize, size: usize) {
+        for card in card_range(addr, size) {
+            let was_dirty = self.card(card).swap(false, Ordering::Relaxed);
+            self.cleaned(card).store(was_dirty, Ordering::Relaxed);
+        }
+    }
+
+    /// Return true if any card spanned by the given object is dirty, or was dirty when the last
+    /// mark cleaned it.
+    pub fn is_dirty_range(&self, addr: usize, size: usize) -> bool {
+        card_range(addr, size).any(|card| {
+            self.card(card).load(Ordering::Relaxed) || self.cleaned(card).load(Ordering::Relaxed)
+        })
+    }
+}
+
+
+/// Range of card numbers that an object occupies. Zero sized objects occupy one card.
+#[inline]
+fn card_range(addr: usize, size: usize) -> ::std::ops::Range<usize> {
+    let last = addr + if size > 0 { size - 1 } else { 0 };
+    (addr >> CARD_SHIFT)..((last >> CARD_SHIFT) + 1)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use constants::CARD_SHIFT;
+
+    use super::CardTable;
+
+
+    #[test]
+    fn test_cleaned_card_stays_dirty_for_one_mark() {
+        let cards = CardTable::new();
+        let addr = 3 << CARD_SHIFT;
+
+        assert!(!cards.is_dirty_range(addr, 1));
+        cards.barrier(addr);
+        assert!(cards.is_dirty_range(addr, 1));
+
+        // the first clean still reports the card dirty, for a store after the barrier
+        cards.clean_range(addr, 1);
+        assert!(cards.is_dirty_range(addr, 1));
+
+        cards.clean_range(addr, 1);
+        assert!(!cards.is_dirty_range(addr, 1));
+
+        // a barrier between marks restarts the count
+        cards.clean_range(addr, 1);
+        cards.barrier(addr);
+        cards.clean_range(addr, 1);
+        assert!(cards.is_dirty_range(addr, 1));
+        cards.clean_range(addr, 1);
+        assert!(!cards.is_dirty_range(addr, 1));
+    }
+}
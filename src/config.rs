@@ -0,0 +1,366 @@
+//! Runtime GC parameters
+
+
+use std::fmt;
+
+use num_cpus;
+
+use affinity;
+use constants::{AGE_SHIFT, MAX_ENTRY_BATCH, USER_FLAG_SHIFT};
+
+
+/// Parameters that tune GC behavior, given to `GcThread::spawn_gc_with_config()`.
+#[derive(Copy, Clone)]
+pub struct GcConfig {
+    /// Size of the GC thread pool
+    pub num_threads: usize,
+
+    /// Number of young generation age buckets. A new object ages by one bucket for each minor
+    /// collection it survives and becomes eligible for promotion to the mature heap once it
+    /// reaches the last bucket.
+    pub nursery_ages: usize,
+
+    /// Number of consecutive idle GC cycles, in which no journal entries were read, after which
+    /// the thread pool is shut down. It is created again when collection resumes. Zero keeps the
+    /// thread pool alive for the lifetime of the GC.
+    pub pool_idle_cycles: usize,
+
+    /// Learn which young generation objects are leaves: an object that is traversible but whose
+    /// `trace()` pushed nothing is not traced again until it is written to.
+    pub learn_leaves: bool,
+
+    /// Mark and sweep the young generation in a single shard, in address order, so that a given
+    /// heap is always collected the same way. Useful for reproducing test failures. The mature
+    /// heap should also be created with a single thread.
+    pub deterministic: bool,
+
+    /// Optimize for a single app thread: reference counts are adjusted without atomics and
+    /// collection is not sharded. The GC thread panics if a second app thread connects while the
+    /// first is still running.
+    pub single_threaded: bool,
+
+    /// Initial capacity of the buffer that holds reference count decrements between collections.
+    pub deferred_capacity: usize,
+
+    /// Soft cap on the number of buffered decrements. When reading the journals fills the buffer
+    /// to this size, the decrements are applied as soon as every journal has been read to empty
+    /// since they were read, rather than after the next collection, which shortens the window in
+    /// which an app thread's unrooting is deferred. Zero means no cap.
+    ///
+    /// This is not a hard bound. A decrement cannot be applied before the increments written
+    /// ahead of it, which may still be in another journal, so the buffer holds the cap plus every
+    /// decrement read since each journal was last read to empty. Under constant journal traffic
+    /// that never lets every journal empty, it grows until the next collection merges it. See
+    /// `YoungHeap::deferred_peak()`.
+    pub deferred_limit: usize,
+
+    /// Buffered decrements are merged into the reference counts in the thread pool only if there
+    /// are at least this many. Fewer are merged on the GC thread, where the work is cheaper than
+    /// dispatching it to the pool.
+    pub merge_threshold: usize,
+
+    /// Throttle allocation so that app threads can allocate at most this many new objects ahead
+    /// of the GC reading them from the journals. Each new object takes a token from a bucket of
+    /// this size and the GC puts tokens back at the rate it reads new objects, blocking app
+    /// threads while the bucket is empty. Zero does not throttle allocation.
+    pub alloc_tokens: usize,
+
+    /// What to do on finding that an internal invariant has been broken, which means the journal
+    /// or the heap has been corrupted.
+    pub on_violation: InvariantViolation,
+
+    /// CPUs that the GC thread and its thread pool are pinned to, as a bit mask with bit n for
+    /// CPU n. Zero leaves them unpinned. Only the first 64 CPUs can be named and pinning is only
+    /// supported on Linux: elsewhere the mask is ignored.
+    pub cpu_affinity: u64,
+
+    /// Run a minor collection only once at least this many new objects have been read from the
+    /// journals since the last one. While fewer have, the GC thread just keeps reading the
+    /// journals; a collection still runs once the app threads go quiet. Zero runs a minor
+    /// collection on every GC loop iteration that read anything.
+    pub minor_threshold: usize,
+
+    /// Drop unreachable objects on a dedicated thread rather than on the thread pool as they are
+    /// swept, so that slow destructors do not hold up collection. Objects are then dropped some
+    /// time after the collection that found them unreachable.
+    pub drop_thread: bool,
+
+    /// Maximum number of rounds of a minor and a major collection to run at shutdown. Rounds are
+    /// run until nothing is live or a round frees nothing, so that garbage that takes more than
+    /// one collection to be found, such as objects whose last decrement was read in the previous
+    /// round, is dropped. Anything still live after the last round is reported as leaked. At
+    /// least one round is always run.
+    pub shutdown_collections: usize,
+
+    /// While the thread pool marks the mature heap, move whatever the app threads have written
+    /// to the journals out of the journal buffers every this many milliseconds, so that a long
+    /// mark does not leave the app threads piling up buffers. The entries are processed after
+    /// the collection as usual. Zero leaves the journals alone until the collection is done.
+    pub mark_journal_interval: usize,
+
+    /// The most entries moved out of each journal's buffers during a mark, see
+    /// `mark_journal_interval`. They are held in memory until the collection is done, so past
+    /// this many an app thread piles up buffers again instead of the GC holding ever more.
+    pub mark_journal_limit: usize,
+
+    /// Consider the heap under memory pressure while more than this many objects are live after
+    /// a collection. Under pressure the GC thread runs a major collection after every minor
+    /// collection and major collections promote every rooted new object regardless of its age,
+    /// so that long-lived objects stop being traced by minor collections and mature garbage is
+    /// found sooner. Pressure only ends once no more than three quarters of this many objects are
+    /// live, so that a heap hovering about the limit does not flip in and out of it at every
+    /// collection. Zero means no limit.
+    pub heap_limit: usize,
+
+    /// Collect the mature heaps on a dedicated thread with its own thread pool, while the GC
+    /// thread carries on reading the journals and running minor collections, so that a long
+    /// major collection does not leave the nursery to grow unchecked. A major collection that
+    /// falls due while one is running is skipped. Collections that callers wait for, such as
+    /// `AppThread::collect_now()`, and the final collections still collect the mature heaps on
+    /// the GC thread, after waiting for a running one to finish.
+    pub background_major: bool,
+
+    /// Never sleep: while the journals are empty the GC thread yields and reads them again
+    /// rather than backing off, so that it picks up work as soon as there is any. This is for
+    /// throughput benchmarks: the GC thread keeps a CPU fully busy for as long as any app thread
+    /// is connected, even while every app thread is idle.
+    pub never_sleep: bool,
+
+    /// Called on the GC thread for each object a major collection promotes from the young
+    /// generation to the mature heap, with the object's address, its `Trace` vtable and the age
+    /// bucket it was promoted from, for analyzing the generational policy. The object's size can
+    /// be read through its vtable. Objects tenured by `GcRoot::tenure()` are not reported. The
+    /// callback runs in the middle of the collection so it should be quick, such as pushing onto
+    /// a buffer that is written out later.
+    pub on_promote: Option<fn(usize, usize, usize)>,
+
+    /// Limit the time spent reading the journals in each GC loop iteration to about this many
+    /// milliseconds. The journals are read pass after pass until a pass finds them empty or the
+    /// time is up, and whatever is left is read in the next iteration, so that app threads
+    /// writing faster than the GC reads do not hold off collections and requests. Each pass reads
+    /// up to 1024 entries from every journal, and the limit is checked every 64 entries read, so
+    /// a pass over many busy journals is cut short rather than run to its end. Zero reads a fixed
+    /// number of passes instead.
+    pub journal_drain_time: usize,
+
+    /// Drop the objects a sweep finds unreachable children first: each object is dropped after
+    /// every other object of the same sweep that it points at, so that a destructor can rely on
+    /// what its object owns having been finalized. The order among objects on a cycle is
+    /// arbitrary. Ordering means tracing the unreachable objects once more and dropping them one
+    /// at a time on the GC thread, or the drop thread, rather than in parallel. Objects that
+    /// become unreachable in different collections, such as a young child of a mature parent,
+    /// are dropped in the order of those collections.
+    pub topological_drop: bool,
+}
+
+
+/// The policy for a broken internal invariant, such as a reference count decrement for an object
+/// the GC does not know about.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InvariantViolation {
+    /// Abort the process, for failing fast while debugging
+    Abort,
+    /// Panic the GC thread
+    Panic,
+    /// Report the violation to the `StatsLogger` and carry on, for resilience in production.
+    /// Objects involved may leak.
+    LogAndContinue,
+}
+
+
+/// A combination of `GcConfig` parameters the GC cannot run with, see `GcConfig::validate()`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// `num_threads` is zero, leaving no thread to collect with and no shard to split work into
+    ZeroThreads,
+    /// `nursery_ages` is zero, leaving new objects no age bucket to be in
+    ZeroNurseryAges,
+    /// `nursery_ages` is more than the given maximum, the most ages the root flags can count
+    TooManyNurseryAges(usize),
+    /// `alloc_tokens` is given but no more than `MAX_ENTRY_BATCH`, so an app thread holding back
+    /// a batch of new objects could block waiting for tokens that the GC only puts back once it
+    /// has read them
+    TooFewAllocTokens,
+    /// `minor_threshold` is more than `heap_limit`, so the heap is over its limit before every
+    /// minor collection
+    MinorThresholdOverHeapLimit,
+    /// `mark_journal_interval` is given but `mark_journal_limit` is zero, so nothing would be
+    /// moved out of the journal buffers during a mark
+    ZeroMarkJournalLimit,
+    /// `cpu_affinity`, which is given, names no CPU the calling thread may run on
+    AffinityOutOfRange(u64),
+}
+
+
+impl GcConfig {
+    /// Default parameters, parallelized across all available CPUs.
+    pub fn new() -> GcConfig {
+        GcConfig {
+            num_threads: num_cpus::get(),
+            nursery_ages: 1,
+            pool_idle_cycles: 0,
+            learn_leaves: false,
+            deterministic: false,
+            single_threaded: false,
+            deferred_capacity: 0,
+            deferred_limit: 0,
+            merge_threshold: 1024,
+            alloc_tokens: 0,
+            on_violation: InvariantViolation::Panic,
+            cpu_affinity: 0,
+            minor_threshold: 0,
+            drop_thread: false,
+            shutdown_collections: 8,
+            mark_journal_interval: 0,
+            mark_journal_limit: 1 << 20,
+            heap_limit: 0,
+            background_major: false,
+            never_sleep: false,
+            on_promote: None,
+            journal_drain_time: 0,
+            topological_drop: false,
+        }
+    }
+
+    /// The number of shards collection work is split into and the size of the thread pool.
+    pub fn shard_count(&self) -> usize {
+        if self.deterministic || self.single_threaded {
+            1
+        } else {
+            self.num_threads
+        }
+    }
+
+    /// Check that the parameters can be run with, returning the first problem found otherwise.
+    /// `GcThread::spawn_gc_with_config()` and the other ways of spawning a GC panic with the
+    /// error rather than failing later on the GC thread.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.num_threads == 0 {
+            return Err(ConfigError::ZeroThreads);
+        }
+
+        if self.nursery_ages == 0 {
+            return Err(ConfigError::ZeroNurseryAges);
+        }
+
+        let max_ages = 1 << (USER_FLAG_SHIFT - AGE_SHIFT);
+        if self.nursery_ages > max_ages {
+            return Err(ConfigError::TooManyNurseryAges(max_ages));
+        }
+
+        if self.alloc_tokens != 0 && self.alloc_tokens <= MAX_ENTRY_BATCH {
+            return Err(ConfigError::TooFewAllocTokens);
+        }
+
+        if self.heap_limit != 0 && self.minor_threshold > self.heap_limit {
+            return Err(ConfigError::MinorThresholdOverHeapLimit);
+        }
+
+        if self.mark_journal_interval != 0 && self.mark_journal_limit == 0 {
+            return Err(ConfigError::ZeroMarkJournalLimit);
+        }
+
+        // where the affinity cannot be queried it cannot be set either, and is ignored
+        if self.cpu_affinity != 0 {
+            if let Some(allowed) = affinity::current_thread() {
+                if self.cpu_affinity & allowed == 0 {
+                    return Err(ConfigError::AffinityOutOfRange(self.cpu_affinity));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::ZeroThreads => write!(f, "num_threads must be at least one"),
+            ConfigError::ZeroNurseryAges => write!(f, "nursery_ages must be at least one"),
+            ConfigError::TooManyNurseryAges(max) => {
+                write!(f, "nursery_ages must be no more than {}", max)
+            }
+            ConfigError::TooFewAllocTokens => {
+                write!(f, "alloc_tokens must be zero or more than {}", MAX_ENTRY_BATCH)
+            }
+            ConfigError::MinorThresholdOverHeapLimit => {
+                write!(f, "minor_threshold must be no more than heap_limit")
+            }
+            ConfigError::ZeroMarkJournalLimit => {
+                write!(f, "mark_journal_limit must be at least one if mark_journal_interval is set")
+            }
+            ConfigError::AffinityOutOfRange(mask) => {
+                write!(f, "cpu_affinity {:#x} names no CPU this process may run on", mask)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use affinity;
+    use constants::{AGE_SHIFT, MAX_ENTRY_BATCH, USER_FLAG_SHIFT};
+
+    use super::{ConfigError, GcConfig};
+
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(GcConfig::new().validate(), Ok(()));
+
+        let mut config = GcConfig::new();
+        config.num_threads = 0;
+        assert_eq!(config.validate(), Err(ConfigError::ZeroThreads));
+
+        // single threaded collection does not use the thread count but it must still be valid
+        config.single_threaded = true;
+        assert_eq!(config.validate(), Err(ConfigError::ZeroThreads));
+
+        let mut config = GcConfig::new();
+        config.nursery_ages = 0;
+        assert_eq!(config.validate(), Err(ConfigError::ZeroNurseryAges));
+
+        let max_ages = 1 << (USER_FLAG_SHIFT - AGE_SHIFT);
+        config.nursery_ages = max_ages;
+        assert_eq!(config.validate(), Ok(()));
+        config.nursery_ages = max_ages + 1;
+        assert_eq!(config.validate(), Err(ConfigError::TooManyNurseryAges(max_ages)));
+
+        let mut config = GcConfig::new();
+        config.alloc_tokens = MAX_ENTRY_BATCH;
+        assert_eq!(config.validate(), Err(ConfigError::TooFewAllocTokens));
+        config.alloc_tokens = MAX_ENTRY_BATCH + 1;
+        assert_eq!(config.validate(), Ok(()));
+
+        // without a heap limit any minor threshold is fine
+        let mut config = GcConfig::new();
+        config.minor_threshold = 1000;
+        assert_eq!(config.validate(), Ok(()));
+        config.heap_limit = 999;
+        assert_eq!(config.validate(), Err(ConfigError::MinorThresholdOverHeapLimit));
+
+        let mut config = GcConfig::new();
+        config.mark_journal_limit = 0;
+        assert_eq!(config.validate(), Ok(()));
+        config.mark_journal_interval = 1;
+        assert_eq!(config.validate(), Err(ConfigError::ZeroMarkJournalLimit));
+
+        if let Some(allowed) = affinity::current_thread() {
+            if allowed != !0 {
+                let forbidden = 1 << (!allowed).trailing_zeros();
+
+                let mut config = GcConfig::new();
+                config.cpu_affinity = forbidden;
+                assert_eq!(config.validate(), Err(ConfigError::AffinityOutOfRange(forbidden)));
+
+                // a mask naming at least one allowed CPU is fine
+                config.cpu_affinity |= allowed;
+                assert_eq!(config.validate(), Ok(()));
+            }
+        }
+    }
+}
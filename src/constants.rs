@@ -7,19 +7,102 @@
 pub const JOURNAL_BUFFER_SIZE: usize = 32768;
 pub const BUFFER_RUN: usize = 1024;
 pub const JOURNAL_RUN: usize = 32;
+// entries read from a journal between checks of GcConfig::journal_drain_time
+pub const DRAIN_CHECK_RUN: usize = 64;
 pub const MAX_SLEEP_DUR: usize = 100;  // milliseconds
 pub const MIN_SLEEP_DUR: usize = 1;    // milliseconds
 pub const MAJOR_COLLECT_THRESHOLD: usize = 1 << 20;
+// memory pressure, once over GcConfig::heap_limit, lasts until the live count is back under the
+// limit less this fraction of it, as a right shift: a quarter
+pub const HEAP_LOW_WATER_SHIFT: usize = 2;
+pub const JOURNAL_ALLOC_RETRIES: usize = 100;
+// the most journal entries an app thread can hold back in a batch, see AppThread::batch_entries()
+pub const MAX_ENTRY_BATCH: usize = 64;
+// the GC duty cycle weighs the most recent this many milliseconds most heavily
+pub const DUTY_CYCLE_WINDOW: usize = 1000;  // milliseconds
 
 // Cache line in bytes
 pub const CACHE_LINE: usize = 64;
 
+// Object size histograms: class 0 holds objects of up to SMALLEST_SIZE_CLASS bytes and each class
+// after it objects of up to twice the size, the last class holding everything larger
+pub const SIZE_CLASSES: usize = 16;
+pub const SMALLEST_SIZE_CLASS: usize = 8;
+
+// Card table parameters: card size is 1 << CARD_SHIFT bytes, table size must be a power of two
+pub const CARD_SHIFT: usize = 9;
+pub const CARD_TABLE_SIZE: usize = 1 << 20;
+
 // Bits and masks
-pub const PTR_MASK: usize = !3;
+//
+// The low bits of object addresses and vtable pointers are always zero and carry flags instead.
+// How many bits are free depends on the alignment the target guarantees for both: every layout
+// needs at least 4 byte alignment for the two journal flag bits and the mark and traverse bits.
+// The constants here are the layout for the target, as `BitLayout::for_alignment(PTR_ALIGN)`,
+// and are what every masking site uses: `BitLayout` only describes and checks layouts, it does
+// not drive the masking at run time.
+
+// guaranteed alignment in bytes of GC managed objects and vtables, and its log2
+#[cfg(target_pointer_width = "32")]
+pub const PTR_ALIGN: usize = 4;
+#[cfg(target_pointer_width = "32")]
+pub const PTR_SHIFT: usize = 2;
+#[cfg(not(target_pointer_width = "32"))]
+pub const PTR_ALIGN: usize = 8;
+#[cfg(not(target_pointer_width = "32"))]
+pub const PTR_SHIFT: usize = 3;
+
+pub const PTR_MASK: usize = !FLAGS_MASK;
+// the low bits of a Gc pointer that can carry a user tag, see Gc::with_tag()
+pub const TAG_BITS: usize = PTR_SHIFT;
+pub const TAG_MASK: usize = PTR_ALIGN - 1;
 pub const MARK_BIT: usize = 1;
-pub const MARK_MASK: usize = !1;
+pub const MARK_MASK: usize = !MARK_BIT;
 pub const TRAVERSE_BIT: usize = 2;
 
+// set in the vtable of an INC journal entry that is followed by an entry holding the count
+pub const BATCH_BIT: usize = 1;
+pub const BATCH_MASK: usize = !1;
+// set in the vtable of a DEC journal entry to request that the object be tenured instead
+pub const TENURE_BIT: usize = 1;
+// set in the vtable of a DEC journal entry to request that the GC stop tracking the object
+// instead, without dropping it
+pub const UNTRACK_BIT: usize = 2;
+
+// set in the vtable of a NEW or NEW_INC journal entry that is followed by an entry holding the
+// kind of mature heap the object is to be promoted into
+pub const KIND_BIT: usize = 1;
+pub const KIND_MASK: usize = !1;
+
+// mask for the vtable pointer in a vtable word that carries mark and traverse bits
+pub const VTABLE_MASK: usize = !(PTR_ALIGN - 1);
+
+// root flags bit hinting that an object has been seen to trace no children
+pub const LEAF_BIT: usize = 4;
+pub const LEAF_MASK: usize = !4;
+
+// mature heap kind is stored in the root flags from this bit up to the age
+pub const KIND_SHIFT: usize = 3;
+pub const MAX_KINDS: usize = 32;
+
+// set in the kind operand of a new object journal entry, and kept in the root flags, for an
+// object that is never promoted, see `Gc::new_ephemeral()`
+pub const EPHEMERAL_KIND: usize = MAX_KINDS;
+pub const EPHEMERAL_BIT: usize = 256;
+
+// young generation age bucket is stored in the root flags above this bit, up to the user flags
+pub const AGE_SHIFT: usize = 9;
+
+// the top bits of the root flags are never touched by the GC and are left to extensions, see
+// `YoungHeap::set_user_flag()`
+pub const USER_FLAG_BITS: usize = 8;
+#[cfg(target_pointer_width = "32")]
+pub const USER_FLAG_SHIFT: usize = 24;
+#[cfg(not(target_pointer_width = "32"))]
+pub const USER_FLAG_SHIFT: usize = 56;
+// mask for the root flags used by the GC
+pub const INTERNAL_FLAGS_MASK: usize = (1 << USER_FLAG_SHIFT) - 1;
+
 // mask for low bits of address of object through journal
 pub const FLAGS_MASK: usize = 3;
 
@@ -38,3 +121,108 @@ pub const NEW: usize = 2;
 pub const INC: usize = 1;
 // decrement refcount value
 pub const DEC: usize = 0;
+
+
+/// The flag bits available in an object address or vtable pointer aligned to a given number of
+/// bytes, for checking that a layout fits the alignment a platform or allocator guarantees.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BitLayout {
+    /// the guaranteed alignment in bytes
+    pub align: usize,
+    /// how far an address can be shifted right without losing information
+    pub ptr_shift: usize,
+    /// mask for the address in a journal entry
+    pub ptr_mask: usize,
+    /// mask for the journal flags in a journal entry
+    pub flags_mask: usize,
+    /// the mark bit in a vtable word
+    pub mark_bit: usize,
+    /// the traverse bit in a vtable word
+    pub traverse_bit: usize,
+    /// mask for the vtable pointer in a vtable word
+    pub vtable_mask: usize,
+}
+
+
+impl BitLayout {
+    /// The layout for addresses aligned to `align` bytes. The journal flags and the mark and
+    /// traverse bits are fixed by the journal and vtable word formats and are the same in every
+    /// layout; the alignment decides the pointer shift, the vtable mask and the spare bits.
+    ///
+    /// Panics if `align` is not a power of two of at least 4.
+    pub fn for_alignment(align: usize) -> BitLayout {
+        assert!(align >= 4 && align.is_power_of_two(),
+                "alignment {} leaves too few flag bits",
+                align);
+
+        BitLayout {
+            align: align,
+            ptr_shift: align.trailing_zeros() as usize,
+            ptr_mask: !FLAGS_MASK,
+            flags_mask: FLAGS_MASK,
+            mark_bit: MARK_BIT,
+            traverse_bit: TRAVERSE_BIT,
+            vtable_mask: !(align - 1),
+        }
+    }
+
+    /// The layout the constants in this module describe for the target.
+    pub fn target() -> BitLayout {
+        BitLayout {
+            align: PTR_ALIGN,
+            ptr_shift: PTR_SHIFT,
+            ptr_mask: PTR_MASK,
+            flags_mask: FLAGS_MASK,
+            mark_bit: MARK_BIT,
+            traverse_bit: TRAVERSE_BIT,
+            vtable_mask: VTABLE_MASK,
+        }
+    }
+
+    /// The number of low bits left unused by every flag, for packing more flags.
+    pub fn spare_bits(&self) -> usize {
+        let used = self.flags_mask | self.mark_bit | self.traverse_bit;
+        ((self.align - 1) & !used).count_ones() as usize
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::BitLayout;
+
+
+    #[test]
+    fn test_bit_layouts() {
+        let four = BitLayout::for_alignment(4);
+        assert_eq!(four.ptr_shift, 2);
+        assert_eq!(four.vtable_mask, !3);
+        assert_eq!(four.spare_bits(), 0);
+
+        let eight = BitLayout::for_alignment(8);
+        assert_eq!(eight.ptr_shift, 3);
+        assert_eq!(eight.vtable_mask, !7);
+        assert_eq!(eight.spare_bits(), 1);
+
+        let sixteen = BitLayout::for_alignment(16);
+        assert_eq!(sixteen.ptr_shift, 4);
+        assert_eq!(sixteen.vtable_mask, !15);
+        assert_eq!(sixteen.spare_bits(), 2);
+
+        for layout in [four, eight, sixteen].iter() {
+            // flags never overlap each other or the pointer
+            let vtable_flags = layout.mark_bit | layout.traverse_bit;
+            assert_eq!(vtable_flags & layout.vtable_mask, 0);
+            assert_eq!(layout.mark_bit & layout.traverse_bit, 0);
+            assert_eq!(layout.flags_mask & layout.ptr_mask, 0);
+            assert_eq!(layout.flags_mask | layout.ptr_mask, !0);
+
+            // shifting out the journal flags loses no address bits
+            assert!(1 << layout.ptr_shift <= layout.align);
+            assert!(1 << layout.ptr_shift > layout.flags_mask);
+        }
+
+        assert_eq!(BitLayout::target(), BitLayout::for_alignment(super::PTR_ALIGN));
+    }
+}
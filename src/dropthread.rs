@@ -0,0 +1,86 @@
+//! A dedicated thread for running the destructors of swept objects.
+//!
+//! By default objects are dropped where they are swept, on the GC thread pool, so a slow or
+//! blocking `Drop` holds up the collection it is swept in. With `GcConfig::drop_thread` set, the
+//! sweep instead hands each unreachable object to a `DropQueue` and a single drop thread calls
+//! `Trace::gc_drop()` on it, in the order the objects were queued.
+//!
+//! Objects are queued in batches, one per sweep shard, so that a sweep costs one channel send per
+//! pool thread rather than one per object.
+
+
+use std::sync::mpsc;
+use std::thread;
+
+use appthread::enter_gc_thread;
+use heap::Object;
+
+
+/// The sending side of the drop thread's queue. Each sweep shard takes its own clone.
+#[derive(Clone)]
+pub struct DropQueue {
+    tx: mpsc::Sender<Vec<Object>>,
+}
+
+
+/// Owns the drop thread. Dropping this waits for every queued object to be dropped.
+pub struct DropThread {
+    queue: Option<DropQueue>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+
+impl DropQueue {
+    /// Queue unreachable objects to be dropped on the drop thread. Does nothing if `objects` is
+    /// empty.
+    pub fn send(&self, objects: Vec<Object>) {
+        if !objects.is_empty() {
+            self.tx.send(objects).expect("Drop thread has exited!");
+        }
+    }
+}
+
+
+impl DropThread {
+    /// Spawn the drop thread.
+    pub fn spawn() -> DropThread {
+        let (tx, rx) = mpsc::channel::<Vec<Object>>();
+
+        let handle = thread::Builder::new()
+            .name("gc-drop".to_string())
+            .spawn(move || {
+                enter_gc_thread();
+
+                for objects in rx.iter() {
+                    for obj in objects {
+                        unsafe { obj.gc_drop() };
+                    }
+                }
+            })
+            .expect("Failed to spawn the drop thread!");
+
+        DropThread {
+            queue: Some(DropQueue { tx: tx }),
+            handle: Some(handle),
+        }
+    }
+
+    /// Return a new sender to the queue.
+    pub fn queue(&self) -> DropQueue {
+        self.queue.as_ref().expect("Drop thread has exited!").clone()
+    }
+}
+
+
+impl Drop for DropThread {
+    fn drop(&mut self) {
+        // the thread exits once every sender is gone and the queue is empty
+        self.queue.take();
+
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() && !thread::panicking() {
+                panic!("Drop thread panicked!");
+            }
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! A fixed length array that can be held in a GC managed object, with its elements in a single
+//! allocation and a trace that is split across threads when the array is large.
+//!
+//! Tracing a large collection one element after another on a single GC worker leaves the rest of
+//! the thread pool idle. `GcArray` instead divides its index range into shards of at least
+//! `PAR_TRACE_MIN` elements and traces them on a `ShardPool`, a thread pool each GC keeps for the
+//! purpose and finds through the trace stacks of its mark phases. The pool's threads are created
+//! the first time a large array is traced, as many as the GC's own, `GcConfig::shard_count()`,
+//! and again at the new size when the GC's pool is resized. They are created by a GC worker,
+//! whose CPU affinity they inherit. The objects each shard finds are handed back to the GC worker
+//! that traced the array. Arrays nested inside the elements of an array being traced in shards
+//! are traced on the shard's thread, and arrays traced onto a stack that no GC gave out on the
+//! calling thread.
+
+
+use std::cell::Cell;
+use std::cmp::max;
+use std::mem;
+use std::ops::{Index, IndexMut};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use scoped_pool::Pool;
+
+use appthread::enter_gc_thread;
+use heap::TraceStack;
+use trace::Trace;
+
+
+// arrays with fewer elements than this are traced on the calling thread
+const PAR_TRACE_MIN: usize = 4096;
+
+
+thread_local!(static IN_SHARD: Cell<bool> = Cell::new(false));
+
+
+/// An array of `T` whose length is fixed on creation, indexable in constant time.
+///
+/// Mutate it through a `Gc` or `GcRoot` so that the write barrier sees the change. A trace
+/// running concurrently with `set()` may still visit the element being replaced, so elements
+/// that own memory of their own must not free it while a trace could be reading it; GC pointers
+/// and plain values are always safe.
+pub struct GcArray<T: Trace> {
+    elements: Box<[T]>,
+}
+
+
+// A range of elements to trace on the array's thread pool
+struct Shard<T> {
+    elements: *const T,
+    len: usize,
+}
+
+
+// `Trace::trace()` must be thread-safe, so elements may be traced on any thread.
+unsafe impl<T> Send for Shard<T> {}
+
+
+/// The thread pool a GC traces the shards of large arrays on. Each `YoungHeap` creates its own and
+/// hands it to its mature heaps; the mark phases find it through the `TraceStack`s they take from
+/// a `TraceStackPool` given it. The threads are shut down once the GC has dropped the pool and no
+/// trace is still using them.
+pub struct ShardPool {
+    threads: AtomicUsize,
+    current: Mutex<Option<Arc<TracePool>>>,
+}
+
+
+// The threads of a ShardPool at one size, shut down once the last trace using them is done
+struct TracePool {
+    threads: usize,
+    pool: Pool,
+}
+
+
+impl Drop for TracePool {
+    fn drop(&mut self) {
+        self.pool.shutdown();
+    }
+}
+
+
+impl<T: Trace> GcArray<T> {
+    /// Create an array of `len` copies of `value`.
+    pub fn new(len: usize, value: T) -> GcArray<T>
+        where T: Clone
+    {
+        GcArray::from_vec(vec![value; len])
+    }
+
+    /// Create an array holding the elements of `elements`, in order.
+    pub fn from_vec(elements: Vec<T>) -> GcArray<T> {
+        GcArray { elements: elements.into_boxed_slice() }
+    }
+
+    /// Return the number of elements.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Return true if there are no elements.
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Return a reference to the element at `index`, or `None` if out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.elements.get(index)
+    }
+
+    /// Replace the element at `index` with `value`, returning the old element.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize, value: T) -> T {
+        let len = self.len();
+        match self.elements.get_mut(index) {
+            Some(element) => mem::replace(element, value),
+            None => panic!("GcArray index {} out of bounds for length {}", index, len),
+        }
+    }
+
+    /// Return the elements as a slice.
+    pub fn as_slice(&self) -> &[T] {
+        &self.elements
+    }
+}
+
+
+unsafe impl<T: Trace> Trace for GcArray<T> {
+    // elements that hold no GC pointers now may be set to ones that do
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        let shards = if self.len() < PAR_TRACE_MIN * 2 {
+            None
+        } else {
+            stack.shard_pool()
+        };
+
+        self.trace_on(stack, shards.as_ref().map(|shards| &**shards));
+    }
+}
+
+
+impl<T: Trace> GcArray<T> {
+    // Trace the elements, in shards on `shards` if given and the array is large
+    unsafe fn trace_on(&self, stack: &mut TraceStack, shards: Option<&ShardPool>) {
+        let in_shard = IN_SHARD.with(|flag| flag.get());
+        let threads = shards.map_or(0, |shards| shards.threads());
+
+        let shards = match shards {
+            Some(shards) if self.len() >= PAR_TRACE_MIN * 2 && threads >= 2 && !in_shard => shards,
+            _ => {
+                for element in self.elements.iter() {
+                    element.trace(stack);
+                }
+                return;
+            }
+        };
+
+        let shard_len = max(PAR_TRACE_MIN, (self.len() + threads - 1) / threads);
+        let mut found: Vec<TraceStack> = self.elements
+            .chunks(shard_len)
+            .map(|_| TraceStack::new())
+            .collect();
+
+        let pool = shards.pool();
+
+        pool.pool.scoped(|scope| {
+            for (chunk, shard_stack) in self.elements.chunks(shard_len).zip(found.iter_mut()) {
+                let shard = Shard {
+                    elements: chunk.as_ptr(),
+                    len: chunk.len(),
+                };
+
+                scope.execute(move || {
+                    IN_SHARD.with(|flag| flag.set(true));
+                    enter_gc_thread();
+
+                    for index in 0..shard.len {
+                        (*shard.elements.offset(index as isize)).trace(shard_stack);
+                    }
+                });
+            }
+        });
+
+        for shard_stack in found.iter_mut() {
+            stack.append(shard_stack);
+        }
+    }
+}
+
+
+impl<T: Trace> Index<usize> for GcArray<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(element) => element,
+            None => panic!("GcArray index {} out of bounds for length {}", index, self.len()),
+        }
+    }
+}
+
+
+impl<T: Trace> IndexMut<usize> for GcArray<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len();
+        match self.elements.get_mut(index) {
+            Some(element) => element,
+            None => panic!("GcArray index {} out of bounds for length {}", index, len),
+        }
+    }
+}
+
+
+impl ShardPool {
+    /// Create a pool that traces on `threads` threads, which are not started until needed.
+    pub fn new(threads: usize) -> ShardPool {
+        ShardPool {
+            threads: AtomicUsize::new(threads),
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Trace on `threads` threads from now on, the size of the GC's own thread pool. Threads of
+    /// another size still tracing shards are replaced once those traces are done.
+    pub fn set_threads(&self, threads: usize) {
+        self.threads.store(threads, Ordering::Release);
+    }
+
+    // The number of threads to trace shards on
+    fn threads(&self) -> usize {
+        self.threads.load(Ordering::Acquire)
+    }
+
+    // The threads to trace shards on, created if there are none of the current size
+    fn pool(&self) -> Arc<TracePool> {
+        let threads = self.threads();
+        let mut current = self.current.lock().expect("ShardPool lock poisoned!");
+
+        if let Some(ref pool) = *current {
+            if pool.threads == threads {
+                return pool.clone();
+            }
+        }
+
+        let pool = Arc::new(TracePool {
+            threads: threads,
+            pool: Pool::new(threads),
+        });
+        *current = Some(pool.clone());
+
+        pool
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::Cell;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use num_cpus;
+
+    use appthread::{AppThread, Gc, GcRoot};
+    use gcthread::GcThread;
+    use heap::{Object, TraceStack};
+    use trace::Trace;
+
+    use super::{GcArray, ShardPool, PAR_TRACE_MIN};
+
+
+    static NEXT_THREAD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    thread_local!(static THREAD: Cell<usize> = Cell::new(0));
+
+
+    // Return a number unique to the calling thread
+    fn thread_number() -> usize {
+        THREAD.with(|number| {
+            if number.get() == 0 {
+                number.set(NEXT_THREAD.fetch_add(1, Ordering::SeqCst) + 1);
+            }
+            number.get()
+        })
+    }
+
+
+    /// Pushes its value to the trace stack as if it were a pointer, along with the number of the
+    /// thread that traced it
+    #[derive(Clone)]
+    struct Probe(usize);
+
+    unsafe impl Trace for Probe {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            stack.push(Object {
+                ptr: self.0,
+                vtable: thread_number(),
+            });
+        }
+    }
+
+
+    /// Counts its own drops
+    struct Segment {
+        drops: Arc<AtomicUsize>,
+    }
+
+    unsafe impl Trace for Segment {}
+
+    impl Drop for Segment {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+
+    #[test]
+    fn test_parallel_trace() {
+        const COUNT: usize = PAR_TRACE_MIN * 64;
+        const TEST_THREADS: usize = 4;
+
+        let array = GcArray::from_vec((0..COUNT).map(Probe).collect());
+
+        let shards = ShardPool::new(TEST_THREADS);
+        let mut stack = TraceStack::new();
+        unsafe { array.trace_on(&mut stack, Some(&shards)) };
+
+        let mut values = Vec::with_capacity(COUNT);
+        let mut threads = HashSet::new();
+        while let Some(obj) = stack.pop() {
+            values.push(obj.ptr);
+            threads.insert(obj.vtable);
+        }
+
+        values.sort();
+        assert_eq!(values, (0..COUNT).collect::<Vec<_>>());
+
+        // the shards were traced on the array's thread pool, not on this thread
+        if num_cpus::get() > 1 {
+            assert!(!threads.contains(&thread_number()));
+            assert!(threads.len() > 1, "traced on {} thread", threads.len());
+        }
+
+        // without a GC's shard pool the whole array is traced on the calling thread
+        unsafe { array.trace(&mut stack) };
+        assert_eq!(stack.len(), COUNT);
+        while let Some(obj) = stack.pop() {
+            assert_eq!(obj.vtable, thread_number());
+        }
+
+        // small arrays are traced on the calling thread
+        let small = GcArray::new(PAR_TRACE_MIN, Probe(0));
+        unsafe { small.trace(&mut stack) };
+        assert_eq!(stack.len(), PAR_TRACE_MIN);
+        while let Some(obj) = stack.pop() {
+            assert_eq!(obj.vtable, thread_number());
+        }
+    }
+
+    #[test]
+    fn test_survival() {
+        const COUNT: usize = PAR_TRACE_MIN * 16;
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let segments: Vec<_> = (0..COUNT)
+                .map(|_| GcRoot::new(Segment { drops: app_drops.clone() }))
+                .collect();
+
+            let mut array = GcRoot::new(GcArray::from_vec(segments.iter()
+                .map(|segment| segment.as_gc())
+                .collect()));
+            drop(segments);
+
+            // the segments are reachable only through the array
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 0);
+            assert_eq!(array.len(), COUNT);
+
+            array.set(COUNT / 2, Gc::null());
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 1);
+            assert!(array[COUNT / 2].is_null());
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), COUNT);
+    }
+}
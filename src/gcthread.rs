@@ -2,21 +2,31 @@
 
 
 use std::any::Any;
-use std::cmp::min;
-use std::mem::size_of;
-use std::sync::mpsc;
+use std::cmp::{max, min};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::vec;
 
 use num_cpus;
 use scoped_pool::Pool;
 
-use appthread::AppThread;
-use constants::{MAJOR_COLLECT_THRESHOLD, MAX_SLEEP_DUR, MIN_SLEEP_DUR};
-use heap::{CollectOps, Object};
+use affinity;
+use appthread::{enter_gc_thread, AppThread, GcBox, RootSnapshot};
+use cardtable::CardTable;
+use config::{ConfigError, GcConfig};
+use constants::{DUTY_CYCLE_WINDOW, MAJOR_COLLECT_THRESHOLD, MAX_SLEEP_DUR, MIN_SLEEP_DUR,
+                PTR_SHIFT};
+use heap::{CollectOps, HeapError, HeapSnapshot, Object, RootEntry, WeakTable};
 use journal;
 use parheap::ParHeap;
-use statistics::{StatsLogger, DefaultLogger};
+use statistics::{Accounting, CollectionProgress, CollectionReport, StatsLogger, DefaultLogger};
+use trace::Trace;
 use youngheap::YoungHeap;
 
 
@@ -26,7 +36,8 @@ pub type EntrySender = journal::Sender<Object>;
 pub type JournalReceiver = mpsc::Receiver<EntryReceiver>;
 pub type JournalSender = mpsc::Sender<EntryReceiver>;
 
-pub type JournalList = Vec<EntryReceiver>;
+/// Journals paired with the id they were given when they connected
+pub type JournalList = Vec<(usize, EntryReceiver)>;
 
 
 /// The Garbage Collection thread handle.
@@ -36,6 +47,133 @@ pub struct GcThread<S: StatsLogger> {
 
     /// The GC thread's handle to join on.
     handle: thread::JoinHandle<S>,
+
+    /// Allocation accounting shared with the GC thread and app threads.
+    accounting: Arc<Accounting>,
+
+    /// Write barrier card table shared with the GC thread and app threads.
+    cards: Arc<CardTable>,
+
+    /// Live object count published by the GC thread after each collection.
+    status: Arc<HeapStatus>,
+
+    /// Requests to the GC thread.
+    ctl_chan: mpsc::Sender<GcCommand>,
+}
+
+
+/// The objects still live after the final collection, as `(address, vtable)` pairs, from
+/// `GcThread::join_with_survivors()`. The objects have not been dropped and never will be, so
+/// they can be inspected through their `Trace` vtables.
+pub struct Survivors {
+    objects: vec::IntoIter<Object>,
+}
+
+
+/// A handle to a detached GC thread, see `GcThread::detach()`. It can spawn app threads and send
+/// requests but not join. Cloning it is cheap.
+#[derive(Clone)]
+pub struct GcHandle {
+    tx_chan: JournalSender,
+    accounting: Arc<Accounting>,
+    cards: Arc<CardTable>,
+    status: Arc<HeapStatus>,
+    ctl_chan: mpsc::Sender<GcCommand>,
+}
+
+
+/// A request to the GC thread, sent with `GcThread::command()` and processed at the top of each
+/// GC loop iteration, between collections.
+pub enum GcCommand {
+    /// Read the journals and run a minor collection, even if paused or idle
+    MinorNow,
+    /// Read the journals and run a minor and a major collection, even if paused or idle
+    MajorNow,
+    /// As `MajorNow` but only collect the mature heaps of the given kinds
+    MajorOf(Vec<usize>),
+    /// Stop reading the journals and collecting until `Resume`. Journals keep growing meanwhile.
+    Pause,
+    /// Undo `Pause`
+    Resume,
+    /// Run a final collection and exit the GC thread even if app threads are still connected.
+    /// Nothing allocated afterwards is ever freed.
+    Shutdown,
+    /// Replace the GC parameters. The mature heap keeps the thread count it was created with, use
+    /// `SetPoolSize` to change it too. Parameters that do not pass `GcConfig::validate()` are
+    /// rejected, keeping the current ones, and the error is logged. `GcThread::set_config()`
+    /// returns the error instead.
+    SetConfig(GcConfig),
+    /// Resize the thread pool and reshard the young and mature heaps to match, see
+    /// `GcThread::set_pool_size()`
+    SetPoolSize(usize),
+    /// Read the journals and collect until everything unrooted and unreachable so far has been
+    /// dropped, then reply. Sent by `AppThread::collect_now()`.
+    Collect(mpsc::Sender<()>),
+    /// Run steps of a `Collect` until the budget is used up, carrying on from where the last one
+    /// stopped, then reply with how far it got. Sent by `AppThread::collect_in_steps()`.
+    CollectInSteps(Duration, mpsc::Sender<CollectionProgress>),
+    /// Read the journals and run exactly one minor collection, even if paused, then reply with
+    /// its report. Nothing is promoted beyond explicitly tenured objects and the mature heaps are
+    /// not collected. Sent by `GcThread::minor_now_blocking()` and `AppThread::minor_now()`.
+    MinorOnly(mpsc::Sender<CollectionReport>),
+    /// Count what a major collection would drop without dropping anything and send back the
+    /// count, see `GcThread::major_dry_run()`
+    MajorDryRun(mpsc::Sender<Option<usize>>),
+    /// Check heap invariants and send back the result
+    Verify(mpsc::Sender<Result<(), Vec<HeapError>>>),
+    /// Call the function with the address of each reachable object and the addresses of its
+    /// children, then reply
+    Visit(Box<FnMut(usize, &[usize]) + Send>, mpsc::Sender<()>),
+    /// Record every journal entry read from now on to the file, for `YoungHeap::replay_journal()`
+    Record(File),
+    /// Take a reference to every rooted object and send back their entries, see
+    /// `GcThread::snapshot_roots()`
+    SnapshotRoots(mpsc::Sender<Vec<RootEntry>>),
+    /// Send back whether the object at the address is reachable, see `GcThread::is_reachable()`
+    IsReachable(usize, mpsc::Sender<Option<bool>>),
+    /// Send back the unreachable cycles of objects, see `GcThread::find_cycles()`
+    FindCycles(mpsc::Sender<Vec<Vec<usize>>>),
+    /// Send back the reference count of the object at the address, see `GcThread::refcount_of()`
+    RefcountOf(usize, mpsc::Sender<Option<usize>>),
+    /// Start tracking an object allocated outside of the app threads, with one root reference
+    /// held on behalf of the caller, then reply. See `GcThread::register_foreign()`.
+    RegisterForeign(usize, usize, bool, mpsc::Sender<()>),
+    /// Drop the root reference `RegisterForeign` took on the object at the address
+    ReleaseForeign(usize),
+    /// Drop the references `SnapshotRoots` took on the objects, sent by `RootSnapshot` when it is
+    /// dropped
+    ReleaseSnapshot(Vec<RootEntry>),
+    /// Prune the table during each major collection from now on, see `WeakTable`
+    AddWeakTable(Box<WeakTable>),
+}
+
+
+/// A thread pool that is only created when a collection needs it, and that is shut down again
+/// after a number of consecutive idle cycles.
+struct LazyPool {
+    num_threads: usize,
+    idle_limit: usize,
+    idle_cycles: usize,
+    created: usize,
+    pool: Option<Pool>,
+}
+
+
+/// Live object count and collection epoch, published by the GC thread after every collection.
+struct HeapStatus {
+    live: AtomicUsize,
+    journals: AtomicUsize,
+    /// time in microseconds the GC loop spent working, decayed along with `total_us`
+    active_us: AtomicUsize,
+    /// time in microseconds the GC loop spent working or sleeping, halved on passing
+    /// `DUTY_CYCLE_WINDOW` so that recent activity dominates
+    total_us: AtomicUsize,
+    /// objects still live after the final collection
+    leaked: Mutex<Vec<Object>>,
+    /// the mature heaps after the final collection
+    heap: Mutex<HeapSnapshot>,
+    epoch: Mutex<usize>,
+    collected: Condvar,
 }
 
 
@@ -56,13 +194,63 @@ impl<S: StatsLogger + 'static> GcThread<S> {
     pub fn spawn_gc_with<T>(num_threads: usize, mature: T, logger: S) -> GcThread<S>
         where T: CollectOps + Send + 'static
     {
+        let mut config = GcConfig::new();
+        config.num_threads = num_threads;
+
+        Self::spawn_gc_with_config(config, mature, logger)
+    }
+
+    /// As `spawn_gc_with()` but takes a full set of GC parameters.
+    ///
+    /// Panics if `config` does not pass `GcConfig::validate()`.
+    pub fn spawn_gc_with_config<T>(config: GcConfig, mature: T, logger: S) -> GcThread<S>
+        where T: CollectOps + Send + 'static
+    {
+        Self::spawn_gc_with_heaps(config, vec![mature], logger)
+    }
+
+    /// As `spawn_gc_with_config()` but with a mature heap for each kind of object, as
+    /// `YoungHeap::with_mature_heaps()`. Major collections collect every heap unless a subset is
+    /// requested with `GcCommand::MajorOf`.
+    ///
+    /// Panics if `config` does not pass `GcConfig::validate()`.
+    pub fn spawn_gc_with_heaps<T>(config: GcConfig, mature: Vec<T>, logger: S) -> GcThread<S>
+        where T: CollectOps + Send + 'static
+    {
+        if let Err(error) = config.validate() {
+            panic!("Invalid GcConfig: {}", error);
+        }
+
         let (tx, rx) = mpsc::channel();
+        let (ctl_tx, ctl_rx) = mpsc::channel();
+
+        let accounting = Arc::new(Accounting::new());
+        let gc_accounting = accounting.clone();
 
-        let handle = thread::spawn(move || gc_thread(num_threads, rx, mature, logger));
+        let cards = Arc::new(CardTable::new());
+        let gc_cards = cards.clone();
+
+        let status = Arc::new(HeapStatus::new());
+        let gc_status = status.clone();
+
+        let handle = thread::spawn(move || {
+            gc_thread(config,
+                      rx,
+                      ctl_rx,
+                      mature,
+                      logger,
+                      gc_accounting,
+                      gc_cards,
+                      gc_status)
+        });
 
         GcThread {
             tx_chan: tx,
             handle: handle,
+            accounting: accounting,
+            cards: cards,
+            status: status,
+            ctl_chan: ctl_tx,
         }
     }
 
@@ -72,7 +260,216 @@ impl<S: StatsLogger + 'static> GcThread<S> {
               F: Send + 'static,
               T: Send + 'static
     {
-        AppThread::spawn_from_gc(self.tx_chan.clone(), f)
+        AppThread::spawn_from_gc(self.tx_chan.clone(),
+                                 self.accounting.clone(),
+                                 self.cards.clone(),
+                                 self.ctl_chan.clone(),
+                                 f)
+    }
+
+    /// Return the allocation accounting for this GC. Once all app threads have finished and their
+    /// journals have been read, the allocated and tracked counts should be equal.
+    pub fn accounting(&self) -> &Accounting {
+        &self.accounting
+    }
+
+    /// Return the live object count, young and mature, as of the most recent collection.
+    pub fn live_count(&self) -> usize {
+        self.status.live.load(Ordering::Acquire)
+    }
+
+    /// Return the number of app thread journals connected to the GC thread.
+    pub fn num_journals(&self) -> usize {
+        self.status.journals.load(Ordering::Acquire)
+    }
+
+    /// Return the fraction of time, between 0 and 1, that the GC thread has recently spent
+    /// reading journals and collecting rather than sleeping. Updated on every GC loop iteration
+    /// and weighted towards roughly the last `DUTY_CYCLE_WINDOW` milliseconds, so it can be
+    /// polled to react to GC load as it changes.
+    pub fn duty_cycle(&self) -> f64 {
+        self.status.duty_cycle()
+    }
+
+    /// Block until the live object count drops below `size`, returning true, or until `timeout`
+    /// elapses, returning false. The GC thread keeps collecting in the meantime.
+    pub fn wait_until_heap_below(&self, size: usize, timeout: Duration) -> bool {
+        self.status.wait_until_below(size, timeout)
+    }
+
+    /// Ask the GC thread to read the journals and run a single young generation collection, and
+    /// wait for it to finish. Young garbage that has been read is dropped but survivors are not
+    /// promoted and the mature heaps are left alone, which makes the pause predictable, for
+    /// example at a frame boundary. Returns the report of the collection.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn minor_now_blocking(&self) -> CollectionReport {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::MinorOnly(tx));
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// Ask the GC thread, between collections, how many mature objects a major collection would
+    /// drop now, without dropping them, for tuning collection thresholds. Returns `None` if a
+    /// mature heap cannot count without collecting. See `YoungHeap::major_dry_run()` for why the
+    /// count is a lower bound.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn major_dry_run(&self) -> Option<usize> {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::MajorDryRun(tx));
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// Ask the GC thread to check heap invariants between collections and wait for the result.
+    /// Any violations found are returned as a list of errors.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn verify_heap(&self) -> Result<(), Vec<HeapError>> {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::Verify(tx));
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// Ask the GC thread to walk the object graph from the roots between collections, calling
+    /// `visit` for each reachable object with its address and the addresses of the objects it
+    /// points at. Nothing is marked or collected. Returns once the walk is complete.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn visit_reachable<F>(&self, visit: F)
+        where F: FnMut(usize, &[usize]) + Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::Visit(Box::new(visit), tx));
+        rx.recv().expect("GC thread has exited!");
+    }
+
+    /// Ask the GC thread whether `object` is reachable from the roots, between collections. For
+    /// debugging. Returns `None` if the GC does not know the object: it has not read the object
+    /// from the journal yet or it has already dropped it. `Some(false)` means the object will be
+    /// dropped by a coming collection.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn is_reachable<T: Trace>(&self, object: *const GcBox<T>) -> Option<bool> {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::IsReachable(object as usize, tx));
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// Ask the GC thread, between collections, for the addresses of objects that point at each
+    /// other in cycles but are no longer reachable from any root, one list per cycle. For leak
+    /// analysis: these are the objects reference counting alone would never reclaim. They are
+    /// dropped by the next collection that traces them.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn find_cycles(&self) -> Vec<Vec<usize>> {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::FindCycles(tx));
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// Change the number of threads in the GC thread pool to `num_threads`, for when the
+    /// parallelism available to the application changes. Collection work in the young and mature
+    /// heaps is split into that many shards from then on. Can be called at any time: the change is
+    /// made between collections and the pool is recreated at the new size when next needed.
+    ///
+    /// Panics if `num_threads` is zero or the GC thread has exited.
+    pub fn set_pool_size(&self, num_threads: usize) {
+        assert!(num_threads > 0, "GC thread pool must have at least one thread");
+        self.command(GcCommand::SetPoolSize(num_threads));
+    }
+
+    /// Replace the GC parameters, as `GcCommand::SetConfig`, once the GC thread is between
+    /// collections. Returns the error and leaves the parameters as they were if `config` does not
+    /// pass `GcConfig::validate()`.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn set_config(&self, config: GcConfig) -> Result<(), ConfigError> {
+        try!(config.validate());
+        self.command(GcCommand::SetConfig(config));
+        Ok(())
+    }
+
+    /// Ask the GC thread for the reference count of an object, for diagnosing refcount
+    /// imbalances. The count is read between collections and decrements are only merged into it
+    /// by a collection, so call `AppThread::collect_now()` on the thread that unrooted the object
+    /// first to see them. Returns `None` if the GC does not know the object.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn refcount_of<T: Trace>(&self, object: *const GcBox<T>) -> Option<usize> {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::RefcountOf(object as usize, tx));
+        rx.recv().expect("GC thread has exited!")
+    }
+
+    /// Hand an object that was allocated without `Gc::new()`, for example by foreign code, to the
+    /// GC so that it takes part in collection. `ptr` and `vtable` are the data and vtable
+    /// pointers of the object as a `Trace` trait object. If `traversible` is false the object is
+    /// never traced. Can be called from any thread and returns once the GC thread has registered
+    /// the object.
+    ///
+    /// The object is registered holding one root reference on behalf of the caller, so it stays
+    /// live until `release_foreign()` is called for it. From then on it is dropped, with
+    /// `Trace::gc_drop()`, once it is unrooted and unreachable like any other object.
+    ///
+    /// Unsafe because the GC takes ownership of the object: it must be a live, `PTR_ALIGN`
+    /// aligned object of the type `vtable` belongs to, allocated as its `gc_drop()` expects (a
+    /// `Box` for the default), not already known to the GC and not freed by anything else.
+    /// `traversible` must be true if the object can hold GC managed pointers.
+    ///
+    /// Panics if the GC thread has exited.
+    pub unsafe fn register_foreign(&self, ptr: *mut (), vtable: *mut (), traversible: bool) {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::RegisterForeign(ptr as usize, vtable as usize, traversible, tx));
+        rx.recv().expect("GC thread has exited!");
+    }
+
+    /// Give up the root reference `register_foreign()` holds on the object at `ptr`.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn release_foreign(&self, ptr: *mut ()) {
+        self.command(GcCommand::ReleaseForeign(ptr as usize));
+    }
+
+    /// Ask the GC thread to capture the objects rooted as of the journal entries it has read so
+    /// far, between collections. Objects an app thread has rooted but the GC has not yet read are
+    /// not included; call `AppThread::collect_now()` on that thread first to be sure of them.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn snapshot_roots(&self) -> RootSnapshot {
+        let (tx, rx) = mpsc::channel();
+
+        self.command(GcCommand::SnapshotRoots(tx));
+        let entries = rx.recv().expect("GC thread has exited!");
+
+        unsafe { RootSnapshot::from_entries(entries, self.ctl_chan.clone()) }
+    }
+
+    /// Ask the GC thread to record every journal entry it reads from now on to the file at
+    /// `path`, which is created or truncated here. Entries read before the request is handled are
+    /// not recorded, so request recording before spawning app threads to capture a whole
+    /// workload. The recording is flushed when the GC thread exits.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn record_journal<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = try!(File::create(path));
+        self.command(GcCommand::Record(file));
+        Ok(())
+    }
+
+    /// Send a request to the GC thread. It is acted on before the next collection.
+    ///
+    /// Panics if the GC thread has exited.
+    pub fn command(&self, command: GcCommand) {
+        self.ctl_chan.send(command).expect("GC thread has exited!");
     }
 
     /// Wait for the GC thread to finish. On success, returns the object that implements
@@ -80,17 +477,145 @@ impl<S: StatsLogger + 'static> GcThread<S> {
     pub fn join(self) -> Result<S, Box<Any + Send + 'static>> {
         self.handle.join()
     }
+
+    /// As `join()` but also returns the objects that were still live after the final collection,
+    /// for inspecting leaks after the fact.
+    pub fn join_with_survivors(self) -> Result<(S, Survivors), Box<Any + Send + 'static>> {
+        let status = self.status.clone();
+        let logger = try!(self.handle.join());
+
+        let objects = mem::replace(&mut *status.leaked.lock().expect("HeapStatus lock poisoned!"),
+                                   Vec::new());
+
+        Ok((logger, Survivors { objects: objects.into_iter() }))
+    }
+
+    /// As `join()` but also returns the objects left in the mature heaps after the final
+    /// collection, with the pointers between them, for inspecting what an app left behind.
+    pub fn join_with_heap(self) -> Result<(S, HeapSnapshot), Box<Any + Send + 'static>> {
+        let status = self.status.clone();
+        let logger = try!(self.handle.join());
+
+        let snapshot = mem::replace(&mut *status.heap.lock().expect("HeapStatus lock poisoned!"),
+                                    HeapSnapshot { heaps: Vec::new() });
+
+        Ok((logger, snapshot))
+    }
+
+    /// Give up the ability to join the GC thread, returning a handle that can still spawn app
+    /// threads and send requests. The GC thread keeps running until every app thread journal has
+    /// disconnected and then exits on its own, dropping the `StatsLogger`.
+    pub fn detach(self) -> GcHandle {
+        self.detach_with(|_| {})
+    }
+
+    /// As `detach()` but `on_exit` is called with the result the GC thread would have given
+    /// `join()`, on a thread of its own, once the GC thread has exited.
+    pub fn detach_with<F>(self, on_exit: F) -> GcHandle
+        where F: FnOnce(Result<S, Box<Any + Send + 'static>>) + Send + 'static
+    {
+        let handle = self.handle;
+        thread::spawn(move || on_exit(handle.join()));
+
+        GcHandle {
+            tx_chan: self.tx_chan,
+            accounting: self.accounting,
+            cards: self.cards,
+            status: self.status,
+            ctl_chan: self.ctl_chan,
+        }
+    }
+}
+
+
+impl Iterator for Survivors {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        self.objects.next().map(|obj| (obj.ptr, obj.vtable))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.objects.size_hint()
+    }
+}
+
+
+impl ExactSizeIterator for Survivors {}
+
+
+impl GcHandle {
+    /// Spawn an app thread that journals to the GC thread, as `GcThread::spawn()`. The GC thread
+    /// must not have exited: it exits once the last app thread finishes, so spawn further app
+    /// threads from a running one to be sure.
+    pub fn spawn<F, T>(&self, f: F) -> thread::JoinHandle<T>
+        where F: FnOnce() -> T,
+              F: Send + 'static,
+              T: Send + 'static
+    {
+        AppThread::spawn_from_gc(self.tx_chan.clone(),
+                                 self.accounting.clone(),
+                                 self.cards.clone(),
+                                 self.ctl_chan.clone(),
+                                 f)
+    }
+
+    /// Return the allocation accounting for this GC.
+    pub fn accounting(&self) -> &Accounting {
+        &self.accounting
+    }
+
+    /// Return the live object count, young and mature, as of the most recent collection.
+    pub fn live_count(&self) -> usize {
+        self.status.live.load(Ordering::Acquire)
+    }
+
+    /// Return the number of app thread journals connected to the GC thread.
+    pub fn num_journals(&self) -> usize {
+        self.status.journals.load(Ordering::Acquire)
+    }
+
+    /// Send a request to the GC thread, as `GcThread::command()`. Returns false if the GC thread
+    /// has exited.
+    pub fn command(&self, command: GcCommand) -> bool {
+        self.ctl_chan.send(command).is_ok()
+    }
+
+    /// Stop collecting until `resume()`.
+    pub fn pause(&self) -> bool {
+        self.command(GcCommand::Pause)
+    }
+
+    /// Undo `pause()`.
+    pub fn resume(&self) -> bool {
+        self.command(GcCommand::Resume)
+    }
 }
 
 
 /// Main GC thread loop.
-fn gc_thread<S, T>(num_threads: usize, rx_chan: JournalReceiver, mature: T, logger: S) -> S
+fn gc_thread<S, T>(config: GcConfig,
+                  rx_chan: JournalReceiver,
+                  ctl_chan: mpsc::Receiver<GcCommand>,
+                  mature: Vec<T>,
+                  logger: S,
+                  accounting: Arc<Accounting>,
+                  cards: Arc<CardTable>,
+                  status: Arc<HeapStatus>)
+                  -> S
     where S: StatsLogger,
-          T: CollectOps + Send
+          T: CollectOps + Send + 'static
 {
-    let mut pool = Pool::new(num_threads);
+    enter_gc_thread();
+
+    // pin before the thread pool is created so that its workers inherit the affinity
+    if config.cpu_affinity != 0 && !affinity::set_current_thread(config.cpu_affinity) {
+        logger.log(&format!("GC thread could not be pinned to CPUs {:#x}", config.cpu_affinity));
+    }
+
+    let mut pool = LazyPool::new(config.shard_count(), config.pool_idle_cycles);
 
-    let mut gc = YoungHeap::new(num_threads, mature, logger);
+    let mut gc = YoungHeap::with_mature_heaps(config, mature, logger, accounting, cards);
 
     // block, wait for first journal
     gc.add_journal(rx_chan.recv().expect("Failed to receive first app journal!"));
@@ -100,43 +625,242 @@ fn gc_thread<S, T>(num_threads: usize, rx_chan: JournalReceiver, mature: T, logg
     // next duration to sleep if all journals are empty
     let mut sleep_dur: usize = 0;
 
+    // count of consecutive loop iterations in which no journal entries were read
+    let mut idle_cycles: usize = 0;
+
+    let mut paused = false;
+
+    // start of the previous loop iteration and how long it slept, for the duty cycle
+    let mut iteration_start = Instant::now();
+    let mut slept = Duration::new(0, 0);
+
     // loop until all journals are disconnected
     while gc.num_journals() > 0 {
 
+        status.add_duty(iteration_start.elapsed(), slept);
+        iteration_start = Instant::now();
+        slept = Duration::new(0, 0);
+
         // new appthread connected
         if let Ok(journal) = rx_chan.try_recv() {
             gc.add_journal(journal);
         }
 
+        status.journals.store(gc.num_journals(), Ordering::Release);
+
+        let mut force_minor = false;
+        let mut force_major = false;
+        let mut major_kinds = None;
+        let mut shutdown = false;
+
+        // requests are handled between collections, with the mature heaps in place
+        while let Ok(command) = ctl_chan.try_recv() {
+            if gc.finish_major().is_some() {
+                status.publish(gc.live_count());
+            }
+
+            match command {
+                GcCommand::MinorNow => force_minor = true,
+                GcCommand::MajorNow => force_major = true,
+                GcCommand::MajorOf(kinds) => major_kinds = Some(kinds),
+                GcCommand::Pause => paused = true,
+                GcCommand::Resume => paused = false,
+                GcCommand::Shutdown => shutdown = true,
+
+                GcCommand::SetConfig(config) => {
+                    match config.validate() {
+                        Ok(()) => {
+                            pool.configure(&config);
+                            gc.set_config(config);
+                        }
+
+                        Err(error) => gc.logger().log(&format!("Rejected GcConfig: {}", error)),
+                    }
+                }
+
+                GcCommand::SetPoolSize(num_threads) => {
+                    gc.set_num_threads(num_threads);
+                    pool.configure(gc.config());
+                }
+
+                GcCommand::Collect(reply) => {
+                    gc.collect_all(pool.get());
+                    status.publish(gc.live_count());
+                    let _ = reply.send(());
+                }
+
+                GcCommand::CollectInSteps(budget, reply) => {
+                    let progress = gc.collect_in_steps(pool.get(), budget);
+                    status.publish(gc.live_count());
+                    let _ = reply.send(progress);
+                }
+
+                GcCommand::MinorOnly(reply) => {
+                    gc.read_journals();
+                    let report = gc.minor_collection(pool.get());
+                    status.publish(gc.live_count());
+                    let _ = reply.send(report);
+                }
+
+                GcCommand::MajorDryRun(reply) => {
+                    let _ = reply.send(gc.major_dry_run(pool.get()));
+                }
+
+                GcCommand::Verify(reply) => {
+                    let _ = reply.send(gc.verify());
+                }
+
+                GcCommand::Visit(mut visit, reply) => {
+                    gc.visit_reachable(&mut *visit);
+                    let _ = reply.send(());
+                }
+
+                GcCommand::Record(file) => gc.record_to(file),
+
+                GcCommand::SnapshotRoots(reply) => {
+                    let _ = reply.send(gc.snapshot_roots());
+                }
+
+                GcCommand::IsReachable(addr, reply) => {
+                    let _ = reply.send(gc.is_reachable(addr));
+                }
+
+                GcCommand::FindCycles(reply) => {
+                    let _ = reply.send(gc.find_cycles());
+                }
+
+                GcCommand::RefcountOf(addr, reply) => {
+                    let _ = reply.send(gc.refcount_of(addr));
+                }
+
+                GcCommand::RegisterForeign(ptr, vtable, traversible, reply) => {
+                    gc.register_foreign(ptr, vtable, traversible);
+                    let _ = reply.send(());
+                }
+
+                GcCommand::ReleaseForeign(ptr) => gc.release_foreign(ptr),
+                GcCommand::ReleaseSnapshot(entries) => gc.release_snapshot(&entries),
+
+                GcCommand::AddWeakTable(table) => gc.add_weak_table(table),
+            }
+        }
+
+        if shutdown {
+            break;
+        }
+
+        // take the mature heaps back as soon as a background major collection is done
+        if gc.poll_major().is_some() {
+            status.publish(gc.live_count());
+        }
+
+        let forced = force_minor || force_major || major_kinds.is_some();
+
+        if paused && !forced {
+            if gc.config().never_sleep {
+                thread::yield_now();
+            } else {
+                thread::sleep(Duration::from_millis(MIN_SLEEP_DUR as u64));
+                slept = Duration::from_millis(MIN_SLEEP_DUR as u64);
+                gc.logger().add_sleep(MIN_SLEEP_DUR);
+            }
+            continue;
+        }
+
         let entries_read = gc.read_journals();
 
         // sleep if nothing read from journal
-        if entries_read == 0 {
-            thread::sleep(Duration::from_millis(sleep_dur as u64));
+        if entries_read == 0 && !forced {
+            if gc.config().never_sleep {
+                thread::yield_now();
+            } else {
+                thread::sleep(Duration::from_millis(sleep_dur as u64));
+                slept = Duration::from_millis(sleep_dur as u64);
 
-            gc.logger().add_sleep(sleep_dur);
+                gc.logger().add_sleep(sleep_dur);
+            }
 
-            // back off exponentially up to the max
+            // back off exponentially up to the max, which still tells collection heuristics that
+            // the app threads have gone quiet when not sleeping
             sleep_dur = min(sleep_dur * 2, MAX_SLEEP_DUR);
+
+            idle_cycles += 1;
         } else {
             // reset next sleep duration on receiving no entries
             sleep_dur = MIN_SLEEP_DUR;
+
+            idle_cycles = 0;
+        }
+
+        // with a pool that is shut down when idle, skip collecting while there is nothing new to
+        // collect, the first idle cycle still having the last read decrements to act on. Those
+        // waiting on a collection are still woken.
+        if gc.config().pool_idle_cycles > 0 && idle_cycles > 1 && !forced {
+            pool.idle();
+            status.publish(gc.live_count());
+            continue;
+        }
+
+        // while the app threads are busy, only collect once enough has been allocated
+        if entries_read > 0 && !forced && !gc.minor_due() {
+            continue;
         }
 
         // TODO: base this call on a duration since last call?
-        let young_count = gc.minor_collection(&mut pool);
+        let young_count = gc.minor_collection(pool.get()).young_live;
 
+        // always do a major collection while the heap is over its limit
+        //
         // do a major collection if the young count reaches a threshold and we're not just trying
         // to keep up with the app threads
         // TODO: force a major collection every n minutes
-        if sleep_dur != MIN_SLEEP_DUR && young_count >= MAJOR_COLLECT_THRESHOLD {
-            gc.major_collection(&mut pool);
+        if force_major || gc.is_over_limit() ||
+           (sleep_dur != MIN_SLEEP_DUR && young_count >= MAJOR_COLLECT_THRESHOLD) {
+            if gc.config().background_major {
+                gc.start_major();
+            } else {
+                gc.major_collection(pool.get());
+            }
+        } else if let Some(kinds) = major_kinds {
+            if gc.config().background_major {
+                gc.start_major_of(&kinds);
+            } else {
+                gc.major_collection_of(pool.get(), &kinds);
+            }
+        }
+
+        status.publish(gc.live_count());
+    }
+
+    // collect until everything is dropped, all roots should be unrooted by now. Decrements read
+    // in one round only take effect in the next, so garbage can take more than one round to drain
+    let mut live = gc.live_count();
+    let rounds = max(gc.config().shutdown_collections, 1);
+    for round in 0..rounds {
+        gc.minor_collection(pool.get());
+        gc.major_collection(pool.get());
+
+        let remaining = gc.live_count();
+        if remaining == 0 || (round > 0 && remaining == live) {
+            break;
         }
+        live = remaining;
     }
 
-    // do a final collection where all roots should be unrooted
-    gc.minor_collection(&mut pool);
-    gc.major_collection(&mut pool);
+    status.journals.store(0, Ordering::Release);
+
+    let residual = gc.live_count();
+    if residual > 0 {
+        gc.logger().log(&format!("{} objects still live after the final collections", residual));
+        *status.leaked.lock().expect("HeapStatus lock poisoned!") = gc.live_objects();
+    }
+    *status.heap.lock().expect("HeapStatus lock poisoned!") = gc.mature_snapshot();
+
+    status.publish(gc.live_count());
+
+    if let Err(error) = gc.stop_recording() {
+        gc.logger().log(&format!("Failed to record the journal: {}", error));
+    }
 
     // return logger to calling thread
     gc.logger().mark_end_time();
@@ -144,13 +868,1899 @@ fn gc_thread<S, T>(num_threads: usize, rx_chan: JournalReceiver, mature: T, logg
 }
 
 
-/// Pointers are word-aligned, meaning the least-significant 2 or 3 bits are always 0, depending
-/// on the word size.
+/// Wait for the GC thread to finish as `GcThread::join()` does, then assert that the final
+/// collection left no live objects. Intended for tests: if anything leaked, panics with the
+/// address and `Trace` vtable of each object still in the heap. Returns the `StatsLogger`.
+///
+/// Panics if the GC thread panicked.
+pub fn assert_no_leaks<S: StatsLogger + 'static>(gc: GcThread<S>) -> S {
+    let status = gc.status.clone();
+    let logger = gc.join().expect("GC thread panicked!");
+
+    let live = status.live.load(Ordering::Acquire);
+    if live > 0 {
+        let leaked = status.leaked.lock().expect("HeapStatus lock poisoned!");
+
+        let mut message = format!("{} objects leaked after the final collection:", live);
+        for obj in leaked.iter() {
+            message.push_str(&format!("\n    0x{:x} (vtable 0x{:x})", obj.ptr, obj.vtable));
+        }
+
+        panic!("{}", message);
+    }
+
+    logger
+}
+
+
+impl LazyPool {
+    /// A zero `idle_limit` means the pool is never shut down once created.
+    fn new(num_threads: usize, idle_limit: usize) -> LazyPool {
+        LazyPool {
+            num_threads: num_threads,
+            idle_limit: idle_limit,
+            idle_cycles: 0,
+            created: 0,
+            pool: None,
+        }
+    }
+
+    /// Take on new parameters. A running pool of the wrong size is shut down and is created again
+    /// at the new size when next needed.
+    fn configure(&mut self, config: &GcConfig) {
+        self.idle_limit = config.pool_idle_cycles;
+
+        if config.shard_count() != self.num_threads {
+            self.num_threads = config.shard_count();
+
+            if let Some(pool) = self.pool.take() {
+                pool.shutdown();
+            }
+        }
+    }
+
+    /// Return the thread pool, creating it if necessary.
+    fn get(&mut self) -> &mut Pool {
+        self.idle_cycles = 0;
+
+        if self.pool.is_none() {
+            self.pool = Some(Pool::new(self.num_threads));
+            self.created += 1;
+        }
+
+        self.pool.as_mut().unwrap()
+    }
+
+    /// Count an idle cycle, shutting the pool down once the idle limit is reached.
+    fn idle(&mut self) {
+        self.idle_cycles += 1;
+
+        if self.idle_limit > 0 && self.idle_cycles >= self.idle_limit {
+            if let Some(pool) = self.pool.take() {
+                pool.shutdown();
+            }
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.pool.is_some()
+    }
+}
+
+
+impl HeapStatus {
+    fn new() -> HeapStatus {
+        HeapStatus {
+            live: AtomicUsize::new(0),
+            journals: AtomicUsize::new(0),
+            active_us: AtomicUsize::new(0),
+            total_us: AtomicUsize::new(0),
+            leaked: Mutex::new(Vec::new()),
+            heap: Mutex::new(HeapSnapshot { heaps: Vec::new() }),
+            epoch: Mutex::new(0),
+            collected: Condvar::new(),
+        }
+    }
+
+    /// Store the latest live object count and wake up any waiting threads.
+    fn publish(&self, live: usize) {
+        self.live.store(live, Ordering::Release);
+
+        let mut epoch = self.epoch.lock().expect("HeapStatus lock poisoned!");
+        *epoch += 1;
+        self.collected.notify_all();
+    }
+
+    /// Add a GC loop iteration that took `elapsed` in total, of which `slept` was spent sleeping.
+    fn add_duty(&self, elapsed: Duration, slept: Duration) {
+        let total = micros(elapsed);
+        let active = total.saturating_sub(micros(slept));
+
+        let mut total_us = self.total_us.load(Ordering::Relaxed) + total;
+        let mut active_us = self.active_us.load(Ordering::Relaxed) + active;
+
+        if total_us > DUTY_CYCLE_WINDOW * 1000 {
+            total_us /= 2;
+            active_us /= 2;
+        }
+
+        // only the GC thread writes these
+        self.active_us.store(active_us, Ordering::Release);
+        self.total_us.store(total_us, Ordering::Release);
+    }
+
+    /// Return the fraction of recent time the GC loop spent working.
+    fn duty_cycle(&self) -> f64 {
+        let active = self.active_us.load(Ordering::Acquire);
+        let total = self.total_us.load(Ordering::Acquire);
+
+        if total == 0 {
+            0.0
+        } else {
+            // the pair is not read atomically, a halving in between may push the ratio over 1
+            (active as f64 / total as f64).min(1.0)
+        }
+    }
+
+    /// Wait on collections until the live count drops below `size` or `timeout` elapses.
+    fn wait_until_below(&self, size: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        let mut epoch = self.epoch.lock().expect("HeapStatus lock poisoned!");
+
+        loop {
+            if self.live.load(Ordering::Acquire) < size {
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            let (guard, _) = self.collected
+                .wait_timeout(epoch, deadline - now)
+                .expect("HeapStatus lock poisoned!");
+            epoch = guard;
+        }
+    }
+}
+
+
+/// Return a duration in whole microseconds.
+fn micros(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1_000_000 + duration.subsec_nanos() as usize / 1000
+}
+
+
+/// Pointers are aligned to `PTR_ALIGN`, meaning the least-significant `PTR_SHIFT` bits are always
+/// 0.
 #[inline]
 pub fn ptr_shift() -> i32 {
-    if size_of::<usize>() == 32 {
-        2
-    } else {
-        3
+    PTR_SHIFT as i32
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::cmp::max;
+    use std::mem;
+    use std::raw::TraitObject;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use appthread::{AppThread, Gc, GcAtomic, GcBox, GcBuilder, GcRoot, SyncGc};
+    use config::{ConfigError, GcConfig};
+    use constants::JOURNAL_BUFFER_SIZE;
+    use heap::TraceStack;
+    use parheap::ParHeap;
+    use statistics::{CollectionProgress, DefaultLogger, StatsLogger};
+    use trace::Trace;
+
+    use super::{assert_no_leaks, GcCommand, GcThread, HeapStatus, LazyPool};
+
+
+    /// Counts its own drops
+    struct Counted {
+        drops: Arc<AtomicUsize>,
+    }
+
+
+    unsafe impl Trace for Counted {}
+
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+
+    /// A numbered node of a singly linked list that counts its drops
+    struct Link {
+        index: usize,
+        next: Gc<Link>,
+        drops: Arc<AtomicUsize>,
+    }
+
+
+    unsafe impl Trace for Link {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            self.next.trace(stack);
+        }
+    }
+
+
+    impl Drop for Link {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+
+    /// Keeps track of journal connections and of the most journal buffers used up in one read
+    struct JournalLogger {
+        connected: AtomicUsize,
+        disconnected: AtomicUsize,
+        peak_buffers: Mutex<usize>,
+    }
+
+
+    impl StatsLogger for JournalLogger {
+        fn mark_start_time(&mut self) {}
+
+        fn mark_end_time(&mut self) {}
+
+        fn add_sleep(&self, _ms: usize) {}
+
+        fn add_dropped(&self, _count: usize) {}
+
+        fn current_heap_size(&self, _size: usize) {}
+
+        fn journal_connected(&self, _id: usize) {
+            self.connected.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn journal_disconnected(&self, _id: usize) {
+            self.disconnected.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn add_journal_stats(&self, _items_read: usize, buffer_transitions: usize) {
+            let mut peak = self.peak_buffers.lock().unwrap();
+            *peak = max(*peak, buffer_transitions);
+        }
+
+        fn dump_to_stdout(&self) {}
+    }
+
+
+    /// The number of collections the GC thread has published
+    fn current_epoch(gc: &GcThread<DefaultLogger>) -> usize {
+        *gc.status.epoch.lock().unwrap()
+    }
+
+    /// Wait for the GC thread to publish a collection after `epoch`, returning the new epoch
+    fn wait_for_collection(gc: &GcThread<DefaultLogger>, epoch: usize) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        loop {
+            let current = current_epoch(gc);
+            if current > epoch {
+                return current;
+            }
+
+            assert!(Instant::now() < deadline, "GC thread did not collect");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+
+    #[test]
+    fn test_wait_until_heap_below() {
+        let gc = GcThread::spawn_gc();
+
+        let (allocated_tx, allocated_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app = gc.spawn(move || {
+            let roots: Vec<_> = (0..10000usize).map(|i| GcRoot::new(i)).collect();
+            allocated_tx.send(()).unwrap();
+
+            release_rx.recv().unwrap();
+            drop(roots);
+
+            // keep the journal connected until the test is done waiting
+            done_rx.recv().unwrap();
+        });
+
+        allocated_rx.recv().unwrap();
+        while gc.live_count() < 10000 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(!gc.wait_until_heap_below(100, Duration::from_millis(100)));
+
+        release_tx.send(()).unwrap();
+        assert!(gc.wait_until_heap_below(100, Duration::from_secs(10)));
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+        gc.join().unwrap();
+    }
+
+    #[test]
+    fn test_verify_heap() {
+        let gc = GcThread::spawn_gc();
+
+        let (allocated_tx, allocated_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app = gc.spawn(move || {
+            let roots: Vec<_> = (0..1000usize).map(|i| GcRoot::new(i)).collect();
+            allocated_tx.send(()).unwrap();
+
+            done_rx.recv().unwrap();
+            drop(roots);
+        });
+
+        allocated_rx.recv().unwrap();
+        assert_eq!(gc.verify_heap(), Ok(()));
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+        gc.join().unwrap();
+    }
+
+    #[test]
+    fn test_lazy_pool() {
+        let mut pool = LazyPool::new(2, 3);
+        assert!(!pool.is_active());
+
+        pool.get().scoped(|scope| scope.execute(|| ()));
+        assert!(pool.is_active());
+        assert_eq!(pool.created, 1);
+
+        pool.idle();
+        pool.idle();
+        assert!(pool.is_active());
+
+        pool.idle();
+        assert!(!pool.is_active());
+
+        pool.get().scoped(|scope| scope.execute(|| ()));
+        assert!(pool.is_active());
+        assert_eq!(pool.created, 2);
+    }
+
+    #[test]
+    fn test_idle_pool_teardown() {
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+        config.pool_idle_cycles = 1;
+
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+
+        let app = gc.spawn(|| {
+            for _ in 0..3 {
+                for i in 0..1000usize {
+                    GcRoot::new(i);
+                }
+
+                // long enough for the GC to go idle and shut the pool down
+                thread::sleep(Duration::from_millis(300));
+            }
+        });
+
+        app.join().unwrap();
+        let logger = gc.join().unwrap();
+
+        assert_eq!(logger.total_dropped(), 3000);
+    }
+
+    #[test]
+    fn test_idle_cycles_publish() {
+        for &idle_limit in [0, 1].iter() {
+            let mut config = GcConfig::new();
+            config.num_threads = 2;
+            config.pool_idle_cycles = idle_limit;
+
+            let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+
+            let (done_tx, done_rx) = mpsc::channel::<()>();
+            let app = gc.spawn(move || {
+                done_rx.recv().unwrap();
+            });
+
+            // the GC loop goes on publishing while the app thread writes nothing, whether or not
+            // it skips collecting
+            let mut epoch = current_epoch(&gc);
+            for _ in 0..5 {
+                epoch = wait_for_collection(&gc, epoch);
+            }
+
+            done_tx.send(()).unwrap();
+            app.join().unwrap();
+            gc.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+
+        let (allocate_tx, allocate_rx) = mpsc::channel::<()>();
+        let (allocated_tx, allocated_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app = gc.spawn(move || {
+            allocate_rx.recv().unwrap();
+            for i in 0..1000usize {
+                GcRoot::new(i);
+            }
+            allocated_tx.send(()).unwrap();
+
+            // keep the journal connected until the GC has been shut down
+            done_rx.recv().unwrap();
+        });
+
+        // commands are processed in order, so a verify reply means the pause has taken effect
+        gc.command(GcCommand::Pause);
+        assert_eq!(gc.verify_heap(), Ok(()));
+
+        let epoch = current_epoch(&gc);
+        allocate_tx.send(()).unwrap();
+        allocated_rx.recv().unwrap();
+
+        // commands are handled at the top of each GC loop iteration, so by the reply the loop has
+        // come round since the allocation without collecting
+        assert_eq!(gc.verify_heap(), Ok(()));
+        assert_eq!(current_epoch(&gc), epoch);
+
+        // a forced collection reads the journal while paused. The decrements are merged after
+        // its sweep so nothing is dropped yet
+        gc.command(GcCommand::MinorNow);
+        let epoch = wait_for_collection(&gc, epoch);
+        assert_eq!(gc.live_count(), 1000);
+
+        assert_eq!(gc.verify_heap(), Ok(()));
+        assert_eq!(current_epoch(&gc), epoch);
+
+        gc.command(GcCommand::MajorNow);
+        let epoch = wait_for_collection(&gc, epoch);
+        assert_eq!(gc.live_count(), 0);
+
+        // collection carries on by itself after resuming, at the new pool size
+        let mut config = GcConfig::new();
+        config.num_threads = 1;
+
+        gc.command(GcCommand::SetConfig(config));
+        gc.command(GcCommand::Resume);
+        wait_for_collection(&gc, epoch);
+
+        // the GC exits while the app thread is still connected
+        gc.command(GcCommand::Shutdown);
+        let logger = gc.join().unwrap();
+        assert_eq!(logger.total_dropped(), 1000);
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+    }
+
+    #[test]
+    fn test_journal_connections() {
+        const THREADS: usize = 8;
+
+        let gc = GcThread::spawn_gc();
+
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        let blocked = gc.spawn(move || {
+            let _root = GcRoot::new(0usize);
+            release_rx.recv().unwrap();
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while gc.num_journals() != 1 {
+            assert!(Instant::now() < deadline, "journal did not connect");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let apps: Vec<_> = (0..THREADS)
+            .map(|_| {
+                gc.spawn(|| {
+                    for i in 0..1000usize {
+                        GcRoot::new(i);
+                    }
+                })
+            })
+            .collect();
+
+        for app in apps {
+            app.join().unwrap();
+        }
+
+        release_tx.send(()).unwrap();
+        blocked.join().unwrap();
+
+        let logger = gc.join().unwrap();
+        assert_eq!(logger.journals_connected(), THREADS + 1);
+        assert_eq!(logger.journals_disconnected(), THREADS + 1);
+    }
+
+    #[test]
+    fn test_collect_now() {
+        const COUNT: usize = 10000;
+
+        let gc = GcThread::spawn_gc();
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let app_drops = drops.clone();
+
+        let app = gc.spawn(move || {
+            let roots: Vec<_> = (0..COUNT)
+                .map(|_| GcRoot::new(Counted { drops: app_drops.clone() }))
+                .collect();
+            drop(roots);
+
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), COUNT);
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), COUNT);
+    }
+
+    #[test]
+    fn test_assert_no_leaks() {
+        let gc = GcThread::spawn_gc();
+
+        let app = gc.spawn(|| {
+            let roots: Vec<_> = (0..1000usize).map(|i| GcRoot::new(i)).collect();
+            drop(roots);
+        });
+        app.join().unwrap();
+
+        let logger = assert_no_leaks(gc);
+        assert_eq!(logger.total_dropped(), 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "objects leaked after the final collection:\n    0x")]
+    fn test_assert_no_leaks_immortal() {
+        let gc = GcThread::spawn_gc();
+
+        let app = gc.spawn(|| {
+            // never unrooted
+            mem::forget(GcRoot::new(0usize));
+        });
+        app.join().unwrap();
+
+        assert_no_leaks(gc);
+    }
+
+    #[test]
+    fn test_leaked_root() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let leaked = GcRoot::new(Counted { drops: app_drops.clone() }).leak();
+
+            // the object outlives its last root and the app thread
+            let root = GcRoot::new(Counted { drops: app_drops });
+            drop(root);
+            AppThread::collect_now();
+            assert_eq!(leaked.drops.load(Ordering::SeqCst), 1);
+        });
+        app.join().unwrap();
+
+        // the final collection drops nothing more
+        gc.join().unwrap();
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_alloc_tokens() {
+        const TOKENS: usize = 1000;
+        const COUNT: usize = 200000;
+
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+        config.alloc_tokens = TOKENS;
+
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+        let accounting = gc.accounting.clone();
+
+        let app = gc.spawn(move || {
+            let mut max_outstanding = 0;
+
+            for i in 0..COUNT {
+                Gc::new(i);
+
+                // read tracked first: the GC only counts entries that have been allocated
+                let tracked = accounting.tracked();
+                let outstanding = accounting.allocated() - tracked;
+                max_outstanding = max(max_outstanding, outstanding);
+            }
+
+            max_outstanding
+        });
+
+        // app threads never get further ahead of the GC than the size of the bucket
+        let max_outstanding = app.join().unwrap();
+        assert!(max_outstanding <= TOKENS, "{} objects outstanding", max_outstanding);
+
+        let logger = gc.join().unwrap();
+        assert_eq!(logger.total_dropped(), COUNT);
+    }
+
+    #[test]
+    fn test_is_reachable() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let (unroot_tx, unroot_rx) = mpsc::channel::<()>();
+        let (unrooted_tx, unrooted_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let object = Gc::new(Counted { drops: app_drops });
+            let root = object.root_n(1);
+
+            // make sure the GC has read the object before asking about it
+            AppThread::collect_now();
+            addr_tx.send(object.as_raw().unwrap() as usize).unwrap();
+
+            unroot_rx.recv().unwrap();
+            drop(root);
+            AppThread::collect_now();
+            unrooted_tx.send(()).unwrap();
+
+            // keep the journal connected until the test is done asking
+            done_rx.recv().unwrap();
+        });
+
+        let addr = addr_rx.recv().unwrap() as *const GcBox<Counted>;
+        assert_eq!(gc.is_reachable(addr), Some(true));
+
+        unroot_tx.send(()).unwrap();
+        unrooted_rx.recv().unwrap();
+
+        // unreachable and dropped, so no longer known to the GC
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert_eq!(gc.is_reachable(addr), None);
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+        gc.join().unwrap();
+    }
+
+    #[test]
+    fn test_major_dry_run() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let _root = GcRoot::new(Counted { drops: app_drops });
+            AppThread::collect_now();
+            ready_tx.send(()).unwrap();
+
+            // keep the object rooted until the test is done asking
+            done_rx.recv().unwrap();
+        });
+
+        // the ParHeap can count, and the rooted object is not counted
+        ready_rx.recv().unwrap();
+        assert_eq!(gc.major_dry_run(), Some(0));
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+        gc.join().unwrap();
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_gc_builder_cycle() {
+        const COUNT: usize = 1000;
+
+        /// A link in a ring
+        struct Link {
+            next: Gc<Link>,
+            _counted: Counted,
+        }
+
+        unsafe impl Trace for Link {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.next.trace(stack);
+            }
+        }
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let mut builder = GcBuilder::new();
+
+            let links: Vec<_> = (0..COUNT)
+                .map(|_| {
+                    builder.alloc(Link {
+                        next: Gc::null(),
+                        _counted: Counted { drops: app_drops.clone() },
+                    })
+                })
+                .collect();
+
+            for i in 0..COUNT {
+                let mut link = links[i];
+                link.next = links[(i + 1) % COUNT];
+            }
+
+            let root = builder.publish(links[0]);
+
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 0);
+
+            drop(root);
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), COUNT);
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), COUNT);
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        const ITERATIONS: usize = 50;
+        const OBJECTS: usize = 10000;
+
+        // GC loop iterations of 10ms each, given rather than timed
+        let status = HeapStatus::new();
+        for _ in 0..ITERATIONS {
+            status.add_duty(Duration::from_millis(10), Duration::from_millis(9));
+        }
+        let idle = status.duty_cycle();
+        assert!((idle - 0.1).abs() < 0.01, "idle duty cycle {}", idle);
+
+        for _ in 0..ITERATIONS {
+            status.add_duty(Duration::from_millis(10), Duration::from_millis(1));
+        }
+        let busy = status.duty_cycle();
+        assert!(busy > idle && busy <= 1.0,
+                "busy duty cycle {} after idle {}",
+                busy,
+                idle);
+
+        // the GC loop counts the time it works and the time it sleeps as it runs
+        let gc = GcThread::spawn_gc();
+        let status = gc.status.clone();
+
+        let app = gc.spawn(|| {
+            for i in 0..OBJECTS {
+                Gc::new(i);
+            }
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        assert!(status.active_us.load(Ordering::Acquire) > 0);
+        assert!(status.total_us.load(Ordering::Acquire) > 0);
+    }
+
+    #[test]
+    fn test_minor_now_blocking() {
+        const GARBAGE: usize = 100;
+        const SURVIVORS: usize = 10;
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        // keep the GC loop from collecting on its own
+        gc.command(GcCommand::Pause);
+
+        let (allocated_tx, allocated_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            for _ in 0..GARBAGE {
+                Gc::new(Counted { drops: app_drops.clone() });
+            }
+
+            let roots: Vec<_> = (0..SURVIVORS)
+                .map(|_| GcRoot::new(Counted { drops: app_drops.clone() }))
+                .collect();
+
+            allocated_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+
+            drop(roots);
+        });
+
+        allocated_rx.recv().unwrap();
+
+        let report = gc.minor_now_blocking();
+        assert!(!report.major);
+        assert_eq!(report.dropped, GARBAGE);
+        assert_eq!(report.young_live, SURVIVORS);
+        assert_eq!(report.promoted, 0);
+        assert_eq!(report.mature_live, 0);
+        assert_eq!(drops.load(Ordering::SeqCst), GARBAGE);
+
+        // survivors stay in the young generation however many minor collections run
+        let report = gc.minor_now_blocking();
+        assert_eq!(report.dropped, 0);
+        assert_eq!(report.young_live, SURVIVORS);
+        assert_eq!(report.mature_live, 0);
+
+        gc.command(GcCommand::Resume);
+        done_tx.send(()).unwrap();
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), GARBAGE + SURVIVORS);
+    }
+
+    #[test]
+    fn test_snapshot_roots() {
+        const COUNT: usize = 10;
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let (addrs_tx, addrs_rx) = mpsc::channel();
+        let (unroot_tx, unroot_rx) = mpsc::channel::<()>();
+        let (unrooted_tx, unrooted_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        // the original context
+        let app_drops = drops.clone();
+        let original = gc.spawn(move || {
+            let roots: Vec<_> = (0..COUNT)
+                .map(|_| GcRoot::new(Counted { drops: app_drops.clone() }))
+                .collect();
+
+            AppThread::collect_now();
+            let addrs: Vec<_> = roots.iter().map(|root| &**root as *const _ as usize).collect();
+            addrs_tx.send(addrs).unwrap();
+
+            // drop every root after the snapshot is taken
+            unroot_rx.recv().unwrap();
+            drop(roots);
+            AppThread::collect_now();
+            AppThread::collect_now();
+            unrooted_tx.send(()).unwrap();
+
+            done_rx.recv().unwrap();
+        });
+
+        let addrs = addrs_rx.recv().unwrap();
+
+        let snapshot = gc.snapshot_roots();
+        assert_eq!(snapshot.entries().len(), COUNT);
+        for entry in snapshot.entries() {
+            assert_eq!(entry.refcount, 1);
+        }
+
+        unroot_tx.send(()).unwrap();
+        unrooted_rx.recv().unwrap();
+
+        // the snapshot keeps the objects alive even though the original unrooted them
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        for &addr in addrs.iter() {
+            let object = addr as *const GcBox<Counted>;
+            assert!(snapshot.contains(object));
+            assert_eq!(gc.is_reachable(object), Some(true));
+        }
+
+        // the forked context roots the objects itself and then releases the snapshot
+        let fork_drops = drops.clone();
+        let fork = gc.spawn(move || {
+            let roots: Vec<GcRoot<Counted>> = snapshot.entries()
+                .iter()
+                .map(|entry| unsafe { snapshot.root(entry) })
+                .collect();
+            drop(snapshot);
+
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(fork_drops.load(Ordering::SeqCst), 0);
+
+            drop(roots);
+        });
+
+        fork.join().unwrap();
+        done_tx.send(()).unwrap();
+        original.join().unwrap();
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), COUNT);
+    }
+
+    #[test]
+    fn test_snapshot_dropped_off_app_thread() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let (rooted_tx, rooted_rx) = mpsc::channel::<()>();
+        let (released_tx, released_rx) = mpsc::channel::<()>();
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let root = GcRoot::new(Counted { drops: app_drops.clone() });
+            AppThread::collect_now();
+            rooted_tx.send(()).unwrap();
+
+            released_rx.recv().unwrap();
+            drop(root);
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 1);
+        });
+
+        rooted_rx.recv().unwrap();
+        let snapshot = gc.snapshot_roots();
+        assert_eq!(snapshot.entries().len(), 1);
+
+        // this is not an app thread, so the references go back through the GC thread
+        drop(snapshot);
+        released_tx.send(()).unwrap();
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
+    }
+
+    #[test]
+    fn test_new_uninit() {
+        /// Points at an object that may not be initialized yet
+        struct Parent {
+            child: Gc<Counted>,
+        }
+
+        unsafe impl Trace for Parent {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.child.trace(stack);
+            }
+        }
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let uninit = Gc::<Counted>::new_uninit();
+            let parent = GcRoot::new(Parent { child: uninit.as_gc() });
+
+            // the parent is traced while its child is uninitialized
+            AppThread::collect_now();
+            AppThread::collect_now();
+
+            let child = uninit.write(Counted { drops: app_drops.clone() });
+            assert!(child.is(parent.child));
+
+            // the child is now known to the GC and reachable from the parent
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 0);
+
+            drop(parent);
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 1);
+
+            // an allocation that is never initialized is freed without dropping a value
+            drop(Gc::<Counted>::new_uninit());
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_journal_after_thread_exit() {
+        /// Allocates and drops a root to a new object when dropped
+        struct Allocates {
+            drops: Arc<AtomicUsize>,
+        }
+
+        impl Drop for Allocates {
+            fn drop(&mut self) {
+                drop(GcRoot::new(Counted { drops: self.drops.clone() }));
+            }
+        }
+
+        thread_local!(
+            static HELD: RefCell<Option<(GcRoot<Counted>, Allocates)>> = RefCell::new(None)
+        );
+
+        let gc = GcThread::spawn_gc();
+        let accounting = gc.accounting.clone();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let root = GcRoot::new(Counted { drops: app_drops.clone() });
+            let allocates = Allocates { drops: app_drops };
+
+            // dropped by the thread-local destructor after the journal is gone
+            HELD.with(|held| *held.borrow_mut() = Some((root, allocates)));
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        // the root dropped during teardown leaks its object rather than it being freed early and
+        // the object allocated during teardown is never known to the GC
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        assert_eq!(accounting.allocated(), 1);
+        assert_eq!(accounting.tracked(), 1);
+    }
+
+    #[test]
+    fn test_journal_after_thread_panic() {
+        thread_local!(
+            static HELD: RefCell<Option<GcRoot<Counted>>> = RefCell::new(None)
+        );
+
+        let gc = GcThread::spawn_gc();
+        let accounting = gc.accounting.clone();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let root = GcRoot::new(Counted { drops: app_drops });
+
+            // dropped by the thread-local destructor after unwinding has dropped the journal
+            HELD.with(|held| *held.borrow_mut() = Some(root));
+            panic!("app thread panicked");
+        });
+
+        assert!(app.join().is_err());
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        assert_eq!(accounting.tracked(), 1);
+    }
+
+    #[test]
+    fn test_held_back_new_after_thread_panic() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            AppThread::coalesce_new(true);
+            Gc::new(Counted { drops: app_drops });
+
+            // the new object entry is still held back when the thread unwinds
+            panic!("app thread panicked");
+        });
+
+        assert!(app.join().is_err());
+        gc.join().unwrap();
+
+        // the entry was written as the thread unwound, so the object was collected
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_cpu_affinity() {
+        use affinity;
+
+        // pin to the lowest CPU this process may run on
+        let allowed = affinity::current_thread().expect("affinity unsupported");
+        let cpu = 1u64 << allowed.trailing_zeros();
+
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+        config.cpu_affinity = cpu;
+
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+
+        let (rooted_tx, rooted_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app = gc.spawn(move || {
+            let _root = GcRoot::new(Counted { drops: drops });
+
+            // make sure the GC has read the root before it is visited
+            AppThread::collect_now();
+            rooted_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+        });
+
+        rooted_rx.recv().unwrap();
+
+        // the visitor runs on the GC thread
+        let (affinity_tx, affinity_rx) = mpsc::channel();
+        gc.visit_reachable(move |_, _| {
+            affinity_tx.send(affinity::current_thread()).unwrap();
+        });
+
+        assert_eq!(affinity_rx.recv().unwrap(), Some(cpu));
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+        gc.join().unwrap();
+    }
+
+    #[test]
+    fn test_detach() {
+        const THREADS: usize = 4;
+        const COUNT: usize = 1000;
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let (exit_tx, exit_rx) = mpsc::channel();
+        let handle = gc.detach_with(move |result| {
+            exit_tx.send(result.map(|logger| logger.total_dropped()).ok()).unwrap();
+        });
+
+        let (done_tx, done_rx) = mpsc::channel();
+
+        // the first app thread spawns the rest so that the GC cannot exit in between
+        let app_handle = handle.clone();
+        let app_drops = drops.clone();
+        handle.spawn(move || {
+            let apps: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    let drops = app_drops.clone();
+                    app_handle.spawn(move || {
+                        for _ in 0..COUNT {
+                            drop(GcRoot::new(Counted { drops: drops.clone() }));
+                        }
+
+                        // still collecting while detached
+                        AppThread::collect_now();
+                        assert!(drops.load(Ordering::SeqCst) >= COUNT);
+                    })
+                })
+                .collect();
+
+            for app in apps {
+                app.join().unwrap();
+            }
+
+            done_tx.send(()).unwrap();
+        });
+
+        done_rx.recv().unwrap();
+
+        // the GC exits once every app thread has finished and the logger is handed over
+        let dropped = exit_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(dropped, Some(THREADS * COUNT));
+        assert_eq!(drops.load(Ordering::SeqCst), THREADS * COUNT);
+
+        assert!(!handle.command(GcCommand::MinorNow));
+    }
+
+    #[test]
+    fn test_join_with_survivors() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            // garbage is collected as usual
+            drop(GcRoot::new(Counted { drops: app_drops.clone() }));
+
+            let immortal = GcRoot::new(Counted { drops: app_drops });
+            immortal.leak() as *const Counted as usize
+        });
+
+        let leaked = app.join().unwrap();
+        let (logger, survivors) = gc.join_with_survivors().unwrap();
+
+        assert_eq!(logger.total_dropped(), 1);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+        let survivors: Vec<_> = survivors.collect();
+        assert_eq!(survivors.len(), 1);
+        assert_eq!(survivors[0].0, leaked);
+        assert!(survivors[0].1 != 0);
+    }
+
+    #[test]
+    fn test_join_with_heap() {
+        const LENGTH: usize = 5;
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            // garbage is collected as usual
+            drop(GcRoot::new(Counted { drops: app_drops.clone() }));
+
+            // a list that outlives the app
+            let mut head = Gc::null();
+            let mut addrs = Vec::new();
+            for index in 0..LENGTH {
+                let link = GcRoot::new(Link {
+                    index: index,
+                    next: head,
+                    drops: app_drops.clone(),
+                });
+                head = link.as_gc();
+                addrs.push(link.leak() as *const Link as usize);
+            }
+
+            addrs
+        });
+
+        let addrs = app.join().unwrap();
+        let (logger, snapshot) = gc.join_with_heap().unwrap();
+
+        assert_eq!(logger.total_dropped(), 1);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+
+        let mut expected = addrs.clone();
+        expected.sort();
+        let found: Vec<usize> = snapshot.heaps[0].iter().map(|obj| obj.ptr).collect();
+        assert_eq!(found, expected);
+        assert_eq!(snapshot.len(), LENGTH);
+
+        // each link points at the one allocated before it
+        assert!(snapshot.get(addrs[0]).unwrap().children.is_empty());
+        for index in 1..LENGTH {
+            let link = snapshot.get(addrs[index]).unwrap();
+            assert_eq!(link.children, vec![addrs[index - 1]]);
+            assert!(link.size > 0);
+        }
+    }
+
+    #[test]
+    fn test_update_while_tracing() {
+        const SWAPS: usize = 1000;
+
+        /// Two children, each reachable only through the pair
+        struct Pair {
+            left: Gc<Counted>,
+            right: Gc<Counted>,
+        }
+
+        unsafe impl Trace for Pair {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.left.trace(stack);
+                self.right.trace(stack);
+            }
+        }
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let pair = GcRoot::new(Pair {
+                left: Gc::new(Counted { drops: app_drops.clone() }),
+                right: Gc::new(Counted { drops: app_drops.clone() }),
+            });
+            let (left, right) = (pair.left, pair.right);
+
+            for i in 0..SWAPS {
+                pair.update(|pair| {
+                    let moved = pair.left;
+                    pair.left = pair.right;
+
+                    // now only the right child is reachable through the pair: collect in the
+                    // torn state every so often, otherwise the GC runs concurrently
+                    if i % 50 == 0 {
+                        AppThread::collect_now();
+                    }
+
+                    pair.right = moved;
+                });
+            }
+
+            AppThread::collect_now();
+            AppThread::collect_now();
+
+            // no child was lost
+            assert_eq!(app_drops.load(Ordering::SeqCst), 0);
+            assert!(pair.left.is(left) && pair.right.is(right));
+        });
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
+
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_sync_gc_registry() {
+        const OBJECTS: usize = 100;
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+        let registry: Arc<Mutex<Vec<SyncGc<Counted>>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let (registered_tx, registered_rx) = mpsc::channel();
+        let (rooted_tx, rooted_rx) = mpsc::channel();
+
+        // one app thread allocates and registers objects, keeping them rooted until the other has
+        // rooted them too
+        let app_drops = drops.clone();
+        let app_registry = registry.clone();
+        let producer = gc.spawn(move || {
+            let roots: Vec<_> = (0..OBJECTS)
+                .map(|_| GcRoot::new(Counted { drops: app_drops.clone() }))
+                .collect();
+
+            {
+                let mut registry = app_registry.lock().unwrap();
+                for root in roots.iter() {
+                    registry.push(SyncGc::from_gc(root.as_gc()));
+                }
+
+                // clones share identity
+                let copy = registry[0].clone();
+                assert!(copy == registry[0] && copy != registry[1]);
+            }
+
+            registered_tx.send(()).unwrap();
+            rooted_rx.recv().unwrap();
+        });
+
+        let app_drops = drops.clone();
+        let app_registry = registry.clone();
+        let consumer = gc.spawn(move || {
+            registered_rx.recv().unwrap();
+
+            let roots: Vec<_> = app_registry.lock().unwrap().iter().map(|obj| obj.root()).collect();
+            rooted_tx.send(()).unwrap();
+
+            // the producer's roots are gone but these keep every registered object live
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 0);
+
+            let registry = app_registry.lock().unwrap();
+            assert!(roots.iter().zip(registry.iter()).all(|(root, obj)| obj.is(root.as_gc())));
+        });
+
+        producer.join().unwrap();
+        consumer.join().unwrap();
+        assert_no_leaks(gc);
+
+        assert_eq!(drops.load(Ordering::SeqCst), OBJECTS);
+    }
+
+    #[test]
+    fn test_shutdown_drains_cycles() {
+        const RINGS: usize = 10;
+        const RING_LEN: usize = 10;
+
+        /// A node in a ring, which may also lead down to another ring
+        struct RingNode {
+            next: GcAtomic<RingNode>,
+            down: GcAtomic<RingNode>,
+            drops: Arc<AtomicUsize>,
+        }
+
+        unsafe impl Trace for RingNode {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.next.trace(stack);
+                self.down.trace(stack);
+            }
+        }
+
+        impl Drop for RingNode {
+            fn drop(&mut self) {
+                self.drops.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        // objects stay young for a few collections, so the last decrement, which only takes
+        // effect after the collection it was read in, leaves the rings for a later round
+        let mut config = GcConfig::new();
+        config.nursery_ages = 4;
+
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            // every node is rooted while the graph is built
+            let mut roots = Vec::new();
+
+            {
+                let mut ring = || {
+                    let nodes: Vec<_> = (0..RING_LEN)
+                        .map(|_| {
+                            GcRoot::new(RingNode {
+                                next: GcAtomic::null(),
+                                down: GcAtomic::null(),
+                                drops: app_drops.clone(),
+                            })
+                        })
+                        .collect();
+
+                    for i in 0..RING_LEN {
+                        nodes[i].next.store(nodes[(i + 1) % RING_LEN].as_gc());
+                    }
+
+                    let first = nodes[0].as_gc();
+                    roots.extend(nodes);
+                    first
+                };
+
+                // a chain of rings, each leading down to the next
+                let mut upper = ring();
+                for _ in 1..RINGS {
+                    let lower = ring();
+                    upper.down.store(lower);
+                    upper = lower;
+                }
+            }
+
+            // unrooted just as the app thread exits
+            drop(roots);
+        });
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
+
+        assert_eq!(drops.load(Ordering::SeqCst), RINGS * RING_LEN);
+    }
+
+    #[test]
+    fn test_root_scope() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let roots: Vec<_> = (0..3)
+                .map(|_| GcRoot::new(Counted { drops: app_drops.clone() }))
+                .collect();
+
+            {
+                let scope = root_scope!(roots[0].as_gc(), roots[1].as_gc(), roots[2].as_gc());
+                assert_eq!(scope.len(), 3);
+
+                // only the scope roots them now
+                drop(roots);
+
+                AppThread::collect_now();
+                AppThread::collect_now();
+                assert_eq!(app_drops.load(Ordering::SeqCst), 0);
+            }
+
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 3);
+        });
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
+    }
+
+    #[test]
+    fn test_refcount_of() {
+        const ROOTS: usize = 5;
+
+        let gc = GcThread::spawn_gc();
+
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let (unroot_tx, unroot_rx) = mpsc::channel::<()>();
+        let (unrooted_tx, unrooted_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app = gc.spawn(move || {
+            let first = GcRoot::new(Counted { drops: Arc::new(AtomicUsize::new(0)) });
+            let mut roots: Vec<_> = (1..ROOTS).map(|_| first.clone()).collect();
+            roots.push(first);
+
+            AppThread::collect_now();
+            addr_tx.send(roots[0].as_gc().as_raw().unwrap() as usize).unwrap();
+
+            unroot_rx.recv().unwrap();
+            roots.truncate(2);
+            AppThread::collect_now();
+            unrooted_tx.send(()).unwrap();
+
+            done_rx.recv().unwrap();
+        });
+
+        let addr = addr_rx.recv().unwrap() as *const GcBox<Counted>;
+        assert_eq!(gc.refcount_of(addr), Some(ROOTS));
+
+        unroot_tx.send(()).unwrap();
+        unrooted_rx.recv().unwrap();
+        assert_eq!(gc.refcount_of(addr), Some(2));
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+        gc.join().unwrap();
+    }
+
+    #[test]
+    fn test_register_foreign() {
+        /// Allocated by hand and pointing at a GC managed object
+        struct Holder {
+            child: Gc<Counted>,
+            _counted: Counted,
+        }
+
+        unsafe impl Trace for Holder {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.child.trace(stack);
+            }
+        }
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let (foreign_tx, foreign_rx) = mpsc::channel();
+        let (unroot_tx, unroot_rx) = mpsc::channel::<()>();
+        let (unrooted_tx, unrooted_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let child = GcRoot::new(Counted { drops: app_drops.clone() });
+            AppThread::collect_now();
+
+            // allocated outside of the GC, which never sees it in a journal
+            let holder: Box<Trace> = Box::new(Holder {
+                child: child.as_gc(),
+                _counted: Counted { drops: app_drops },
+            });
+            let tobj: TraitObject = unsafe { mem::transmute(Box::into_raw(holder)) };
+            foreign_tx.send((tobj.data as usize, tobj.vtable as usize)).unwrap();
+
+            unroot_rx.recv().unwrap();
+            drop(child);
+            AppThread::collect_now();
+            AppThread::collect_now();
+            unrooted_tx.send(()).unwrap();
+
+            done_rx.recv().unwrap();
+        });
+
+        let (data, vtable) = foreign_rx.recv().unwrap();
+        unsafe { gc.register_foreign(data as *mut (), vtable as *mut (), true) };
+
+        // the child is only reachable through the foreign object, which is traced
+        unroot_tx.send(()).unwrap();
+        unrooted_rx.recv().unwrap();
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        // once released the foreign object and its child are collected
+        gc.release_foreign(data as *mut ());
+        let (tx, rx) = mpsc::channel();
+        gc.command(GcCommand::Collect(tx));
+        rx.recv().unwrap();
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+
+        done_tx.send(()).unwrap();
+        app.join().unwrap();
+        gc.join().unwrap();
+    }
+
+    #[test]
+    fn test_set_pool_size() {
+        const OBJECTS: usize = 1000;
+
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let (round_tx, round_rx) = mpsc::channel::<bool>();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            // some objects live across the resizes, in the mature heap
+            let kept: Vec<_> = (0..OBJECTS)
+                .map(|_| GcRoot::new(Counted { drops: app_drops.clone() }))
+                .collect();
+
+            while round_rx.recv().unwrap() {
+                for _ in 0..OBJECTS {
+                    GcRoot::new(Counted { drops: app_drops.clone() });
+                }
+                AppThread::collect_now();
+                AppThread::collect_now();
+                done_tx.send(()).unwrap();
+            }
+
+            drop(kept);
+        });
+
+        for (round, &size) in [2, 4, 1, 3].iter().enumerate() {
+            gc.set_pool_size(size);
+
+            round_tx.send(true).unwrap();
+            done_rx.recv().unwrap();
+
+            assert_eq!(drops.load(Ordering::SeqCst), (round + 1) * OBJECTS);
+            assert!(gc.verify_heap().is_ok());
+        }
+
+        round_tx.send(false).unwrap();
+        app.join().unwrap();
+        assert_no_leaks(gc);
+
+        assert_eq!(drops.load(Ordering::SeqCst), 5 * OBJECTS);
+    }
+
+    #[test]
+    fn test_release_root() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let kept = GcRoot::new(Counted { drops: app_drops.clone() });
+            let released = GcRoot::new(Counted { drops: app_drops.clone() });
+
+            released.release();
+
+            // collectible straight away, while `kept` is still in scope
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 1);
+
+            drop(kept);
+        });
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_collect_in_steps_budget() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            GcRoot::new(Counted { drops: app_drops.clone() }).release();
+
+            // a budget too small for any step runs one step per call
+            let tiny = Duration::new(0, 1);
+            assert!(AppThread::collect_in_steps(tiny) == CollectionProgress::Partial(2));
+
+            let mut calls = 1;
+            loop {
+                calls += 1;
+                if AppThread::collect_in_steps(tiny) == CollectionProgress::Complete {
+                    break;
+                }
+            }
+            assert_eq!(calls, 3);
+            assert_eq!(app_drops.load(Ordering::SeqCst), 1);
+
+            // with time to spare the whole collection runs in one call
+            GcRoot::new(Counted { drops: app_drops.clone() }).release();
+            let progress = AppThread::collect_in_steps(Duration::from_secs(60));
+            assert!(progress == CollectionProgress::Complete);
+            assert_eq!(app_drops.load(Ordering::SeqCst), 2);
+        });
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
+    }
+
+    #[test]
+    fn test_many_app_threads() {
+        const THREADS: usize = 32;
+        const LISTS: usize = 20;
+        const LENGTH: usize = 500;
+        // each link is written as a new root and later unrooted
+        const ENTRIES: usize = 2 * LISTS * LENGTH;
+        // no read of the journals can find more buffers than the app threads fill between them
+        const PEAK_BUFFERS: usize = THREADS * ((ENTRIES + JOURNAL_BUFFER_SIZE - 1) /
+                                               JOURNAL_BUFFER_SIZE);
+
+        let mut config = GcConfig::new();
+        config.num_threads = 4;
+
+        let logger = JournalLogger {
+            connected: AtomicUsize::new(0),
+            disconnected: AtomicUsize::new(0),
+            peak_buffers: Mutex::new(0),
+        };
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(4), logger);
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let apps: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let drops = drops.clone();
+
+                gc.spawn(move || {
+                    let mut previous: Option<GcRoot<Link>> = None;
+
+                    for _ in 0..LISTS {
+                        let mut head = GcRoot::new(Link {
+                            index: 0,
+                            next: Gc::null(),
+                            drops: drops.clone(),
+                        });
+
+                        for index in 1..LENGTH {
+                            head = GcRoot::new(Link {
+                                index: index,
+                                next: head.as_gc(),
+                                drops: drops.clone(),
+                            });
+                        }
+
+                        // the previous list survived whatever collections ran while it was held
+                        if let Some(list) = previous.take() {
+                            let mut link = list.as_gc();
+                            for index in (0..LENGTH).rev() {
+                                assert_eq!(link.index, index);
+                                link = link.next;
+                            }
+                            assert!(link.as_raw().is_none());
+                        }
+
+                        previous = Some(head);
+                    }
+                })
+            })
+            .collect();
+
+        for app in apps {
+            app.join().unwrap();
+        }
+
+        let logger = assert_no_leaks(gc);
+
+        assert_eq!(drops.load(Ordering::SeqCst), THREADS * LISTS * LENGTH);
+        assert_eq!(logger.connected.load(Ordering::SeqCst), THREADS);
+        assert_eq!(logger.disconnected.load(Ordering::SeqCst), THREADS);
+
+        let peak = *logger.peak_buffers.lock().unwrap();
+        assert!(peak <= PEAK_BUFFERS, "{} journal buffers used up in a single read", peak);
+    }
+
+    #[test]
+    fn test_never_sleep() {
+        let mut config = GcConfig::new();
+        config.never_sleep = true;
+
+        let gc = GcThread::spawn_gc_with_config(config, ParHeap::new(2), DefaultLogger::new());
+
+        let app = gc.spawn(|| {
+            for _ in 0..10 {
+                for i in 0..1000usize {
+                    GcRoot::new(i);
+                }
+
+                // leave the journal empty for a while
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        app.join().unwrap();
+        let logger = gc.join().unwrap();
+
+        assert_eq!(logger.total_dropped(), 10000);
+        assert_eq!(logger.sleep_time(), 0);
+    }
+
+    #[cfg(feature = "barrier_stats")]
+    #[test]
+    fn test_write_barrier_counts() {
+        const STORES: usize = 100;
+
+        /// A mature object pointing at a young one
+        struct Holder {
+            child: Gc<Counted>,
+        }
+
+        unsafe impl Trace for Holder {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                self.child.trace(stack);
+            }
+        }
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let mut holder = GcRoot::new(Holder { child: Gc::null() });
+            holder.tenure();
+            AppThread::collect_now();
+
+            // each store through the root is one barrier
+            for _ in 0..STORES {
+                holder.child = Gc::new(Counted { drops: app_drops.clone() });
+            }
+        });
+
+        app.join().unwrap();
+        let logger = gc.join().unwrap();
+
+        assert_eq!(logger.write_barriers(), STORES);
+        assert!(logger.cards_dirtied() >= 1);
+        assert!(logger.cards_dirtied() <= STORES);
+        assert_eq!(drops.load(Ordering::SeqCst), STORES);
+    }
+
+    #[test]
+    fn test_set_config_rejects_invalid() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let mut config = GcConfig::new();
+        config.num_threads = 0;
+        assert_eq!(gc.set_config(config), Err(ConfigError::ZeroThreads));
+
+        // sent as a command it is rejected on the GC thread, which carries on with its parameters
+        gc.command(GcCommand::SetConfig(config));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            drop(GcRoot::new(Counted { drops: app_drops.clone() }));
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 1);
+        });
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
     }
 }
@@ -0,0 +1,378 @@
+//! Trees of GC managed nodes whose `Trace` is correct by construction.
+//!
+//! Each `GcNode` holds a value and a list of children. The children are kept in a `GcVec` of
+//! `GcAtomic` pointers, so the GC thread can trace a node while its app thread adds or removes
+//! children: the list never moves in memory and each child pointer is read and written
+//! atomically. Removing a child moves the last child into its place rather than shifting the
+//! rest down, as a trace running alongside a shift could step over a child that is still there.
+//!
+//! Nodes must be mutated through a `Gc` or `GcRoot`, as for any other GC managed object, so that
+//! the write barrier sees the change. A node removed from its parent is no longer reachable
+//! through it and is dropped by a coming collection unless it is rooted or attached elsewhere.
+
+
+use std::sync::atomic::Ordering;
+
+use appthread::{write_barrier, Gc, GcAtomic, GcRoot};
+use gcvec::GcVec;
+use heap::TraceStack;
+use trace::Trace;
+
+
+/// A node in a tree of GC managed objects.
+pub struct GcNode<T: Trace> {
+    value: T,
+    children: GcVec<GcAtomic<GcNode<T>>>,
+}
+
+
+/// A rooted tree of `GcNode`s. The whole tree stays live while the `GcTree` exists.
+pub struct GcTree<T: Trace> {
+    root: GcRoot<GcNode<T>>,
+}
+
+
+/// A pre-order, depth-first iterator over a tree or subtree, see `GcTree::iter()`.
+pub struct Iter<T: Trace> {
+    stack: Vec<Gc<GcNode<T>>>,
+}
+
+
+impl<T: Trace> GcNode<T> {
+    /// Create a node with no children. It is not on the heap until it is given to `Gc::new()`,
+    /// `GcRoot::new()` or `GcTree::new()`.
+    pub fn new(value: T) -> GcNode<T> {
+        GcNode {
+            value: value,
+            children: GcVec::new(),
+        }
+    }
+
+    /// Return the node's value.
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Return the node's value for modification.
+    pub fn value_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+
+    /// Return the number of children.
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    /// Return the child at `index`, or `None` if out of bounds.
+    pub fn child(&self, index: usize) -> Option<Gc<GcNode<T>>> {
+        self.children.get(index).map(|slot| slot.load_into_gc(Ordering::Acquire))
+    }
+
+    /// Allocate a new node for `value` and append it to the children. Returns the new node.
+    pub fn add_child(&mut self, value: T) -> Gc<GcNode<T>> {
+        // rooted until it is stored and the barrier applied after it, so that no collection can
+        // find it unrooted and not yet reachable
+        let child = GcRoot::new(GcNode::new(value));
+        self.push_child(child.as_gc());
+        child.as_gc()
+    }
+
+    /// Append an existing node, with its subtree, to the children. The node should not already
+    /// be in another tree unless a DAG is intended.
+    pub fn push_child(&mut self, child: Gc<GcNode<T>>) {
+        self.children.push(GcAtomic::from_gc(child));
+
+        // the DerefMut that reached this node marked its card before the push, and a collection
+        // may have cleaned and traced the node in between
+        write_barrier(self as *const GcNode<T> as usize);
+    }
+
+    /// Remove and return the child at `index`, moving the last child into its place. The
+    /// returned node is unrooted.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove_child(&mut self, index: usize) -> Gc<GcNode<T>> {
+        let count = self.children.len();
+        assert!(index < count,
+                "GcNode child index {} out of bounds for {} children",
+                index,
+                count);
+
+        let removed = self.children[index].load_into_gc(Ordering::Acquire);
+
+        // a trace that read the length before the pop still visits the last slot, and one that
+        // reads it after sees the last child in its new slot
+        let last = count - 1;
+        if index != last {
+            let moved = self.children[last].load_into_gc(Ordering::Acquire);
+            self.children[index].store_from_gc(moved, Ordering::Release);
+        }
+
+        self.children.pop();
+
+        removed
+    }
+
+    /// Return an iterator over the children in their current order.
+    pub fn children(&self) -> Children<T> {
+        Children {
+            node: self,
+            index: 0,
+        }
+    }
+}
+
+
+/// An iterator over the children of a node, see `GcNode::children()`.
+pub struct Children<'a, T: Trace + 'a> {
+    node: &'a GcNode<T>,
+    index: usize,
+}
+
+
+impl<'a, T: Trace> Iterator for Children<'a, T> {
+    type Item = Gc<GcNode<T>>;
+
+    fn next(&mut self) -> Option<Gc<GcNode<T>>> {
+        let child = self.node.child(self.index);
+        if child.is_some() {
+            self.index += 1;
+        }
+        child
+    }
+}
+
+
+unsafe impl<T: Trace> Trace for GcNode<T> {
+    // children may be added at any time
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        if self.value.traversible() {
+            self.value.trace(stack);
+        }
+        self.children.trace(stack);
+    }
+}
+
+
+impl<T: Trace> GcTree<T> {
+    /// Create a tree of a single root node holding `value`.
+    pub fn new(value: T) -> GcTree<T> {
+        GcTree { root: GcRoot::new(GcNode::new(value)) }
+    }
+
+    /// Return the root node.
+    pub fn root(&self) -> &GcNode<T> {
+        &self.root
+    }
+
+    /// Return the root node for modification.
+    pub fn root_mut(&mut self) -> &mut GcNode<T> {
+        &mut self.root
+    }
+
+    /// Return a pre-order, depth-first iterator over every node in the tree, starting with the
+    /// root.
+    pub fn iter(&self) -> Iter<T> {
+        Iter { stack: vec![self.root.as_gc()] }
+    }
+
+    /// Return the number of nodes in the tree.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+}
+
+
+impl<T: Trace> Iterator for Iter<T> {
+    type Item = Gc<GcNode<T>>;
+
+    fn next(&mut self) -> Option<Gc<GcNode<T>>> {
+        let node = match self.stack.pop() {
+            Some(node) => node,
+            None => return None,
+        };
+
+        // push in reverse so that the first child is visited next
+        let count = node.child_count();
+        for index in (0..count).rev() {
+            if let Some(child) = node.child(index) {
+                self.stack.push(child);
+            }
+        }
+
+        Some(node)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use appthread::{AppThread, GcRoot};
+    use gcthread::{assert_no_leaks, GcThread};
+    use trace::Trace;
+
+    use super::{GcNode, GcTree};
+
+
+    /// Counts its own drops
+    struct Counted {
+        id: usize,
+        drops: Arc<AtomicUsize>,
+    }
+
+    unsafe impl Trace for Counted {}
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Logs its id when dropped
+    struct Logged {
+        id: usize,
+        dropped: Arc<Mutex<Vec<usize>>>,
+    }
+
+    unsafe impl Trace for Logged {}
+
+    impl Drop for Logged {
+        fn drop(&mut self) {
+            self.dropped.lock().unwrap().push(self.id);
+        }
+    }
+
+    #[test]
+    fn test_build_mutate_collect() {
+        const FANOUT: usize = 10;
+
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let counted = |id| {
+                Counted {
+                    id: id,
+                    drops: app_drops.clone(),
+                }
+            };
+
+            let mut tree = GcTree::new(counted(0));
+            for i in 0..FANOUT {
+                let mut child = tree.root_mut().add_child(counted(i + 1));
+                for j in 0..FANOUT {
+                    child.add_child(counted((i + 1) * 100 + j));
+                }
+
+                // collect while the tree is growing
+                AppThread::collect_now();
+            }
+
+            assert_eq!(tree.len(), 1 + FANOUT + FANOUT * FANOUT);
+            let ids: Vec<_> = tree.root().children().map(|child| child.value().id).collect();
+            assert_eq!(ids, (1..FANOUT + 1).collect::<Vec<_>>());
+
+            // detach a subtree, keeping it alive only by a root
+            let kept = GcRoot::new(GcNode::new(counted(1000)));
+            let mut kept_gc = kept.as_gc();
+            let moved = tree.root_mut().remove_child(0);
+            kept_gc.push_child(moved);
+
+            // detach two subtrees and drop them, each replaced by the last child
+            tree.root_mut().remove_child(3);
+            tree.root_mut().remove_child(3);
+
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 2 * (1 + FANOUT));
+
+            let ids: Vec<_> = tree.root().children().map(|child| child.value().id).collect();
+            assert_eq!(ids, vec![10, 2, 3, 8, 5, 6, 7]);
+            assert_eq!(tree.len(), 1 + 7 * (1 + FANOUT));
+
+            // the moved subtree is intact under its new parent
+            let subtree = kept.child(0).unwrap();
+            assert_eq!(subtree.value().id, 1);
+            assert_eq!(subtree.child_count(), FANOUT);
+        });
+
+        app.join().unwrap();
+        assert_no_leaks(gc);
+
+        // every node was dropped exactly once
+        assert_eq!(drops.load(Ordering::SeqCst), 2 + FANOUT + FANOUT * FANOUT);
+    }
+
+    #[test]
+    fn test_mutate_while_collecting() {
+        const ROUNDS: usize = 2000;
+        const WIDTH: usize = 8;
+
+        let gc = GcThread::spawn_gc();
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        // keeps the GC collecting while the tree is mutated
+        let collector_stop = stop.clone();
+        let collector = gc.spawn(move || {
+            while !collector_stop.load(Ordering::SeqCst) {
+                AppThread::collect_now();
+            }
+        });
+
+        let app_dropped = dropped.clone();
+        let app = gc.spawn(move || {
+            let logged = |id| {
+                Logged {
+                    id: id,
+                    dropped: app_dropped.clone(),
+                }
+            };
+
+            let mut tree = GcTree::new(logged(0));
+            let mut expected = Vec::new();
+
+            // add at the end and remove from anywhere, keeping the root about WIDTH wide
+            for round in 0..ROUNDS {
+                let id = round + 1;
+                tree.root_mut().add_child(logged(id));
+                expected.push(id);
+
+                if expected.len() > WIDTH {
+                    let index = round % expected.len();
+                    tree.root_mut().remove_child(index);
+                    expected.swap_remove(index);
+                }
+            }
+
+            AppThread::collect_now();
+            AppThread::collect_now();
+
+            {
+                let dropped = app_dropped.lock().unwrap();
+                for id in expected.iter() {
+                    assert!(!dropped.contains(id), "node {} dropped while in the tree", id);
+                }
+            }
+
+            let ids: Vec<_> = tree.root().children().map(|child| child.value().id).collect();
+            assert_eq!(ids, expected);
+        });
+
+        app.join().unwrap();
+        stop.store(true, Ordering::SeqCst);
+        collector.join().unwrap();
+        assert_no_leaks(gc);
+
+        assert_eq!(dropped.lock().unwrap().len(), ROUNDS + 1);
+    }
+}
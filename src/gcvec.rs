@@ -0,0 +1,321 @@
+//! A growable vector that can be held in a GC managed object and mutated by its app thread while
+//! the GC thread traces it.
+//!
+//! A `Vec` held in a GC object may reallocate while the GC is tracing it, leaving the GC reading
+//! freed memory. `GcVec` instead stores its elements in chunks that double in size and are never
+//! moved or freed until the `GcVec` itself is dropped. The length is published atomically after
+//! each element is written, so a concurrent trace only ever visits fully written elements.
+
+
+use std::mem::{forget, size_of};
+use std::ops::{Index, IndexMut};
+use std::ptr::{self, null_mut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use heap::TraceStack;
+use trace::Trace;
+
+
+// the first chunk holds 1 << FIRST_CHUNK_SHIFT elements, each following chunk twice as many
+const FIRST_CHUNK_SHIFT: usize = 3;
+const FIRST_CHUNK: usize = 1 << FIRST_CHUNK_SHIFT;
+const MAX_CHUNKS: usize = 32;
+
+
+/// A growable, traceable vector whose elements never move once pushed.
+///
+/// Mutate it through a `Gc` or `GcRoot` so that the write barrier sees the change. A trace
+/// running concurrently with `pop()` may still visit the element being popped, so elements that
+/// own memory of their own must not free it while a trace could be reading it, and a `push()`
+/// after a `pop()` may overwrite the element under such a trace; GC pointers and plain values
+/// are always safe.
+pub struct GcVec<T: Trace> {
+    len: AtomicUsize,
+    chunks: [*mut T; MAX_CHUNKS],
+}
+
+
+unsafe impl<T: Trace + Send> Send for GcVec<T> {}
+unsafe impl<T: Trace + Sync> Sync for GcVec<T> {}
+
+
+impl<T: Trace> GcVec<T> {
+    /// Create an empty vector. No memory is allocated until the first push.
+    pub fn new() -> GcVec<T> {
+        GcVec {
+            len: AtomicUsize::new(0),
+            chunks: [null_mut(); MAX_CHUNKS],
+        }
+    }
+
+    /// Return the number of elements.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Return true if there are no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append an element, allocating a new chunk if the last one is full. Existing elements are
+    /// never moved.
+    ///
+    /// After a `pop()` the new element is written over the popped one, which a trace that began
+    /// before the pop may still be reading. Such a trace sees either the old element or the new
+    /// one if each is a single GC pointer or plain value, but may see an element of several
+    /// fields half written. Push elements like that after a pop only while the vector cannot be
+    /// traced, that is, outside of any collection.
+    pub fn push(&mut self, value: T) {
+        let len = self.len.load(Ordering::Relaxed);
+        let (chunk, offset) = locate(len);
+
+        assert!(chunk < MAX_CHUNKS, "GcVec capacity overflow");
+
+        if self.chunks[chunk].is_null() {
+            self.chunks[chunk] = alloc_chunk(chunk);
+        }
+
+        unsafe { ptr::write(self.chunks[chunk].offset(offset as isize), value) };
+
+        // publish the element to tracing only once it is written
+        self.len.store(len + 1, Ordering::Release);
+    }
+
+    /// Remove and return the last element, or `None` if empty. The chunk it was in is kept for
+    /// reuse.
+    pub fn pop(&mut self) -> Option<T> {
+        let len = self.len.load(Ordering::Relaxed);
+        if len == 0 {
+            return None;
+        }
+
+        // hide the element from tracing before moving it out
+        self.len.store(len - 1, Ordering::Release);
+
+        Some(unsafe { ptr::read(self.element(len - 1)) })
+    }
+
+    /// Return a reference to the element at `index`, or `None` if out of bounds.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index < self.len() {
+            Some(unsafe { &*self.element(index) })
+        } else {
+            None
+        }
+    }
+
+    /// Return a mutable reference to the element at `index`, or `None` if out of bounds.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index < self.len() {
+            Some(unsafe { &mut *self.element(index) })
+        } else {
+            None
+        }
+    }
+
+    // Pointer to the element at `index`, which must be in bounds
+    fn element(&self, index: usize) -> *mut T {
+        let (chunk, offset) = locate(index);
+        unsafe { self.chunks[chunk].offset(offset as isize) }
+    }
+}
+
+
+unsafe impl<T: Trace> Trace for GcVec<T> {
+    // an empty vector at allocation time may later hold GC pointers
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        for index in 0..self.len() {
+            (*self.element(index)).trace(stack);
+        }
+    }
+}
+
+
+impl<T: Trace> Index<usize> for GcVec<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(element) => element,
+            None => panic!("GcVec index {} out of bounds for length {}", index, self.len()),
+        }
+    }
+}
+
+
+impl<T: Trace> IndexMut<usize> for GcVec<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len();
+        match self.get_mut(index) {
+            Some(element) => element,
+            None => panic!("GcVec index {} out of bounds for length {}", index, len),
+        }
+    }
+}
+
+
+impl<T: Trace> Drop for GcVec<T> {
+    fn drop(&mut self) {
+        while let Some(_) = self.pop() {}
+
+        for (chunk, &ptr) in self.chunks.iter().enumerate() {
+            if !ptr.is_null() {
+                unsafe { drop(Vec::from_raw_parts(ptr, 0, FIRST_CHUNK << chunk)) };
+            }
+        }
+    }
+}
+
+
+/// Return the chunk holding the element at `index` and the element's offset within it.
+fn locate(index: usize) -> (usize, usize) {
+    let position = index + FIRST_CHUNK;
+    let top = size_of::<usize>() * 8 - 1 - position.leading_zeros() as usize;
+
+    (top - FIRST_CHUNK_SHIFT, position - (1 << top))
+}
+
+
+/// Allocate uninitialized memory for the elements of chunk number `chunk`.
+fn alloc_chunk<T>(chunk: usize) -> *mut T {
+    let mut elements = Vec::with_capacity(FIRST_CHUNK << chunk);
+    let ptr = elements.as_mut_ptr();
+    forget(elements);
+
+    ptr
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use heap::{Object, TraceStack};
+    use trace::Trace;
+
+    use super::{GcVec, locate};
+
+
+    /// Pushes its value to the trace stack as if it were a pointer
+    struct Slot(usize);
+
+    unsafe impl Trace for Slot {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            stack.push(Object {
+                ptr: self.0,
+                vtable: 0,
+            });
+        }
+    }
+
+    #[test]
+    fn test_locate() {
+        assert_eq!(locate(0), (0, 0));
+        assert_eq!(locate(7), (0, 7));
+        assert_eq!(locate(8), (1, 0));
+        assert_eq!(locate(23), (1, 15));
+        assert_eq!(locate(24), (2, 0));
+    }
+
+    #[test]
+    fn test_push_while_tracing() {
+        const COUNT: usize = 100000;
+
+        let vec = Box::into_raw(Box::new(GcVec::<Slot>::new()));
+        let addr = vec as usize;
+
+        let tracer = thread::spawn(move || {
+            let vec = unsafe { &*(addr as *const GcVec<Slot>) };
+            let mut seen = 0;
+
+            while seen < COUNT {
+                let mut stack = TraceStack::new();
+                unsafe { vec.trace(&mut stack) };
+
+                // every element visited is fully written and the length only grows
+                assert!(stack.len() >= seen);
+                seen = stack.len();
+
+                let mut expected = seen;
+                while let Some(obj) = stack.pop() {
+                    assert_eq!(obj.ptr, expected);
+                    expected -= 1;
+                }
+            }
+        });
+
+        let vec = unsafe { &mut *vec };
+        for i in 0..COUNT {
+            vec.push(Slot(i + 1));
+        }
+
+        tracer.join().unwrap();
+
+        assert_eq!(vec.len(), COUNT);
+        assert_eq!(vec[COUNT - 1].0, COUNT);
+        assert_eq!(vec.pop().map(|slot| slot.0), Some(COUNT));
+        assert!(vec.get(COUNT - 1).is_none());
+
+        unsafe { drop(Box::from_raw(vec as *mut GcVec<Slot>)) };
+    }
+
+    #[test]
+    fn test_push_after_pop_while_tracing() {
+        const LEN: usize = 64;
+        const ROUNDS: usize = 100000;
+
+        // element i always holds a value of i + 1 plus some multiple of LEN
+        let mut vec = GcVec::new();
+        for i in 0..LEN {
+            vec.push(Slot(i + 1));
+        }
+
+        let vec = Box::into_raw(Box::new(vec));
+        let addr = vec as usize;
+        let done = Arc::new(AtomicBool::new(false));
+
+        let tracer_done = done.clone();
+        let tracer = thread::spawn(move || {
+            let vec = unsafe { &*(addr as *const GcVec<Slot>) };
+
+            while !tracer_done.load(Ordering::SeqCst) {
+                let mut stack = TraceStack::new();
+                unsafe { vec.trace(&mut stack) };
+
+                // a slot being written again holds either its old value or its new one
+                let mut index = stack.len();
+                while let Some(obj) = stack.pop() {
+                    index -= 1;
+                    assert_eq!((obj.ptr - 1) % LEN, index, "slot {} held {}", index, obj.ptr);
+                }
+            }
+        });
+
+        let vec = unsafe { &mut *vec };
+        for round in 0..ROUNDS {
+            let last = vec.pop().unwrap();
+            assert_eq!((last.0 - 1) % LEN, LEN - 1);
+            vec.push(Slot(LEN * (round + 2)));
+        }
+
+        done.store(true, Ordering::SeqCst);
+        tracer.join().unwrap();
+
+        assert_eq!(vec.len(), LEN);
+        assert_eq!(vec[LEN - 1].0, LEN * (ROUNDS + 1));
+
+        unsafe { drop(Box::from_raw(vec as *mut GcVec<Slot>)) };
+    }
+}
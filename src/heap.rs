@@ -5,15 +5,26 @@
 
 
 use std::cell::Cell;
-use std::mem::transmute;
+use std::collections::{HashMap, HashSet};
+use std::isize;
+use std::mem::{size_of_val, transmute};
 use std::raw::TraitObject;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use bitmaptrie::Trie;
 use scoped_pool::Pool;
 
-use constants::{MARK_BIT, MARK_MASK, NEW_BIT, NEW_MASK, PTR_MASK, TRAVERSE_BIT};
+use constants::{AGE_SHIFT, EPHEMERAL_BIT, INTERNAL_FLAGS_MASK, KIND_SHIFT, LEAF_BIT, LEAF_MASK,
+                MARK_BIT, MARK_MASK, MAX_KINDS, NEW_BIT, NEW_MASK, TRAVERSE_BIT,
+                USER_FLAG_BITS, USER_FLAG_SHIFT, VTABLE_MASK};
+use dropthread::DropQueue;
+use gcarray::ShardPool;
 use gcthread::ptr_shift;
+#[cfg(feature = "checked")]
+use liveness;
+use statistics::WorkBalance;
 use trace::Trace;
 
 
@@ -27,6 +38,11 @@ pub trait TraceOps {
     /// Buffer the given object for future tracing on the trace stack. This method should be called
     /// by objects that implement the Trace trait, from the Trace::trace() method.
     fn push_to_trace(&mut self, object: &Trace);
+
+    /// Buffer an object given as the data and vtable pointers of a `Trace` trait object, for
+    /// callers that hold raw object identities rather than references. The pair must make up a
+    /// valid `Trace` trait object for as long as it may be traced.
+    unsafe fn push_raw(&mut self, ptr: usize, vtable: usize);
 }
 
 
@@ -38,6 +54,129 @@ pub trait CollectOps {
     /// Run a collection iteration on the heap. Return the total heap size and the number of
     /// dropped objects.
     fn collect(&mut self, thread_pool: &mut Pool, roots: &mut RootMap) -> (usize, usize);
+
+    /// As `collect()`, but while the thread pool marks, the calling thread calls `between` about
+    /// every `interval`, so that it can keep up with other work such as draining the journals
+    /// during a long mark. Heaps that do not mark in the thread pool just call `collect()`.
+    fn collect_interleaved(&mut self,
+                           thread_pool: &mut Pool,
+                           roots: &mut RootMap,
+                           _interval: Duration,
+                           _between: &mut FnMut())
+                           -> (usize, usize) {
+        self.collect(thread_pool, roots)
+    }
+
+    /// As `collect()`, or `collect_interleaved()` if `between` is given, but between the mark and
+    /// the sweep call `settle` with a function that tells whether the object at an address was
+    /// marked. `settle` returns the addresses of unmarked objects that must survive the sweep
+    /// after all, which are marked along with the objects they lead to. Heaps that cannot tell
+    /// report every object as marked.
+    fn collect_settled(&mut self,
+                       thread_pool: &mut Pool,
+                       roots: &mut RootMap,
+                       interval: Duration,
+                       between: Option<&mut FnMut()>,
+                       settle: &mut FnMut(&Fn(usize) -> bool) -> Vec<usize>)
+                       -> (usize, usize) {
+        settle(&|_| true);
+
+        match between {
+            Some(between) => self.collect_interleaved(thread_pool, roots, interval, between),
+            None => self.collect(thread_pool, roots),
+        }
+    }
+
+    /// Mark as `collect()` does but only count the objects that would be dropped, freeing nothing
+    /// and leaving the heap as it was. Returns `None` if the heap does not support a dry run.
+    fn collect_dry_run(&mut self, _thread_pool: &mut Pool, _roots: &mut RootMap) -> Option<usize> {
+        None
+    }
+
+    /// Check heap invariants between collections, adding any violations to `errors`.
+    fn verify(&self, _errors: &mut Vec<HeapError>) {}
+
+    /// Add every object in the heap to `objects`, for diagnostics.
+    fn live_objects(&self, _objects: &mut Vec<Object>) {}
+
+    /// Return true if the object at `addr` is in the heap, for diagnostics. The default searches
+    /// `live_objects()`, heaps that can should look the address up instead.
+    fn contains(&self, addr: usize) -> bool {
+        let mut objects = Vec::new();
+        self.live_objects(&mut objects);
+        objects.iter().any(|obj| obj.ptr == addr)
+    }
+
+    /// The spread of work across the thread pool in the last `collect()`, as the mark and sweep
+    /// balance, for heaps that measure it.
+    fn work_balance(&self) -> Option<(WorkBalance, WorkBalance)> {
+        None
+    }
+
+    /// Hand unreachable objects to `queue` rather than dropping them during `collect()`. Called
+    /// once when the GC is created with `GcConfig::drop_thread` set. Heaps that ignore this drop
+    /// objects in `collect()` as usual.
+    fn set_drop_queue(&mut self, _queue: DropQueue) {}
+
+    /// Drop unreachable objects children first, see `GcConfig::topological_drop`. Called when the
+    /// GC is created and whenever its config is replaced. Heaps that ignore this drop objects in
+    /// any order.
+    fn set_topological_drop(&mut self, _enabled: bool) {}
+
+    /// Split collection work into `num_threads` shards from the next `collect()` on, to match a
+    /// resized thread pool. Heaps that do not shard their work ignore this.
+    fn set_num_threads(&mut self, _num_threads: usize) {}
+
+    /// Trace large `GcArray`s on `shards`, the GC's pool for them, by marking with stacks from a
+    /// `TraceStackPool` given it. Called once when the GC is created. Heaps that ignore this
+    /// trace arrays on their own workers.
+    fn set_shard_pool(&mut self, _shards: Arc<ShardPool>) {}
+
+    /// Forget the object at trie key `ptr`, if the heap holds it, without dropping it. See
+    /// `Gc::untrack()`.
+    fn untrack(&mut self, _ptr: usize) {}
+
+    /// The number of live objects in each size class after the last `collect()`, see
+    /// `size_class()`, for heaps that measure it.
+    fn size_histogram(&self) -> Option<&[usize]> {
+        None
+    }
+
+    /// How long the mark phase of the last `collect()` took, for heaps that measure it.
+    fn mark_time(&self) -> Option<Duration> {
+        None
+    }
+}
+
+
+/// A table that holds a root reference to each of its entries, registered with the GC so that
+/// the GC can release the entries nothing else uses, see `GcInternSet`. During each major
+/// collection run on the GC thread, the table's references to mature entries that nothing else
+/// is counted as referencing are left out of the roots of the mark, and the entries the mark
+/// does not reach are released before the sweep drops them.
+pub trait WeakTable: Send {
+    /// Lock the table and return every entry, forgetting which entries have been handed out so
+    /// far. Return `None` if the table's owner has gone and the GC should forget the table.
+    fn weak_entries(&self) -> Option<Vec<Object>>;
+
+    /// Lock the table and remove the entries at the addresses in `unused`, which the mark did not
+    /// reach, unless they have been handed out since `weak_entries()`. The GC has released the
+    /// root references of those removed, which must be forgotten rather than released again.
+    /// Return the addresses of the entries kept.
+    fn release(&self, unused: &HashSet<usize>) -> Vec<usize>;
+}
+
+
+/// A heap invariant violation found by heap verification. Each variant holds the address of the
+/// offending object.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HeapError {
+    /// The object's vtable pointer is null
+    NullVtable(usize),
+    /// The object is marked outside of a collection
+    StaleMark(usize),
+    /// The object's root reference count has been decremented below zero
+    NegativeRefcount(usize),
 }
 
 
@@ -49,6 +188,42 @@ pub struct Object {
 }
 
 
+/// A rooted object captured by `GcThread::snapshot_roots()`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RootEntry {
+    /// the object's address
+    pub ptr: usize,
+    /// the object's Trace trait vtable pointer
+    pub vtable: usize,
+    /// the object's root reference count when the snapshot was taken
+    pub refcount: usize,
+}
+
+
+/// The mature heaps as they were after the final collection, from `GcThread::join_with_heap()`.
+/// The objects have not been dropped and never will be, so they can be inspected through their
+/// `Trace` vtables.
+#[derive(Clone, Debug)]
+pub struct HeapSnapshot {
+    /// the objects of each mature heap in kind order, each heap's ordered by address
+    pub heaps: Vec<Vec<HeapObject>>,
+}
+
+
+/// An object captured by a `HeapSnapshot`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeapObject {
+    /// the object's address
+    pub ptr: usize,
+    /// the object's Trace trait vtable pointer
+    pub vtable: usize,
+    /// the object's size in bytes
+    pub size: usize,
+    /// the addresses of the objects it pointed at, in the order it traced them
+    pub children: Vec<usize>,
+}
+
+
 /// Root pointer metadata
 pub struct RootMeta {
     /// the root reference count. This gets decremented by multiple threads and thus must be
@@ -79,6 +254,17 @@ pub struct ObjectMeta {
 /// type.
 pub struct TraceStack {
     stack: ObjectBuf,
+    shards: Option<Arc<ShardPool>>,
+}
+
+
+/// `TraceStack`s kept from one collection to the next, so that the workers of each mark phase
+/// take the stacks of the last one, with the capacity they grew to, rather than allocating new
+/// ones. A worker takes one stack when it starts and gives it back when it is done.
+pub struct TraceStackPool {
+    stacks: Mutex<Vec<TraceStack>>,
+    created: AtomicUsize,
+    shards: Option<Arc<ShardPool>>,
 }
 
 
@@ -105,9 +291,125 @@ impl Object {
         let tobj: TraitObject = Object::into(*self);
         unsafe { transmute(tobj) }
     }
+
+    // Return the size of the object in bytes, as given by its vtable
+    pub fn size(&self) -> usize {
+        size_of_val(self.as_trace())
+    }
+
+    // Drop the object and free its memory through Trace::gc_drop(), which a type may override
+    pub unsafe fn gc_drop(self) {
+        // before the memory can be reused by another allocation
+        untrack(self.ptr);
+
+        let tobj: TraitObject = Object::into(self);
+        let fatptr: *mut Trace = transmute(tobj);
+        (*fatptr).gc_drop();
+    }
+}
+
+
+/// Drop `objects`, all unreachable, each after the objects among them that it points at, or
+/// hand them to `queue` in that order. See `GcConfig::topological_drop`.
+pub fn drop_topologically(objects: Vec<Object>, queue: Option<&DropQueue>) {
+    let ordered = topological_order(objects);
+
+    match queue {
+        Some(queue) => queue.send(ordered),
+        None => {
+            for obj in ordered {
+                unsafe { obj.gc_drop() };
+            }
+        }
+    }
+}
+
+
+// Order `objects` so that each comes after every object among them that it points at, visiting
+// them depth first. An object on a cycle comes after whichever objects of the cycle were visited
+// after it.
+fn topological_order(objects: Vec<Object>) -> Vec<Object> {
+    let index: HashMap<usize, usize> = objects.iter()
+        .enumerate()
+        .map(|(i, obj)| (obj.ptr, i))
+        .collect();
+
+    let mut order = Vec::with_capacity(objects.len());
+    let mut visited = vec![false; objects.len()];
+    let mut stack = TraceStack::new();
+
+    // the objects being visited, each with the children it has left to visit
+    let mut path: Vec<(usize, Vec<usize>)> = Vec::new();
+
+    for start in 0..objects.len() {
+        if visited[start] {
+            continue;
+        }
+
+        visited[start] = true;
+        path.push((start, children_among(&objects[start], &index, &mut stack)));
+
+        loop {
+            let child = match path.last_mut() {
+                Some(&mut (_, ref mut children)) => children.pop(),
+                None => break,
+            };
+
+            match child {
+                Some(child) => {
+                    if !visited[child] {
+                        visited[child] = true;
+                        path.push((child, children_among(&objects[child], &index, &mut stack)));
+                    }
+                }
+
+                None => {
+                    let (done, _) = path.pop().expect("topological_order path is empty!");
+                    order.push(objects[done]);
+                }
+            }
+        }
+    }
+
+    order
 }
 
 
+// Return the indexes in `index` of the objects `obj` points at. Nothing else can reach an
+// unreachable object, so it is traced without racing an app thread.
+fn children_among(obj: &Object,
+                  index: &HashMap<usize, usize>,
+                  stack: &mut TraceStack)
+                  -> Vec<usize> {
+    let mut children = Vec::new();
+
+    if obj.vtable & TRAVERSE_BIT != 0 {
+        unsafe { obj.as_trace().trace(stack) };
+
+        while let Some(child) = stack.pop() {
+            if let Some(&i) = index.get(&child.ptr) {
+                children.push(i);
+            }
+        }
+    }
+
+    children
+}
+
+
+/// Record an object as dropped for `Gc::try_deref()`
+#[cfg(feature = "checked")]
+#[inline]
+fn untrack(ptr: usize) {
+    liveness::remove(ptr);
+}
+
+
+#[cfg(not(feature = "checked"))]
+#[inline]
+fn untrack(_ptr: usize) {}
+
+
 impl From<TraitObject> for Object {
     fn from(tobj: TraitObject) -> Object {
         Object {
@@ -122,8 +424,8 @@ impl Into<TraitObject> for Object {
     fn into(self) -> TraitObject {
         TraitObject {
             data: self.ptr as *mut (),
-            // make sure traverse and mark bits are cleared
-            vtable: (self.vtable & PTR_MASK) as *mut (),
+            // make sure traverse, mark and drop bits are cleared
+            vtable: (self.vtable & VTABLE_MASK) as *mut (),
         }
     }
 }
@@ -154,6 +456,12 @@ impl RootMeta {
         self.refcount.fetch_add(1, Ordering::SeqCst);
     }
 
+    // Increment the reference count by `count`
+    #[inline]
+    pub fn inc_by(&self, count: usize) {
+        self.refcount.fetch_add(count, Ordering::SeqCst);
+    }
+
     // Decrement the reference count by 1
     #[inline]
     pub fn dec(&self) {
@@ -167,6 +475,13 @@ impl RootMeta {
         refcount.set(refcount.get() + 1);
     }
 
+    // Increment the reference count by `count`, thread unsafe
+    #[inline]
+    pub fn unsync_inc_by(&self, count: usize) {
+        let refcount = self.unsync_refcount();
+        refcount.set(refcount.get() + count);
+    }
+
     // Decrement the reference count by 1, thread unsafe
     #[inline]
     pub fn unsync_dec(&self) {
@@ -174,6 +489,24 @@ impl RootMeta {
         refcount.set(refcount.get() - 1);
     }
 
+    // Read the reference count
+    #[inline]
+    pub fn refcount(&self) -> usize {
+        self.refcount.load(Ordering::SeqCst)
+    }
+
+    // Return true if the reference count has been decremented below zero
+    #[inline]
+    pub fn is_refcount_negative(&self) -> bool {
+        self.refcount() > isize::MAX as usize
+    }
+
+    // Query the mark bit
+    #[inline]
+    pub fn is_marked(&self) -> bool {
+        self.flags.get() & MARK_BIT != 0
+    }
+
     // Return true if this object has a zero reference count, thread unsafe
     #[inline]
     pub fn unsync_is_unrooted(&self) -> bool {
@@ -181,6 +514,12 @@ impl RootMeta {
         refcount.get() == 0
     }
 
+    // Return true if this object may contain GC managed pointers
+    #[inline]
+    pub fn is_traversible(&self) -> bool {
+        self.vtable & TRAVERSE_BIT != 0
+    }
+
     // Return true if this is a new object
     #[inline]
     pub fn is_new(&self) -> bool {
@@ -198,6 +537,77 @@ impl RootMeta {
         self.flags.set(self.flags.get() & NEW_MASK);
     }
 
+    // Return true if this object must never be promoted
+    #[inline]
+    pub fn is_ephemeral(&self) -> bool {
+        self.flags.get() & EPHEMERAL_BIT != 0
+    }
+
+    // Return true if this object has been seen to trace no children
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.flags.get() & LEAF_BIT != 0
+    }
+
+    // Hint that tracing this object pushes no children
+    #[inline]
+    pub fn set_leaf(&self) {
+        self.flags.set(self.flags.get() | LEAF_BIT);
+    }
+
+    // Remove the leaf hint
+    #[inline]
+    pub fn clear_leaf(&self) {
+        self.flags.set(self.flags.get() & LEAF_MASK);
+    }
+
+    // Return the kind of mature heap this object is promoted into
+    #[inline]
+    pub fn kind(&self) -> usize {
+        (self.flags.get() >> KIND_SHIFT) & (MAX_KINDS - 1)
+    }
+
+    // Return the young generation age bucket of this object
+    #[inline]
+    pub fn age(&self) -> usize {
+        (self.flags.get() & INTERNAL_FLAGS_MASK) >> AGE_SHIFT
+    }
+
+    // Move this object into the next age bucket, up to `max_age`
+    #[inline]
+    pub fn inc_age(&self, max_age: usize) {
+        if self.age() < max_age {
+            self.flags.set(self.flags.get() + (1 << AGE_SHIFT));
+        }
+    }
+
+    /// Set or clear user flag `bit`, numbered from zero up to `USER_FLAG_BITS`. The GC neither
+    /// reads nor changes the user flags, which stay with the root until the object is dropped
+    /// or unrooted. Like the GC's own flags they are not atomic, so set them only where nothing
+    /// else is updating the root's flags, such as on the GC thread between collections.
+    ///
+    /// Panics if `bit` is out of range.
+    #[inline]
+    pub fn set_user_flag(&self, bit: usize, value: bool) {
+        assert!(bit < USER_FLAG_BITS, "user flag {} out of range", bit);
+
+        let mask = 1 << (USER_FLAG_SHIFT + bit);
+        if value {
+            self.flags.set(self.flags.get() | mask);
+        } else {
+            self.flags.set(self.flags.get() & !mask);
+        }
+    }
+
+    /// Return user flag `bit`, see `set_user_flag()`.
+    ///
+    /// Panics if `bit` is out of range.
+    #[inline]
+    pub fn get_user_flag(&self, bit: usize) -> bool {
+        assert!(bit < USER_FLAG_BITS, "user flag {} out of range", bit);
+        self.flags.get() & (1 << (USER_FLAG_SHIFT + bit)) != 0
+    }
+
     // Mark this object and return true if it needs to be traced into
     #[inline]
     pub fn mark_and_needs_trace(&self) -> bool {
@@ -208,7 +618,8 @@ impl RootMeta {
             self.flags.set(flags | MARK_BIT);
         }
 
-        was_unmarked && flags & TRAVERSE_BIT != 0
+        // the traverse bit is carried in the vtable, not the flags
+        was_unmarked && self.is_traversible()
     }
 
     // Reset the mark bit back to 0
@@ -220,7 +631,7 @@ impl RootMeta {
     // Returns the vtable without any flags set
     #[inline]
     pub fn vtable(&self) -> usize {
-        self.vtable & PTR_MASK
+        self.vtable & VTABLE_MASK
     }
 
     // oh the horror, to save a few clock cycles
@@ -237,17 +648,19 @@ impl ObjectMeta {
         ObjectMeta { vtable: Cell::new(vtable) }
     }
 
-    // Mark this object and return true if it needs to be traced into
+    // Mark this object and return true if it needs to be traced into. An object that is
+    // already marked never needs tracing again, which guarantees that each thread traces each
+    // object at most once even through cycles and self-references.
     #[inline]
     pub fn mark_and_needs_trace(&self) -> bool {
         let vtable = self.vtable.get();
 
-        let was_marked = vtable & MARK_BIT == 0;
-        if !was_marked {
+        let was_unmarked = vtable & MARK_BIT == 0;
+        if was_unmarked {
             self.vtable.set(vtable | MARK_BIT);
         }
 
-        !was_marked && vtable & TRAVERSE_BIT != 0
+        was_unmarked && vtable & TRAVERSE_BIT != 0
     }
 
     // Query the mark bit
@@ -263,17 +676,20 @@ impl ObjectMeta {
         self.vtable.set(vtable & MARK_MASK);
     }
 
-    // Get the vtable ptr without mark or traverse bits set
+    // Get the vtable ptr without mark, traverse or drop bits set
     #[inline]
     pub fn vtable(&self) -> usize {
-        self.vtable.get() & PTR_MASK
+        self.vtable.get() & VTABLE_MASK
     }
 }
 
 
 impl TraceStack {
     pub fn new() -> TraceStack {
-        TraceStack { stack: ObjectBuf::new() }
+        TraceStack {
+            stack: ObjectBuf::new(),
+            shards: None,
+        }
     }
 
     pub fn push(&mut self, obj: Object) {
@@ -284,10 +700,97 @@ impl TraceStack {
         self.stack.pop()
     }
 
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Move every object on `other` onto this stack, leaving `other` empty.
+    pub fn append(&mut self, other: &mut TraceStack) {
+        self.stack.append(&mut other.stack);
+    }
+
     // Create initial contents from a slice of Objects
     pub fn from_roots(&mut self, slice: &[Object]) {
         self.stack.extend_from_slice(slice);
     }
+
+    // The number of objects the stack can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        self.stack.capacity()
+    }
+
+    /// The pool of the GC this stack belongs to for tracing large arrays on, if any.
+    pub fn shard_pool(&self) -> Option<Arc<ShardPool>> {
+        self.shards.clone()
+    }
+}
+
+
+impl HeapSnapshot {
+    /// Return the number of objects across all heaps.
+    pub fn len(&self) -> usize {
+        self.heaps.iter().map(|heap| heap.len()).sum()
+    }
+
+    /// Return true if every heap is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the object at `ptr`, if any heap holds it.
+    pub fn get(&self, ptr: usize) -> Option<&HeapObject> {
+        self.heaps.iter().flat_map(|heap| heap.iter()).find(|obj| obj.ptr == ptr)
+    }
+}
+
+
+impl TraceStackPool {
+    pub fn new() -> TraceStackPool {
+        TraceStackPool {
+            stacks: Mutex::new(Vec::new()),
+            created: AtomicUsize::new(0),
+            shards: None,
+        }
+    }
+
+    /// Give the stacks taken from now on `shards` to trace large arrays on.
+    pub fn set_shard_pool(&mut self, shards: Arc<ShardPool>) {
+        self.shards = Some(shards);
+    }
+
+    /// Take a stack, creating one if none are free.
+    pub fn take(&self) -> TraceStack {
+        let stack = self.stacks.lock().expect("TraceStackPool lock poisoned!").pop();
+
+        let mut stack = stack.unwrap_or_else(|| {
+            self.created.fetch_add(1, Ordering::Relaxed);
+            TraceStack::new()
+        });
+        stack.shards = self.shards.clone();
+
+        stack
+    }
+
+    /// Give back a stack for reuse, emptying it but keeping its capacity.
+    pub fn give(&self, mut stack: TraceStack) {
+        stack.stack.clear();
+        self.stacks.lock().expect("TraceStackPool lock poisoned!").push(stack);
+    }
+
+    /// The number of stacks that have been created because none were free.
+    pub fn created(&self) -> usize {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    /// The capacities of the free stacks.
+    pub fn capacities(&self) -> Vec<usize> {
+        self.stacks
+            .lock()
+            .expect("TraceStackPool lock poisoned!")
+            .iter()
+            .map(|stack| stack.capacity())
+            .collect()
+    }
 }
 
 
@@ -296,4 +799,11 @@ impl TraceOps for TraceStack {
         let tobj: TraitObject = unsafe { transmute(object) };
         self.stack.push(Object::from(tobj));
     }
+
+    unsafe fn push_raw(&mut self, ptr: usize, vtable: usize) {
+        self.stack.push(Object {
+            ptr: ptr,
+            vtable: vtable,
+        });
+    }
 }
@@ -0,0 +1,225 @@
+//! A set of interned values, such as symbols, that lets go of the values nothing else uses.
+//!
+//! `GcInternSet::intern()` returns a root to the single GC managed object holding a value equal to
+//! the one given, allocating it the first time. The set holds a root reference to each entry
+//! itself and is registered with the GC as a `WeakTable`. During each major collection run on the
+//! GC thread, the set's references to mature entries that nothing else is counted as referencing
+//! are not treated as roots. The entries the mark does not reach are removed from the set before
+//! the sweep drops them, unless `intern()` has handed them out meanwhile, in which case they are
+//! kept and survive the sweep.
+//!
+//! An entry is only found unused once it has been promoted to a mature heap and the decrements of
+//! the other roots to it have been applied, so it takes a few collections after the last of them
+//! is dropped for the entry to leave the set. A major collection run in the background does not
+//! prune the set.
+
+
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem::{forget, transmute};
+use std::raw::TraitObject;
+use std::sync::{Arc, Mutex};
+
+use appthread::{send_command, GcRoot};
+use gcthread::GcCommand;
+use heap::{Object, WeakTable};
+use trace::Trace;
+
+
+/// A set of interned values, see the module documentation. Must be created and used on an app
+/// thread.
+pub struct GcInternSet<T: Trace + Eq + Hash + 'static> {
+    entries: Arc<Mutex<Entries<T>>>,
+}
+
+
+// The entries of a set, shared with the GC thread
+struct Entries<T: Trace> {
+    // the set's entries, bucketed by the hash of their value
+    buckets: HashMap<u64, Vec<Entry<T>>>,
+    // set once the set has been dropped
+    closed: bool,
+}
+
+
+// The set's root to an interned object
+struct Entry<T: Trace> {
+    root: GcRoot<T>,
+    // set when `intern()` returns the entry, cleared when the GC takes the entries to weaken
+    handed_out: bool,
+}
+
+
+// The GC thread's handle on a set
+struct InternTable<T: Trace> {
+    entries: Arc<Mutex<Entries<T>>>,
+}
+
+
+// The GC thread only reads the addresses of the entries and forgets the roots it removes: it
+// never dereferences, clones or drops them.
+unsafe impl<T: Trace> Send for InternTable<T> {}
+
+
+impl<T: Trace + Eq + Hash + 'static> GcInternSet<T> {
+    /// Create an empty set and register it with the GC.
+    ///
+    /// Panics if called from outside an app thread.
+    pub fn new() -> GcInternSet<T> {
+        let entries = Arc::new(Mutex::new(Entries {
+            buckets: HashMap::new(),
+            closed: false,
+        }));
+
+        let table = InternTable { entries: entries.clone() };
+        assert!(send_command(GcCommand::AddWeakTable(Box::new(table))),
+                "GcInternSet::new() called from outside an app thread");
+
+        GcInternSet { entries: entries }
+    }
+
+    /// Return a root to the interned object equal to `value`, moving `value` to the heap as a
+    /// new entry if there is none.
+    pub fn intern(&self, value: T) -> GcRoot<T> {
+        let hash = hash_of(&value);
+        let mut entries = self.entries.lock().expect("GcInternSet lock poisoned!");
+
+        if let Some(bucket) = entries.buckets.get_mut(&hash) {
+            if let Some(entry) = bucket.iter_mut().find(|entry| *entry.root == value) {
+                // the GC may be marking without the set's reference to the entry
+                entry.handed_out = true;
+                return entry.root.clone();
+            }
+        }
+
+        let root = GcRoot::new(value);
+        entries.buckets.entry(hash).or_insert_with(Vec::new).push(Entry {
+            root: root.clone(),
+            handed_out: true,
+        });
+
+        root
+    }
+
+    /// Return the number of entries.
+    pub fn len(&self) -> usize {
+        let entries = self.entries.lock().expect("GcInternSet lock poisoned!");
+        entries.buckets.values().map(|bucket| bucket.len()).sum()
+    }
+
+    /// Return true if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+impl<T: Trace + Eq + Hash + 'static> Drop for GcInternSet<T> {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock().expect("GcInternSet lock poisoned!");
+
+        // the GC forgets the set the next time it prunes it
+        entries.closed = true;
+        entries.buckets.clear();
+    }
+}
+
+
+impl<T: Trace> WeakTable for InternTable<T> {
+    fn weak_entries(&self) -> Option<Vec<Object>> {
+        let mut entries = self.entries.lock().expect("GcInternSet lock poisoned!");
+        if entries.closed {
+            return None;
+        }
+
+        let mut objects = Vec::new();
+        for entry in entries.buckets.values_mut().flat_map(|bucket| bucket.iter_mut()) {
+            entry.handed_out = false;
+            objects.push(object_of(&entry.root));
+        }
+
+        Some(objects)
+    }
+
+    fn release(&self, unused: &HashSet<usize>) -> Vec<usize> {
+        let mut entries = self.entries.lock().expect("GcInternSet lock poisoned!");
+        let mut kept = Vec::new();
+
+        for bucket in entries.buckets.values_mut() {
+            let mut index = 0;
+            while index < bucket.len() {
+                let ptr = object_of(&bucket[index].root).ptr;
+
+                if !unused.contains(&ptr) {
+                    index += 1;
+                } else if bucket[index].handed_out {
+                    kept.push(ptr);
+                    index += 1;
+                } else {
+                    // the GC has released the reference, and there is no journal on this thread
+                    forget(bucket.swap_remove(index).root);
+                }
+            }
+        }
+
+        entries.buckets.retain(|_, bucket| !bucket.is_empty());
+        kept
+    }
+}
+
+
+// Return the object a root points at, as the GC knows it
+fn object_of<T: Trace>(root: &GcRoot<T>) -> Object {
+    let ptr = root.as_gc().as_raw().expect("GcInternSet entry is null!");
+    let trace: &Trace = unsafe { &*ptr };
+    let tobj: TraitObject = unsafe { transmute(trace) };
+
+    Object::from(tobj)
+}
+
+
+// Hash a value to find its bucket
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use appthread::AppThread;
+    use gcthread::GcThread;
+
+    use super::GcInternSet;
+
+
+    #[test]
+    fn test_intern_prune() {
+        let gc = GcThread::spawn_gc();
+
+        let app = gc.spawn(|| {
+            let set = GcInternSet::new();
+
+            let foo = set.intern("foo".to_string());
+            let bar = set.intern("bar".to_string());
+            assert!(set.intern("foo".to_string()).as_gc().is(foo.as_gc()));
+            assert_eq!(set.len(), 2);
+
+            // nothing refers to "bar" but the set
+            drop(bar);
+            AppThread::collect_now();
+            assert_eq!(set.len(), 1);
+
+            // the surviving entry is reused and the pruned one is allocated again
+            assert!(set.intern("foo".to_string()).as_gc().is(foo.as_gc()));
+            assert_eq!(*set.intern("bar".to_string()), "bar");
+            assert_eq!(set.len(), 2);
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+    }
+}
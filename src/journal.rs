@@ -4,38 +4,38 @@
 //! keep up. Performance is better if the receiver keeps up as the allocator will likely reuse
 //! the same set of memory for each buffer.
 //!
-//! Because of TSO on x86, the store order by the sender means that the receiver can load values
-//! from the buffer without worrying that it'll read invalid data ahead of the sender.
-//! On other architectures, we use atomics with the associated performance penalty.
+//! The sender publishes each item by incrementing the buffer's tail with Release ordering after
+//! writing it, and the receiver loads the tail with Acquire ordering before reading, so the
+//! receiver never reads an item ahead of the sender. On x86 these compile to plain loads and
+//! stores.
+//!
+//! If a buffer can't be allocated, the Sender backs off and retries, giving the receiver time to
+//! catch up and free memory, before finally aborting.
 
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::collections::VecDeque;
 use std::intrinsics::{needs_drop, abort};
 use std::mem::{align_of, size_of};
 use std::ptr::{null_mut, read, write, Unique};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicPtr, Ordering};
-
-#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 extern crate alloc;
 use self::alloc::heap::{allocate, deallocate};
 
-use constants::CACHE_LINE;
-
-
-/// TSO means that we don't need atomics on x86 and that will speed things up.
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-struct MaybeAtomicUsize {
-    value: Cell<usize>,
-}
+use constants::{CACHE_LINE, JOURNAL_ALLOC_RETRIES, MAX_SLEEP_DUR};
 
 
-/// On weaker memory model platforms, default to atomics.
-#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-struct MaybeAtomicUsize {
-    value: AtomicUsize,
+/// How buffer memory is allocated: the allocation function and the number of times to retry it
+/// when it fails.
+#[derive(Copy, Clone)]
+struct BufferAlloc {
+    allocate: unsafe fn(usize, usize) -> *mut u8,
+    retries: usize,
 }
 
 
@@ -46,12 +46,14 @@ struct Buffer<T> {
 
     capacity: usize,
 
-    head: MaybeAtomicUsize,
+    alloc: BufferAlloc,
+
+    head: AtomicUsize,
 
     _cachepadding: [u8; CACHE_LINE],
 
-    tail: MaybeAtomicUsize,
-    tail_max: MaybeAtomicUsize,
+    tail: AtomicUsize,
+    tail_max: AtomicUsize,
 
     next: AtomicPtr<Buffer<T>>,
 }
@@ -67,8 +69,17 @@ struct BufferQueue<T> {
 
     // this pointer is only accessed by the Sender
     tail: Cell<*mut Buffer<T>>,
-    // this value only written once by the Sender, read by the Receiver
-    hup: Cell<bool>,
+    // this value only written once by the Sender, read by the Receiver. Setting it publishes the
+    // final tail of the last buffer, so it is released and acquired.
+    hup: AtomicBool,
+    // set by the Sender to ask for the queue to be read to empty, cleared by the Receiver
+    drain: AtomicBool,
+    // the number of buffers allocated and not yet released by the Receiver
+    buffers: AtomicUsize,
+
+    // these counters are only accessed by the Receiver
+    items_read: Cell<usize>,
+    buffer_transitions: Cell<usize>,
 }
 
 
@@ -88,6 +99,8 @@ pub enum RecvResult {
 /// A journal reader type which can be sent to another thread
 pub struct Receiver<T> {
     buffer: Arc<BufferQueue<T>>,
+    // items moved out of the buffers by prefetch() and not yet received
+    stash: RefCell<VecDeque<T>>,
 }
 
 
@@ -102,15 +115,19 @@ unsafe impl<T> Send for Receiver<T> {}
 
 
 impl<T> BufferQueue<T> {
-    fn new(capacity: usize) -> BufferQueue<T> {
-        let first_buffer = Box::new(Buffer::new(capacity));
+    fn new(capacity: usize, alloc: BufferAlloc) -> BufferQueue<T> {
+        let first_buffer = Box::new(Buffer::new(capacity, alloc));
         let ptr = Box::into_raw(first_buffer);
 
         BufferQueue {
             head: Cell::new(ptr),
             _cachepadding: [0; CACHE_LINE],
             tail: Cell::new(ptr),
-            hup: Cell::new(false),
+            hup: AtomicBool::new(false),
+            drain: AtomicBool::new(false),
+            buffers: AtomicUsize::new(1),
+            items_read: Cell::new(0),
+            buffer_transitions: Cell::new(0),
         }
     }
 
@@ -128,6 +145,13 @@ impl<T> BufferQueue<T> {
     fn replace_head(&self, next_head: *mut Buffer<T>) {
         unsafe { Box::from_raw(self.head.get()) };
         self.head.set(next_head);
+        self.buffer_transitions.set(self.buffer_transitions.get() + 1);
+        self.buffers.fetch_sub(1, Ordering::Release);
+    }
+
+    /// use by Receiver only
+    fn count_read(&self) {
+        self.items_read.set(self.items_read.get() + 1);
     }
 
     /// use by Receiver only
@@ -175,8 +199,21 @@ impl<T> Sender<T> {
 
         if let Some(new_tail) = result {
             self.buffer.tail.set(new_tail);
+            self.buffer.buffers.fetch_add(1, Ordering::Release);
         }
     }
+
+    /// The number of buffers allocated and not yet released by the Receiver, including the one
+    /// being written to.
+    pub fn outstanding_buffers(&self) -> usize {
+        self.buffer.buffers.load(Ordering::Acquire)
+    }
+
+    /// Ask the Receiver to read everything sent so far in one go the next time it reads, rather
+    /// than however much it would normally read at a time.
+    pub fn request_drain(&self) {
+        self.buffer.drain.store(true, Ordering::Release);
+    }
 }
 
 
@@ -184,7 +221,7 @@ impl<T> Drop for Sender<T> {
     fn drop(&mut self) {
         // mark the last buffer as completed and set the HUP flag
         unsafe { &*self.buffer.tail() }.mark_completed();
-        self.buffer.hup.set(true);
+        self.buffer.hup.store(true, Ordering::Release);
     }
 }
 
@@ -192,11 +229,50 @@ impl<T> Drop for Sender<T> {
 impl<T> Receiver<T> {
     /// Read a value from the queue if there is one available, otherwise return without blocking
     pub fn try_recv(&self) -> Result<T, RecvResult> {
+        if let Some(value) = self.stash.borrow_mut().pop_front() {
+            return Ok(value);
+        }
+
+        self.recv_buffered()
+    }
+
+    /// Move what has been sent so far out of the buffers into a queue held by the Receiver,
+    /// releasing the buffers, until the queue holds `limit` items, and return the number of items
+    /// moved. `try_recv()` returns them before anything sent later, so a Receiver that cannot
+    /// process items for a while can call this to keep the Sender from piling up buffers in the
+    /// meantime.
+    pub fn prefetch(&self, limit: usize) -> usize {
+        let mut stash = self.stash.borrow_mut();
+        let mut count = 0;
+
+        while stash.len() < limit {
+            let value = match self.recv_buffered() {
+                Ok(value) => value,
+                Err(_) => break,
+            };
+
+            stash.push_back(value);
+            count += 1;
+        }
+
+        count
+    }
+
+    /// The number of buffers allocated and not yet released, including the one being written to.
+    pub fn outstanding_buffers(&self) -> usize {
+        self.buffer.buffers.load(Ordering::Acquire)
+    }
+
+    // Read a value from the buffers, ignoring prefetched values
+    fn recv_buffered(&self) -> Result<T, RecvResult> {
         let head = unsafe { &*self.buffer.head() };
         let result = head.try_read();
 
         match result {
-            Some(value) => Ok(value),
+            Some(value) => {
+                self.buffer.count_read();
+                Ok(value)
+            }
 
             None => {
                 // is this buffer completed by the sender?
@@ -209,6 +285,7 @@ impl<T> Receiver<T> {
                         // peek at next buffer for a value befure returning empty
                         let new_head = unsafe { &*self.buffer.head() };
                         if let Some(value) = new_head.try_read() {
+                            self.buffer.count_read();
                             Ok(value)
                         } else {
                             Err(RecvResult::Empty)
@@ -216,7 +293,7 @@ impl<T> Receiver<T> {
 
                     } else {
                         // no further buffer, did we get hung-up on?
-                        if self.buffer.hup.get() {
+                        if self.buffer.hup.load(Ordering::Acquire) {
                             Err(RecvResult::Disconnected)
                         } else {
                             Err(RecvResult::Empty)
@@ -237,7 +314,11 @@ impl<T> Receiver<T> {
 
     /// Has the Sender hung up?
     pub fn is_disconnected(&self) -> bool {
-        if self.buffer.hup.get() {
+        if !self.stash.borrow().is_empty() {
+            return false;
+        }
+
+        if self.buffer.hup.load(Ordering::Acquire) {
             if let None = self.buffer.next_head() {
                 return unsafe { &*self.buffer.head() }.is_empty();
             }
@@ -245,43 +326,107 @@ impl<T> Receiver<T> {
 
         false
     }
+
+    /// Has the Sender hung up? Unlike `is_disconnected()` there may still be values to read.
+    pub fn is_hung_up(&self) -> bool {
+        self.buffer.hup.load(Ordering::Acquire)
+    }
+
+    /// Return true, once, if the Sender has called `request_drain()` since the last call.
+    pub fn take_drain_request(&self) -> bool {
+        self.buffer.drain.swap(false, Ordering::AcqRel)
+    }
+
+    /// Put back a drain request taken by `take_drain_request()` that could not be finished, so
+    /// that it is returned again by the next call.
+    pub fn restore_drain_request(&self) {
+        self.buffer.drain.store(true, Ordering::Release);
+    }
+
+    /// The total number of items read from the queue
+    pub fn items_read(&self) -> usize {
+        self.buffer.items_read.get()
+    }
+
+    /// The number of times a completed buffer was released and reading moved on to the next one.
+    /// Each one corresponds to the Sender having filled a buffer and allocated another.
+    pub fn buffer_transitions(&self) -> usize {
+        self.buffer.buffer_transitions.get()
+    }
 }
 
 
 /// Return a Sender/Receiver pair that can be handed over to other threads. The capacity is the
 /// requested size of each internal buffer and will be rounded to the next power of two.
 pub fn make_journal<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
-    let buffer = Arc::new(BufferQueue::new(capacity));
+    make_journal_with_retries(capacity, JOURNAL_ALLOC_RETRIES)
+}
+
+
+/// As `make_journal()` but sets the number of times a failed buffer allocation is retried before
+/// the process is aborted.
+pub fn make_journal_with_retries<T>(capacity: usize, retries: usize) -> (Sender<T>, Receiver<T>) {
+    make_journal_with_alloc(capacity,
+                            BufferAlloc {
+                                allocate: allocate,
+                                retries: retries,
+                            })
+}
+
+
+fn make_journal_with_alloc<T>(capacity: usize, alloc: BufferAlloc) -> (Sender<T>, Receiver<T>) {
+    let buffer = Arc::new(BufferQueue::new(capacity, alloc));
 
     (Sender { buffer: buffer.clone() },
-     Receiver { buffer: buffer })
+     Receiver {
+        buffer: buffer,
+        stash: RefCell::new(VecDeque::new()),
+    })
 }
 
 
 impl<T> Buffer<T> {
     /// Create a new Buffer<T> instance, rounding the capacity up to the nearest power of two.
-    fn new(requested_capacity: usize) -> Buffer<T> {
+    fn new(requested_capacity: usize, alloc: BufferAlloc) -> Buffer<T> {
         let rounded_capacity = requested_capacity.next_power_of_two();
 
         let data = unsafe {
-            let array = allocate(rounded_capacity * size_of::<T>(), align_of::<T>());
-            if array.is_null() {
-                abort()
-            };
+            let array = Self::allocate_data(rounded_capacity, alloc);
             Unique::new(array as *mut T)
         };
 
         Buffer {
             data: data,
             capacity: rounded_capacity,
-            head: MaybeAtomicUsize::new(0),
+            alloc: alloc,
+            head: AtomicUsize::new(0),
             _cachepadding: [0; CACHE_LINE],
-            tail: MaybeAtomicUsize::new(0),
-            tail_max: MaybeAtomicUsize::new(rounded_capacity as usize),
+            tail: AtomicUsize::new(0),
+            tail_max: AtomicUsize::new(rounded_capacity as usize),
             next: AtomicPtr::new(null_mut()),
         }
     }
 
+    /// Allocate the data array, backing off and retrying on failure to give the receiver time to
+    /// free memory. Aborts once the retries are used up.
+    unsafe fn allocate_data(capacity: usize, alloc: BufferAlloc) -> *mut u8 {
+        let mut attempts = 0;
+
+        loop {
+            let array = (alloc.allocate)(capacity * size_of::<T>(), align_of::<T>());
+            if !array.is_null() {
+                return array;
+            }
+
+            if attempts == alloc.retries {
+                abort();
+            }
+
+            attempts += 1;
+            thread::sleep(Duration::from_millis(min(attempts, MAX_SLEEP_DUR) as u64));
+        }
+    }
+
     /// Write to the buffer, returning Some(new_buffer) if the current one was full.
     fn write(&self, item: T) -> Option<*mut Buffer<T>> {
         let tail = self.tail.load(Ordering::Relaxed);
@@ -293,7 +438,7 @@ impl<T> Buffer<T> {
             None
         } else {
             // allocate a new buffer and write to that
-            let buffer = Box::new(Buffer::new(self.capacity));
+            let buffer = Box::new(Buffer::new(self.capacity, self.alloc));
             buffer.write(item);
 
             // save the pointer to the new buffer for the receiver
@@ -382,58 +527,14 @@ impl<'a, T> Iterator for EmptyIter<'a, T> {
 }
 
 
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-impl MaybeAtomicUsize {
-    fn new(value: usize) -> MaybeAtomicUsize {
-        MaybeAtomicUsize { value: Cell::new(value) }
-    }
-
-    #[inline]
-    fn load(&self, _ordering: Ordering) -> usize {
-        self.value.get()
-    }
-
-    #[inline]
-    fn store(&self, value: usize, _ordering: Ordering) {
-        self.value.set(value);
-    }
-
-    #[inline]
-    fn fetch_add(&self, value: usize, _ordering: Ordering) -> usize {
-        let old = self.value.get();
-        self.value.set(old + value);
-        old
-    }
-}
-
-
-#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-impl MaybeAtomicUsize {
-    fn new(value: usize) -> MaybeAtomicUsize {
-        MaybeAtomicUsize { value: AtomicUsize::new(value) }
-    }
-
-    #[inline]
-    fn load(&self, ordering: Ordering) -> usize {
-        self.value.load(ordering)
-    }
-
-    #[inline]
-    fn store(&self, value: usize, ordering: Ordering) {
-        self.value.store(value, ordering);
-    }
-
-    #[inline]
-    fn fetch_add(&self, value: usize, ordering: Ordering) -> usize {
-        self.value.fetch_add(value, ordering)
-    }
-}
-
-
 #[cfg(test)]
 mod tests {
 
-    use super::{make_journal, RecvResult};
+    use std::ptr::null_mut;
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+    use std::thread;
+
+    use super::{allocate, make_journal, make_journal_with_alloc, BufferAlloc, RecvResult};
 
 
     const TEST_COUNT: usize = 12345;
@@ -522,4 +623,112 @@ mod tests {
 
         // TODO: tx.send() should return a Result with a disconnected status
     }
+
+    #[test]
+    fn test_stats() {
+        let (tx, mut rx) = make_journal::<usize>(TEST_BUFFER_SIZE);
+
+        let count = TEST_BUFFER_SIZE * 5 + 7;
+        for i in 0..count {
+            tx.send(i);
+        }
+
+        assert_eq!(rx.iter_until_empty().count(), count);
+        assert_eq!(rx.items_read(), count);
+
+        // a new buffer is allocated on the first write that doesn't fit the current one
+        assert_eq!(rx.buffer_transitions(), (count - 1) / TEST_BUFFER_SIZE);
+
+        // reading an empty queue changes nothing
+        assert!(rx.try_recv().is_err());
+        assert_eq!(rx.items_read(), count);
+        assert_eq!(rx.buffer_transitions(), 5);
+    }
+
+    /// Count of allocations that the throttled allocator will fail before succeeding
+    static ALLOC_FAILURES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    unsafe fn throttled_allocate(size: usize, align: usize) -> *mut u8 {
+        let failures = ALLOC_FAILURES.load(Ordering::SeqCst);
+
+        if failures > 0 {
+            ALLOC_FAILURES.store(failures - 1, Ordering::SeqCst);
+            null_mut()
+        } else {
+            allocate(size, align)
+        }
+    }
+
+    #[test]
+    fn test_alloc_retry() {
+        let alloc = BufferAlloc {
+            allocate: throttled_allocate,
+            retries: 5,
+        };
+
+        let (tx, rx) = make_journal_with_alloc::<usize>(TEST_BUFFER_SIZE, alloc);
+
+        // the next buffer allocation fails a few times but not beyond the retry limit
+        ALLOC_FAILURES.store(4, Ordering::SeqCst);
+
+        for i in 0..TEST_BUFFER_SIZE * 3 {
+            tx.send(i);
+        }
+
+        assert_eq!(ALLOC_FAILURES.load(Ordering::SeqCst), 0);
+
+        for i in 0..TEST_BUFFER_SIZE * 3 {
+            match rx.try_recv() {
+                Ok(packet) => assert_eq!(packet, i),
+                _ => assert!(false),
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_handoff() {
+        // a small buffer makes the receiver follow the sender across many buffers
+        let (tx, rx) = make_journal::<usize>(4);
+
+        let sender = thread::spawn(move || {
+            for i in 0..TEST_COUNT {
+                tx.send(i);
+            }
+        });
+
+        let mut expected = 0;
+        loop {
+            match rx.try_recv() {
+                Ok(packet) => {
+                    assert_eq!(packet, expected);
+                    expected += 1;
+                }
+                Err(RecvResult::Empty) => thread::yield_now(),
+                Err(RecvResult::Disconnected) => break,
+            }
+        }
+
+        sender.join().unwrap();
+        assert_eq!(expected, TEST_COUNT);
+    }
+
+    #[test]
+    fn test_prefetch_limit() {
+        let (tx, rx) = make_journal::<usize>(TEST_BUFFER_SIZE);
+        let count = TEST_BUFFER_SIZE * 4;
+
+        for i in 0..count {
+            tx.send(i);
+        }
+
+        // the stash stops at the limit, leaving the rest in the buffers
+        assert_eq!(rx.prefetch(TEST_BUFFER_SIZE * 2), TEST_BUFFER_SIZE * 2);
+        assert_eq!(rx.prefetch(TEST_BUFFER_SIZE * 2), 0);
+        assert!(rx.outstanding_buffers() >= 2);
+
+        // stashed values come back first, in the order they were sent
+        for i in 0..count {
+            assert_eq!(rx.try_recv().ok(), Some(i));
+        }
+    }
 }
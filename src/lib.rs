@@ -1,6 +1,7 @@
 #![feature(alloc)]
 #![feature(core_intrinsics)]
 #![feature(heap_api)]
+#![feature(optin_builtin_traits)]
 #![feature(raw)]
 #![feature(unique)]
 
@@ -32,23 +33,50 @@ extern crate scoped_pool;
 extern crate time;
 
 
+pub mod affinity;
+#[macro_use]
 mod appthread;
+mod asyncgc;
+pub mod bench;
+mod cardtable;
+mod config;
 mod constants;
+mod dropthread;
+mod gcarray;
 mod gcthread;
+mod gctree;
+mod gcvec;
 mod heap;
+mod internset;
 mod journal;
+#[cfg(feature = "checked")]
+mod liveness;
+mod majorthread;
 mod parheap;
+mod recording;
 mod statistics;
+#[macro_use]
 mod trace;
 mod youngheap;
 
 
-pub use appthread::{AppThread, Gc, GcAtomic, GcBox, GcRoot};
+pub use appthread::{AppThread, Gc, GcAtomic, GcBox, GcBuilder, GcRoot, GcSlice, GcUninit,
+                    RootScope, RootSnapshot, SyncGc};
+pub use asyncgc::{AsyncGc, Poll};
+pub use cardtable::CardTable;
+pub use config::{ConfigError, GcConfig, InvariantViolation};
 pub use constants::*;
-pub use gcthread::GcThread;
-pub use heap::{CollectOps, TraceOps, TraceStack};
-pub use journal::{make_journal, Receiver, Sender};
+pub use dropthread::DropQueue;
+pub use gcarray::GcArray;
+pub use gcthread::{assert_no_leaks, GcCommand, GcHandle, GcThread, Survivors};
+pub use gctree::{Children, GcNode, GcTree, Iter};
+pub use gcvec::GcVec;
+pub use heap::{CollectOps, HeapError, HeapObject, HeapSnapshot, RootEntry, TraceOps, TraceStack,
+               WeakTable};
+pub use internset::GcInternSet;
+pub use journal::{make_journal, make_journal_with_retries, Receiver, Sender};
 pub use parheap::ParHeap;
-pub use statistics::StatsLogger;
-pub use trace::Trace;
+pub use statistics::{size_class, Accounting, CollectionProgress, CollectionReport, DefaultLogger,
+                     StatsLogger, StatsSnapshot, WorkBalance};
+pub use trace::{NoGc, Trace};
 pub use youngheap::YoungHeap;
@@ -0,0 +1,46 @@
+//! A table of the addresses of every object the GC may still drop, for `Gc::try_deref()`. Only
+//! built with the `checked` feature.
+//!
+//! App threads add each object to the table as they journal it as new, and the GC removes it just
+//! before dropping it, wherever it is dropped. A single table serves every GC in the process, as
+//! no two live objects share an address.
+//!
+//! Every allocation and every drop takes the table's lock, so this is for debugging only.
+
+
+use std::collections::HashSet;
+use std::sync::{Mutex, Once, ONCE_INIT};
+
+
+static INIT: Once = ONCE_INIT;
+static mut LIVE: *const Mutex<HashSet<usize>> = 0 as *const Mutex<HashSet<usize>>;
+
+
+/// Record the object at `addr` as live.
+pub fn insert(addr: usize) {
+    table().lock().expect("Liveness table lock poisoned!").insert(addr);
+}
+
+
+/// Record the object at `addr` as dropped.
+pub fn remove(addr: usize) {
+    table().lock().expect("Liveness table lock poisoned!").remove(&addr);
+}
+
+
+/// Return true if the object at `addr` has been recorded as live and not yet dropped.
+pub fn contains(addr: usize) -> bool {
+    table().lock().expect("Liveness table lock poisoned!").contains(&addr)
+}
+
+
+// The table, created on first use and never freed
+fn table() -> &'static Mutex<HashSet<usize>> {
+    unsafe {
+        INIT.call_once(|| {
+            LIVE = Box::into_raw(Box::new(Mutex::new(HashSet::new())));
+        });
+
+        &*LIVE
+    }
+}
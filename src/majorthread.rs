@@ -0,0 +1,130 @@
+//! A dedicated thread for collecting the mature heaps in the background.
+//!
+//! With `GcConfig::background_major` set, `YoungHeap::start_major()` hands the mature heaps to
+//! the major thread along with a snapshot of the roots and returns, so that the GC thread can
+//! carry on reading the journals and running minor collections while the mature heaps are marked
+//! and swept. `YoungHeap::poll_major()` takes the heaps back once the collection is done.
+//!
+//! The major thread has its own thread pool, so that minor collections keep the GC thread's.
+
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use scoped_pool::Pool;
+
+use appthread::enter_gc_thread;
+use heap::{CollectOps, RootMap};
+use youngheap::{add_external_roots, remove_external_roots};
+
+
+/// A major collection handed to the major thread, and handed back once done.
+pub struct MajorJob<T> {
+    /// every mature heap, in kind order
+    pub heaps: Vec<T>,
+    /// the kinds of the heaps to collect
+    pub kinds: Vec<usize>,
+    /// the roots to trace from
+    pub roots: RootMap,
+    /// the heap size and the number of dropped objects of each heap in `kinds`, in order, once
+    /// collected
+    pub counts: Vec<(usize, usize)>,
+    /// when the collection was started
+    pub start: Instant,
+    /// the number of objects promoted into the heaps when the collection was started
+    pub promoted: usize,
+}
+
+
+/// Owns the major thread. Dropping this waits for a running collection to finish.
+pub struct MajorThread<T> {
+    jobs: Option<mpsc::Sender<MajorJob<T>>>,
+    done: mpsc::Receiver<MajorJob<T>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+
+// The roots snapshot is only read by the major thread while it holds the job.
+unsafe impl<T: Send> Send for MajorJob<T> {}
+
+
+impl<T: CollectOps + Send + 'static> MajorThread<T> {
+    /// Spawn the major thread with a thread pool of `num_threads`.
+    pub fn spawn(num_threads: usize) -> MajorThread<T> {
+        let (jobs_tx, jobs_rx) = mpsc::channel::<MajorJob<T>>();
+        let (done_tx, done_rx) = mpsc::channel::<MajorJob<T>>();
+
+        let handle = thread::Builder::new()
+            .name("gc-major".to_string())
+            .spawn(move || {
+                enter_gc_thread();
+                let mut pool = Pool::new(num_threads);
+
+                for mut job in jobs_rx.iter() {
+                    collect(&mut job, &mut pool);
+
+                    if done_tx.send(job).is_err() {
+                        break;
+                    }
+                }
+
+                pool.shutdown();
+            })
+            .expect("Failed to spawn the major thread!");
+
+        MajorThread {
+            jobs: Some(jobs_tx),
+            done: done_rx,
+            handle: Some(handle),
+        }
+    }
+}
+
+
+impl<T> MajorThread<T> {
+    /// Start collecting `job`. Only one job may be running at a time.
+    pub fn start(&self, job: MajorJob<T>) {
+        self.jobs
+            .as_ref()
+            .expect("Major thread has exited!")
+            .send(job)
+            .expect("Major thread has exited!");
+    }
+
+    /// Return the running job if it is done.
+    pub fn try_finish(&self) -> Option<MajorJob<T>> {
+        self.done.try_recv().ok()
+    }
+
+    /// Wait for the running job to finish and return it.
+    pub fn finish(&self) -> MajorJob<T> {
+        self.done.recv().expect("Major thread has exited!")
+    }
+}
+
+
+impl<T> Drop for MajorThread<T> {
+    fn drop(&mut self) {
+        // the thread exits once the job channel is closed and the running job is done
+        self.jobs.take();
+
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() && !thread::panicking() {
+                panic!("Major thread panicked!");
+            }
+        }
+    }
+}
+
+
+/// Collect each heap of the job in turn, as `YoungHeap::major_collection_of()` does
+fn collect<T: CollectOps>(job: &mut MajorJob<T>, pool: &mut Pool) {
+    for &kind in job.kinds.iter() {
+        let external = add_external_roots(&mut job.roots, &job.heaps, kind);
+        let counts = job.heaps[kind].collect(pool, &mut job.roots);
+        remove_external_roots(&mut job.roots, external);
+
+        job.counts.push(counts);
+    }
+}
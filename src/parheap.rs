@@ -1,15 +1,20 @@
 //! A parallel collector for the entire heap.
 
 
-use std::mem::transmute;
-use std::raw::TraitObject;
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use scoped_pool::Pool;
 
+use appthread::enter_gc_thread;
+use constants::SIZE_CLASSES;
+use dropthread::DropQueue;
+use gcarray::ShardPool;
 use gcthread::ptr_shift;
-use heap::{CollectOps, HeapMap, Object, ObjectMeta, RootMap, TraceStack};
+use heap::{drop_topologically, CollectOps, HeapError, HeapMap, Object, ObjectMeta, RootMap,
+           TraceStackPool};
+use statistics::{size_class, WorkBalance};
 use trace::Trace;
 
 
@@ -18,6 +23,24 @@ use trace::Trace;
 pub struct ParHeap {
     num_threads: usize,
     objects: HeapMap,
+
+    // mark and sweep balance of the last collection
+    balance: Option<(WorkBalance, WorkBalance)>,
+
+    // where unreachable objects are sent to be dropped, if not dropped during the sweep
+    drop_queue: Option<DropQueue>,
+
+    // drop unreachable objects children first
+    topological_drop: bool,
+
+    // live objects by size class after the last collection
+    sizes: Vec<usize>,
+
+    // duration of the last mark phase
+    mark_time: Option<Duration>,
+
+    // trace stacks reused by each mark phase
+    stacks: TraceStackPool,
 }
 
 
@@ -27,11 +50,18 @@ unsafe impl Send for ParHeap {}
 impl ParHeap {
     /// In this heap implementation, work is split out into a thread pool. There is no knowing,
     /// though, how much work each split actually represents. One thread may receive a
-    /// disproportionate amount of tracing or sweeping.
+    /// disproportionate amount of tracing or sweeping. How much each thread did is measured and
+    /// can be seen through `CollectOps::work_balance()`.
     pub fn new(num_threads: usize) -> ParHeap {
         ParHeap {
             num_threads: num_threads,
             objects: HeapMap::new(),
+            balance: None,
+            drop_queue: None,
+            topological_drop: false,
+            sizes: Vec::new(),
+            mark_time: None,
+            stacks: TraceStackPool::new(),
         }
     }
 
@@ -39,7 +69,27 @@ impl ParHeap {
     ///  * shares a borrow of the main HeapMap among the thread pool
     ///  * divides the roots among the thread pool
     ///  * each thread traces from it's own slice of roots
-    fn mark(&mut self, thread_pool: &mut Pool, roots: &mut RootMap) {
+    ///
+    /// Only the mark bits in the HeapMap are used. The mark bits in the roots belong to the young
+    /// generation collector and are left untouched.
+    ///
+    /// If `between` is given, the calling thread calls it about every `interval` until every
+    /// thread has finished marking.
+    ///
+    /// Returns the number of objects each thread traced.
+    fn mark(&mut self,
+            thread_pool: &mut Pool,
+            roots: &mut RootMap,
+            interval: Duration,
+            mut between: Option<&mut FnMut()>)
+            -> Vec<usize> {
+        let start = Instant::now();
+
+        let collect_traced = Arc::new(Mutex::new(Vec::with_capacity(self.num_threads)));
+        // the count of threads still marking, and a condition signalled when it reaches zero
+        let marking = Arc::new((Mutex::new(0), Condvar::new()));
+        let stacks = &self.stacks;
+
         // divide the roots among threads and trace
         let mut sharded_roots = roots.borrow_sharded(self.num_threads);
 
@@ -54,21 +104,36 @@ impl ParHeap {
 
                 // make a thread-local trace stack and reference to the heap
                 let objects = shared_objects.clone();
+                let traced = collect_traced.clone();
+                let marking = marking.clone();
+                *marking.0.lock().expect("ParHeap lock poisoned!") += 1;
 
                 // mark using the thread-local slice of roots
                 scope.execute(move || {
+                    enter_gc_thread();
 
-                    let mut stack = TraceStack::new();
+                    let mut stack = stacks.take();
+                    let mut trace_counter = 0;
 
                     for (root_ptr, root_meta) in roots.iter() {
-                        if !root_meta.unsync_is_unrooted() && root_meta.mark_and_needs_trace() {
-                            // read the shard to find roots, which are all positive-refcount
-                            // entries. Trace the roots if they need it.
+                        if root_meta.unsync_is_unrooted() {
+                            continue;
+                        }
 
+                        // read the shard to find roots, which are all positive-refcount
+                        // entries. A mature root is marked in the HeapMap; a young root is
+                        // traced without marking as each root entry is only visited once.
+                        let needs_trace = match objects.get(root_ptr) {
+                            Some(meta) => meta.mark_and_needs_trace(),
+                            None => root_meta.is_traversible(),
+                        };
+
+                        if needs_trace {
                             let obj = Object::from_trie_ptr(root_ptr, root_meta.vtable());
 
                             let object = obj.as_trace();
                             unsafe { object.trace(&mut stack) };
+                            trace_counter += 1;
 
                             // now there may be some child objects on the trace stack: pull
                             // them off and mark them too
@@ -80,24 +145,87 @@ impl ParHeap {
                                     if meta.mark_and_needs_trace() {
                                         let object = obj.as_trace();
                                         unsafe { object.trace(&mut stack) };
+                                        trace_counter += 1;
                                     }
                                 }
                             }
                         }
                     }
+
+                    stacks.give(stack);
+                    traced.lock().expect("ParHeap lock poisoned!").push(trace_counter);
+
+                    let &(ref lock, ref done) = &*marking;
+                    let mut count = lock.lock().expect("ParHeap lock poisoned!");
+                    *count -= 1;
+                    if *count == 0 {
+                        done.notify_one();
+                    }
                 }); // execute
             }
+
+            // do the caller's work while the pool marks, waking as soon as the mark is done
+            if let Some(ref mut between) = between {
+                let &(ref lock, ref done) = &*marking;
+
+                loop {
+                    between();
+
+                    let count = lock.lock().expect("ParHeap lock poisoned!");
+                    if *count == 0 {
+                        break;
+                    }
+
+                    let _ = done.wait_timeout(count, interval).expect("ParHeap lock poisoned!");
+                }
+            }
         }); // scope
+
+        self.mark_time = Some(start.elapsed());
+
+        let traced = collect_traced.lock().expect("ParHeap lock poisoned!").clone();
+        traced
+    }
+
+    /// Mark the objects at the addresses in `addrs` and the unmarked objects they lead to, on the
+    /// calling thread, after the parallel mark.
+    fn mark_from(&mut self, addrs: &[usize]) {
+        let mut stack = self.stacks.take();
+
+        for &addr in addrs.iter() {
+            if let Some(meta) = self.objects.get(addr >> ptr_shift()) {
+                if meta.mark_and_needs_trace() {
+                    let obj = Object::from_trie_ptr(addr >> ptr_shift(), meta.vtable());
+                    unsafe { obj.as_trace().trace(&mut stack) };
+                }
+            }
+
+            while let Some(obj) = stack.pop() {
+                if let Some(meta) = self.objects.get(obj.ptr >> ptr_shift()) {
+                    if meta.mark_and_needs_trace() {
+                        unsafe { obj.as_trace().trace(&mut stack) };
+                    }
+                }
+            }
+        }
+
+        self.stacks.give(stack);
     }
 
     /// A parallel sweep implementation:
     ///  * the main HeapMap tree is split into subtrees and each thread is given a separate subtree
     ///    to sweep
-    /// Returns a tuple of (heap_object_count, dropped_object_count)
-    fn sweep(&mut self, thread_pool: &mut Pool) -> (usize, usize) {
+    /// Returns a tuple of (heap_object_count, dropped_object_count, objects swept by each thread)
+    /// and records the sizes of the surviving objects.
+    fn sweep(&mut self, thread_pool: &mut Pool) -> (usize, usize, Vec<usize>) {
         // set counters
         let collect_heap_size = Arc::new(AtomicUsize::new(0));
         let collect_drop_count = Arc::new(AtomicUsize::new(0));
+        let collect_swept = Arc::new(Mutex::new(Vec::with_capacity(self.num_threads)));
+        let drop_queue = self.drop_queue.clone();
+        let collect_sizes = Arc::new(Mutex::new(vec![0; SIZE_CLASSES]));
+        let topological = self.topological_drop;
+        let collect_condemned = Arc::new(Mutex::new(Vec::new()));
 
         // shard the heap
         let mut sharded_objects = self.objects.borrow_sharded(self.num_threads);
@@ -109,12 +237,19 @@ impl ParHeap {
                 // pass a reference to each counter to each thread
                 let heap_size = collect_heap_size.clone();
                 let drop_count = collect_drop_count.clone();
+                let swept = collect_swept.clone();
+                let drop_queue = drop_queue.clone();
+                let sizes = collect_sizes.clone();
+                let condemned = collect_condemned.clone();
 
                 // each thread sweeps a sub-trie
                 scope.execute(move || {
+                    enter_gc_thread();
 
                     let mut heap_counter = 0;
                     let mut drop_counter = 0;
+                    let mut dropped = Vec::new();
+                    let mut size_counters = [0; SIZE_CLASSES];
 
                     shard.retain_if(|ptr, meta| {
                         heap_counter += 1;
@@ -123,36 +258,65 @@ impl ParHeap {
                             drop_counter += 1;
 
                             // if not marked, drop the object
-                            let tobj = TraitObject {
-                                data: (ptr << ptr_shift()) as *mut (),
-                                vtable: meta.vtable() as *mut (),
-                            };
-
-                            unsafe {
-                                let fatptr: *mut Trace = transmute(tobj);
-                                let owned = Box::from_raw(fatptr);
-                                drop(owned);
+                            let obj = Object::from_trie_ptr(ptr, meta.vtable.get());
+                            if drop_queue.is_some() || topological {
+                                dropped.push(obj);
+                            } else {
+                                unsafe { obj.gc_drop() };
                             }
 
                             false
 
                         } else {
+                            let obj = Object::from_trie_ptr(ptr, meta.vtable.get());
+                            size_counters[size_class(obj.size())] += 1;
+
                             // unmark the object
                             meta.unmark();
                             true
                         }
                     });
 
+                    if topological {
+                        condemned.lock().expect("ParHeap lock poisoned!").append(&mut dropped);
+                    } else if let Some(ref queue) = drop_queue {
+                        queue.send(dropped);
+                    }
+
                     // write out the counters
                     heap_size.fetch_add(heap_counter, Ordering::SeqCst);
                     drop_count.fetch_add(drop_counter, Ordering::SeqCst);
+                    swept.lock().expect("ParHeap lock poisoned!").push(heap_counter);
+
+                    let mut sizes = sizes.lock().expect("ParHeap lock poisoned!");
+                    for (total, &count) in sizes.iter_mut().zip(size_counters.iter()) {
+                        *total += count;
+                    }
                 });
             }
         });
 
+        if topological {
+            let condemned = collect_condemned.lock().expect("ParHeap lock poisoned!").split_off(0);
+            drop_topologically(condemned, self.drop_queue.as_ref());
+        }
+
+        let swept = collect_swept.lock().expect("ParHeap lock poisoned!").clone();
+        self.sizes = collect_sizes.lock().expect("ParHeap lock poisoned!").clone();
+
         // return the counters
         (collect_heap_size.load(Ordering::Acquire),
-         collect_drop_count.load(Ordering::Acquire))
+         collect_drop_count.load(Ordering::Acquire),
+         swept)
+    }
+
+    // Reduce per-thread counts to a balance. Threads that were given no shard count as having
+    // done no work.
+    fn reduce_counts(&self, mut counts: Vec<usize>) -> WorkBalance {
+        while counts.len() < self.num_threads {
+            counts.push(0);
+        }
+        WorkBalance::from_counts(&counts)
     }
 }
 
@@ -166,7 +330,362 @@ impl CollectOps for ParHeap {
     /// Run a collection iteration on the heap. Return the total heap size and the number of
     /// dropped objects.
     fn collect(&mut self, thread_pool: &mut Pool, roots: &mut RootMap) -> (usize, usize) {
-        self.mark(thread_pool, roots);
-        self.sweep(thread_pool)
+        let traced = self.mark(thread_pool, roots, Duration::from_millis(0), None);
+        let (heap_size, drop_count, swept) = self.sweep(thread_pool);
+
+        self.balance = Some((self.reduce_counts(traced), self.reduce_counts(swept)));
+
+        (heap_size, drop_count)
+    }
+
+    /// Call `between` on the calling thread while the pool marks.
+    fn collect_interleaved(&mut self,
+                           thread_pool: &mut Pool,
+                           roots: &mut RootMap,
+                           interval: Duration,
+                           between: &mut FnMut())
+                           -> (usize, usize) {
+        let traced = self.mark(thread_pool, roots, interval, Some(between));
+        let (heap_size, drop_count, swept) = self.sweep(thread_pool);
+
+        self.balance = Some((self.reduce_counts(traced), self.reduce_counts(swept)));
+
+        (heap_size, drop_count)
+    }
+
+    /// Call `settle` after the parallel mark and mark the objects it returns on this thread.
+    fn collect_settled(&mut self,
+                       thread_pool: &mut Pool,
+                       roots: &mut RootMap,
+                       interval: Duration,
+                       between: Option<&mut FnMut()>,
+                       settle: &mut FnMut(&Fn(usize) -> bool) -> Vec<usize>)
+                       -> (usize, usize) {
+        let traced = self.mark(thread_pool, roots, interval, between);
+
+        let rescued = {
+            let objects = &self.objects;
+            settle(&|addr| {
+                match objects.get(addr >> ptr_shift()) {
+                    Some(meta) => meta.is_marked(),
+                    None => true,
+                }
+            })
+        };
+        self.mark_from(&rescued);
+
+        let (heap_size, drop_count, swept) = self.sweep(thread_pool);
+
+        self.balance = Some((self.reduce_counts(traced), self.reduce_counts(swept)));
+
+        (heap_size, drop_count)
+    }
+
+    /// Mark in parallel, then count and unmark in a single pass on this thread.
+    fn collect_dry_run(&mut self, thread_pool: &mut Pool, roots: &mut RootMap) -> Option<usize> {
+        self.mark(thread_pool, roots, Duration::from_millis(0), None);
+
+        let mut unmarked = 0;
+        for (_, meta) in self.objects.iter() {
+            if meta.is_marked() {
+                meta.unmark();
+            } else {
+                unmarked += 1;
+            }
+        }
+
+        Some(unmarked)
+    }
+
+    /// Check that no object has a null vtable or a mark bit left set.
+    fn verify(&self, errors: &mut Vec<HeapError>) {
+        for (ptr, meta) in self.objects.iter() {
+            let addr = ptr << ptr_shift();
+
+            if meta.vtable() == 0 {
+                errors.push(HeapError::NullVtable(addr));
+            }
+
+            if meta.is_marked() {
+                errors.push(HeapError::StaleMark(addr));
+            }
+        }
+    }
+
+    /// Add every object in the heap to `objects`.
+    fn live_objects(&self, objects: &mut Vec<Object>) {
+        for (ptr, meta) in self.objects.iter() {
+            objects.push(Object::from_trie_ptr(ptr, meta.vtable()));
+        }
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        self.objects.get(addr >> ptr_shift()).is_some()
+    }
+
+    /// The number of objects each thread traced and swept in the last collection.
+    fn work_balance(&self) -> Option<(WorkBalance, WorkBalance)> {
+        self.balance
+    }
+
+    fn set_drop_queue(&mut self, queue: DropQueue) {
+        self.drop_queue = Some(queue);
+    }
+
+    fn set_topological_drop(&mut self, enabled: bool) {
+        self.topological_drop = enabled;
+    }
+
+    fn set_num_threads(&mut self, num_threads: usize) {
+        self.num_threads = num_threads;
+    }
+
+    fn set_shard_pool(&mut self, shards: Arc<ShardPool>) {
+        self.stacks.set_shard_pool(shards);
+    }
+
+    fn untrack(&mut self, ptr: usize) {
+        self.objects.remove(ptr);
+    }
+
+    /// Live objects by size class, measured in the last sweep.
+    fn size_histogram(&self) -> Option<&[usize]> {
+        if self.sizes.is_empty() {
+            None
+        } else {
+            Some(&self.sizes[..])
+        }
+    }
+
+    fn mark_time(&self) -> Option<Duration> {
+        self.mark_time
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::Cell;
+    use std::mem::transmute;
+    use std::raw::TraitObject;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scoped_pool::Pool;
+
+    use constants::{SIZE_CLASSES, TRAVERSE_BIT};
+    use gcthread::ptr_shift;
+    use heap::{CollectOps, RootMap, RootMeta, TraceOps, TraceStack};
+    use statistics::size_class;
+    use trace::Trace;
+
+    use super::ParHeap;
+
+
+    /// A chain link that counts its own drops
+    struct Link {
+        next: Cell<usize>,
+        drops: Arc<AtomicUsize>,
+    }
+
+    unsafe impl Trace for Link {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            let next = self.next.get();
+            if next != 0 {
+                stack.push_to_trace(&*(next as *const Link));
+            }
+        }
+    }
+
+    impl Drop for Link {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Allocate `count` chains of `len` links and add them to the heap, returning the trie
+    /// pointer and vtable of the head of each chain
+    fn chains(heap: &mut ParHeap,
+              count: usize,
+              len: usize,
+              drops: &Arc<AtomicUsize>)
+              -> Vec<(usize, usize)> {
+        let mut heads = Vec::new();
+
+        for _ in 0..count {
+            let mut next = 0;
+            let mut head = (0, 0);
+
+            for _ in 0..len {
+                let link = Box::into_raw(Box::new(Link {
+                    next: Cell::new(next),
+                    drops: drops.clone(),
+                }));
+
+                let trace: &Trace = unsafe { &*link };
+                let tobj: TraitObject = unsafe { transmute(trace) };
+
+                head = (tobj.data as usize >> ptr_shift(), tobj.vtable as usize | TRAVERSE_BIT);
+                heap.add_object(head.0, head.1);
+
+                next = link as usize;
+            }
+
+            heads.push(head);
+        }
+
+        heads
+    }
+
+    fn rooted(heads: &[(usize, usize)]) -> RootMap {
+        let mut roots = RootMap::new();
+        for &(ptr, vtable) in heads.iter() {
+            roots.set(ptr, RootMeta::new(1, vtable, 0));
+        }
+        roots
+    }
+
+    #[test]
+    fn test_work_balance() {
+        const THREADS: usize = 4;
+        const OBJECTS: usize = 4096;
+
+        let mut pool = Pool::new(THREADS);
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        // a single long chain can only be traced by one thread
+        let mut skewed = ParHeap::new(THREADS);
+        let heads = chains(&mut skewed, 1, OBJECTS, &drops);
+        skewed.collect(&mut pool, &mut rooted(&heads));
+
+        let (mark, _) = skewed.work_balance().unwrap();
+        assert_eq!(mark.max, OBJECTS);
+        assert_eq!(mark.min, 0);
+        assert!(mark.imbalance() > 1.0);
+
+        // many single objects are spread across every thread
+        let mut balanced = ParHeap::new(THREADS);
+        let heads = chains(&mut balanced, OBJECTS, 1, &drops);
+        balanced.collect(&mut pool, &mut rooted(&heads));
+
+        let (mark, sweep) = balanced.work_balance().unwrap();
+        assert_eq!(mark.mean * THREADS as f64, OBJECTS as f64);
+        assert!(mark.imbalance() < 0.5);
+        assert!(sweep.imbalance() < 0.5);
+
+        // unrooted, everything is dropped
+        skewed.collect(&mut pool, &mut RootMap::new());
+        balanced.collect(&mut pool, &mut RootMap::new());
+        assert_eq!(drops.load(Ordering::SeqCst), 2 * OBJECTS);
+    }
+
+    #[test]
+    fn test_collect_dry_run() {
+        let mut pool = Pool::new(2);
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let mut heap = ParHeap::new(2);
+        let live = chains(&mut heap, 10, 5, &drops);
+        chains(&mut heap, 20, 3, &drops);
+
+        let mut roots = rooted(&live);
+
+        // nothing is freed and the heap is left as it was, so a dry run can be repeated
+        assert_eq!(heap.collect_dry_run(&mut pool, &mut roots), Some(60));
+        assert_eq!(heap.collect_dry_run(&mut pool, &mut roots), Some(60));
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        let mut errors = Vec::new();
+        heap.verify(&mut errors);
+        assert!(errors.is_empty());
+
+        // a real collection drops as many
+        let (heap_size, drop_count) = heap.collect(&mut pool, &mut roots);
+        assert_eq!(heap_size, 110);
+        assert_eq!(drop_count, 60);
+        assert_eq!(drops.load(Ordering::SeqCst), 60);
+
+        heap.collect(&mut pool, &mut RootMap::new());
+    }
+
+    #[test]
+    fn test_contains() {
+        let mut pool = Pool::new(1);
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let mut heap = ParHeap::new(1);
+        let heads = chains(&mut heap, 2, 1, &drops);
+        let addr = heads[0].0 << ptr_shift();
+
+        assert!(heap.contains(addr));
+        assert!(heap.contains(heads[1].0 << ptr_shift()));
+        assert!(!heap.contains(addr + (1 << ptr_shift())));
+
+        heap.collect(&mut pool, &mut RootMap::new());
+        assert!(!heap.contains(addr));
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_size_histogram() {
+        struct Small([u8; 8]);
+        struct Medium([u8; 100]);
+        struct Large([u8; 5000]);
+
+        unsafe impl Trace for Small {}
+        unsafe impl Trace for Medium {}
+        unsafe impl Trace for Large {}
+
+        // add an object to the heap, returning its trie pointer and vtable
+        fn add<T: Trace>(heap: &mut ParHeap, value: T) -> (usize, usize) {
+            let trace: Box<Trace> = Box::new(value);
+            let tobj: TraitObject = unsafe { transmute(Box::into_raw(trace)) };
+
+            let object = (tobj.data as usize >> ptr_shift(), tobj.vtable as usize);
+            heap.add_object(object.0, object.1);
+            object
+        }
+
+        assert_eq!(size_class(8), 0);
+        assert_eq!(size_class(100), 4);
+        assert_eq!(size_class(5000), 10);
+        assert_eq!(size_class(1 << 30), SIZE_CLASSES - 1);
+
+        let mut pool = Pool::new(2);
+        let mut heap = ParHeap::new(2);
+        assert!(heap.size_histogram().is_none());
+
+        let mut live = Vec::new();
+        for _ in 0..30 {
+            live.push(add(&mut heap, Small([0; 8])));
+        }
+        for _ in 0..20 {
+            live.push(add(&mut heap, Medium([0; 100])));
+        }
+        for _ in 0..10 {
+            live.push(add(&mut heap, Large([0; 5000])));
+        }
+
+        // garbage is not counted
+        for _ in 0..40 {
+            add(&mut heap, Medium([0; 100]));
+        }
+
+        heap.collect(&mut pool, &mut rooted(&live));
+
+        let mut expected = vec![0; SIZE_CLASSES];
+        expected[0] = 30;
+        expected[4] = 20;
+        expected[10] = 10;
+        assert_eq!(heap.size_histogram().unwrap(), &expected[..]);
+
+        // everything is dropped
+        heap.collect(&mut pool, &mut RootMap::new());
+        assert!(heap.size_histogram().unwrap().iter().all(|&count| count == 0));
     }
 }
@@ -0,0 +1,170 @@
+//! A compact binary format for recording journal entries to a file and reading them back, so
+//! that a workload can be replayed into a fresh GC.
+//!
+//! Each entry is written as its `ptr` word followed by its `vtable` word, each as eight
+//! little-endian bytes, in the order the GC thread read them. Entries carry raw addresses, so a
+//! recording can only be replayed within the process that made it, while the recorded objects
+//! are still allocated and have not been dropped by another GC.
+
+
+use std::io::{self, BufWriter, Read, Write};
+
+use heap::Object;
+
+
+const WORD_BYTES: usize = 8;
+const ENTRY_BYTES: usize = WORD_BYTES * 2;
+
+
+/// Records entries to a writer through a buffer. A write error stops the recording rather than
+/// panicking the GC thread, and is returned by `finish()`.
+pub struct Recorder<W: Write> {
+    writer: BufWriter<W>,
+    error: Option<io::Error>,
+}
+
+
+impl<W: Write> Recorder<W> {
+    pub fn new(writer: W) -> Recorder<W> {
+        Recorder {
+            writer: BufWriter::new(writer),
+            error: None,
+        }
+    }
+
+    /// Append `entry` to the recording, unless an earlier write failed.
+    pub fn record(&mut self, entry: &Object) {
+        if self.error.is_none() {
+            if let Err(error) = write_entry(&mut self.writer, entry) {
+                self.error = Some(error);
+            }
+        }
+    }
+
+    /// Flush the recording, returning the first error met while recording, if any.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+
+/// Append `entry` to `writer`.
+pub fn write_entry<W: Write>(writer: &mut W, entry: &Object) -> io::Result<()> {
+    let mut bytes = [0u8; ENTRY_BYTES];
+
+    encode_word(entry.ptr as u64, &mut bytes[..WORD_BYTES]);
+    encode_word(entry.vtable as u64, &mut bytes[WORD_BYTES..]);
+
+    writer.write_all(&bytes)
+}
+
+
+/// Read the next entry from `reader`, returning `None` at the end of the recording.
+///
+/// A recording that ends part way through an entry is an `UnexpectedEof` error.
+pub fn read_entry<R: Read>(reader: &mut R) -> io::Result<Option<Object>> {
+    let mut bytes = [0u8; ENTRY_BYTES];
+    let mut filled = 0;
+
+    while filled < ENTRY_BYTES {
+        match reader.read(&mut bytes[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    if filled == 0 {
+        return Ok(None);
+    }
+
+    if filled < ENTRY_BYTES {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated journal entry"));
+    }
+
+    Ok(Some(Object {
+        ptr: decode_word(&bytes[..WORD_BYTES]) as usize,
+        vtable: decode_word(&bytes[WORD_BYTES..]) as usize,
+    }))
+}
+
+
+fn encode_word(word: u64, bytes: &mut [u8]) {
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = (word >> (index * 8)) as u8;
+    }
+}
+
+
+fn decode_word(bytes: &[u8]) -> u64 {
+    bytes.iter().enumerate().fold(0, |word, (index, byte)| word | (*byte as u64) << (index * 8))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{self, Cursor, Write};
+
+    use heap::Object;
+
+    use super::{Recorder, read_entry, write_entry};
+
+
+    /// A writer that fails every write
+    struct Broken;
+
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+
+    #[test]
+    fn test_round_trip() {
+        let entries = [Object { ptr: 0x1000 | 3, vtable: 0xdead_beef },
+                       Object { ptr: 7, vtable: 0 },
+                       Object { ptr: !0, vtable: 1 }];
+
+        let mut buffer = Vec::new();
+        for entry in entries.iter() {
+            write_entry(&mut buffer, entry).unwrap();
+        }
+        assert_eq!(buffer.len(), entries.len() * 16);
+
+        let mut reader = Cursor::new(&buffer);
+        for entry in entries.iter() {
+            let read = read_entry(&mut reader).unwrap().unwrap();
+            assert_eq!((read.ptr, read.vtable), (entry.ptr, entry.vtable));
+        }
+        assert!(read_entry(&mut reader).unwrap().is_none());
+
+        // truncated
+        let mut reader = Cursor::new(&buffer[..20]);
+        assert!(read_entry(&mut reader).unwrap().is_some());
+        assert!(read_entry(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_recorder_error() {
+        let mut recorder = Recorder::new(Broken);
+
+        // the buffer fills and its write fails without panicking
+        for ptr in 0..1000 {
+            recorder.record(&Object { ptr: ptr, vtable: 0 });
+        }
+
+        let error = recorder.finish().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+    }
+}
@@ -1,24 +1,82 @@
 //! Performance counters and statistics
 
 
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use time::{get_time, Timespec};
 
+use constants::{MAX_SLEEP_DUR, SIZE_CLASSES, SMALLEST_SIZE_CLASS};
+
 
 /// Type that provides counters for the GC to gain some measure of performance.
-pub trait StatsLogger: Send {
+///
+/// Methods that take `&mut self` are only ever called from the GC thread. Methods that take
+/// `&self` are counter hooks that may be called concurrently from the GC thread pool workers,
+/// and implementations must be thread safe.
+pub trait StatsLogger: Send + Sync {
     /// mark start of time
     fn mark_start_time(&mut self);
     /// mark end of time
     fn mark_end_time(&mut self);
     /// add a number of milliseconds that the GcThread was asleep
-    fn add_sleep(&mut self, ms: usize);
+    fn add_sleep(&self, ms: usize);
+
+    /// add a count of dropped objects. May be called concurrently.
+    fn add_dropped(&self, count: usize);
+    /// give the current heap object count. May be called concurrently.
+    fn current_heap_size(&self, size: usize);
+
+    /// receive the results of a minor or major collection
+    fn collection_report(&self, _report: &CollectionReport) {}
+
+    /// add counts of dropped objects by the number of minor collections they survived: `ages[n]`
+    /// objects were dropped after surviving `n`. The last young bucket also holds everything older
+    /// and the bucket after it counts objects dropped from the mature heap. May be called
+    /// concurrently.
+    fn add_drop_ages(&self, _ages: &[usize]) {}
+
+    /// an internal invariant was found broken and the GC is carrying on regardless, see
+    /// `InvariantViolation::LogAndContinue`
+    fn invariant_violation(&self, _message: &str) {}
+
+    /// an app thread journal was connected and given `id`
+    fn journal_connected(&self, _id: usize) {}
+    /// the app thread journal with `id` was disconnected and has been fully read
+    fn journal_disconnected(&self, _id: usize) {}
+
+    /// add a count of journal entries read and of journal buffers that were used up while
+    /// reading them
+    fn add_journal_stats(&self, _items_read: usize, _buffer_transitions: usize) {}
+
+    /// the number of journal entries of each kind read in a single pass over the journals, not
+    /// counting operand entries
+    fn entry_kinds(&self, _new: usize, _new_inc: usize, _inc: usize, _dec: usize) {}
+
+    /// the spread of work across the thread pool in a mature heap collection, as the numbers of
+    /// objects each thread traced and swept
+    fn mature_work_balance(&self, _mark: &WorkBalance, _sweep: &WorkBalance) {}
+
+    /// the number of live objects in each size class of a mature heap after a major collection,
+    /// see `size_class()`
+    fn size_histogram(&self, _buckets: &[usize]) {}
+
+    /// how long the mark phase of a mature heap collection took
+    fn mature_mark_time(&self, _duration: Duration) {}
 
-    /// add a count of dropped objects
-    fn add_dropped(&mut self, count: usize);
-    /// give the current heap object count
-    fn current_heap_size(&mut self, size: usize);
+    /// add a count of write barriers applied by app threads and of the clean cards they
+    /// dirtied, which are then traced by the next minor collection. Only called with the
+    /// `barrier_stats` feature.
+    fn add_write_barriers(&self, _barriers: usize, _dirtied: usize) {}
+
+    /// return a copy of the counters taken as of a single moment, consistent with each other
+    /// even while hooks are being called concurrently. `None` if the logger does not keep
+    /// counters.
+    fn atomic_snapshot(&self) -> Option<StatsSnapshot> {
+        None
+    }
 
     /// print statistics
     fn dump_to_stdout(&self);
@@ -30,32 +88,404 @@ pub trait StatsLogger: Send {
 }
 
 
+/// The results of a single minor or major collection.
+#[derive(Copy, Clone, Debug)]
+pub struct CollectionReport {
+    /// true if this was a major collection
+    pub major: bool,
+    /// count of `NEW` objects still live in the young generation
+    pub young_live: usize,
+    /// count of objects live in the mature heap as of the last major collection
+    pub mature_live: usize,
+    /// count of objects dropped by this collection
+    pub dropped: usize,
+    /// count of objects promoted to the mature heap by this collection
+    pub promoted: usize,
+    /// time taken by this collection
+    pub duration: Duration,
+}
+
+
+/// How far a budgeted collection got, see `YoungHeap::collect_in_steps()`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CollectionProgress {
+    /// the collection is done: everything unrooted and unreachable when it began has been dropped
+    Complete,
+    /// the budget ran out with this many collection steps still to run
+    Partial(usize),
+}
+
+
+/// The counters of a `DefaultLogger` as of a single moment, see `StatsLogger::atomic_snapshot()`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StatsSnapshot {
+    /// the largest heap object count seen
+    pub max_heap_size: usize,
+    /// the total count of dropped objects
+    pub total_dropped: usize,
+    /// the number of times a count of dropped objects was added
+    pub drop_iterations: usize,
+    /// the total number of journal entries read
+    pub journal_items: usize,
+    /// the total number of journal buffers used up
+    pub buffer_transitions: usize,
+    /// the total numbers of NEW, NEW_INC, INC and DEC journal entries read
+    pub entry_kinds: [usize; 4],
+    /// the number of journals that have connected
+    pub journals_connected: usize,
+    /// the number of journals that have disconnected
+    pub journals_disconnected: usize,
+    /// the total number of milliseconds the GcThread was asleep
+    pub sleep_time: usize,
+    /// the total number of write barriers applied
+    pub write_barriers: usize,
+    /// the total number of clean cards dirtied by write barriers
+    pub cards_dirtied: usize,
+}
+
+
+/// The spread of work across the threads of a parallel collection phase, reduced from a count
+/// of the objects each thread processed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WorkBalance {
+    /// the most objects processed by one thread
+    pub max: usize,
+    /// the fewest objects processed by one thread
+    pub min: usize,
+    /// the mean number of objects processed per thread
+    pub mean: f64,
+    /// the standard deviation of the number of objects processed per thread
+    pub stddev: f64,
+}
+
+
+/// Allocation accounting shared between app threads and the GC thread.
+///
+/// App threads count every new object they allocate and the GC thread counts every new object
+/// it reads from the journals. Once the journals have been drained the two counts must be equal;
+/// if they are not, journal entries have been lost and objects have leaked or been freed early.
+///
+/// Allocation can also be throttled by a token bucket: each new object takes a token and the GC
+/// thread puts back one for each new object it reads from the journals, so app threads can only
+/// get ahead of the GC by the size of the bucket.
+pub struct Accounting {
+    allocated: AtomicUsize,
+    tracked: AtomicUsize,
+
+    /// size of the token bucket, zero if allocation is not throttled
+    token_capacity: AtomicUsize,
+    tokens: AtomicUsize,
+    refill_lock: Mutex<()>,
+    refilled: Condvar,
+}
+
+
 pub struct DefaultLogger {
-    max_heap_size: usize,
+    max_heap_size: AtomicUsize,
+
+    total_dropped: AtomicUsize,
+    drop_iterations: AtomicUsize,
+
+    journal_items: AtomicUsize,
+    buffer_transitions: AtomicUsize,
+
+    // totals of NEW, NEW_INC, INC and DEC entries
+    entry_kinds: [AtomicUsize; 4],
+
+    journals_connected: AtomicUsize,
+    journals_disconnected: AtomicUsize,
 
-    total_dropped: usize,
-    drop_iterations: usize,
+    drop_ages: Mutex<Vec<usize>>,
+
+    violations: Mutex<Vec<String>>,
+
+    // mark and sweep balance of the last mature heap collection
+    work_balance: Mutex<Option<(WorkBalance, WorkBalance)>>,
+
+    // live objects by size class after the last mature heap collection
+    size_histogram: Mutex<Vec<usize>>,
 
     start_time: Timespec,
     stop_time: Timespec,
-    sleep_time: u64,
+    sleep_time: AtomicUsize,
+
+    write_barriers: AtomicUsize,
+    cards_dirtied: AtomicUsize,
+
+    // held shared while a hook updates the counters and exclusively while they are snapshotted,
+    // so that hooks do not block each other but a snapshot never sees a hook half done
+    updating: RwLock<()>,
 }
 
 
 unsafe impl Send for DefaultLogger {}
 
 
+impl Accounting {
+    pub fn new() -> Accounting {
+        Accounting {
+            allocated: AtomicUsize::new(0),
+            tracked: AtomicUsize::new(0),
+            token_capacity: AtomicUsize::new(0),
+            tokens: AtomicUsize::new(0),
+            refill_lock: Mutex::new(()),
+            refilled: Condvar::new(),
+        }
+    }
+
+    /// Called by app threads for each newly allocated object
+    #[inline]
+    pub fn add_allocated(&self) {
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called by the GC thread with a count of new objects read from the journals
+    #[inline]
+    pub fn add_tracked(&self, count: usize) {
+        self.tracked.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Total number of objects allocated by app threads
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Total number of new objects the GC has read from the journals
+    pub fn tracked(&self) -> usize {
+        self.tracked.load(Ordering::Relaxed)
+    }
+
+    /// Throttle allocation with a full bucket of `capacity` tokens, or stop throttling if
+    /// `capacity` is zero. Called by the GC thread.
+    pub fn set_token_capacity(&self, capacity: usize) {
+        let _guard = self.refill_lock.lock().expect("Accounting lock poisoned!");
+
+        self.token_capacity.store(capacity, Ordering::Release);
+        self.tokens.store(capacity, Ordering::Release);
+        self.refilled.notify_all();
+    }
+
+    /// Called by app threads before allocating each new object. If allocation is throttled, take a
+    /// token, blocking until the GC thread puts one back if there are none.
+    #[inline]
+    pub fn take_token(&self) {
+        loop {
+            if self.token_capacity.load(Ordering::Acquire) == 0 {
+                return;
+            }
+
+            let tokens = self.tokens.load(Ordering::Acquire);
+            if tokens > 0 {
+                if self.tokens
+                    .compare_exchange(tokens, tokens - 1, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok() {
+                    return;
+                }
+                continue;
+            }
+
+            let guard = self.refill_lock.lock().expect("Accounting lock poisoned!");
+            if self.tokens.load(Ordering::Acquire) == 0 &&
+               self.token_capacity.load(Ordering::Acquire) != 0 {
+                let _ = self.refilled
+                    .wait_timeout(guard, Duration::from_millis(MAX_SLEEP_DUR as u64))
+                    .expect("Accounting lock poisoned!");
+            }
+        }
+    }
+
+    /// Called by the GC thread with a count of new objects read from the journals, putting back
+    /// as many tokens, up to the size of the bucket.
+    pub fn refill_tokens(&self, count: usize) {
+        let capacity = self.token_capacity.load(Ordering::Acquire);
+        if capacity == 0 || count == 0 {
+            return;
+        }
+
+        let mut tokens = self.tokens.load(Ordering::Acquire);
+        loop {
+            let refilled = min(capacity, tokens + count);
+            match self.tokens
+                .compare_exchange(tokens, refilled, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(previous) => tokens = previous,
+            }
+        }
+
+        let _guard = self.refill_lock.lock().expect("Accounting lock poisoned!");
+        self.refilled.notify_all();
+    }
+}
+
+
+impl WorkBalance {
+    /// Reduce per-thread counts of processed objects. Threads that were given no work count as
+    /// zero.
+    pub fn from_counts(counts: &[usize]) -> WorkBalance {
+        if counts.is_empty() {
+            return WorkBalance {
+                max: 0,
+                min: 0,
+                mean: 0.0,
+                stddev: 0.0,
+            };
+        }
+
+        let n = counts.len() as f64;
+        let mean = counts.iter().fold(0, |sum, &count| sum + count) as f64 / n;
+        let variance = counts.iter()
+            .map(|&count| (count as f64 - mean) * (count as f64 - mean))
+            .fold(0.0, |sum, square| sum + square) / n;
+
+        WorkBalance {
+            max: counts.iter().cloned().max().unwrap_or(0),
+            min: counts.iter().cloned().min().unwrap_or(0),
+            mean: mean,
+            stddev: variance.sqrt(),
+        }
+    }
+
+    /// The standard deviation relative to the mean: zero when every thread did the same amount
+    /// of work, rising as the work is concentrated in fewer threads. Zero if there was no work.
+    pub fn imbalance(&self) -> f64 {
+        if self.mean > 0.0 {
+            self.stddev / self.mean
+        } else {
+            0.0
+        }
+    }
+}
+
+
+/// Return the size class of an object of `bytes` bytes for a size histogram. Class 0 holds
+/// objects of up to `SMALLEST_SIZE_CLASS` bytes and each class after it objects of up to twice
+/// the size, up to class `SIZE_CLASSES - 1`, which holds everything larger.
+pub fn size_class(bytes: usize) -> usize {
+    let mut class = 0;
+    let mut limit = SMALLEST_SIZE_CLASS;
+
+    while bytes > limit && class < SIZE_CLASSES - 1 {
+        class += 1;
+        limit *= 2;
+    }
+
+    class
+}
+
+
 impl DefaultLogger {
     pub fn new() -> DefaultLogger {
         DefaultLogger {
-            max_heap_size: 0,
-            total_dropped: 0,
-            drop_iterations: 0,
+            max_heap_size: AtomicUsize::new(0),
+            total_dropped: AtomicUsize::new(0),
+            drop_iterations: AtomicUsize::new(0),
+            journal_items: AtomicUsize::new(0),
+            buffer_transitions: AtomicUsize::new(0),
+            entry_kinds: [AtomicUsize::new(0),
+                          AtomicUsize::new(0),
+                          AtomicUsize::new(0),
+                          AtomicUsize::new(0)],
+            journals_connected: AtomicUsize::new(0),
+            journals_disconnected: AtomicUsize::new(0),
+            drop_ages: Mutex::new(Vec::new()),
+            violations: Mutex::new(Vec::new()),
+            work_balance: Mutex::new(None),
+            size_histogram: Mutex::new(Vec::new()),
             start_time: Timespec::new(0, 0),
             stop_time: Timespec::new(0, 0),
-            sleep_time: 0,
+            sleep_time: AtomicUsize::new(0),
+            write_barriers: AtomicUsize::new(0),
+            cards_dirtied: AtomicUsize::new(0),
+            updating: RwLock::new(()),
         }
     }
+
+    // Take the lock that keeps counter updates out of snapshots
+    fn updating(&self) -> RwLockReadGuard<()> {
+        self.updating.read().expect("DefaultLogger lock poisoned!")
+    }
+
+    /// The largest heap object count seen
+    pub fn max_heap_size(&self) -> usize {
+        self.max_heap_size.load(Ordering::Relaxed)
+    }
+
+    /// The total count of dropped objects
+    pub fn total_dropped(&self) -> usize {
+        self.total_dropped.load(Ordering::Relaxed)
+    }
+
+    /// The number of times a count of dropped objects was added
+    pub fn drop_iterations(&self) -> usize {
+        self.drop_iterations.load(Ordering::Relaxed)
+    }
+
+    /// The total number of journal entries read
+    pub fn journal_items(&self) -> usize {
+        self.journal_items.load(Ordering::Relaxed)
+    }
+
+    /// The total number of journal buffers used up
+    pub fn buffer_transitions(&self) -> usize {
+        self.buffer_transitions.load(Ordering::Relaxed)
+    }
+
+    /// The total numbers of NEW, NEW_INC, INC and DEC journal entries read, see
+    /// `StatsLogger::entry_kinds()`
+    pub fn entry_kind_totals(&self) -> (usize, usize, usize, usize) {
+        (self.entry_kinds[0].load(Ordering::Relaxed),
+         self.entry_kinds[1].load(Ordering::Relaxed),
+         self.entry_kinds[2].load(Ordering::Relaxed),
+         self.entry_kinds[3].load(Ordering::Relaxed))
+    }
+
+    /// The number of journals that have connected
+    pub fn journals_connected(&self) -> usize {
+        self.journals_connected.load(Ordering::Relaxed)
+    }
+
+    /// The number of journals that have disconnected
+    pub fn journals_disconnected(&self) -> usize {
+        self.journals_disconnected.load(Ordering::Relaxed)
+    }
+
+    /// The count of dropped objects by the number of minor collections they survived, see
+    /// `StatsLogger::add_drop_ages()`
+    pub fn drop_age_histogram(&self) -> Vec<usize> {
+        self.drop_ages.lock().expect("DefaultLogger lock poisoned!").clone()
+    }
+
+    /// The messages of the invariant violations the GC carried on from
+    pub fn invariant_violations(&self) -> Vec<String> {
+        self.violations.lock().expect("DefaultLogger lock poisoned!").clone()
+    }
+
+    /// The mark and sweep work balance of the last mature heap collection, see
+    /// `StatsLogger::mature_work_balance()`
+    pub fn last_work_balance(&self) -> Option<(WorkBalance, WorkBalance)> {
+        *self.work_balance.lock().expect("DefaultLogger lock poisoned!")
+    }
+
+    /// The number of live objects in each size class after the last mature heap collection, see
+    /// `StatsLogger::size_histogram()`. Empty if there has been none.
+    pub fn last_size_histogram(&self) -> Vec<usize> {
+        self.size_histogram.lock().expect("DefaultLogger lock poisoned!").clone()
+    }
+
+    /// The total number of milliseconds the GcThread was asleep
+    pub fn sleep_time(&self) -> usize {
+        self.sleep_time.load(Ordering::Relaxed)
+    }
+
+    /// The total number of write barriers applied, see `StatsLogger::add_write_barriers()`
+    pub fn write_barriers(&self) -> usize {
+        self.write_barriers.load(Ordering::Relaxed)
+    }
+
+    /// The total number of clean cards dirtied by write barriers
+    pub fn cards_dirtied(&self) -> usize {
+        self.cards_dirtied.load(Ordering::Relaxed)
+    }
 }
 
 
@@ -68,34 +498,223 @@ impl StatsLogger for DefaultLogger {
         self.stop_time = get_time();
     }
 
-    fn add_sleep(&mut self, ms: usize) {
-        self.sleep_time += ms as u64;
+    fn add_sleep(&self, ms: usize) {
+        let _updating = self.updating();
+        self.sleep_time.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    fn add_dropped(&self, count: usize) {
+        let _updating = self.updating();
+        self.total_dropped.fetch_add(count, Ordering::Relaxed);
+        self.drop_iterations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn journal_connected(&self, _id: usize) {
+        let _updating = self.updating();
+        self.journals_connected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn journal_disconnected(&self, _id: usize) {
+        let _updating = self.updating();
+        self.journals_disconnected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add_journal_stats(&self, items_read: usize, buffer_transitions: usize) {
+        let _updating = self.updating();
+        self.journal_items.fetch_add(items_read, Ordering::Relaxed);
+        self.buffer_transitions.fetch_add(buffer_transitions, Ordering::Relaxed);
+    }
+
+    fn entry_kinds(&self, new: usize, new_inc: usize, inc: usize, dec: usize) {
+        let _updating = self.updating();
+        self.entry_kinds[0].fetch_add(new, Ordering::Relaxed);
+        self.entry_kinds[1].fetch_add(new_inc, Ordering::Relaxed);
+        self.entry_kinds[2].fetch_add(inc, Ordering::Relaxed);
+        self.entry_kinds[3].fetch_add(dec, Ordering::Relaxed);
+    }
+
+    fn add_write_barriers(&self, barriers: usize, dirtied: usize) {
+        let _updating = self.updating();
+        self.write_barriers.fetch_add(barriers, Ordering::Relaxed);
+        self.cards_dirtied.fetch_add(dirtied, Ordering::Relaxed);
+    }
+
+    fn mature_work_balance(&self, mark: &WorkBalance, sweep: &WorkBalance) {
+        *self.work_balance.lock().expect("DefaultLogger lock poisoned!") = Some((*mark, *sweep));
+    }
+
+    fn size_histogram(&self, buckets: &[usize]) {
+        *self.size_histogram.lock().expect("DefaultLogger lock poisoned!") = buckets.to_vec();
     }
 
-    fn add_dropped(&mut self, count: usize) {
-        self.total_dropped += count;
-        self.drop_iterations += 1;
+    fn invariant_violation(&self, message: &str) {
+        self.violations.lock().expect("DefaultLogger lock poisoned!").push(message.to_string());
     }
 
-    fn current_heap_size(&mut self, size: usize) {
-        self.max_heap_size = max(self.max_heap_size, size);
+    fn add_drop_ages(&self, ages: &[usize]) {
+        let mut histogram = self.drop_ages.lock().expect("DefaultLogger lock poisoned!");
+
+        if histogram.len() < ages.len() {
+            histogram.resize(ages.len(), 0);
+        }
+
+        for (bucket, count) in histogram.iter_mut().zip(ages.iter()) {
+            *bucket += *count;
+        }
+    }
+
+    fn current_heap_size(&self, size: usize) {
+        let _updating = self.updating();
+        let mut current = self.max_heap_size.load(Ordering::Relaxed);
+
+        while size > current {
+            match self.max_heap_size
+                .compare_exchange(current, size, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(previous) => current = previous,
+            }
+        }
+    }
+
+    fn atomic_snapshot(&self) -> Option<StatsSnapshot> {
+        let _snapshotting = self.updating.write().expect("DefaultLogger lock poisoned!");
+
+        let (new, new_inc, inc, dec) = self.entry_kind_totals();
+
+        Some(StatsSnapshot {
+            max_heap_size: self.max_heap_size(),
+            total_dropped: self.total_dropped(),
+            drop_iterations: self.drop_iterations(),
+            journal_items: self.journal_items(),
+            buffer_transitions: self.buffer_transitions(),
+            entry_kinds: [new, new_inc, inc, dec],
+            journals_connected: self.journals_connected(),
+            journals_disconnected: self.journals_disconnected(),
+            sleep_time: self.sleep_time(),
+            write_barriers: self.write_barriers(),
+            cards_dirtied: self.cards_dirtied(),
+        })
     }
 
     fn dump_to_stdout(&self) {
         // calculate timing
         let total_time = max((self.stop_time - self.start_time).num_milliseconds(), 1);
-        let active_time = total_time - self.sleep_time as i64;
+        let active_time = total_time - self.sleep_time() as i64;
         let percent_active_time = active_time * 100 / total_time;
 
         // calculate drop rate
-        let dropped_per_second = self.total_dropped as i64 * 1000 / active_time;
+        let dropped_per_second = self.total_dropped() as i64 * 1000 / active_time;
 
         println!("max-heap {}; dropped {} (per second {}); active {}/{}ms ({}%)",
-                 self.max_heap_size,
-                 self.total_dropped,
+                 self.max_heap_size(),
+                 self.total_dropped(),
                  dropped_per_second,
                  active_time,
                  total_time,
                  percent_active_time);
+
+        println!("journal entries {}; buffers {}",
+                 self.journal_items(),
+                 self.buffer_transitions());
+
+        println!("dropped by age {:?}", self.drop_age_histogram());
+
+        if self.write_barriers() > 0 {
+            println!("write barriers {}; cards dirtied {}",
+                     self.write_barriers(),
+                     self.cards_dirtied());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::{DefaultLogger, StatsLogger};
+
+
+    const TEST_THREADS: usize = 8;
+    const TEST_COUNT: usize = 10000;
+
+
+    #[test]
+    fn test_concurrent_hooks() {
+        let logger = Arc::new(DefaultLogger::new());
+
+        let handles: Vec<_> = (0..TEST_THREADS)
+            .map(|t| {
+                let logger = logger.clone();
+
+                thread::spawn(move || {
+                    for i in 0..TEST_COUNT {
+                        logger.add_dropped(2);
+                        logger.add_sleep(1);
+                        logger.current_heap_size(t * TEST_COUNT + i);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().expect("logger thread failed");
+        }
+
+        assert_eq!(logger.total_dropped(), TEST_THREADS * TEST_COUNT * 2);
+        assert_eq!(logger.drop_iterations(), TEST_THREADS * TEST_COUNT);
+        assert_eq!(logger.sleep_time(), TEST_THREADS * TEST_COUNT);
+        assert_eq!(logger.max_heap_size(), TEST_THREADS * TEST_COUNT - 1);
+    }
+
+    #[test]
+    fn test_atomic_snapshot() {
+        let logger = Arc::new(DefaultLogger::new());
+
+        let handles: Vec<_> = (0..TEST_THREADS)
+            .map(|_| {
+                let logger = logger.clone();
+
+                thread::spawn(move || {
+                    for i in 0..TEST_COUNT {
+                        logger.add_dropped(2);
+                        logger.add_journal_stats(i, i);
+                        logger.entry_kinds(i, i, i, i);
+                    }
+                })
+            })
+            .collect();
+
+        // every snapshot sees each hook either completely or not at all
+        let mut snapshots = 0;
+        let mut last = logger.atomic_snapshot().unwrap();
+
+        while snapshots < 1000 || last.drop_iterations < TEST_THREADS * TEST_COUNT {
+            let snapshot = logger.atomic_snapshot().unwrap();
+
+            assert!(snapshot.total_dropped == snapshot.drop_iterations * 2,
+                    "torn snapshot {:?}",
+                    snapshot);
+            assert!(snapshot.journal_items == snapshot.buffer_transitions,
+                    "torn snapshot {:?}",
+                    snapshot);
+            assert!(snapshot.entry_kinds.iter().all(|&count| count == snapshot.entry_kinds[0]),
+                    "torn snapshot {:?}",
+                    snapshot);
+            assert!(snapshot.drop_iterations >= last.drop_iterations);
+
+            last = snapshot;
+            snapshots += 1;
+        }
+
+        for handle in handles {
+            handle.join().expect("logger thread failed");
+        }
+
+        let last = logger.atomic_snapshot().unwrap();
+        assert_eq!(last.total_dropped, TEST_THREADS * TEST_COUNT * 2);
+        assert_eq!(last.journal_items, TEST_THREADS * TEST_COUNT * (TEST_COUNT - 1) / 2);
     }
 }
@@ -1,6 +1,9 @@
 //! The Trace trait must be implemented by every type that can be GC managed.
 
 
+use appthread::{Gc, GcAtomic, GcRoot, GcSlice, GcUninit, SyncGc};
+use gcarray::GcArray;
+use gcvec::GcVec;
 use heap::TraceStack;
 
 
@@ -17,7 +20,94 @@ pub unsafe trait Trace {
     /// This function must be thread-safe!
     ///
     /// It must read a snapshot of the data structure it is implemented for.
+    ///
+    /// The GC only sees writes made through `DerefMut` on a `Gc` or `GcRoot`, and through
+    /// `GcAtomic`. A pointer stored through interior mutability, such as a `Cell` reached through
+    /// `Deref`, must be followed by a call to `Gc::write_barrier()` on the object holding it, or
+    /// the object may not be traced again and the pointer's target may be dropped.
+    ///
+    /// It must not allocate GC managed objects, for example to materialize a lazily built field:
+    /// the GC calls it on its own threads, which have no journal to record a new object in, and
+    /// allocating on a GC thread panics. The same goes for `Drop` implementations of GC managed
+    /// types. Allocate on the app thread instead, such as on first access.
     unsafe fn trace(&self, _stack: &mut TraceStack) {}
+
+    /// Drop the object and free its memory. Called by the GC when it sweeps an unreachable
+    /// object.
+    ///
+    /// The default assumes the object was allocated as a `Box`. Types that are allocated any
+    /// other way, such as `GcSlice`, must override this.
+    unsafe fn gc_drop(&mut self) {
+        drop(Box::from_raw(self as *mut Self));
+    }
+}
+
+
+/// Implemented for every type that cannot hold a GC managed pointer. It is an auto trait: a type
+/// implements it unless one of its fields is a GC pointer type or a trait object. Used by
+/// `no_trace!` to check at compile time that a type is safe to make non-traversible.
+pub unsafe trait NoGc {}
+
+
+unsafe impl NoGc for .. {}
+
+impl<T: Trace> !NoGc for Gc<T> {}
+impl<T: Trace> !NoGc for GcRoot<T> {}
+impl<T: Trace> !NoGc for GcAtomic<T> {}
+impl<T: Trace> !NoGc for SyncGc<T> {}
+impl<T: Trace> !NoGc for GcSlice<T> {}
+impl<T: Trace> !NoGc for GcUninit<T> {}
+impl<T: Trace> !NoGc for GcVec<T> {}
+impl<T: Trace> !NoGc for GcArray<T> {}
+
+
+/// Implement `Trace` as non-traversible for each of the given types, without `unsafe`. Each type
+/// must implement `NoGc`, so a type with a GC pointer anywhere among its fields fails to compile
+/// rather than having its pointers go untraced. Only types without generic parameters can be
+/// named.
+///
+/// ```
+/// #[macro_use]
+/// extern crate mo_gc;
+///
+/// enum State {
+///     Idle,
+///     Failed(String),
+/// }
+///
+/// no_trace!(State);
+///
+/// fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate mo_gc;
+///
+/// use mo_gc::Gc;
+///
+/// enum Link {
+///     End,
+///     Next(Gc<usize>),
+/// }
+///
+/// no_trace!(Link);
+///
+/// fn main() {}
+/// ```
+#[macro_export]
+macro_rules! no_trace {
+    ($($t:ty),+) => {
+        $(
+            unsafe impl $crate::Trace for $t {
+                fn traversible(&self) -> bool {
+                    fn assert_no_gc<T: $crate::NoGc + ?Sized>() {}
+                    assert_no_gc::<$t>();
+                    false
+                }
+            }
+        )+
+    };
 }
 
 
@@ -35,3 +125,170 @@ unsafe impl Trace for f32 {}
 unsafe impl Trace for f64 {}
 unsafe impl<'a> Trace for &'a str {}
 unsafe impl Trace for String {}
+
+
+unsafe impl Trace for Box<Trace> {
+    fn traversible(&self) -> bool {
+        (**self).traversible()
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        (**self).trace(stack);
+    }
+}
+
+
+/// Traces each element. Traversible even when empty or when `T` is not, as the traverse bit is
+/// taken when the vector is allocated and elements holding GC pointers may be pushed later.
+///
+/// The trace iterates over the elements the vector holds when it starts, but the GC thread has
+/// no way to stop the app thread from growing or shrinking the vector meanwhile, which may free
+/// the buffer being traced. The app thread must not push, pop or otherwise resize a `Vec` held
+/// in a GC managed object while the GC may be tracing it, only overwrite its elements; build it
+/// before moving it to the heap, or use `GcVec` for a vector that grows.
+unsafe impl<T: Trace> Trace for Vec<T> {
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        for element in self.iter() {
+            element.trace(stack);
+        }
+    }
+}
+
+
+/// Traces the value, if any. Traversible even when `None`, as a value holding GC pointers may be
+/// set later.
+unsafe impl<T: Trace> Trace for Option<T> {
+    fn traversible(&self) -> bool {
+        true
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        if let Some(ref value) = *self {
+            value.trace(stack);
+        }
+    }
+}
+
+
+unsafe impl<T: Trace> Trace for Box<T> {
+    fn traversible(&self) -> bool {
+        (**self).traversible()
+    }
+
+    unsafe fn trace(&self, stack: &mut TraceStack) {
+        (**self).trace(stack);
+    }
+}
+
+
+// There is deliberately no impl for `RefCell`. The GC traces concurrently with the app thread:
+// taking a borrow to trace the value would race on the cell's non-atomic borrow count, and reading
+// the value without one would race with a `borrow_mut()` writing it. A type with a `RefCell` of
+// GC pointers must implement `Trace` itself, reading the pointers in a way that is safe against
+// the app thread's writes, and call `Gc::write_barrier()` after changing them.
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use appthread::{AppThread, Gc, GcRoot};
+    use gcthread::GcThread;
+    use heap::TraceStack;
+
+    use super::{NoGc, Trace};
+
+
+    /// An error type with no GC pointers
+    enum Failure {
+        Timeout(u64),
+        Message(String),
+    }
+
+    no_trace!(Failure);
+
+    #[test]
+    fn test_no_trace() {
+        fn is_no_gc<T: NoGc>() {}
+        is_no_gc::<Failure>();
+        is_no_gc::<Vec<String>>();
+
+        for failure in vec![Failure::Timeout(5), Failure::Message("lost".to_string())] {
+            assert!(!failure.traversible());
+
+            let mut stack = TraceStack::new();
+            unsafe { failure.trace(&mut stack) };
+            assert_eq!(stack.len(), 0);
+        }
+    }
+
+    /// A graph node reaching others through standard containers, counting its drops
+    struct Node {
+        edges: Vec<Gc<Node>>,
+        next: Option<Box<Gc<Node>>>,
+        drops: Arc<AtomicUsize>,
+    }
+
+    unsafe impl Trace for Node {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            self.edges.trace(stack);
+            self.next.trace(stack);
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_std_containers() {
+        let gc = GcThread::spawn_gc();
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let app_drops = drops.clone();
+        let app = gc.spawn(move || {
+            let node = |edges: Vec<Gc<Node>>, next: Option<Box<Gc<Node>>>| {
+                GcRoot::new(Node {
+                    edges: edges,
+                    next: next,
+                    drops: app_drops.clone(),
+                })
+            };
+
+            let leaves: Vec<_> = (0..10).map(|_| node(Vec::new(), None)).collect();
+            let tail = node(Vec::new(), None);
+            let hub = node(leaves.iter().map(|leaf| leaf.as_gc()).collect(),
+                           Some(Box::new(tail.as_gc())));
+            let garbage = node(vec![hub.as_gc()], None);
+
+            // only the hub is rooted: the leaves and the tail are reachable through it
+            drop(leaves);
+            drop(tail);
+            drop(garbage);
+
+            AppThread::collect_now();
+            AppThread::collect_now();
+            assert_eq!(app_drops.load(Ordering::SeqCst), 1);
+
+            assert_eq!(hub.edges.len(), 10);
+            assert!(hub.next.as_ref().map_or(false, |next| next.edges.is_empty()));
+        });
+
+        app.join().unwrap();
+        gc.join().unwrap();
+
+        assert_eq!(drops.load(Ordering::SeqCst), 13);
+    }
+}
@@ -6,18 +6,36 @@
 //! with reference counts from the journal.
 
 
-use std::cmp::max;
-use std::mem::transmute;
-use std::raw::TraitObject;
-use std::sync::Arc;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::intrinsics::abort;
+use std::io::{self, BufReader};
+use std::mem::replace;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use scoped_pool::Pool;
 
-use constants::{BUFFER_RUN, DEC, FLAGS_MASK, INC, JOURNAL_RUN, NEW, NEW_BIT, NEW_INC};
-use heap::{CollectOps, Object, ObjectBuf, RootMap, RootMeta, TraceStack};
+use appthread::enter_gc_thread;
+use cardtable::CardTable;
+use config::{GcConfig, InvariantViolation};
+use dropthread::DropThread;
+use gcarray::ShardPool;
+use constants::{BATCH_BIT, BATCH_MASK, BUFFER_RUN, DEC, DRAIN_CHECK_RUN, EPHEMERAL_BIT,
+                EPHEMERAL_KIND, FLAGS_MASK, HEAP_LOW_WATER_SHIFT, INC, JOURNAL_BUFFER_SIZE,
+                JOURNAL_RUN, KIND_BIT, KIND_MASK, KIND_SHIFT, MAX_KINDS, NEW, NEW_BIT, NEW_INC,
+                TENURE_BIT, TRAVERSE_BIT, UNTRACK_BIT};
+use heap::{drop_topologically, CollectOps, HeapError, HeapObject, HeapSnapshot, Object, ObjectBuf,
+           RootEntry, RootMap, RootMeta, TraceStack, TraceStackPool, WeakTable};
 use gcthread::{EntryReceiver, JournalList, ptr_shift};
-use statistics::StatsLogger;
+use journal::make_journal;
+use majorthread::{MajorJob, MajorThread};
+use recording::{Recorder, read_entry};
+use statistics::{Accounting, CollectionProgress, CollectionReport, StatsLogger};
 use trace::Trace;
 
 
@@ -29,47 +47,236 @@ use trace::Trace;
 ///
 /// During tracing, positive reference count objects and non-`NEW` objects are considered
 /// possible roots and only `NEW` objects are considered for marking and sweeping. Entries
-/// can be both roots and `NEW`.
+/// can be both roots and `NEW`. Non-`NEW` objects are only traced if the card table says they
+/// may have been written to since they last pointed at `NEW` objects.
+///
+/// `NEW` objects that survive a minor collection move into the next of `nursery_ages` age
+/// buckets. Only rooted objects in the last bucket are promoted to the mature heap by a major
+/// collection.
 ///
 /// Collection is run in a thread pool across all CPUs by default by sharding the root trie
 /// across threads.
 pub struct YoungHeap<S: StatsLogger, T: CollectOps + Send> {
-    /// Size of the thread pool
-    num_threads: usize,
+    /// GC parameters, including the size of the thread pool
+    config: GcConfig,
 
     /// A list of AppThread journals to read from
     journals: JournalList,
 
+    /// The id to give the next journal to connect
+    next_journal_id: usize,
+
     /// Map of object addresses to reference counts and other data
     roots: RootMap,
 
     /// Buffer of deferred negative reference count adjustments
     deferred: ObjectBuf,
 
-    /// The mature object space
-    mature: T,
+    /// The most decrements the deferred buffer has held at once while reading the journals
+    deferred_peak: usize,
+
+    /// Trie keys of objects to move to the mature heap in the next collection
+    tenured: Vec<usize>,
+
+    /// Trie keys of objects to stop tracking once the journals have been read
+    untracked: Vec<usize>,
+
+    /// The mature object spaces, indexed by the kind given to objects when they are allocated.
+    /// Empty while they are collected on the major thread.
+    mature: Vec<T>,
+
+    /// Objects tenured while the mature heaps are on the major thread, as the heap index, trie
+    /// key and vtable of each, added to the heaps when they are taken back
+    pending_mature: Vec<(usize, usize, usize)>,
+
+    /// Trie keys of objects untracked while the mature heaps are on the major thread
+    pending_untracked: Vec<usize>,
+
+    /// The thread mature heaps are collected on if `GcConfig::background_major` is set, spawned
+    /// on the first background major collection
+    major_thread: Option<MajorThread<T>>,
 
     /// Something that implements statistics logging
     logger: S,
+
+    /// Allocation accounting shared with the app threads
+    accounting: Arc<Accounting>,
+
+    /// Write barrier card table shared with the app threads
+    cards: Arc<CardTable>,
+
+    /// Count of `NEW` objects that survived the last minor collection
+    young_live: usize,
+
+    /// Whether more than `GcConfig::heap_limit` objects were live after the last collection
+    over_limit: bool,
+
+    /// Count of `NEW` objects read from the journals since the last minor collection
+    new_since_minor: usize,
+
+    /// Count of mature objects that survived the last major collection of each mature heap
+    mature_live: Vec<usize>,
+
+    /// Where to record each journal entry read, if recording
+    recorder: Option<Recorder<File>>,
+
+    /// The next step of a collection begun by `collect_in_steps()` that ran out of budget
+    budgeted_step: Option<usize>,
+
+    /// Trace stacks reused by each mark phase
+    stacks: TraceStackPool,
+
+    /// This GC's pool for tracing large arrays on, shared with the mature heaps
+    shards: Arc<ShardPool>,
+
+    /// The clock `GcConfig::journal_drain_time` is measured by, replaced in tests
+    clock: fn() -> Instant,
+
+    /// Tables whose unused entries are released after each major collection
+    weak_tables: Vec<Box<WeakTable>>,
+
+    /// The thread unreachable objects are dropped on, if `GcConfig::drop_thread` is set. Declared
+    /// after the mature heaps so that their queues are gone by the time it is joined.
+    drop_thread: Option<DropThread>,
 }
 
 
 impl<S: StatsLogger, T: CollectOps + Send> YoungHeap<S, T> {
     /// Create a new young generation heap and roots reference count tracker
-    pub fn new(num_threads: usize, mature: T, logger: S) -> YoungHeap<S, T> {
+    pub fn new(config: GcConfig,
+               mature: T,
+               logger: S,
+               accounting: Arc<Accounting>,
+               cards: Arc<CardTable>)
+               -> YoungHeap<S, T> {
+        Self::with_mature_heaps(config, vec![mature], logger, accounting, cards)
+    }
+
+    /// As `new()` but with a mature heap for each kind of object, in kind order. Objects are
+    /// promoted into the heap of the kind they were allocated with by `GcRoot::new_in()` or
+    /// `Gc::new_in()`, or into the first heap if there is no heap of that kind. Each heap can be
+    /// collected on its own with `major_collection_of()`.
+    ///
+    /// While one heap is collected, every object in the others is treated as a root, so mature
+    /// heaps must implement `CollectOps::live_objects()`. A garbage cycle that spans heaps is
+    /// never dropped.
+    ///
+    /// Panics if there are no heaps or more than `MAX_KINDS`.
+    pub fn with_mature_heaps(config: GcConfig,
+                             mature: Vec<T>,
+                             logger: S,
+                             accounting: Arc<Accounting>,
+                             cards: Arc<CardTable>)
+                             -> YoungHeap<S, T> {
+        assert!(!mature.is_empty() && mature.len() <= MAX_KINDS,
+                "YoungHeap needs between one and MAX_KINDS mature heaps");
+
+        let kinds = mature.len();
+        accounting.set_token_capacity(config.alloc_tokens);
+
+        let mut mature = mature;
+        for heap in mature.iter_mut() {
+            heap.set_topological_drop(config.topological_drop);
+        }
+
+        let drop_thread = if config.drop_thread {
+            let drop_thread = DropThread::spawn();
+            for heap in mature.iter_mut() {
+                heap.set_drop_queue(drop_thread.queue());
+            }
+            Some(drop_thread)
+        } else {
+            None
+        };
+
+        let shards = Arc::new(ShardPool::new(config.shard_count()));
+        for heap in mature.iter_mut() {
+            heap.set_shard_pool(shards.clone());
+        }
+        let mut stacks = TraceStackPool::new();
+        stacks.set_shard_pool(shards.clone());
+
         YoungHeap {
-            num_threads: num_threads,
+            config: config,
             journals: JournalList::new(),
+            next_journal_id: 0,
             roots: RootMap::new(),
-            deferred: ObjectBuf::new(),
+            deferred: ObjectBuf::with_capacity(config.deferred_capacity),
+            deferred_peak: 0,
+            tenured: Vec::new(),
+            untracked: Vec::new(),
             mature: mature,
+            pending_mature: Vec::new(),
+            pending_untracked: Vec::new(),
+            major_thread: None,
             logger: logger,
+            accounting: accounting,
+            cards: cards,
+            young_live: 0,
+            over_limit: false,
+            new_since_minor: 0,
+            mature_live: vec![0; kinds],
+            recorder: None,
+            budgeted_step: None,
+            stacks: stacks,
+            shards: shards,
+            clock: Instant::now,
+            weak_tables: Vec::new(),
+            drop_thread: drop_thread,
         }
     }
 
     /// Add a new journal to the list of journals to read
+    ///
+    /// Panics in single threaded mode if the app thread of a journal already connected is still
+    /// running. A journal whose app thread has exited may still be waiting to be read.
     pub fn add_journal(&mut self, recv: EntryReceiver) {
-        self.journals.push(recv);
+        assert!(!self.config.single_threaded ||
+                self.journals.iter().all(|&(_, ref journal)| journal.is_hung_up()),
+                "second app thread connected to a single threaded GC");
+
+        let id = self.next_journal_id;
+        self.next_journal_id += 1;
+
+        self.journals.push((id, recv));
+        self.logger.journal_connected(id);
+    }
+
+    /// Replace the GC parameters. Objects aged beyond a reduced number of `nursery_ages` are
+    /// treated as being in the oldest bucket. A new `deferred_capacity` or `drop_thread` has no
+    /// effect.
+    pub fn set_config(&mut self, config: GcConfig) {
+        if config.alloc_tokens != self.config.alloc_tokens {
+            self.accounting.set_token_capacity(config.alloc_tokens);
+        }
+
+        if config.topological_drop != self.config.topological_drop {
+            self.finish_major();
+            for heap in self.mature.iter_mut() {
+                heap.set_topological_drop(config.topological_drop);
+            }
+        }
+
+        self.config = config;
+        self.shards.set_threads(self.config.shard_count());
+    }
+
+    /// Change the number of threads collection work is shared across, in the young generation and
+    /// every mature heap. The caller must resize its thread pool to match `config().shard_count()`.
+    pub fn set_num_threads(&mut self, num_threads: usize) {
+        self.finish_major();
+        self.config.num_threads = num_threads;
+
+        let shards = self.config.shard_count();
+        for heap in self.mature.iter_mut() {
+            heap.set_num_threads(shards);
+        }
+        self.shards.set_threads(shards);
+    }
+
+    /// Return the current GC parameters.
+    pub fn config(&self) -> &GcConfig {
+        &self.config
     }
 
     /// Returns the number of journals currently connected to the GC
@@ -77,6 +284,57 @@ impl<S: StatsLogger, T: CollectOps + Send> YoungHeap<S, T> {
         self.journals.len()
     }
 
+    /// Record every journal entry read from now on to the file at `path`, replacing any existing
+    /// file and any recording already in progress. See `replay_journal()`.
+    pub fn record_journal<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let file = try!(File::create(path));
+        self.record_to(file);
+        Ok(())
+    }
+
+    /// Record every journal entry read from now on to `file`.
+    pub fn record_to(&mut self, file: File) {
+        self.recorder = Some(Recorder::new(file));
+    }
+
+    /// Stop recording journal entries and flush the recording. Returns the first error met while
+    /// writing the recording, which stopped it at that point.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Read a recording made by `record_journal()` through a new journal as if an app thread had
+    /// written it, returning the number of entries replayed. No collection is run.
+    ///
+    /// The recorded addresses must still refer to the same live objects, which are owned by this
+    /// heap from then on, so the recording must be replayed in the process that made it and the
+    /// heap that recorded it must not collect them.
+    pub fn replay_journal<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let mut reader = BufReader::new(try!(File::open(path)));
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        let mut count = 0;
+
+        while let Some(entry) = try!(read_entry(&mut reader)) {
+            tx.send(entry);
+            count += 1;
+        }
+        drop(tx);
+
+        let id = self.next_journal_id;
+        self.add_journal(rx);
+
+        // the journal is removed once it has been read to the end
+        while self.journals.iter().any(|&(journal_id, _)| journal_id == id) {
+            self.read_journals();
+        }
+
+        Ok(count)
+    }
+
     /// Read all journals for a number of iterations, updating the roots and keeping a reference
     /// count increment for each, and putting decrements into the deferred buffer.
     ///
@@ -88,244 +346,3784 @@ impl<S: StatsLogger, T: CollectOps + Send> YoungHeap<S, T> {
     /// Returns the number of journal entries read.
     pub fn read_journals(&mut self) -> usize {
         let mut entry_count = 0;
+        let mut new_count = 0;
+        let mut transitions = 0;
+
+        // counts of entries by their flags, indexed by DEC, INC, NEW and NEW_INC
+        let mut kinds = [0usize; 4];
+
+        let single_threaded = self.config.single_threaded;
+        let deferred_limit = self.config.deferred_limit;
+
+        let clock = self.clock;
+        let drain_start = clock();
+        let drain_time = Duration::from_millis(self.config.journal_drain_time as u64);
+        let timed = drain_time != Duration::new(0, 0);
+        let mut timed_out = false;
+        // entries read since the drain time was last checked
+        let mut unchecked = 0;
+        let mut passes = 0;
+
+        // counts of broken invariants, reported once the journals have been read
+        let mut bad_kinds = 0;
+        let mut missing = 0;
+
+        // a journal whose app thread asked for a drain is read pass after pass until it is empty,
+        // taking its turn with the others in each pass
+        let mut draining: Vec<bool> = self.journals
+            .iter()
+            .map(|&(_, ref journal)| journal.take_drain_request())
+            .collect();
+
+        // read through the journals a few times, or for as long as allowed
+        loop {
+            passes += 1;
+            let read_before = entry_count;
 
-        // read through the journals a few times
-        for _ in 0..JOURNAL_RUN {
+            // the decrements buffered before this pass, and whether the pass reads every journal
+            // until it is empty
+            let settled = self.deferred.len();
+            let mut emptied_all = true;
 
             // for each journal
-            for journal in self.journals.iter_mut() {
+            for (index, &mut (_, ref mut journal)) in self.journals.iter_mut().enumerate() {
+                // the journals after one cut short are left for the next pass
+                if timed_out {
+                    emptied_all = false;
+                    break;
+                }
+
+                let transitions_before = journal.buffer_transitions();
 
+                // read the journal until empty or a limited number of entries have been pulled,
+                // stopping part way once the drain time is up
+                let mut emptied = false;
+                for _ in 0..BUFFER_RUN {
+                    if timed {
+                        unchecked += 1;
+                        if unchecked == DRAIN_CHECK_RUN {
+                            unchecked = 0;
+
+                            if clock() - drain_start >= drain_time {
+                                timed_out = true;
+                                break;
+                            }
+                        }
+                    }
 
-                // read the journal until empty or a limited number of entries have been pulled
-                for entry in journal.iter_until_empty().take(BUFFER_RUN) {
+                    let entry = match journal.try_recv() {
+                        Ok(entry) => entry,
+                        Err(_) => {
+                            emptied = true;
+                            break;
+                        }
+                    };
 
                     entry_count += 1;
+                    record(&mut self.recorder, &entry);
+                    kinds[entry.ptr & FLAGS_MASK] += 1;
 
                     match entry.ptr & FLAGS_MASK {
-                        NEW_INC => {
+                        flags @ NEW_INC | flags @ NEW => {
+                            new_count += 1;
                             let ptr = entry.ptr >> ptr_shift();
-                            self.roots.set(ptr, RootMeta::one(entry.vtable, NEW_BIT));
-                        }
+                            let refcount = if flags == NEW_INC { 1 } else { 0 };
 
-                        NEW => {
-                            let ptr = entry.ptr >> ptr_shift();
-                            self.roots.set(ptr, RootMeta::zero(entry.vtable, NEW_BIT));
+                            let meta = if entry.vtable & KIND_BIT != 0 {
+                                entry_count += 1;
+
+                                let kind = read_operand(journal);
+                                record(&mut self.recorder, &kind);
+
+                                if kind.ptr & !EPHEMERAL_KIND >= MAX_KINDS {
+                                    bad_kinds += 1;
+                                }
+
+                                let mut kind_flags = (kind.ptr & (MAX_KINDS - 1)) << KIND_SHIFT;
+                                if kind.ptr & EPHEMERAL_KIND != 0 {
+                                    kind_flags |= EPHEMERAL_BIT;
+                                }
+
+                                RootMeta::new(refcount,
+                                              entry.vtable & KIND_MASK,
+                                              NEW_BIT | kind_flags)
+                            } else {
+                                RootMeta::new(refcount, entry.vtable, NEW_BIT)
+                            };
+
+                            self.roots.set(ptr, meta);
                         }
 
                         INC => {
                             let ptr = entry.ptr >> ptr_shift();
+                            let vtable = entry.vtable & BATCH_MASK;
+
+                            let count = if entry.vtable & BATCH_BIT != 0 {
+                                entry_count += 1;
+
+                                let count = read_operand(journal);
+                                record(&mut self.recorder, &count);
+                                count.ptr
+                            } else {
+                                1
+                            };
 
                             let meta = self.roots.get_default_mut(ptr, || {
-                                RootMeta::zero(entry.vtable, 0)
+                                RootMeta::zero(vtable, 0)
                             });
 
-                            meta.inc();
+                            if single_threaded {
+                                meta.unsync_inc_by(count);
+                            } else {
+                                meta.inc_by(count);
+                            }
                         }
 
-                        DEC => self.deferred.push(entry),
+                        DEC => {
+                            if entry.vtable & TENURE_BIT != 0 {
+                                self.tenured.push(entry.ptr >> ptr_shift());
+                            } else if entry.vtable & UNTRACK_BIT != 0 {
+                                self.untracked.push(entry.ptr >> ptr_shift());
+                            } else {
+                                self.deferred.push(entry);
+                            }
+                        }
 
                         _ => unreachable!(),
                     }
                 }
+
+                transitions += journal.buffer_transitions() - transitions_before;
+                emptied_all = emptied_all && emptied;
+                self.deferred_peak = max(self.deferred_peak, self.deferred.len());
+
+                if emptied {
+                    draining[index] = false;
+                }
+            }
+
+            // A decrement may only be applied once the increments written before it have been
+            // read from every journal. That is so for the decrements buffered before a pass that
+            // read every journal to empty, so over the limit they are applied early.
+            if deferred_limit > 0 && self.deferred.len() >= deferred_limit && emptied_all &&
+               settled > 0 {
+                let mut unsettled = self.deferred.split_off(settled);
+                missing += merge_serial(&mut self.deferred, &mut self.roots, single_threaded);
+                self.deferred.append(&mut unsettled);
+            }
+
+            if !timed {
+                if passes >= JOURNAL_RUN && !draining.iter().any(|&d| d) {
+                    break;
+                }
+            } else if timed_out || entry_count == read_before ||
+                      clock() - drain_start >= drain_time {
+                break;
+            }
+        }
+
+        // a drain cut short by the time limit carries on in the next call
+        for (&(_, ref journal), &still_draining) in self.journals.iter().zip(draining.iter()) {
+            if still_draining {
+                journal.restore_drain_request();
             }
         }
 
         // remove any disconnected journals
-        self.journals.retain(|ref j| !j.is_disconnected());
+        let logger = &self.logger;
+        self.journals.retain(|&(id, ref j)| {
+            if j.is_disconnected() {
+                logger.journal_disconnected(id);
+                false
+            } else {
+                true
+            }
+        });
+
+        if bad_kinds > 0 {
+            invariant_violation(self.config.on_violation,
+                                &self.logger,
+                                &format!("{} journal entries had an out of range kind",
+                                         bad_kinds));
+        }
+        self.report_missing(missing);
+        self.untrack();
+
+        self.new_since_minor += new_count;
+        self.accounting.add_tracked(new_count);
+        self.accounting.refill_tokens(new_count);
+        self.logger.add_journal_stats(entry_count, transitions);
+        self.logger.entry_kinds(kinds[NEW], kinds[NEW_INC], kinds[INC], kinds[DEC]);
 
         entry_count
     }
 
-    /// Do a young generation collection. Returns the number of new objects in the young generation
-    /// heap.
-    pub fn minor_collection(&mut self, pool: &mut Pool) -> usize {
+    /// Return true if enough new objects have been read since the last minor collection to run
+    /// another, see `GcConfig::minor_threshold`.
+    pub fn minor_due(&self) -> bool {
+        self.new_since_minor >= self.config.minor_threshold
+    }
+
+    /// Do a young generation collection. Returns a report that includes the number of new objects
+    /// in the young generation heap.
+    pub fn minor_collection(&mut self, pool: &mut Pool) -> CollectionReport {
+        let start = Instant::now();
+
+        self.new_since_minor = 0;
+
+        let promoted = self.tenure();
+
+        let (barriers, dirtied) = self.cards.take_barrier_counts();
+        if barriers > 0 {
+            self.logger.add_write_barriers(barriers, dirtied);
+        }
+
         self.mark(pool);
         let (young_size, drop_count) = self.sweep(pool);
         self.merge_deferred(pool);
 
         self.logger.add_dropped(drop_count);
 
-        young_size
-    }
+        self.young_live = young_size;
+        self.update_over_limit();
 
-    /// Do a major collection, moving `NEW` objects to the mature heap and tracing the mature heap
-    pub fn major_collection(&mut self, pool: &mut Pool) {
-        // first move any new-objects into the mature heap by copying and unsetting the new-object
-        // flag in the roots
-        for (ptr, meta) in self.roots.iter_mut() {
-            if !meta.unsync_is_unrooted() && meta.is_new() {
-                // object must have a positive reference count and be marked as new-object to be
-                // moved to the mature set
-                self.mature.add_object(ptr, meta.vtable());
-                // unset the new-object bit. This object will now be treated as a simple reference
-                // counted root and won't be dropped from here.
-                meta.set_not_new();
-            }
-        }
+        let report = CollectionReport {
+            major: false,
+            young_live: self.young_live,
+            mature_live: self.mature_count(),
+            dropped: drop_count,
+            promoted: promoted,
+            duration: start.elapsed(),
+        };
 
-        let (heap_size, drop_count) = self.mature.collect(pool, &mut self.roots);
+        self.logger.collection_report(&report);
+        report
+    }
 
-        self.logger.current_heap_size(heap_size);
-        self.logger.add_dropped(drop_count);
+    /// Do a major collection, moving `NEW` objects to the mature heaps and tracing every mature
+    /// heap
+    pub fn major_collection(&mut self, pool: &mut Pool) -> CollectionReport {
+        let kinds: Vec<usize> = (0..self.mature_live.len()).collect();
+        self.major_collection_of(pool, &kinds)
     }
 
-    /// Use >0 refcount objects and 0-refcount non-new objects to mark new objects
-    fn mark(&mut self, pool: &mut Pool) {
+    /// Do a major collection, moving `NEW` objects to the mature heaps but only tracing the
+    /// mature heaps of the given kinds. Kinds without a heap are ignored. A major collection
+    /// running in the background is finished first.
+    pub fn major_collection_of(&mut self, pool: &mut Pool, kinds: &[usize]) -> CollectionReport {
+        self.finish_major();
 
-        let shared_objects = self.roots.borrow_sync();
-        let sharded_objects = shared_objects.borrow_sharded(self.num_threads);
+        let start = Instant::now();
 
-        pool.scoped(|scope| {
+        let promoted = self.promote();
+        self.young_live -= min(promoted, self.young_live);
 
-            for shard in sharded_objects.iter() {
-                let objects = shared_objects.clone();
-                // here there is a shard of the heap and a shared reference to the whole
-                // heap (objects) for each thread
+        let mut drop_count = 0;
 
-                scope.execute(move || {
-                    let mut stack = TraceStack::new();
+        for &kind in kinds.iter().filter(|&&kind| kind < self.mature.len()) {
+            let external = add_external_roots(&mut self.roots, &self.mature, kind);
+            let weakened = self.weaken_entries(kind);
+            let mut released = Vec::new();
 
-                    for (root_ptr, root_meta) in shard.iter() {
-                        if !root_meta.unsync_is_unrooted() || !root_meta.is_new() {
-                            // read the shard to find roots, which are non-zero-refcount
-                            // entries. Also consider non-new entries as possible roots of new
-                            // objects: this is our equivalent of searching a card table
+            let (heap_size, heap_drop_count) = {
+                let interval = Duration::from_millis(self.config.mark_journal_interval as u64);
+                let limit = self.config.mark_journal_limit;
+                let journals = &self.journals;
+                let mut prefetch = || {
+                    for &(_, ref journal) in journals.iter() {
+                        journal.prefetch(limit);
+                    }
+                };
 
-                            if root_meta.mark_and_needs_trace() {
-                                // mark the root, and if it needs tracing then look into it
-                                let obj = Object::from_trie_ptr(root_ptr, root_meta.vtable());
+                let between: Option<&mut FnMut()> = if self.config.mark_journal_interval > 0 {
+                    Some(&mut prefetch)
+                } else {
+                    None
+                };
 
-                                let object = obj.as_trace();
-                                unsafe { object.trace(&mut stack) };
+                let tables = &self.weak_tables;
+                let weakened = &weakened;
+                let released = &mut released;
+                let mut settle = |is_marked: &Fn(usize) -> bool| {
+                    release_unmarked(tables, weakened, is_marked, released)
+                };
 
-                                // now there may be some child objects on the trace stack: pull
-                                // them off and mark them too
-                                while let Some(obj) = stack.pop() {
+                self.mature[kind]
+                    .collect_settled(pool, &mut self.roots, interval, between, &mut settle)
+            };
 
-                                    let ptr = obj.ptr >> ptr_shift();
-                                    if let Some(meta) = objects.get(ptr) {
+            remove_external_roots(&mut self.roots, external);
+            self.settle_entries(weakened, released);
 
-                                        if meta.mark_and_needs_trace() {
-                                            let object = obj.as_trace();
-                                            unsafe { object.trace(&mut stack) };
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                });
-            }
-        });
+            drop_count += self.mature_collected(kind, heap_size, heap_drop_count);
+        }
+
+        self.end_major(start, promoted, drop_count)
     }
 
-    /// Drop unmarked new objects and remove unrooted objects.
-    /// Returns tuple (young_object_count, dropped_count)
-    fn sweep(&mut self, pool: &mut Pool) -> (usize, usize) {
-        // set counters
-        let collect_young_count= Arc::new(AtomicUsize::new(0));
-        let collect_drop_count = Arc::new(AtomicUsize::new(0));
+    /// Count the mature objects a major collection of every mature heap would drop now, without
+    /// dropping anything, or `None` if a heap cannot tell, see `CollectOps::collect_dry_run()`.
+    /// Nothing is promoted, the deferred decrements are not applied first and every young object
+    /// is taken to be live, so the count is a lower bound on what `major_collection()` would drop.
+    /// A major collection running in the background is finished first.
+    pub fn major_dry_run(&mut self, pool: &mut Pool) -> Option<usize> {
+        self.finish_major();
 
-        let mut split_objects = self.roots.borrow_sharded(self.num_threads);
+        // the mature heaps trace young objects only from roots
+        let raised: Vec<usize> = self.roots
+            .iter()
+            .filter(|&(_, meta)| meta.is_new() && meta.unsync_is_unrooted())
+            .map(|(ptr, _)| ptr)
+            .collect();
 
-        pool.scoped(|scope| {
+        for &ptr in raised.iter() {
+            if let Some(meta) = self.roots.get(ptr) {
+                meta.unsync_inc();
+            }
+        }
 
-            for mut node in split_objects.drain() {
+        let mut total = Some(0);
 
-                // pass a reference to each counter to each thread
-                let young_count = collect_young_count.clone();
-                let drop_count = collect_drop_count.clone();
+        for kind in 0..self.mature.len() {
+            let external = add_external_roots(&mut self.roots, &self.mature, kind);
+            let count = self.mature[kind].collect_dry_run(pool, &mut self.roots);
+            remove_external_roots(&mut self.roots, external);
 
-                scope.execute(move || {
+            total = match (total, count) {
+                (Some(total), Some(count)) => Some(total + count),
+                _ => None,
+            };
+        }
 
-                    let mut young_counter = 0;
-                    let mut drop_counter = 0;
+        for ptr in raised {
+            if let Some(meta) = self.roots.get(ptr) {
+                meta.unsync_dec();
+            }
+        }
 
-                    node.retain_if(|ptr, meta| {
+        total
+    }
 
-                        if meta.is_new_and_unmarked() {
-                            drop_counter += 1;
+    /// Return true if a major collection started by `start_major()` has not yet been taken back.
+    /// While it runs the mature heaps are away on the major thread: they are left out of
+    /// `verify()`, `live_objects()` and the other diagnostics until it is finished.
+    pub fn major_running(&self) -> bool {
+        self.mature.is_empty()
+    }
 
-                            // unmarked new-object (implies zero-refcount)
-                            let obj = Object::from_trie_ptr(ptr, meta.vtable);
-                            let tobj: TraitObject = Object::into(obj);
+    /// If a major collection is running in the background and is done, take the mature heaps back
+    /// and return its report.
+    pub fn poll_major(&mut self) -> Option<CollectionReport> {
+        let job = match self.major_thread {
+            Some(ref thread) if self.major_running() => thread.try_finish(),
+            _ => None,
+        };
 
-                            unsafe {
-                                let fatptr: *mut Trace = transmute(tobj);
-                                let owned = Box::from_raw(fatptr);
-                                drop(owned);
-                            }
+        job.map(|job| self.end_background_major(job))
+    }
 
-                            false
+    /// If a major collection is running in the background, wait for it and take the mature heaps
+    /// back, returning its report.
+    pub fn finish_major(&mut self) -> Option<CollectionReport> {
+        let job = match self.major_thread {
+            Some(ref thread) if self.major_running() => Some(thread.finish()),
+            _ => None,
+        };
 
-                        } else if !meta.is_new() && meta.unsync_is_unrooted() {
-                            false
+        job.map(|job| self.end_background_major(job))
+    }
 
-                        } else {
-                            if meta.is_new() {
-                                young_counter += 1;
-                            }
+    // Log the collection of the heap of `kind`, which left `heap_size` objects of which
+    // `heap_drop_count` were dropped. Returns the number dropped.
+    fn mature_collected(&mut self,
+                        kind: usize,
+                        heap_size: usize,
+                        heap_drop_count: usize)
+                        -> usize {
+        if let Some(duration) = self.mature[kind].mark_time() {
+            self.logger.mature_mark_time(duration);
+        }
 
-                            meta.unmark();
-                            true
-                        }
-                    });
+        if let Some((mark, sweep)) = self.mature[kind].work_balance() {
+            self.logger.mature_work_balance(&mark, &sweep);
+        }
 
-                    // write out the counters
-                    young_count.fetch_add(young_counter, Ordering::SeqCst);
-                    drop_count.fetch_add(drop_counter, Ordering::SeqCst);
-                });
-            }
-        });
+        if let Some(buckets) = self.mature[kind].size_histogram() {
+            self.logger.size_histogram(buckets);
+        }
 
-        // return the counters
-        (collect_young_count.load(Ordering::Acquire),
-         collect_drop_count.load(Ordering::Acquire))
+        self.mature_live[kind] = heap_size - heap_drop_count;
+        heap_drop_count
     }
 
-    /// Move the deferred refcount decrements into the root set's reference counts.
-    fn merge_deferred(&mut self, pool: &mut Pool) {
-        let chunk_size = max(1, self.deferred.len() / self.num_threads);
+    // Take back the mature heaps of a finished background major collection, give them the
+    // objects tenured and untracked while they were away and report the collection
+    fn end_background_major(&mut self, job: MajorJob<T>) -> CollectionReport {
+        let MajorJob { heaps, kinds, counts, start, promoted, .. } = job;
+        self.mature = heaps;
 
-        {
-            let shared_roots = self.roots.borrow_sync();
-            let chunks = self.deferred.chunks(chunk_size);
+        for (heap, ptr, vtable) in self.pending_mature.drain(..) {
+            self.mature[heap].add_object(ptr, vtable);
+        }
 
-            pool.scoped(|scope| {
+        for ptr in self.pending_untracked.drain(..) {
+            for heap in self.mature.iter_mut() {
+                heap.untrack(ptr);
+            }
+        }
 
-                for chunk in chunks {
+        let mut drop_count = 0;
+        for (&kind, &(heap_size, heap_drop_count)) in kinds.iter().zip(counts.iter()) {
+            drop_count += self.mature_collected(kind, heap_size, heap_drop_count);
+        }
 
-                    let roots = shared_roots.clone();
+        self.end_major(start, promoted, drop_count)
+    }
 
-                    scope.execute(move || {
-                        for object in chunk {
-                            let ptr = object.ptr >> ptr_shift();
+    // Log the end of a major collection that dropped `drop_count` mature objects, prune the weak
+    // tables and report the collection
+    fn end_major(&mut self,
+                 start: Instant,
+                 promoted: usize,
+                 drop_count: usize)
+                 -> CollectionReport {
+        self.logger.current_heap_size(self.mature_count() + drop_count);
+        self.logger.add_dropped(drop_count);
 
-                            if let Some(ref mut meta) = roots.get(ptr) {
-                                // this is the only place where the reference count needs to
-                                // be thread-safely adjusted
-                                meta.dec();
-                            } else {
-                                // there should never be something in the deferred buffer that
-                                // isn't in the heap
-                                unreachable!();
-                            }
-                        }
-                    });
-                }
-            });
+        if drop_count > 0 {
+            // mature objects are counted in the bucket after the oldest young bucket
+            let mut drop_ages = vec![0; self.config.nursery_ages + 1];
+            drop_ages[self.config.nursery_ages] = drop_count;
+            self.logger.add_drop_ages(&drop_ages);
         }
 
-        self.deferred.clear();
+        self.update_over_limit();
+
+        let report = CollectionReport {
+            major: true,
+            young_live: self.young_live,
+            mature_live: self.mature_count(),
+            dropped: drop_count,
+            promoted: promoted,
+            duration: start.elapsed(),
+        };
+
+        self.logger.collection_report(&report);
+        report
     }
 
-    /// Return a reference to the logger
-    pub fn logger(&mut self) -> &mut S {
-        &mut self.logger
+    /// Read the journals and collect both generations such that every object that was unrooted
+    /// and unreachable when this was called is dropped. The first minor collection merges the
+    /// pending decrements, the second drops the newly unrooted young objects and the major
+    /// collection drops unrooted mature objects.
+    pub fn collect_all(&mut self, pool: &mut Pool) {
+        self.read_journals();
+
+        self.minor_collection(pool);
+        self.minor_collection(pool);
+        self.major_collection(pool);
     }
 
-    /// Call to return the logger on shutdown
-    pub fn shutdown(self) -> S {
-        self.logger
+    /// Do the collection of `collect_all()` in steps, starting no new step once `budget` has been
+    /// used up, for callers that can only spare so much time at once. The steps are the two minor
+    /// collections and a major collection of each mature heap in turn. The next call carries on
+    /// from the first step not yet run, and the call that runs the last step returns `Complete`.
+    ///
+    /// The budget is best-effort, not a bound. At least one step is run per call, so that the
+    /// collection always progresses, and a step is never interrupted: a call overruns its budget
+    /// by up to the length of one step, which is a whole minor collection or a whole major
+    /// collection of one mature heap and grows with the size of the heap.
+    pub fn collect_in_steps(&mut self, pool: &mut Pool, budget: Duration) -> CollectionProgress {
+        let start = Instant::now();
+        let steps = 2 + self.mature_live.len();
+
+        let mut step = match self.budgeted_step.take() {
+            Some(step) => step,
+            None => {
+                self.read_journals();
+                0
+            }
+        };
+
+        loop {
+            if step < 2 {
+                self.minor_collection(pool);
+            } else {
+                self.major_collection_of(pool, &[step - 2]);
+            }
+
+            step += 1;
+
+            if step == steps {
+                return CollectionProgress::Complete;
+            }
+
+            if start.elapsed() >= budget {
+                self.budgeted_step = Some(step);
+                return CollectionProgress::Partial(steps - step);
+            }
+        }
+    }
+
+    /// Return the most decrements the deferred buffer has held at once while reading the
+    /// journals, for sizing `GcConfig::deferred_capacity` and `GcConfig::deferred_limit`.
+    pub fn deferred_peak(&self) -> usize {
+        self.deferred_peak
+    }
+
+    /// Return the count of live young and mature objects as of the last collections
+    pub fn live_count(&self) -> usize {
+        self.young_live + self.mature_count()
+    }
+
+    /// Return true if the heap is under memory pressure: more than `GcConfig::heap_limit` objects
+    /// were live after a collection and the live count has not since fallen to the low water mark
+    /// below it. The next major collection then promotes every rooted new object.
+    pub fn is_over_limit(&self) -> bool {
+        self.over_limit
+    }
+
+    // Set the over-limit flag from the current live count, clearing it only once the count is
+    // down to the low water mark
+    fn update_over_limit(&mut self) {
+        let limit = self.config.heap_limit;
+        let live = self.live_count();
+
+        self.over_limit = if limit == 0 {
+            false
+        } else if self.over_limit {
+            live > limit - (limit >> HEAP_LOW_WATER_SHIFT)
+        } else {
+            live > limit
+        };
+    }
+
+    /// Return the count of live objects in all mature heaps as of the last major collections
+    fn mature_count(&self) -> usize {
+        self.mature_live.iter().fold(0, |sum, live| sum + live)
+    }
+
+    /// Check the young and mature heap invariants. Must only be called between collections, when
+    /// no mark bits should be set and all deferred decrements have been merged.
+    pub fn verify(&self) -> Result<(), Vec<HeapError>> {
+        let mut errors = Vec::new();
+
+        for (ptr, meta) in self.roots.iter() {
+            let addr = ptr << ptr_shift();
+
+            if meta.vtable() == 0 {
+                errors.push(HeapError::NullVtable(addr));
+            }
+
+            if meta.is_marked() {
+                errors.push(HeapError::StaleMark(addr));
+            }
+
+            if meta.is_refcount_negative() {
+                errors.push(HeapError::NegativeRefcount(addr));
+            }
+        }
+
+        for heap in self.mature.iter() {
+            heap.verify(&mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Return every object known to the young and mature heaps, ordered by address. Objects may
+    /// be live only because they have not been collected yet, so this is for diagnostics such as
+    /// listing leaked objects after a final collection.
+    pub fn live_objects(&self) -> Vec<Object> {
+        let mut objects = Vec::new();
+
+        for (ptr, meta) in self.roots.iter() {
+            objects.push(Object::from_trie_ptr(ptr, meta.vtable()));
+        }
+
+        for heap in self.mature.iter() {
+            heap.live_objects(&mut objects);
+        }
+
+        // rooted mature objects are known to both
+        objects.sort_by_key(|obj| obj.ptr);
+        let mut last = 0;
+        objects.retain(|obj| {
+            let unique = obj.ptr != last;
+            last = obj.ptr;
+            unique
+        });
+        objects
+    }
+
+    /// Capture every object in the mature heaps along with the objects each points at. Like
+    /// `live_objects()` this is for diagnostics, such as inspecting what was left after a final
+    /// collection: the objects are traced on the calling thread while app threads may be
+    /// mutating them.
+    pub fn mature_snapshot(&self) -> HeapSnapshot {
+        let mut heaps = Vec::with_capacity(self.mature.len());
+        let mut stack = TraceStack::new();
+
+        for heap in self.mature.iter() {
+            let mut objects = Vec::new();
+            heap.live_objects(&mut objects);
+            objects.sort_by_key(|obj| obj.ptr);
+
+            let captured = objects.iter()
+                .map(|obj| {
+                    let object = obj.as_trace();
+                    if object.traversible() {
+                        unsafe { object.trace(&mut stack) };
+                    }
+
+                    let mut children = Vec::with_capacity(stack.len());
+                    while let Some(child) = stack.pop() {
+                        children.push(child.ptr);
+                    }
+                    children.reverse();
+
+                    HeapObject {
+                        ptr: obj.ptr,
+                        vtable: obj.vtable,
+                        size: obj.size(),
+                        children: children,
+                    }
+                })
+                .collect();
+
+            heaps.push(captured);
+        }
+
+        HeapSnapshot { heaps: heaps }
+    }
+
+    /// Return the address, vtable and reference count of every rooted object, taking a reference
+    /// to each on behalf of the snapshot so that none are dropped while it is held. The references
+    /// are given back by `release_snapshot()` when the `RootSnapshot` is dropped.
+    pub fn snapshot_roots(&mut self) -> Vec<RootEntry> {
+        let mut entries = Vec::new();
+
+        for (ptr, meta) in self.roots.iter() {
+            let refcount = meta.refcount();
+
+            if refcount > 0 && !meta.is_refcount_negative() {
+                meta.inc();
+
+                entries.push(RootEntry {
+                    ptr: ptr << ptr_shift(),
+                    vtable: meta.vtable(),
+                    refcount: refcount,
+                });
+            }
+        }
+
+        entries
+    }
+
+    /// Walk the object graph from the roots without marking or sweeping anything, calling `visit`
+    /// once for each reachable object with its address and the addresses of the objects it
+    /// points at. This allows the object graph to be mirrored by an external tool.
+    pub fn visit_reachable<F>(&self, mut visit: F)
+        where F: FnMut(usize, &[usize])
+    {
+        let mut visited = HashSet::new();
+        let mut pending = Vec::new();
+        let mut stack = TraceStack::new();
+        let mut children = Vec::new();
+
+        for (ptr, meta) in self.roots.iter() {
+            if !meta.unsync_is_unrooted() {
+                pending.push(Object::from_trie_ptr(ptr, meta.vtable()));
+            }
+        }
+
+        while let Some(obj) = pending.pop() {
+            // the visited set stands in for the mark bit
+            if !visited.insert(obj.ptr) {
+                continue;
+            }
+
+            unsafe { obj.as_trace().trace(&mut stack) };
+
+            children.clear();
+            while let Some(child) = stack.pop() {
+                children.push(child.ptr);
+                pending.push(child);
+            }
+            children.reverse();
+
+            visit(obj.ptr, &children);
+        }
+    }
+
+    /// Return whether the object at `addr` is reachable from the roots, or `None` if the object
+    /// is not known to the GC: it has not yet been read from a journal or it has been dropped.
+    /// An object that is known but unreachable will be dropped by a coming collection. Like
+    /// `visit_reachable()` this walks the object graph, so it is slow and intended for debugging.
+    pub fn is_reachable(&self, addr: usize) -> Option<bool> {
+        let known = self.roots.get(addr >> ptr_shift()).is_some() ||
+                    self.mature.iter().any(|heap| heap.contains(addr));
+
+        if !known {
+            return None;
+        }
+
+        let mut reachable = false;
+        self.visit_reachable(|ptr, _| {
+            if ptr == addr {
+                reachable = true;
+            }
+        });
+
+        Some(reachable)
+    }
+
+    /// Return the groups of objects that point at each other in a cycle but that are not
+    /// reachable from any root, each group ordered by address. A reference counting scheme would
+    /// never reclaim these. The mark-sweep collections here do, so a cycle is only reported until
+    /// the next collection that traces it, but a cycle reported again and again is a sign of code
+    /// that keeps building garbage cycles. An object that points at itself is a cycle on its own.
+    /// Like `visit_reachable()` this walks the object graph, so it is slow and intended for
+    /// debugging.
+    pub fn find_cycles(&self) -> Vec<Vec<usize>> {
+        let mut reachable = HashSet::new();
+        self.visit_reachable(|ptr, _| {
+            reachable.insert(ptr);
+        });
+
+        let unreachable: Vec<Object> = self.live_objects()
+            .into_iter()
+            .filter(|obj| !reachable.contains(&obj.ptr))
+            .collect();
+        let known: HashSet<usize> = unreachable.iter().map(|obj| obj.ptr).collect();
+
+        // the object graph among unreachable objects only
+        let mut edges = HashMap::new();
+        let mut stack = TraceStack::new();
+
+        for obj in unreachable.iter() {
+            unsafe { obj.as_trace().trace(&mut stack) };
+
+            let mut children = Vec::new();
+            while let Some(child) = stack.pop() {
+                if known.contains(&child.ptr) {
+                    children.push(child.ptr);
+                }
+            }
+
+            edges.insert(obj.ptr, children);
+        }
+
+        let mut cycles: Vec<Vec<usize>> = strong_components(&edges)
+            .into_iter()
+            .filter(|component| component.len() > 1 || edges[&component[0]].contains(&component[0]))
+            .collect();
+
+        for cycle in cycles.iter_mut() {
+            cycle.sort();
+        }
+        cycles.sort();
+
+        cycles
+    }
+
+    /// Set or clear user flag `bit` of the root of the object at `addr`, numbered from zero up to
+    /// `USER_FLAG_BITS`, for extensions of the collector. The GC neither reads nor changes the
+    /// user flags, which stay with the root until the object is dropped or unrooted. Returns
+    /// false, setting nothing, if the object is not in the root set.
+    ///
+    /// Panics if `bit` is out of range.
+    pub fn set_user_flag(&self, addr: usize, bit: usize, value: bool) -> bool {
+        match self.roots.get(addr >> ptr_shift()) {
+            Some(meta) => {
+                meta.set_user_flag(bit, value);
+                true
+            }
+
+            None => false,
+        }
+    }
+
+    /// Return user flag `bit` of the root of the object at `addr`, or `None` if the object is not
+    /// in the root set, see `set_user_flag()`.
+    ///
+    /// Panics if `bit` is out of range.
+    pub fn user_flag(&self, addr: usize, bit: usize) -> Option<bool> {
+        self.roots.get(addr >> ptr_shift()).map(|meta| meta.get_user_flag(bit))
+    }
+
+    /// Return the reference count of the object at `addr`, or `None` if the object is not known
+    /// to the GC. Decrements read since the last collection are not merged until the next one,
+    /// so the count is as of the end of the last collection plus any increments read since. A
+    /// count that has been decremented below zero, which means a refcount imbalance, is reported
+    /// as zero. Intended for debugging.
+    pub fn refcount_of(&self, addr: usize) -> Option<usize> {
+        let key = addr >> ptr_shift();
+
+        if let Some(meta) = self.roots.get(key) {
+            if meta.is_refcount_negative() {
+                return Some(0);
+            }
+            return Some(meta.refcount());
+        }
+
+        // unrooted mature objects are not in the root set
+        if self.mature.iter().any(|heap| heap.contains(addr)) {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Start tracking an object that was not allocated through a journal, as a new object holding
+    /// one root reference. `vtable` is its `Trace` vtable. See `GcThread::register_foreign()`.
+    pub fn register_foreign(&mut self, ptr: usize, vtable: usize, traversible: bool) {
+        let vtable = if traversible {
+            vtable | TRAVERSE_BIT
+        } else {
+            vtable
+        };
+
+        self.roots.set(ptr >> ptr_shift(), RootMeta::one(vtable, NEW_BIT));
+        self.new_since_minor += 1;
+    }
+
+    /// Drop the root reference taken by `register_foreign()`. Like a journaled decrement it takes
+    /// effect in the next collection.
+    pub fn release_foreign(&mut self, ptr: usize) {
+        self.deferred.push(Object {
+            ptr: ptr,
+            vtable: 0,
+        });
+    }
+
+    /// Drop the references `snapshot_roots()` took on the objects of `entries`. Like journaled
+    /// decrements they take effect in the next collection.
+    pub fn release_snapshot(&mut self, entries: &[RootEntry]) {
+        for entry in entries.iter() {
+            self.deferred.push(Object {
+                ptr: entry.ptr,
+                vtable: 0,
+            });
+        }
+    }
+
+    /// Prune `table` during each major collection run on the GC thread from now on, see
+    /// `WeakTable`. A major collection run in the background keeps every mature object known to
+    /// the roots, so it does not prune the tables.
+    pub fn add_weak_table(&mut self, table: Box<WeakTable>) {
+        self.weak_tables.push(table);
+    }
+
+    // Take the references the weak tables hold to mature objects of `kind` that nothing else is
+    // counted as referencing out of the reference counts, so that the coming mark of the heap of
+    // `kind` does not start from them. Returns the entries weakened in each table, in table
+    // order, forgetting the tables whose owners have gone.
+    fn weaken_entries(&mut self, kind: usize) -> Vec<Vec<Object>> {
+        let tables = replace(&mut self.weak_tables, Vec::new());
+        let heaps = self.mature.len();
+        let mut weakened = Vec::new();
+
+        for table in tables {
+            let entries = match table.weak_entries() {
+                Some(entries) => entries,
+                None => continue,
+            };
+
+            let mut weak = Vec::new();
+            for obj in entries {
+                if let Some(meta) = self.roots.get(obj.ptr >> ptr_shift()) {
+                    if !meta.is_new() && meta.refcount() == 1 &&
+                       heap_index(meta.kind(), heaps) == kind {
+                        meta.dec();
+                        weak.push(obj);
+                    }
+                }
+            }
+
+            weakened.push(weak);
+            self.weak_tables.push(table);
+        }
+
+        weakened
+    }
+
+    // Give the references of the weakened entries that survived the collection back to the
+    // reference counts, and forget the released entries, which the sweep has dropped, along with
+    // any decrements still deferred for them.
+    fn settle_entries(&mut self, weakened: Vec<Vec<Object>>, released: Vec<usize>) {
+        let released: HashSet<usize> = released.into_iter().collect();
+
+        for obj in weakened.iter().flat_map(|entries| entries.iter()) {
+            if !released.contains(&obj.ptr) {
+                if let Some(meta) = self.roots.get(obj.ptr >> ptr_shift()) {
+                    meta.inc();
+                }
+            }
+        }
+
+        for ptr in released {
+            self.untracked.push(ptr >> ptr_shift());
+        }
+        self.untrack();
+    }
+
+    /// Return the count of `NEW` objects in each age bucket
+    pub fn age_bucket_counts(&self) -> Vec<usize> {
+        let oldest = self.config.nursery_ages - 1;
+        let mut counts = vec![0; self.config.nursery_ages];
+
+        for (_, meta) in self.roots.iter() {
+            if meta.is_new() {
+                counts[min(meta.age(), oldest)] += 1;
+            }
+        }
+
+        counts
+    }
+
+    /// Move any rooted new-objects in the oldest age bucket into the mature heap by copying and
+    /// unsetting the new-object flag in the roots. Returns the number of objects promoted.
+    fn promote(&mut self) -> usize {
+        // under memory pressure survivors are promoted however young they are
+        let oldest = if self.over_limit {
+            0
+        } else {
+            self.config.nursery_ages - 1
+        };
+        let mut promoted = 0;
+
+        for (ptr, meta) in self.roots.iter_mut() {
+            if !meta.unsync_is_unrooted() && meta.is_new() && !meta.is_ephemeral() &&
+               meta.age() >= oldest {
+                // object must have a positive reference count, be marked as new-object and have
+                // survived enough minor collections to be moved to the mature set. The traverse
+                // bit is kept in the vtable.
+                let age = meta.age();
+                let heap = heap_index(meta.kind(), self.mature.len());
+                self.mature[heap].add_object(ptr, meta.vtable);
+                // unset the new-object bit. This object will now be treated as a simple reference
+                // counted root and won't be dropped from here.
+                meta.set_not_new();
+
+                // the object may point at new objects that were not promoted, so it must be
+                // traced in the next minor collection
+                let obj = Object::from_trie_ptr(ptr, meta.vtable());
+                self.cards.dirty_range(obj.ptr, obj.size());
+
+                if let Some(on_promote) = self.config.on_promote {
+                    on_promote(obj.ptr, obj.vtable, age);
+                }
+
+                promoted += 1;
+            }
+        }
+
+        promoted
+    }
+
+    /// Forget the objects app threads have untracked, along with any decrements still deferred
+    /// for them, without dropping them. This happens as soon as the journals are read, so that no
+    /// sweep can drop an untracked object.
+    fn untrack(&mut self) {
+        if self.untracked.is_empty() {
+            return;
+        }
+
+        let untracked: HashSet<usize> = self.untracked.drain(..).collect();
+        self.deferred.retain(|entry| !untracked.contains(&(entry.ptr >> ptr_shift())));
+
+        // objects tenured while the mature heaps are away are not in them yet
+        self.pending_mature.retain(|&(_, ptr, _)| !untracked.contains(&ptr));
+
+        for &ptr in untracked.iter() {
+            self.roots.remove(ptr);
+
+            if self.major_running() {
+                self.pending_untracked.push(ptr);
+            }
+
+            for heap in self.mature.iter_mut() {
+                heap.untrack(ptr);
+            }
+        }
+    }
+
+    /// Move new objects that app threads have asked to be tenured into the mature heap regardless
+    /// of their age, as `promote()` does. Returns the number of objects moved.
+    fn tenure(&mut self) -> usize {
+        let mut tenured = 0;
+
+        for ptr in self.tenured.drain(..) {
+            if let Some(meta) = self.roots.get(ptr) {
+                if meta.is_new() {
+                    let heap = heap_index(meta.kind(), self.mature_live.len());
+                    if self.mature.is_empty() {
+                        self.pending_mature.push((heap, ptr, meta.vtable));
+                    } else {
+                        self.mature[heap].add_object(ptr, meta.vtable);
+                    }
+                    meta.set_not_new();
+
+                    // the object may point at new objects
+                    let obj = Object::from_trie_ptr(ptr, meta.vtable());
+                    self.cards.dirty_range(obj.ptr, obj.size());
+
+                    tenured += 1;
+                }
+            }
+        }
+
+        tenured
+    }
+
+    /// Use >0 refcount objects and 0-refcount non-new objects to mark new objects. Non-new
+    /// objects are only traced if they have a dirty card.
+    ///
+    /// Returns the number of non-new objects that were traced.
+    fn mark(&mut self, pool: &mut Pool) -> usize {
+        let collect_old_count = Arc::new(AtomicUsize::new(0));
+
+        let cards = &*self.cards;
+        let stacks = &self.stacks;
+        let learn = self.config.learn_leaves;
+        let shared_objects = self.roots.borrow_sync();
+        let sharded_objects = shared_objects.borrow_sharded(self.config.shard_count());
+
+        pool.scoped(|scope| {
+
+            for shard in sharded_objects.iter() {
+                let objects = shared_objects.clone();
+                let old_count = collect_old_count.clone();
+                // here there is a shard of the heap and a shared reference to the whole
+                // heap (objects) for each thread
+
+                scope.execute(move || {
+                    enter_gc_thread();
+
+                    let mut stack = stacks.take();
+                    let mut old_counter = 0;
+
+                    for (root_ptr, root_meta) in shard.iter() {
+                        if !root_meta.unsync_is_unrooted() || !root_meta.is_new() {
+                            // read the shard to find roots, which are non-zero-refcount
+                            // entries. Also consider non-new entries with dirty cards as
+                            // possible roots of new objects
+                            let obj = Object::from_trie_ptr(root_ptr, root_meta.vtable());
+                            let is_old = !root_meta.is_new();
+
+                            // whether the object was written to, read before an old object's
+                            // cards are cleaned so that its leaf hint is not trusted after a write
+                            let size = obj.size();
+                            let dirty = cards.is_dirty_range(obj.ptr, size);
+
+                            if is_old {
+                                if !dirty {
+                                    continue;
+                                }
+
+                                // clean before tracing so that a concurrent write barrier
+                                // re-dirties the card. The card still reads dirty in the next
+                                // mark, for a store that lands after this trace
+                                cards.clean_range(obj.ptr, size);
+                                old_counter += 1;
+                            }
+
+                            let mut found_new = false;
+
+                            let traced = root_meta.mark_and_needs_trace();
+                            if traced {
+                                // mark the root, and if it needs tracing then look into it
+                                trace_object(&obj, root_meta, &mut stack, dirty, learn);
+
+                                // now there may be some child objects on the trace stack: pull
+                                // them off and mark them too
+                                while let Some(obj) = stack.pop() {
+
+                                    let ptr = obj.ptr >> ptr_shift();
+                                    if let Some(meta) = objects.get(ptr) {
+
+                                        // a mature object only leads to new objects if it has
+                                        // been written to, in which case its dirty card makes
+                                        // it a root of its own above
+                                        if !meta.is_new() {
+                                            continue;
+                                        }
+
+                                        found_new = true;
+
+                                        if meta.mark_and_needs_trace() {
+                                            let dirty = learn &&
+                                                        cards.is_dirty_range(obj.ptr, obj.size());
+                                            trace_object(&obj, meta, &mut stack, dirty, learn);
+                                        }
+                                    }
+                                }
+                            }
+
+                            // an old object that still leads to new objects, or that another
+                            // thread is tracing, must stay dirty for the next collection
+                            if is_old && (found_new || (!traced && root_meta.is_traversible())) {
+                                cards.dirty(obj.ptr);
+                            }
+                        }
+                    }
+
+                    stacks.give(stack);
+                    old_count.fetch_add(old_counter, Ordering::SeqCst);
+                });
+            }
+        });
+
+        collect_old_count.load(Ordering::Acquire)
+    }
+
+    /// Drop unmarked new objects, remove unrooted objects and age surviving new objects.
+    /// Returns tuple (young_object_count, dropped_count)
+    fn sweep(&mut self, pool: &mut Pool) -> (usize, usize) {
+        // set counters
+        let collect_young_count= Arc::new(AtomicUsize::new(0));
+        let collect_drop_count = Arc::new(AtomicUsize::new(0));
+        let rooted_count = AtomicUsize::new(0);
+
+        let oldest = self.config.nursery_ages - 1;
+
+        let logger = &self.logger;
+        let drop_queue = self.drop_thread.as_ref().map(|t| t.queue());
+        let topological = self.config.topological_drop;
+        let condemned = Mutex::new(Vec::new());
+        let mut split_objects = self.roots.borrow_sharded(self.config.shard_count());
+
+        pool.scoped(|scope| {
+
+            for mut node in split_objects.drain() {
+
+                // pass a reference to each counter to each thread
+                let young_count = collect_young_count.clone();
+                let drop_count = collect_drop_count.clone();
+                let rooted_count = &rooted_count;
+                let drop_queue = drop_queue.clone();
+                let condemned = &condemned;
+
+                scope.execute(move || {
+                    enter_gc_thread();
+
+                    let mut young_counter = 0;
+                    let mut drop_counter = 0;
+                    let mut rooted_counter = 0;
+                    let mut drop_ages = vec![0; oldest + 1];
+                    let mut dropped = Vec::new();
+
+                    node.retain_if(|ptr, meta| {
+
+                        if meta.is_new_and_unmarked() && !meta.unsync_is_unrooted() {
+                            // rooted objects are always marked, keep it rather than drop it
+                            rooted_counter += 1;
+                            meta.unmark();
+                            true
+
+                        } else if meta.is_new_and_unmarked() {
+                            drop_counter += 1;
+                            drop_ages[min(meta.age(), oldest)] += 1;
+
+                            // unmarked new-object (implies zero-refcount)
+                            let obj = Object::from_trie_ptr(ptr, meta.vtable);
+                            if drop_queue.is_some() || topological {
+                                dropped.push(obj);
+                            } else {
+                                unsafe { obj.gc_drop() };
+                            }
+
+                            false
+
+                        } else if !meta.is_new() && meta.unsync_is_unrooted() {
+                            false
+
+                        } else {
+                            if meta.is_new() {
+                                young_counter += 1;
+                                meta.inc_age(oldest);
+                            }
+
+                            meta.unmark();
+                            true
+                        }
+                    });
+
+                    if topological {
+                        condemned.lock().expect("YoungHeap lock poisoned!").append(&mut dropped);
+                    } else if let Some(ref queue) = drop_queue {
+                        queue.send(dropped);
+                    }
+
+                    // write out the counters
+                    young_count.fetch_add(young_counter, Ordering::SeqCst);
+                    drop_count.fetch_add(drop_counter, Ordering::SeqCst);
+                    rooted_count.fetch_add(rooted_counter, Ordering::SeqCst);
+
+                    if drop_counter > 0 {
+                        logger.add_drop_ages(&drop_ages);
+                    }
+                });
+            }
+        });
+
+        if topological {
+            let condemned = condemned.into_inner().expect("YoungHeap lock poisoned!");
+            drop_topologically(condemned, drop_queue.as_ref());
+        }
+
+        let rooted = rooted_count.load(Ordering::Acquire);
+        if rooted > 0 {
+            invariant_violation(self.config.on_violation,
+                                logger,
+                                &format!("{} rooted new objects were left unmarked", rooted));
+        }
+
+        // return the counters
+        (collect_young_count.load(Ordering::Acquire),
+         collect_drop_count.load(Ordering::Acquire))
+    }
+
+    /// Move the deferred refcount decrements into the root set's reference counts. Returns true
+    /// if the merge was done on the calling thread rather than in the thread pool.
+    fn merge_deferred(&mut self, pool: &mut Pool) -> bool {
+        if self.config.single_threaded || self.deferred.len() < self.config.merge_threshold {
+            let missing = merge_serial(&mut self.deferred,
+                                       &mut self.roots,
+                                       self.config.single_threaded);
+            self.report_missing(missing);
+            return true;
+        }
+
+        let chunk_size = max(1, self.deferred.len() / self.config.shard_count());
+        let missing = AtomicUsize::new(0);
+
+        {
+            let shared_roots = self.roots.borrow_sync();
+            let chunks = self.deferred.chunks(chunk_size);
+
+            pool.scoped(|scope| {
+
+                for chunk in chunks {
+
+                    let roots = shared_roots.clone();
+                    let missing = &missing;
+
+                    scope.execute(move || {
+                        enter_gc_thread();
+
+                        for object in chunk {
+                            let ptr = object.ptr >> ptr_shift();
+
+                            if let Some(ref mut meta) = roots.get(ptr) {
+                                // this is the only place where the reference count needs to
+                                // be thread-safely adjusted
+                                meta.dec();
+                            } else {
+                                // there should never be something in the deferred buffer that
+                                // isn't in the heap
+                                missing.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    });
+                }
+            });
+        }
+
+        self.deferred.clear();
+        self.report_missing(missing.load(Ordering::Relaxed));
+        false
+    }
+
+    /// Report deferred decrements that were found for objects not in the roots.
+    fn report_missing(&self, missing: usize) {
+        if missing > 0 {
+            invariant_violation(self.config.on_violation,
+                                &self.logger,
+                                &format!("{} decrements were for objects not in the roots",
+                                         missing));
+        }
+    }
+
+    /// Return a reference to the logger
+    pub fn logger(&mut self) -> &mut S {
+        &mut self.logger
+    }
+
+    /// Call to return the logger on shutdown. Allocation is no longer throttled, as nothing
+    /// would put tokens back.
+    pub fn shutdown(mut self) -> S {
+        self.finish_major();
+        self.accounting.set_token_capacity(0);
+        self.logger
+    }
+}
+
+
+impl<S: StatsLogger, T: CollectOps + Send + 'static> YoungHeap<S, T> {
+    /// Start a major collection of every mature heap in the background, see `start_major_of()`.
+    pub fn start_major(&mut self) -> bool {
+        let kinds: Vec<usize> = (0..self.mature_live.len()).collect();
+        self.start_major_of(&kinds)
+    }
+
+    /// As `major_collection_of()`, but promote `NEW` objects and hand the mature heaps of the
+    /// given kinds to the major thread to collect, returning straight away so that the journals
+    /// can be read and minor collections run meanwhile. Take the heaps back with `poll_major()`
+    /// or `finish_major()`. Returns false, doing nothing, if a major collection is already
+    /// running.
+    ///
+    /// The major collection traces from a snapshot of the roots taken now: every mature object
+    /// known to the roots and every mature object a `NEW` object points at. Objects rooted or
+    /// written to later are not looked at, in the same way that a major collection does not look
+    /// at journal entries written while it marks. Objects tenured or untracked meanwhile are
+    /// only added to or removed from the mature heaps once they are taken back.
+    pub fn start_major_of(&mut self, kinds: &[usize]) -> bool {
+        if self.major_running() {
+            return false;
+        }
+
+        let start = Instant::now();
+
+        let promoted = self.promote();
+        self.young_live -= min(promoted, self.young_live);
+
+        let roots = self.major_snapshot();
+        let heaps = replace(&mut self.mature, Vec::new());
+        let kinds = kinds.iter().cloned().filter(|&kind| kind < heaps.len()).collect();
+
+        if self.major_thread.is_none() {
+            self.major_thread = Some(MajorThread::spawn(self.config.shard_count()));
+        }
+
+        self.major_thread.as_ref().unwrap().start(MajorJob {
+            heaps: heaps,
+            kinds: kinds,
+            roots: roots,
+            counts: Vec::new(),
+            start: start,
+            promoted: promoted,
+        });
+
+        true
+    }
+
+    // Build the roots of a background major collection. Minor collections carry on while it
+    // runs, so it must not trace young objects, which a minor collection may drop, and must not
+    // drop mature objects a minor collection may trace. Every non-`NEW` object known to the
+    // roots is made a root, rooted or not, along with every object a `NEW` object points at in
+    // place of the `NEW` object itself. None are marked traversible, so that objects that are
+    // not in a mature heap, such as new objects not yet read from the journals, are not traced.
+    fn major_snapshot(&self) -> RootMap {
+        let mut snapshot = RootMap::new();
+        let mut stack = TraceStack::new();
+
+        for (ptr, meta) in self.roots.iter() {
+            if !meta.is_new() {
+                snapshot.set(ptr, RootMeta::one(meta.vtable(), 0));
+            } else if meta.is_traversible() {
+                let obj = Object::from_trie_ptr(ptr, meta.vtable());
+                unsafe { obj.as_trace().trace(&mut stack) };
+            }
+        }
+
+        while let Some(child) = stack.pop() {
+            let ptr = child.ptr >> ptr_shift();
+
+            let young = match self.roots.get(ptr) {
+                Some(meta) => meta.is_new(),
+                None => false,
+            };
+
+            if !young && snapshot.get(ptr).is_none() {
+                snapshot.set(ptr, RootMeta::one(child.vtable, 0));
+            }
+        }
+
+        snapshot
+    }
+}
+
+
+/// Act on a broken internal invariant as the `policy` says.
+fn invariant_violation<S: StatsLogger>(policy: InvariantViolation, logger: &S, message: &str) {
+    match policy {
+        InvariantViolation::Abort => {
+            let _ = writeln!(io::stderr(), "GC invariant violated: {}", message);
+            unsafe { abort() };
+        }
+
+        InvariantViolation::Panic => panic!("GC invariant violated: {}", message),
+
+        InvariantViolation::LogAndContinue => logger.invariant_violation(message),
+    }
+}
+
+
+/// Move the deferred refcount decrements into the root set's reference counts on the calling
+/// thread, for when there are too few to be worth sharding or the heap is single threaded.
+/// Returns the number of decrements for objects that are not in the roots, which there should
+/// never be.
+fn merge_serial(deferred: &mut ObjectBuf, roots: &mut RootMap, single_threaded: bool) -> usize {
+    let mut missing = 0;
+
+    for object in deferred.iter() {
+        let ptr = object.ptr >> ptr_shift();
+
+        if let Some(meta) = roots.get(ptr) {
+            if single_threaded {
+                meta.unsync_dec();
+            } else {
+                meta.dec();
+            }
+        } else {
+            missing += 1;
+        }
+    }
+
+    deferred.clear();
+    missing
+}
+
+
+/// Between the mark and the sweep of a mature heap, release the entries weakened in each weak
+/// table that the mark did not reach, adding their addresses to `released`. Returns the addresses
+/// of those a table has handed out since they were weakened, which must survive the sweep.
+fn release_unmarked(tables: &[Box<WeakTable>],
+                    weakened: &[Vec<Object>],
+                    is_marked: &Fn(usize) -> bool,
+                    released: &mut Vec<usize>)
+                    -> Vec<usize> {
+    let mut rescued = Vec::new();
+
+    for (table, entries) in tables.iter().zip(weakened.iter()) {
+        let unmarked: HashSet<usize> = entries.iter()
+            .map(|obj| obj.ptr)
+            .filter(|&ptr| !is_marked(ptr))
+            .collect();
+
+        if unmarked.is_empty() {
+            continue;
+        }
+
+        let kept: HashSet<usize> = table.release(&unmarked).into_iter().collect();
+        released.extend(unmarked.iter().filter(|ptr| !kept.contains(ptr)));
+        rescued.extend(kept);
+    }
+
+    rescued
+}
+
+
+/// Read the entry holding the count or kind that follows a batch increment or kind entry. The
+/// app thread writes the two entries one after the other so the operand is never far behind, but
+/// it may be descheduled in between, so yield rather than spin.
+#[inline]
+fn read_operand(journal: &EntryReceiver) -> Object {
+    loop {
+        if let Ok(entry) = journal.try_recv() {
+            return entry;
+        }
+
+        thread::yield_now();
+    }
+}
+
+
+/// Return the index of the mature heap for objects of `kind`, falling back to the first heap.
+#[inline]
+fn heap_index(kind: usize, heaps: usize) -> usize {
+    if kind < heaps { kind } else { 0 }
+}
+
+
+/// Root every object in the mature heaps other than the one of `kind`, so that objects reachable
+/// only through other heaps survive collection of the heap of `kind`. Objects not yet in the
+/// roots are added and conservatively assumed to be traversible, and unrooted objects already in
+/// the roots have their reference counts raised. Returns the trie keys of the roots added and of
+/// those raised, for `remove_external_roots()` to look up by address.
+pub fn add_external_roots<T: CollectOps>(roots: &mut RootMap,
+                                         heaps: &[T],
+                                         kind: usize)
+                                         -> (Vec<usize>, Vec<usize>) {
+    let mut added = Vec::new();
+    let mut raised = Vec::new();
+
+    if heaps.len() == 1 {
+        return (added, raised);
+    }
+
+    let mut objects = Vec::new();
+    for (other, heap) in heaps.iter().enumerate() {
+        if other != kind {
+            heap.live_objects(&mut objects);
+        }
+    }
+
+    for obj in objects {
+        let ptr = obj.ptr >> ptr_shift();
+
+        if let Some(meta) = roots.get(ptr) {
+            if meta.unsync_is_unrooted() {
+                meta.unsync_inc();
+                raised.push(ptr);
+            }
+            continue;
+        }
+
+        roots.set(ptr, RootMeta::one(obj.vtable | TRAVERSE_BIT, 0));
+        added.push(ptr);
+    }
+
+    (added, raised)
+}
+
+
+/// Undo `add_external_roots()`, visiting only the roots it added or raised rather than the whole
+/// root trie.
+pub fn remove_external_roots(roots: &mut RootMap, (added, raised): (Vec<usize>, Vec<usize>)) {
+    for ptr in raised {
+        if let Some(meta) = roots.get(ptr) {
+            meta.unsync_dec();
+        }
+    }
+
+    for ptr in added {
+        roots.remove(ptr);
+    }
+}
+
+
+/// Write `entry` to the recording, if there is one.
+#[inline]
+fn record(recorder: &mut Option<Recorder<File>>, entry: &Object) {
+    if let Some(ref mut recorder) = *recorder {
+        recorder.record(entry);
+    }
+}
+
+
+/// Return the strongly connected components of the graph given by `edges`, which must have an
+/// entry for every node, using Tarjan's algorithm without recursion
+fn strong_components(edges: &HashMap<usize, Vec<usize>>) -> Vec<Vec<usize>> {
+    let mut index = HashMap::new();
+    let mut lowlink = HashMap::new();
+    let mut on_stack = HashSet::new();
+    let mut stack = Vec::new();
+    let mut components = Vec::new();
+    let mut next_index = 0;
+
+    let mut nodes: Vec<usize> = edges.keys().cloned().collect();
+    nodes.sort();
+
+    for &start in nodes.iter() {
+        if index.contains_key(&start) {
+            continue;
+        }
+
+        // each node being visited with the position of the next edge to follow from it
+        let mut work = vec![(start, 0)];
+
+        while !work.is_empty() {
+            let top = work.len() - 1;
+            let (node, edge) = work[top];
+
+            if edge == 0 {
+                index.insert(node, next_index);
+                lowlink.insert(node, next_index);
+                next_index += 1;
+
+                stack.push(node);
+                on_stack.insert(node);
+            }
+
+            let children = &edges[&node];
+            if edge < children.len() {
+                work[top].1 += 1;
+
+                let child = children[edge];
+                if !index.contains_key(&child) {
+                    work.push((child, 0));
+                } else if on_stack.contains(&child) {
+                    let low = min(lowlink[&node], index[&child]);
+                    lowlink.insert(node, low);
+                }
+                continue;
+            }
+
+            // every edge has been followed
+            work.pop();
+
+            if let Some(&(parent, _)) = work.last() {
+                let low = min(lowlink[&parent], lowlink[&node]);
+                lowlink.insert(parent, low);
+            }
+
+            if lowlink[&node] == index[&node] {
+                let mut component = Vec::new();
+                loop {
+                    let member = stack.pop().expect("Tarjan stack underflow!");
+                    on_stack.remove(&member);
+                    component.push(member);
+
+                    if member == node {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+
+    components
+}
+
+
+/// Trace `obj` into `stack`. If `learn` is set, an object whose trace pushed nothing while its
+/// cards were clean is hinted as a leaf and is not traced again until its cards are dirtied.
+/// `dirty` must be read from the cards before the mark cleans them.
+#[inline]
+fn trace_object(obj: &Object, meta: &RootMeta, stack: &mut TraceStack, dirty: bool, learn: bool) {
+    if !learn {
+        unsafe { obj.as_trace().trace(stack) };
+        return;
+    }
+
+    // the hint is only trusted, and only learned, while nothing has written to the object
+    let clean = !dirty;
+    if clean && meta.is_leaf() {
+        return;
+    }
+
+    if meta.is_leaf() {
+        meta.clear_leaf();
+    }
+
+    let depth = stack.len();
+    unsafe { obj.as_trace().trace(stack) };
+
+    if clean && stack.len() == depth {
+        meta.set_leaf();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::mem::transmute;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::ptr::null_mut;
+    use std::raw::TraitObject;
+    use std::sync::{Arc, Barrier, Mutex, mpsc};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use scoped_pool::Pool;
+
+    use cardtable::CardTable;
+    use config::{GcConfig, InvariantViolation};
+    use constants::{BATCH_BIT, BUFFER_RUN, DEC, DRAIN_CHECK_RUN, EPHEMERAL_KIND, FLAGS_MASK, INC,
+                    JOURNAL_BUFFER_SIZE, KIND_BIT, NEW, NEW_INC, PTR_MASK, TENURE_BIT,
+                    TRAVERSE_BIT, UNTRACK_BIT, USER_FLAG_BITS};
+    use gcthread::ptr_shift;
+    use heap::{CollectOps, HeapError, Object, RootMap, TraceOps, TraceStack};
+    use journal::make_journal;
+    use parheap::ParHeap;
+    use statistics::{Accounting, DefaultLogger};
+    use trace::Trace;
+
+    use super::{YoungHeap, read_operand};
+
+
+    const TEST_BUFFER_SIZE: usize = 32;
+
+
+    thread_local!(static EPOCH: Instant = Instant::now());
+    thread_local!(static TICKS: Cell<u64> = Cell::new(0));
+
+
+    // A clock that moves on a millisecond each time it is read, however long reading took
+    fn ticking_clock() -> Instant {
+        let ticks = TICKS.with(|ticks| {
+            ticks.set(ticks.get() + 1);
+            ticks.get()
+        });
+
+        EPOCH.with(|epoch| *epoch + Duration::from_millis(ticks))
+    }
+
+
+    /// An object that spans many cards
+    struct Big {
+        _data: [u8; 8192],
+    }
+
+
+    unsafe impl Trace for Big {}
+
+
+    /// A traversible object that never has any children, counting each time it is traced
+    struct Empty {
+        traces: Arc<AtomicUsize>,
+    }
+
+
+    unsafe impl Trace for Empty {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, _stack: &mut TraceStack) {
+            self.traces.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+
+    /// A ring node that adversarially traces itself as well as the next node
+    struct Node {
+        next: Cell<usize>,
+        drops: Arc<AtomicUsize>,
+    }
+
+
+    unsafe impl Trace for Node {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            stack.push_to_trace(self);
+
+            let next = self.next.get();
+            if next != 0 {
+                stack.push_to_trace(&*(next as *const Node));
+            }
+        }
+    }
+
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+
+    /// Logs its id when dropped
+    struct Logged {
+        id: Cell<usize>,
+        log: Arc<Mutex<Vec<usize>>>,
+    }
+
+
+    unsafe impl Trace for Logged {}
+
+
+    impl Drop for Logged {
+        fn drop(&mut self) {
+            self.log.lock().unwrap().push(self.id.get());
+        }
+    }
+
+
+    /// Logs its id when dropped, and points at another of its kind
+    struct Chained {
+        id: usize,
+        child: Cell<usize>,
+        log: Arc<Mutex<Vec<usize>>>,
+    }
+
+
+    unsafe impl Trace for Chained {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            let child = self.child.get();
+            if child != 0 {
+                stack.push_to_trace(&*(child as *const Chained));
+            }
+        }
+    }
+
+
+    impl Drop for Chained {
+        fn drop(&mut self) {
+            self.log.lock().unwrap().push(self.id);
+        }
+    }
+
+
+    /// Holds another object by its raw data and vtable pointers
+    struct RawEdge {
+        ptr: usize,
+        vtable: usize,
+    }
+
+
+    unsafe impl Trace for RawEdge {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            stack.push_raw(self.ptr, self.vtable);
+        }
+    }
+
+
+    /// A graph vertex with edges to other vertices
+    struct Vertex {
+        edges: RefCell<Vec<usize>>,
+    }
+
+
+    unsafe impl Trace for Vertex {
+        fn traversible(&self) -> bool {
+            true
+        }
+
+        unsafe fn trace(&self, stack: &mut TraceStack) {
+            for edge in self.edges.borrow().iter() {
+                stack.push_to_trace(&*(*edge as *const Vertex));
+            }
+        }
+    }
+
+
+    /// A mature heap whose collections wait to be let through, to hold a major collection open
+    struct GatedHeap {
+        heap: ParHeap,
+        gate: mpsc::Receiver<()>,
+    }
+
+
+    impl CollectOps for GatedHeap {
+        fn add_object(&mut self, ptr: usize, vtable: usize) {
+            self.heap.add_object(ptr, vtable);
+        }
+
+        fn collect(&mut self, pool: &mut Pool, roots: &mut RootMap) -> (usize, usize) {
+            self.gate.recv().unwrap();
+            self.heap.collect(pool, roots)
+        }
+
+        fn live_objects(&self, objects: &mut Vec<Object>) {
+            self.heap.live_objects(objects);
+        }
+    }
+
+
+    fn test_heap() -> YoungHeap<DefaultLogger, ParHeap> {
+        test_heap_with(1, 1)
+    }
+
+    fn test_heap_with(num_threads: usize,
+                      nursery_ages: usize)
+                      -> YoungHeap<DefaultLogger, ParHeap> {
+        let mut config = GcConfig::new();
+        config.num_threads = num_threads;
+        config.nursery_ages = nursery_ages;
+
+        test_heap_from(config, vec![ParHeap::new(num_threads)])
+    }
+
+    fn test_heap_from(config: GcConfig, mature: Vec<ParHeap>) -> YoungHeap<DefaultLogger, ParHeap> {
+        YoungHeap::with_mature_heaps(config,
+                                     mature,
+                                     DefaultLogger::new(),
+                                     Arc::new(Accounting::new()),
+                                     Arc::new(CardTable::new()))
+    }
+
+    /// Return a journal entry for an object already on the heap, as the app thread would write it
+    fn entry<T: Trace>(object: *mut T, flags: usize) -> Object {
+        let trace: &Trace = unsafe { &*object };
+        let tobj: TraitObject = unsafe { transmute(trace) };
+
+        let mut vtable = tobj.vtable as usize;
+        if flags & NEW != 0 && trace.traversible() {
+            vtable |= TRAVERSE_BIT;
+        }
+
+        Object {
+            ptr: tobj.data as usize | flags,
+            vtable: vtable,
+        }
+    }
+
+    /// Move `value` to the heap and return a journal entry for it as a new, rooted object
+    fn new_rooted<T: Trace>(value: T) -> Object {
+        entry(Box::into_raw(Box::new(value)), NEW_INC)
+    }
+
+    /// Allocate a ring of nodes, each pointing at the next
+    fn ring(len: usize, drops: &Arc<AtomicUsize>) -> Vec<*mut Node> {
+        let nodes: Vec<_> = (0..len)
+            .map(|_| {
+                Box::into_raw(Box::new(Node {
+                    next: Cell::new(0),
+                    drops: drops.clone(),
+                }))
+            })
+            .collect();
+
+        for i in 0..len {
+            unsafe { &*nodes[i] }.next.set(nodes[(i + 1) % len] as usize);
+        }
+
+        nodes
+    }
+
+    /// Return a journal entry that unroots the object given by `entry`
+    fn unroot(entry: &Object) -> Object {
+        Object {
+            ptr: entry.ptr & PTR_MASK,
+            vtable: entry.vtable,
+        }
+    }
+
+
+    /// A journal entry for a new, unrooted object at a fake address. These are never
+    /// dereferenced unless a collection is run.
+    fn new_entry(index: usize) -> Object {
+        Object {
+            ptr: ((index + 1) << ptr_shift()) | NEW,
+            vtable: 0,
+        }
+    }
+
+    #[test]
+    fn test_lost_entry_detected() {
+        let mut heap = test_heap();
+        let accounting = heap.accounting.clone();
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        for i in 0..100 {
+            accounting.add_allocated();
+
+            // simulate an entry being dropped under backpressure
+            if i != 42 {
+                tx.send(new_entry(i));
+            }
+        }
+
+        drop(tx);
+        heap.read_journals();
+
+        assert_eq!(accounting.allocated(), 100);
+        assert_eq!(accounting.tracked(), 99);
+    }
+
+    #[test]
+    fn test_only_dirty_old_objects_traced() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let mut addrs = Vec::new();
+        for _ in 0..10 {
+            let entry = new_rooted(Big { _data: [0; 8192] });
+            addrs.push(entry.ptr & PTR_MASK);
+            tx.send(entry);
+        }
+
+        heap.read_journals();
+
+        // promotion dirties the cards of every promoted object
+        heap.promote();
+        assert_eq!(heap.mark(&mut pool), 10);
+        heap.sweep(&mut pool);
+
+        // the cleaned cards are traced once more, for writes that landed after the first trace
+        assert_eq!(heap.mark(&mut pool), 10);
+        heap.sweep(&mut pool);
+
+        // nothing written since the last mark
+        assert_eq!(heap.mark(&mut pool), 0);
+        heap.sweep(&mut pool);
+
+        // write to the middle of a few objects so that no card is shared with a neighbour
+        for i in &[1, 4, 7] {
+            heap.cards.dirty(addrs[*i] + 4096);
+        }
+
+        assert_eq!(heap.mark(&mut pool), 3);
+        heap.sweep(&mut pool);
+    }
+
+    #[test]
+    fn test_age_buckets() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap_with(1, 3);
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let short = new_rooted(1usize);
+        tx.send(short);
+        tx.send(new_rooted(2usize));
+
+        heap.read_journals();
+        assert_eq!(heap.age_bucket_counts(), vec![2, 0, 0]);
+
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.age_bucket_counts(), vec![0, 2, 0]);
+
+        // the decrement is merged after this collection's sweep
+        tx.send(unroot(&short));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.age_bucket_counts(), vec![0, 0, 2]);
+
+        // the short-lived object is now dropped, the long-lived object stays in the last bucket
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.age_bucket_counts(), vec![0, 0, 1]);
+
+        tx.send(new_rooted(3usize));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.age_bucket_counts(), vec![0, 1, 1]);
+
+        // only the object in the last bucket is promoted
+        heap.promote();
+        assert_eq!(heap.age_bucket_counts(), vec![0, 1, 0]);
+
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.age_bucket_counts(), vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn test_self_referential_rings() {
+        const RING: usize = 1000;
+        const THREADS: usize = 8;
+
+        let mut pool = Pool::new(THREADS);
+        let mut heap = test_heap_with(THREADS, 1);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        // a ring held by one root, and an unrooted garbage ring
+        let live = ring(RING, &drops);
+        let garbage = ring(RING, &drops);
+
+        let root = entry(live[0], NEW_INC);
+        tx.send(root);
+        for node in live.iter().skip(1).chain(garbage.iter()) {
+            tx.send(entry(*node, NEW));
+        }
+
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), RING);
+        assert_eq!(heap.live_count(), RING);
+
+        // promote the root: the rest of its ring stays young and is kept alive through the root
+        heap.major_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), RING);
+
+        // unroot: the decrement is merged after this collection's sweep
+        tx.send(unroot(&root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        // the now-unrooted mature object's card is still dirty, keeping its ring alive once more
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), RING);
+
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), RING * 2 - 1);
+
+        heap.major_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), RING * 2);
+        assert_eq!(heap.live_count(), 0);
+    }
+
+    #[test]
+    fn test_collection_reports() {
+        let mut pool = Pool::new(2);
+        let mut heap = test_heap_with(2, 1);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        // five rooted single-node rings and a seven node garbage ring
+        for node in (0..5).map(|_| ring(1, &drops)[0]) {
+            tx.send(entry(node, NEW_INC));
+        }
+        for node in ring(7, &drops) {
+            tx.send(entry(node, NEW));
+        }
+
+        heap.read_journals();
+
+        let minor = heap.minor_collection(&mut pool);
+        assert!(!minor.major);
+        assert_eq!(minor.young_live, 5);
+        assert_eq!(minor.mature_live, 0);
+        assert_eq!(minor.dropped, 7);
+        assert_eq!(minor.dropped, drops.load(Ordering::SeqCst));
+        assert_eq!(minor.promoted, 0);
+
+        let major = heap.major_collection(&mut pool);
+        assert!(major.major);
+        assert_eq!(major.young_live, 0);
+        assert_eq!(major.mature_live, 5);
+        assert_eq!(major.dropped, 0);
+        assert_eq!(major.promoted, 5);
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let root = entry(ring(3, &drops)[0], NEW_INC);
+        tx.send(root);
+
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.verify(), Ok(()));
+
+        heap.major_collection(&mut pool);
+        assert_eq!(heap.verify(), Ok(()));
+
+        // inject a refcount imbalance by unrooting twice
+        tx.send(unroot(&root));
+        tx.send(unroot(&root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        let addr = root.ptr & PTR_MASK;
+        assert_eq!(heap.verify(), Err(vec![HeapError::NegativeRefcount(addr)]));
+    }
+
+    #[test]
+    fn test_leaf_hint() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+        heap.config.learn_leaves = true;
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let traces = Arc::new(AtomicUsize::new(0));
+        let root = new_rooted(Empty { traces: traces.clone() });
+        tx.send(root);
+        heap.read_journals();
+
+        // the first trace pushes nothing, so the object is learned to be a leaf
+        heap.minor_collection(&mut pool);
+        assert_eq!(traces.load(Ordering::SeqCst), 1);
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(traces.load(Ordering::SeqCst), 1);
+
+        // a write invalidates the hint
+        heap.cards.dirty(root.ptr & PTR_MASK);
+        heap.minor_collection(&mut pool);
+        assert_eq!(traces.load(Ordering::SeqCst), 2);
+
+        tx.send(unroot(&root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.live_count(), 0);
+    }
+
+    /// Collect a heap of rooted and garbage objects in deterministic mode, returning the order in
+    /// which they were dropped. Objects are numbered in address order so that runs compare.
+    fn deterministic_drops(count: usize) -> Vec<usize> {
+        const THREADS: usize = 8;
+
+        let mut pool = Pool::new(THREADS);
+        let mut heap = test_heap_with(THREADS, 1);
+        heap.config.deterministic = true;
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let mut objects: Vec<_> = (0..count)
+            .map(|_| {
+                Box::into_raw(Box::new(Logged {
+                    id: Cell::new(0),
+                    log: log.clone(),
+                }))
+            })
+            .collect();
+
+        objects.sort();
+        for (id, object) in objects.iter().enumerate() {
+            unsafe { &**object }.id.set(id);
+        }
+
+        // root every third object
+        let entries: Vec<_> = objects.iter()
+            .enumerate()
+            .map(|(id, object)| entry(*object, if id % 3 == 0 { NEW_INC } else { NEW }))
+            .collect();
+
+        for entry in entries.iter() {
+            tx.send(*entry);
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        for entry in entries.iter().filter(|entry| entry.ptr & FLAGS_MASK == NEW_INC) {
+            tx.send(unroot(entry));
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(heap.live_count(), 0);
+
+        let drops = log.lock().unwrap().clone();
+        drops
+    }
+
+    #[test]
+    fn test_deterministic() {
+        const COUNT: usize = 1000;
+
+        let first = deterministic_drops(COUNT);
+        let second = deterministic_drops(COUNT);
+
+        assert_eq!(first.len(), COUNT);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_visit_reachable() {
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let vertices: Vec<_> = (0..5)
+            .map(|_| Box::into_raw(Box::new(Vertex { edges: RefCell::new(Vec::new()) })))
+            .collect();
+        let addr = |index: usize| vertices[index] as usize;
+
+        // 0 -> 1, 0 -> 2, 1 -> 2, 2 -> 0 with 0 rooted, 3 is garbage, 4 is rooted alone
+        let edges = vec![(0, 1), (0, 2), (1, 2), (2, 0), (3, 0)];
+        for &(from, to) in edges.iter() {
+            unsafe { &*vertices[from] }.edges.borrow_mut().push(addr(to));
+        }
+
+        for (index, vertex) in vertices.iter().enumerate() {
+            tx.send(entry(*vertex, if index == 0 || index == 4 { NEW_INC } else { NEW }));
+        }
+        heap.read_journals();
+
+        let mut graph = HashMap::new();
+        heap.visit_reachable(|object, children| {
+            assert!(graph.insert(object, children.to_vec()).is_none());
+        });
+
+        let mut expected = HashMap::new();
+        expected.insert(addr(0), vec![addr(1), addr(2)]);
+        expected.insert(addr(1), vec![addr(2)]);
+        expected.insert(addr(2), vec![addr(0)]);
+        expected.insert(addr(4), vec![]);
+
+        assert_eq!(graph, expected);
+
+        // nothing was marked or collected
+        assert_eq!(heap.verify(), Ok(()));
+        assert_eq!(heap.roots.iter().count(), 5);
+    }
+
+    #[test]
+    fn test_single_threaded() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+        heap.config.single_threaded = true;
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(100, &drops);
+
+        let root = entry(nodes[0], NEW_INC);
+        tx.send(root);
+        for node in nodes.iter().skip(1) {
+            tx.send(entry(*node, NEW));
+        }
+
+        // a second root, then both are dropped
+        tx.send(entry(nodes[0], INC));
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        tx.send(unroot(&root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        tx.send(unroot(&root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_single_threaded_second_journal() {
+        let mut heap = test_heap();
+        heap.config.single_threaded = true;
+
+        let (_first_tx, first_rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        let (_second_tx, second_rx) = make_journal(JOURNAL_BUFFER_SIZE);
+
+        heap.add_journal(first_rx);
+        heap.add_journal(second_rx);
+    }
+
+    #[test]
+    fn test_single_threaded_replaced_journal() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+        heap.config.single_threaded = true;
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(1, &drops);
+
+        // the first app thread exits before its journal is read
+        let (first_tx, first_rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(first_rx);
+        first_tx.send(entry(nodes[0], NEW));
+        drop(first_tx);
+
+        let (_second_tx, second_rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(second_rx);
+
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_tenure() {
+        const AGES: usize = 4;
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap_with(1, AGES);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let traces = Arc::new(AtomicUsize::new(0));
+        let object = Box::into_raw(Box::new(Empty { traces: traces.clone() }));
+
+        let root = entry(object, NEW_INC);
+        let mut tenure = entry(object, DEC);
+        tenure.vtable |= TENURE_BIT;
+
+        tx.send(root);
+        tx.send(tenure);
+        heap.read_journals();
+
+        // moved straight to the mature heap and traced once because its card was dirtied
+        let report = heap.minor_collection(&mut pool);
+        assert_eq!(report.promoted, 1);
+        assert_eq!(report.young_live, 0);
+        assert_eq!(heap.age_bucket_counts(), vec![0; AGES]);
+        assert_eq!(traces.load(Ordering::SeqCst), 1);
+
+        // and once more as its cleaned card still reads dirty, then minor collections no longer
+        // trace it
+        heap.minor_collection(&mut pool);
+        assert_eq!(traces.load(Ordering::SeqCst), 2);
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(traces.load(Ordering::SeqCst), 2);
+
+        // once unrooted only a major collection drops it
+        tx.send(unroot(&root));
+        heap.read_journals();
+        assert_eq!(heap.minor_collection(&mut pool).dropped, 0);
+        assert_eq!(heap.minor_collection(&mut pool).dropped, 0);
+        assert_eq!(heap.major_collection(&mut pool).dropped, 1);
+    }
+
+    #[test]
+    fn test_push_raw() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let child = ring(1, &drops)[0];
+        let garbage = ring(1, &drops)[0];
+
+        let trace: &Trace = unsafe { &*child };
+        let tobj: TraitObject = unsafe { transmute(trace) };
+
+        let holder = new_rooted(RawEdge {
+            ptr: tobj.data as usize,
+            vtable: tobj.vtable as usize,
+        });
+
+        tx.send(holder);
+        tx.send(entry(child, NEW));
+        tx.send(entry(garbage, NEW));
+        heap.read_journals();
+
+        // the child is only reachable through its raw pointers
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert_eq!(heap.live_count(), 2);
+
+        tx.send(unroot(&holder));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        assert_eq!(heap.live_count(), 0);
+    }
+
+    #[test]
+    fn test_deferred_limit() {
+        const LIMIT: usize = 64;
+        const CHURN: usize = 500;
+        const ROUNDS: usize = 10;
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+        heap.config.deferred_limit = LIMIT;
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(1, &drops);
+
+        let root = entry(nodes[0], NEW_INC);
+        tx.send(root);
+
+        // many more roots are created and dropped between collections than the limit allows
+        for _ in 0..ROUNDS {
+            for _ in 0..CHURN {
+                tx.send(entry(nodes[0], INC));
+            }
+            for _ in 0..CHURN {
+                tx.send(unroot(&root));
+            }
+
+            heap.read_journals();
+            assert!(heap.deferred.len() < LIMIT);
+        }
+
+        // each round is read in a single pass: the buffer never held more than the cap plus one
+        // round's decrements, where without the cap it would have held every round's
+        assert!(2 * CHURN < BUFFER_RUN);
+        assert!(heap.deferred_peak() >= CHURN);
+        assert!(heap.deferred_peak() <= LIMIT + CHURN);
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        tx.send(unroot(&root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_mature_short_circuit() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let traces = Arc::new(AtomicUsize::new(0));
+        let mature = Box::into_raw(Box::new(Empty { traces: traces.clone() }));
+
+        let mature_root = entry(mature, NEW_INC);
+        let mut tenure = entry(mature, DEC);
+        tenure.vtable |= TENURE_BIT;
+
+        tx.send(mature_root);
+        tx.send(tenure);
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(traces.load(Ordering::SeqCst), 2);
+
+        // young root -> young object -> rooted mature object
+        let middle = Box::into_raw(Box::new(RawEdge {
+            ptr: mature as usize,
+            vtable: entry(mature, 0).vtable,
+        }));
+        let young = Box::into_raw(Box::new(RawEdge {
+            ptr: middle as usize,
+            vtable: entry(middle, 0).vtable,
+        }));
+
+        tx.send(entry(young, NEW_INC));
+        tx.send(entry(middle, NEW));
+        heap.read_journals();
+
+        // the young objects are marked but the mature object is not traced into
+        let report = heap.minor_collection(&mut pool);
+        assert_eq!(report.young_live, 2);
+        assert_eq!(report.dropped, 0);
+        assert_eq!(traces.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_record_replay() {
+        // unique to this run so that concurrent test runs each record their own
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let name = format!("mo-gc-test_record_replay-{}-{}-{:p}.journal",
+                           now.as_secs(),
+                           now.subsec_nanos(),
+                           &now);
+        let path = env::temp_dir().join(name);
+
+        let mut recorded = test_heap();
+        recorded.record_journal(&path).unwrap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        recorded.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(10, &drops);
+        let garbage = ring(1, &drops);
+
+        let root = entry(nodes[0], NEW_INC);
+        tx.send(root);
+        for node in nodes.iter().skip(1) {
+            tx.send(entry(*node, NEW));
+        }
+        tx.send(entry(garbage[0], NEW));
+
+        // two more roots in a batch, then all three are dropped
+        let mut batch = entry(nodes[0], INC);
+        batch.vtable |= BATCH_BIT;
+        tx.send(batch);
+        tx.send(Object { ptr: 2, vtable: 0 });
+        for _ in 0..3 {
+            tx.send(unroot(&root));
+        }
+
+        assert_eq!(recorded.read_journals(), 16);
+        recorded.stop_recording().unwrap();
+
+        // the recording heap never collects, the replaying heap owns the objects
+        let mut replayed = test_heap();
+        assert_eq!(replayed.replay_journal(&path).unwrap(), 16);
+        fs::remove_file(&path).unwrap();
+
+        let state = |heap: &YoungHeap<DefaultLogger, ParHeap>| {
+            heap.live_objects().iter().map(|obj| (obj.ptr, obj.vtable)).collect::<Vec<_>>()
+        };
+        assert_eq!(state(&replayed), state(&recorded));
+        assert_eq!(replayed.deferred.len(), recorded.deferred.len());
+        assert_eq!(replayed.age_bucket_counts(), recorded.age_bucket_counts());
+
+        let mut pool = Pool::new(1);
+        replayed.minor_collection(&mut pool);
+        replayed.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 11);
+        assert_eq!(replayed.live_count(), 0);
+    }
+
+    #[test]
+    fn test_drop_ages() {
+        const AGES: usize = 4;
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap_with(1, AGES);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (x, y, z, w) = (ring(1, &drops), ring(1, &drops), ring(1, &drops), ring(1, &drops));
+
+        let y_root = entry(y[0], NEW_INC);
+        let z_root = entry(z[0], NEW_INC);
+        let w_root = entry(w[0], NEW_INC);
+        let mut w_tenure = entry(w[0], DEC);
+        w_tenure.vtable |= TENURE_BIT;
+
+        // x is never rooted, w goes straight to the mature heap
+        tx.send(entry(x[0], NEW));
+        tx.send(y_root);
+        tx.send(z_root);
+        tx.send(w_root);
+        tx.send(w_tenure);
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        // decrements are merged after the next collection, which y survives a second time
+        tx.send(unroot(&y_root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+
+        // z reaches the oldest bucket before it is dropped
+        tx.send(unroot(&z_root));
+        tx.send(unroot(&w_root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        heap.major_collection(&mut pool);
+
+        assert_eq!(drops.load(Ordering::SeqCst), 4);
+        assert_eq!(heap.logger().drop_age_histogram(), vec![1, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_mature_heap_kinds() {
+        let mut pool = Pool::new(1);
+        let mut config = GcConfig::new();
+        config.num_threads = 1;
+
+        let mut heap = test_heap_from(config, vec![ParHeap::new(1), ParHeap::new(1)]);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let node = |next: *mut Node| {
+            Box::into_raw(Box::new(Node {
+                next: Cell::new(next as usize),
+                drops: drops.clone(),
+            }))
+        };
+
+        // root (kind 0) -> inner (kind 0) -> outer (kind 1), all moved straight to the heaps
+        let outer = node(null_mut());
+        let inner = node(outer);
+        let root = node(inner);
+
+        let root_entry = entry(root, NEW_INC);
+        let kinds = [(root, NEW_INC, 0), (inner, NEW, 0), (outer, NEW, 1)];
+        for &(object, flags, kind) in kinds.iter() {
+            let mut new = entry(object, flags);
+            new.vtable |= KIND_BIT;
+            tx.send(new);
+            tx.send(Object { ptr: kind, vtable: 0 });
+        }
+        for object in [root, inner, outer].iter() {
+            let mut tenure = entry(*object, DEC);
+            tenure.vtable |= TENURE_BIT;
+            tx.send(tenure);
+        }
+        heap.read_journals();
+        assert_eq!(heap.minor_collection(&mut pool).promoted, 3);
+
+        // outer is only reachable through inner, which is not a root
+        assert_eq!(heap.major_collection_of(&mut pool, &[1]).dropped, 0);
+        assert_eq!(heap.major_collection_of(&mut pool, &[0]).dropped, 0);
+
+        tx.send(unroot(&root_entry));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+
+        // inner is garbage but outer is kept until the heap holding inner is collected
+        assert_eq!(heap.major_collection_of(&mut pool, &[1]).dropped, 0);
+        assert_eq!(heap.major_collection_of(&mut pool, &[0]).dropped, 2);
+        assert_eq!(heap.major_collection_of(&mut pool, &[1]).dropped, 1);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+        assert_eq!(heap.live_count(), 0);
+    }
+
+    #[test]
+    fn test_merge_threshold() {
+        let mut pool = Pool::new(4);
+        let mut heap = test_heap_with(4, 1);
+        heap.config.merge_threshold = 16;
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(1, &drops);
+        let key = (nodes[0] as usize) >> ptr_shift();
+
+        let root = entry(nodes[0], NEW_INC);
+        tx.send(root);
+        for _ in 0..9 {
+            tx.send(entry(nodes[0], INC));
+        }
+        for _ in 0..5 {
+            tx.send(unroot(&root));
+        }
+        heap.read_journals();
+
+        // too few decrements to be worth sending to the pool
+        assert!(heap.merge_deferred(&mut pool));
+        assert!(heap.deferred.is_empty());
+        assert!(!heap.roots.get(key).unwrap().unsync_is_unrooted());
+
+        for _ in 0..5 {
+            tx.send(unroot(&root));
+        }
+        heap.read_journals();
+
+        heap.config.merge_threshold = 0;
+        assert!(!heap.merge_deferred(&mut pool));
+        assert!(heap.roots.get(key).unwrap().unsync_is_unrooted());
+
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_invariant_violation_policy() {
+        // a heap that has read a decrement for an object that was never allocated
+        let heap_with = |policy| {
+            let mut heap = test_heap();
+            heap.config.on_violation = policy;
+
+            let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+            heap.add_journal(rx);
+
+            tx.send(Object {
+                ptr: 1 << 20,
+                vtable: 0,
+            });
+            heap.read_journals();
+            heap
+        };
+
+        let mut pool = Pool::new(1);
+
+        // the violation is logged and collection carries on
+        let mut heap = heap_with(InvariantViolation::LogAndContinue);
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+
+        let violations = heap.logger().invariant_violations();
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].contains("not in the roots"));
+
+        // the merge panics
+        let mut heap = heap_with(InvariantViolation::Panic);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| heap.minor_collection(&mut pool)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_entry_kinds() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(3, &drops);
+
+        let root = entry(nodes[0], NEW_INC);
+        tx.send(root);
+        tx.send(entry(nodes[1], NEW));
+        tx.send(entry(nodes[2], NEW));
+
+        for _ in 0..4 {
+            tx.send(entry(nodes[0], INC));
+        }
+
+        // a batched increment counts once, its operand not at all
+        let mut batch = entry(nodes[0], INC);
+        batch.vtable |= BATCH_BIT;
+        tx.send(batch);
+        tx.send(Object {
+            ptr: 2,
+            vtable: 0,
+        });
+
+        for _ in 0..7 {
+            tx.send(unroot(&root));
+        }
+
+        heap.read_journals();
+        assert_eq!(heap.logger().entry_kind_totals(), (2, 1, 5, 7));
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_drain_request() {
+        const ENTRIES: usize = 100000;
+
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        for i in 0..ENTRIES {
+            tx.send(new_entry(i));
+        }
+
+        // read at most BUFFER_RUN entries per pass
+        let first = heap.read_journals();
+        assert!(first < ENTRIES);
+
+        // the rest is read in one go
+        tx.request_drain();
+        assert_eq!(heap.read_journals(), ENTRIES - first);
+        assert_eq!(heap.read_journals(), 0);
+
+        // the request is used up
+        for i in 0..ENTRIES {
+            tx.send(new_entry(ENTRIES + i));
+        }
+        assert_eq!(heap.read_journals(), first);
+    }
+
+    #[test]
+    fn test_drain_request_shares_passes() {
+        const ENTRIES: usize = 100000;
+
+        let mut heap = test_heap();
+
+        let (drained_tx, drained_rx) = make_journal(TEST_BUFFER_SIZE);
+        let (other_tx, other_rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(drained_rx);
+        heap.add_journal(other_rx);
+
+        for i in 0..ENTRIES {
+            drained_tx.send(new_entry(i));
+        }
+        for i in 0..ENTRIES * 2 {
+            other_tx.send(new_entry(ENTRIES + i));
+        }
+
+        drained_tx.request_drain();
+        heap.read_journals();
+
+        // the drained journal is read to empty and the other is read a run in every pass it took
+        let passes = (ENTRIES + BUFFER_RUN - 1) / BUFFER_RUN;
+        assert_eq!(heap.journals[0].1.items_read(), ENTRIES);
+        assert_eq!(heap.journals[1].1.items_read(), passes * BUFFER_RUN);
+    }
+
+    #[test]
+    fn test_journal_drain_time() {
+        const DRAIN_TIME: u64 = 20;
+
+        let mut heap = test_heap();
+        heap.config.journal_drain_time = DRAIN_TIME as usize;
+        heap.clock = ticking_clock;
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let producer_stop = stop.clone();
+
+        // write entries as fast as possible until stopped
+        let producer = thread::spawn(move || {
+            let mut sent = 0;
+            while !producer_stop.load(Ordering::Relaxed) {
+                tx.send(new_entry(sent));
+                sent += 1;
+            }
+            sent
+        });
+
+        // the clock is read at least once every DRAIN_CHECK_RUN entries, so no call reads for
+        // more than DRAIN_TIME readings of it however fast the producer is
+        let mut read = 0;
+        for _ in 0..5 {
+            let count = heap.read_journals();
+            assert!(count < DRAIN_TIME as usize * DRAIN_CHECK_RUN,
+                    "read {} entries in one call",
+                    count);
+            read += count;
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        let sent = producer.join().unwrap();
+
+        // what was left over is read by the following calls
+        loop {
+            let count = heap.read_journals();
+            if count == 0 {
+                break;
+            }
+            read += count;
+        }
+        assert_eq!(read, sent);
+    }
+
+    #[test]
+    fn test_journal_drain_time_within_pass() {
+        const DRAIN_TIME: u64 = 10;
+        const JOURNALS: usize = 256;
+
+        let mut heap = test_heap();
+        heap.config.journal_drain_time = DRAIN_TIME as usize;
+        heap.clock = ticking_clock;
+
+        // a single pass over every journal reads the clock far more than DRAIN_TIME times
+        let mut senders = Vec::new();
+        for j in 0..JOURNALS {
+            let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+            heap.add_journal(rx);
+
+            for i in 0..BUFFER_RUN {
+                tx.send(new_entry(j * BUFFER_RUN + i));
+            }
+            senders.push(tx);
+        }
+
+        // the pass is cut short part way through a journal, at the first check of the clock
+        // after the drain time is up
+        let first = heap.read_journals();
+        assert!(first < DRAIN_TIME as usize * DRAIN_CHECK_RUN,
+                "read {} entries in one call",
+                first);
+        assert!(first % BUFFER_RUN != 0);
+
+        // the journals cut off are read by the following calls
+        let mut read = first;
+        loop {
+            let count = heap.read_journals();
+            if count == 0 {
+                break;
+            }
+            read += count;
+        }
+        assert_eq!(read, JOURNALS * BUFFER_RUN);
+    }
+
+    #[test]
+    fn test_minor_threshold() {
+        const THRESHOLD: usize = 100;
+        const BATCH: usize = 10;
+        const BATCHES: usize = 100;
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+        heap.config.minor_threshold = THRESHOLD;
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut collections = 0;
+
+        for _ in 0..BATCHES {
+            for node in ring(BATCH, &drops) {
+                tx.send(entry(node, NEW));
+            }
+
+            heap.read_journals();
+            if heap.minor_due() {
+                heap.minor_collection(&mut pool);
+                collections += 1;
+            }
+        }
+
+        // one collection per THRESHOLD new objects, each dropping what was read since the last
+        assert_eq!(collections, BATCHES * BATCH / THRESHOLD);
+        assert_eq!(drops.load(Ordering::SeqCst), BATCHES * BATCH);
+        assert!(!heap.minor_due());
+    }
+
+    #[test]
+    fn test_drop_thread() {
+        const OBJECTS: usize = 20;
+
+        /// Records the name of the thread it is dropped on
+        struct Named {
+            threads: Arc<Mutex<Vec<Option<String>>>>,
+        }
+
+        unsafe impl Trace for Named {}
+
+        impl Drop for Named {
+            fn drop(&mut self) {
+                let name = thread::current().name().map(String::from);
+                self.threads.lock().unwrap().push(name);
+            }
+        }
+
+        let mut config = GcConfig::new();
+        config.num_threads = 1;
+        config.drop_thread = true;
+
+        let mut heap = test_heap_from(config, vec![ParHeap::new(1)]);
+        let mut pool = Pool::new(1);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..OBJECTS {
+            let named = Box::into_raw(Box::new(Named { threads: threads.clone() }));
+            tx.send(entry(named, NEW));
+        }
+        heap.read_journals();
+
+        // the sweep only queues the objects
+        let report = heap.minor_collection(&mut pool);
+        assert_eq!(report.dropped, OBJECTS);
+
+        // every queued object is dropped before the heap is gone, on the drop thread rather than
+        // on this thread or a worker of the pool
+        drop(heap);
+        let threads = threads.lock().unwrap();
+        assert_eq!(threads.len(), OBJECTS);
+        assert!(threads.iter().all(|name| name.as_ref().map(|n| &n[..]) == Some("gc-drop")),
+                "dropped on {:?}",
+                *threads);
+    }
+
+    #[test]
+    fn test_journals_read_during_mark() {
+        const NODES: usize = 1000;
+        const ROUNDS: usize = 64;
+
+        /// Holds up the first mark that traces it until released, then traces the ring
+        struct Gate {
+            barrier: Mutex<Option<Arc<Barrier>>>,
+            next: *const Node,
+            drops: Arc<AtomicUsize>,
+        }
+
+        unsafe impl Trace for Gate {
+            fn traversible(&self) -> bool {
+                true
+            }
+
+            unsafe fn trace(&self, stack: &mut TraceStack) {
+                let barrier = self.barrier.lock().unwrap().take();
+                if let Some(barrier) = barrier {
+                    // the mark has started, wait to be released
+                    barrier.wait();
+                    barrier.wait();
+                }
+
+                stack.push_to_trace(&*self.next);
+            }
+        }
+
+        impl Drop for Gate {
+            fn drop(&mut self) {
+                self.drops.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        // a mature heap whose mark is held up for as long as the test likes
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(NODES, &drops);
+        let barrier = Arc::new(Barrier::new(2));
+        let gate = Box::into_raw(Box::new(Gate {
+            barrier: Mutex::new(Some(barrier.clone())),
+            next: nodes[0],
+            drops: drops.clone(),
+        }));
+
+        let mut mature = ParHeap::new(2);
+        for &node in nodes.iter() {
+            let object = entry(node, NEW);
+            mature.add_object(object.ptr >> ptr_shift(), object.vtable);
+        }
+        let object = entry(gate, NEW);
+        mature.add_object(object.ptr >> ptr_shift(), object.vtable);
+
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+        config.mark_journal_interval = 1;
+
+        let mut heap = test_heap_from(config, vec![mature]);
+        let mut pool = Pool::new(2);
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let root = entry(gate, INC);
+        tx.send(root);
+        heap.read_journals();
+
+        // while the mark is held, an app thread fills buffers with balanced root changes one
+        // after another, each of which the GC thread moves out of the buffers before it is
+        // released
+        let sender = thread::spawn(move || {
+            barrier.wait();
+
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let mut read = 0;
+            'rounds: while read < ROUNDS {
+                for _ in 0..TEST_BUFFER_SIZE / 2 {
+                    tx.send(root);
+                    tx.send(unroot(&root));
+                }
+
+                while tx.outstanding_buffers() > 1 {
+                    if Instant::now() >= deadline {
+                        break 'rounds;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+                read += 1;
+            }
+
+            // release the mark whatever happened, so that a failure does not hang the test
+            barrier.wait();
+            (tx, read)
+        });
+
+        heap.major_collection(&mut pool);
+
+        let (tx, read) = sender.join().unwrap();
+        assert_eq!(read, ROUNDS, "journal not read during the mark");
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        // the prefetched entries are read as usual, leaving the ring rooted once until unrooted
+        tx.send(unroot(&root));
+        tx.request_drain();
+        heap.read_journals();
+
+        heap.major_collection(&mut pool);
+        heap.major_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), NODES + 1);
+    }
+
+    #[test]
+    fn test_eager_promotion_over_limit() {
+        const AGES: usize = 4;
+        const COUNT: usize = 10;
+
+        let mut pool = Pool::new(1);
+
+        let mut relaxed = test_heap_with(1, AGES);
+        let mut pressed = test_heap_with(1, AGES);
+        pressed.config.heap_limit = COUNT / 2;
+
+        let mut results = Vec::new();
+
+        for heap in vec![&mut relaxed, &mut pressed] {
+            let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+            heap.add_journal(rx);
+
+            let roots: Vec<_> = (0..COUNT).map(|i| new_rooted(i)).collect();
+            for root in roots.iter() {
+                tx.send(*root);
+            }
+            heap.read_journals();
+            heap.minor_collection(&mut pool);
+
+            let over_limit = heap.is_over_limit();
+            let promoted = heap.major_collection(&mut pool).promoted;
+
+            // unroot everything: the first minor collection merges the decrements
+            for root in roots.iter() {
+                tx.send(unroot(root));
+            }
+            heap.read_journals();
+            heap.minor_collection(&mut pool);
+            let dropped = heap.major_collection(&mut pool).dropped;
+
+            results.push((over_limit, promoted, dropped));
+
+            // clean up
+            heap.minor_collection(&mut pool);
+            assert_eq!(heap.live_count(), 0);
+        }
+
+        // without pressure the young objects are too young to promote and a major collection
+        // does not reclaim them
+        assert_eq!(results[0], (false, 0, 0));
+
+        // under pressure they are promoted straight away and the major collection drops them
+        assert_eq!(results[1], (true, COUNT, COUNT));
+        assert!(!pressed.is_over_limit());
+    }
+
+    #[test]
+    fn test_over_limit_hysteresis() {
+        let mut heap = test_heap_with(1, 1);
+        heap.config.heap_limit = 8;
+
+        // the low water mark is three quarters of the limit
+        let steps = [(8, false), (9, true), (8, true), (7, true), (6, false), (8, false)];
+        for &(live, over_limit) in steps.iter() {
+            heap.young_live = live;
+            heap.update_over_limit();
+            assert_eq!(heap.is_over_limit(), over_limit, "{} objects live", live);
+        }
+    }
+
+    #[test]
+    fn test_ephemeral_never_promoted() {
+        const AGES: usize = 2;
+        const CYCLES: usize = 5;
+
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap_with(1, AGES);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(2, &drops);
+        for node in nodes.iter() {
+            unsafe { &**node }.next.set(0);
+        }
+
+        // the first node is ephemeral, the second an ordinary object
+        let mut ephemeral = entry(nodes[0], NEW_INC);
+        ephemeral.vtable |= KIND_BIT;
+        tx.send(ephemeral);
+        tx.send(Object {
+            ptr: EPHEMERAL_KIND,
+            vtable: 0,
+        });
+        tx.send(entry(nodes[1], NEW_INC));
+        heap.read_journals();
+
+        for _ in 0..CYCLES {
+            heap.minor_collection(&mut pool);
+            heap.major_collection(&mut pool);
+        }
+
+        // only the ordinary object was promoted, the ephemeral one stays in the last bucket
+        let key = (nodes[0] as usize) >> ptr_shift();
+        assert!(heap.roots.get(key).unwrap().is_ephemeral());
+        assert_eq!(heap.age_bucket_counts(), vec![0, 1]);
+        assert_eq!(heap.mature_count(), 1);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        // still not promoted under memory pressure
+        heap.config.heap_limit = 1;
+        heap.minor_collection(&mut pool);
+        assert!(heap.is_over_limit());
+        assert_eq!(heap.major_collection(&mut pool).promoted, 0);
+
+        // collected by a minor collection once unrooted
+        tx.send(unroot(&ephemeral));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert_eq!(heap.age_bucket_counts(), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_trace_stacks_reused() {
+        const THREADS: usize = 2;
+        const RING: usize = 100;
+
+        let mut pool = Pool::new(THREADS);
+        let mut heap = test_heap_with(THREADS, 4);
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(RING, &drops);
+        tx.send(entry(nodes[0], NEW_INC));
+        for node in nodes[1..].iter() {
+            tx.send(entry(*node, NEW));
+        }
+        heap.read_journals();
+
+        heap.minor_collection(&mut pool);
+        let mut capacities = heap.stacks.capacities();
+        capacities.sort();
+
+        for _ in 0..50 {
+            heap.minor_collection(&mut pool);
+        }
+
+        // no stacks were allocated after the first collection and they kept their capacity
+        assert!(heap.stacks.created() <= heap.config.shard_count());
+        assert!(capacities.iter().any(|&capacity| capacity > 0));
+
+        let mut reused = heap.stacks.capacities();
+        reused.sort();
+        assert_eq!(reused, capacities);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let vertices: Vec<_> = (0..7)
+            .map(|_| Box::into_raw(Box::new(Vertex { edges: RefCell::new(Vec::new()) })))
+            .collect();
+        let addr = |index: usize| vertices[index] as usize;
+
+        // 0 <-> 1 with 0 rooted, 2 -> 3 -> 4 -> 2 unrooted, 5 -> 2 is garbage outside the cycle
+        // and 6 points at itself
+        let edges = vec![(0, 1), (1, 0), (2, 3), (3, 4), (4, 2), (5, 2), (6, 6)];
+        for &(from, to) in edges.iter() {
+            unsafe { &*vertices[from] }.edges.borrow_mut().push(addr(to));
+        }
+
+        for (index, vertex) in vertices.iter().enumerate() {
+            tx.send(entry(*vertex, if index == 0 { NEW_INC } else { NEW }));
+        }
+        heap.read_journals();
+
+        let mut ring = vec![addr(2), addr(3), addr(4)];
+        ring.sort();
+        let mut expected = vec![ring, vec![addr(6)]];
+        expected.sort();
+
+        assert_eq!(heap.find_cycles(), expected);
+
+        // nothing was marked or collected
+        assert_eq!(heap.verify(), Ok(()));
+        assert_eq!(heap.roots.iter().count(), 7);
+    }
+
+    #[test]
+    fn test_untrack() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(2, &drops);
+
+        // the first node was rooted and then unrooted, the second was never rooted
+        let rooted = entry(nodes[0], NEW_INC);
+        tx.send(rooted);
+        tx.send(entry(nodes[1], NEW));
+        heap.read_journals();
+        heap.collect_all(&mut pool);
+
+        tx.send(unroot(&rooted));
+        for node in nodes.iter() {
+            let mut untrack = entry(*node, DEC);
+            untrack.vtable |= UNTRACK_BIT;
+            tx.send(untrack);
+        }
+        heap.read_journals();
+
+        // neither is known to the GC any longer, nor dropped by it
+        for _ in 0..3 {
+            heap.collect_all(&mut pool);
+        }
+
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        assert_eq!(heap.live_objects().len(), 0);
+        assert_eq!(heap.verify(), Ok(()));
+
+        // the memory is still valid and owned by the caller
+        assert_eq!(unsafe { &*nodes[0] }.next.get(), nodes[1] as usize);
+        for node in nodes {
+            unsafe { drop(Box::from_raw(node)) };
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_background_major() {
+        const NODES: usize = 100000;
+        const ROUNDS: usize = 50;
+        const PER_ROUND: usize = 1000;
+
+        // a big mature heap, rooted at its first node
+        let drops = Arc::new(AtomicUsize::new(0));
+        let nodes = ring(NODES, &drops);
+
+        let (gate, gate_rx) = mpsc::channel();
+        let mut mature = GatedHeap {
+            heap: ParHeap::new(2),
+            gate: gate_rx,
+        };
+        for &node in nodes.iter() {
+            let object = entry(node, NEW);
+            mature.add_object(object.ptr >> ptr_shift(), object.vtable);
+        }
+
+        let mut config = GcConfig::new();
+        config.num_threads = 2;
+        config.background_major = true;
+
+        let mut heap = test_heap_from(config, vec![mature]);
+        let mut pool = Pool::new(2);
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let root = entry(nodes[0], INC);
+        tx.send(root);
+        heap.read_journals();
+
+        assert!(heap.start_major());
+        assert!(heap.major_running());
+        assert!(!heap.start_major());
+
+        // the app thread keeps allocating short lived objects while the major collection is held
+        // open, and the minor collections keep up with it
+        let young_drops = Arc::new(AtomicUsize::new(0));
+        for _ in 0..ROUNDS {
+            for _ in 0..PER_ROUND {
+                let object = new_rooted(Node {
+                    next: Cell::new(0),
+                    drops: young_drops.clone(),
+                });
+                tx.send(object);
+                tx.send(unroot(&object));
+            }
+
+            tx.request_drain();
+            heap.read_journals();
+
+            // decrements read now take effect in the next collection
+            let report = heap.minor_collection(&mut pool);
+            assert!(report.young_live <= 2 * PER_ROUND, "{} young objects", report.young_live);
+        }
+
+        assert!(heap.major_running());
+        assert!(heap.poll_major().is_none());
+        assert!(young_drops.load(Ordering::SeqCst) >= (ROUNDS - 1) * PER_ROUND);
+
+        // the ring is still rooted
+        gate.send(()).unwrap();
+        let report = heap.finish_major().unwrap();
+        assert!(report.major);
+        assert_eq!(report.dropped, 0);
+        assert_eq!(report.mature_live, NODES);
+        assert!(!heap.major_running());
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        // once unrooted the ring is dropped by the next major collections
+        tx.send(unroot(&root));
+        tx.request_drain();
+        heap.read_journals();
+
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+        heap.major_collection(&mut pool);
+        heap.major_collection(&mut pool);
+        assert_eq!(drops.load(Ordering::SeqCst), NODES);
+
+        heap.minor_collection(&mut pool);
+        assert_eq!(young_drops.load(Ordering::SeqCst), ROUNDS * PER_ROUND);
+    }
+
+    thread_local!(static PROMOTIONS: RefCell<Vec<(usize, usize, usize)>> =
+        RefCell::new(Vec::new()));
+
+    fn record_promotion(ptr: usize, vtable: usize, age: usize) {
+        PROMOTIONS.with(|promotions| promotions.borrow_mut().push((ptr, vtable, age)));
+    }
+
+    #[test]
+    fn test_on_promote() {
+        let mut pool = Pool::new(1);
+
+        let mut config = GcConfig::new();
+        config.num_threads = 1;
+        config.nursery_ages = 3;
+        config.on_promote = Some(record_promotion);
+
+        let mut heap = test_heap_from(config, vec![ParHeap::new(1)]);
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let first = Box::into_raw(Box::new(1usize));
+        let second = Box::into_raw(Box::new(2usize));
+        tx.send(entry(first, NEW_INC));
+        tx.send(entry(second, NEW_INC));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+
+        // too young to be promoted
+        tx.send(new_rooted(3usize));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        heap.promote();
+        heap.promote();
+
+        let vtable = entry(first, 0).vtable;
+        let mut promotions = PROMOTIONS.with(|promotions| promotions.borrow().clone());
+        promotions.sort();
+
+        let mut expected = vec![(first as usize, vtable, 2), (second as usize, vtable, 2)];
+        expected.sort();
+        assert_eq!(promotions, expected);
+    }
+
+    #[test]
+    fn test_user_flags() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap_with(1, 3);
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let root = new_rooted(1usize);
+        tx.send(root);
+        heap.read_journals();
+
+        let addr = root.ptr & PTR_MASK;
+        let ptr = addr >> ptr_shift();
+        assert!(heap.set_user_flag(addr, 0, true));
+        assert!(heap.set_user_flag(addr, USER_FLAG_BITS - 1, true));
+        assert!(heap.set_user_flag(addr, 3, true));
+        assert!(heap.set_user_flag(addr, 3, false));
+
+        // not in the root set
+        assert!(!heap.set_user_flag(addr + 1024, 0, true));
+        assert_eq!(heap.user_flag(addr + 1024, 0), None);
+
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+
+        // the object aged and was marked and unmarked around the user flags
+        {
+            let meta = heap.roots.get(ptr).unwrap();
+            assert_eq!(meta.age(), 2);
+            assert!(meta.is_new());
+            assert!(!meta.is_marked());
+            assert_eq!(meta.kind(), 0);
+
+            let flags: Vec<_> = (0..USER_FLAG_BITS)
+                .map(|bit| heap.user_flag(addr, bit).unwrap())
+                .collect();
+            let mut expected = vec![false; USER_FLAG_BITS];
+            expected[0] = true;
+            expected[USER_FLAG_BITS - 1] = true;
+            assert_eq!(flags, expected);
+        }
+
+        heap.major_collection(&mut pool);
+
+        assert!(!heap.roots.get(ptr).unwrap().is_new());
+        assert_eq!(heap.user_flag(addr, 0), Some(true));
+        assert_eq!(heap.user_flag(addr, USER_FLAG_BITS - 1), Some(true));
+    }
+
+    #[test]
+    fn test_topological_drop() {
+        const THREADS: usize = 2;
+
+        let mut pool = Pool::new(THREADS);
+        let mut heap = test_heap_with(THREADS, 1);
+        heap.config.topological_drop = true;
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let chain = |ids: &[usize]| -> Vec<*mut Chained> {
+            let links: Vec<_> = ids.iter()
+                .map(|&id| {
+                    Box::into_raw(Box::new(Chained {
+                        id: id,
+                        child: Cell::new(0),
+                        log: log.clone(),
+                    }))
+                })
+                .collect();
+
+            for pair in links.windows(2) {
+                unsafe { &*pair[0] }.child.set(pair[1] as usize);
+            }
+            links
+        };
+
+        // a chain 0 -> 1 -> .. -> 7, journaled parent first
+        let links = chain(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        for link in links.iter() {
+            tx.send(entry(*link, NEW));
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        assert_eq!(*log.lock().unwrap(), vec![7, 6, 5, 4, 3, 2, 1, 0]);
+
+        // a cycle is dropped whole, in either order
+        log.lock().unwrap().clear();
+        let cycle = chain(&[8, 9]);
+        unsafe { &*cycle[1] }.child.set(cycle[0] as usize);
+        for link in cycle.iter() {
+            tx.send(entry(*link, NEW));
+        }
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        let mut dropped = log.lock().unwrap().clone();
+        dropped.sort();
+        assert_eq!(dropped, vec![8, 9]);
+    }
+
+    #[test]
+    fn test_leaf_hint_after_promotion() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+        heap.config.learn_leaves = true;
+
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let parent = Box::into_raw(Box::new(Chained {
+            id: 0,
+            child: Cell::new(0),
+            log: log.clone(),
+        }));
+
+        // learn the childless parent as a leaf, then promote it and clean its cards
+        tx.send(entry(parent, NEW_INC));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+        heap.promote();
+        heap.minor_collection(&mut pool);
+
+        // store a new child through DerefMut, which dirties the parent's card
+        let child = Box::into_raw(Box::new(Chained {
+            id: 1,
+            child: Cell::new(0),
+            log: log.clone(),
+        }));
+        heap.cards.dirty(parent as usize);
+        unsafe { &*parent }.child.set(child as usize);
+
+        tx.send(entry(child, NEW));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+        heap.minor_collection(&mut pool);
+
+        assert!(log.lock().unwrap().is_empty(), "child dropped while reachable");
+    }
+
+    #[test]
+    fn test_deferred_limit_waits_for_increments() {
+        let mut heap = test_heap();
+        heap.config.deferred_limit = 1;
+
+        let (first_tx, first_rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        let (second_tx, second_rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(first_rx);
+        heap.add_journal(second_rx);
+
+        // the first pass stops reading the first journal just short of the object's entries, but
+        // reads the decrement of the clone from the second journal
+        let filler = Box::into_raw(Box::new(0usize));
+        first_tx.send(entry(filler, NEW_INC));
+        for _ in 1..BUFFER_RUN {
+            first_tx.send(entry(filler, INC));
+        }
+
+        let object = Box::into_raw(Box::new(1usize));
+        first_tx.send(entry(object, NEW_INC));
+        first_tx.send(entry(object, INC));
+        second_tx.send(entry(object, DEC));
+
+        // applying the decrement before the object is read would be reported as a violation
+        heap.read_journals();
+
+        let meta = heap.roots.get(object as usize >> ptr_shift()).expect("object not read");
+        assert_eq!(meta.refcount(), 1);
+        assert!(heap.deferred.is_empty());
+    }
+
+    #[test]
+    fn test_read_operand_waits_for_writer() {
+        let (tx, rx) = make_journal(TEST_BUFFER_SIZE);
+
+        // the operand turns up only after the reader has started waiting for it
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            tx.send(Object { ptr: 8, vtable: 3 });
+            tx
+        });
+
+        let operand = read_operand(&rx);
+        assert_eq!(operand.ptr, 8);
+        assert_eq!(operand.vtable, 3);
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_major_dry_run() {
+        let mut pool = Pool::new(1);
+        let mut heap = test_heap();
+
+        let (tx, rx) = make_journal(JOURNAL_BUFFER_SIZE);
+        heap.add_journal(rx);
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let live = ring(5, &drops);
+        let doomed = ring(3, &drops);
+
+        let doomed_root = entry(doomed[0], NEW_INC);
+        tx.send(entry(live[0], NEW_INC));
+        tx.send(doomed_root);
+        for node in live.iter().skip(1).chain(doomed.iter().skip(1)) {
+            tx.send(entry(*node, NEW));
+        }
+        heap.read_journals();
+        heap.major_collection(&mut pool);
+
+        tx.send(unroot(&doomed_root));
+        heap.read_journals();
+        heap.minor_collection(&mut pool);
+
+        // counting twice drops nothing and leaves nothing marked
+        assert_eq!(heap.major_dry_run(&mut pool), Some(3));
+        assert_eq!(heap.major_dry_run(&mut pool), Some(3));
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        assert_eq!(heap.major_collection(&mut pool).dropped, 3);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
     }
 }